@@ -131,6 +131,152 @@ pub enum PerpsError {
     InvalidStopLossCondition,
     #[msg("Stop loss condition not met")]
     StopLossConditionNotMet,
+
+    // Withdrawal queue errors
+    #[msg("Queued withdrawal is not claimable yet")]
+    WithdrawalNotReady,
+    #[msg("Pending withdrawal account required for this close")]
+    PendingWithdrawalRequired,
+
+    // Invariant check errors
+    #[msg("Position does not belong to this market")]
+    PositionMarketMismatch,
+    #[msg("Position account passed more than once")]
+    DuplicatePositionAccount,
+
+    // Position lifecycle errors
+    #[msg("Position is already open")]
+    PositionAlreadyOpen,
+    #[msg("Position is being liquidated")]
+    PositionBeingLiquidated,
+    #[msg("Position payout is pending settlement")]
+    PositionPendingSettlement,
+
+    // Take profit errors
+    #[msg("Invalid take profit configuration")]
+    InvalidTakeProfit,
+    #[msg("Take profit conditions not met")]
+    TakeProfitNotTriggered,
+    #[msg("User orders account required to place an order")]
+    UserOrdersRequired,
+
+    // Market expiry errors
+    #[msg("Market is close-only ahead of expiry")]
+    MarketCloseOnly,
+    #[msg("Market has not expired yet")]
+    MarketNotExpired,
+    #[msg("Market has already been settled")]
+    MarketAlreadySettled,
+
+    // Rate limit errors
+    #[msg("User rate limit account required while withdrawals are rate limited")]
+    UserRateLimitRequired,
+
+    // Skew surcharge errors
+    #[msg("Market insurance fund accounts required to pay the skew surcharge")]
+    InsuranceFundRequired,
+
+    // Dead-oracle errors
+    #[msg("Market oracle is marked dead; positions can only be emergency settled")]
+    OracleMarkedDead,
+    #[msg("Emergency settlement needs the market's oracle marked dead")]
+    OracleNotMarkedDead,
+
+    // Netting errors
+    #[msg("Netted positions must be on opposite sides of the market")]
+    PositionsNotOpposed,
+
+    // AMM errors
+    #[msg("AMM was rebalanced too recently")]
+    AmmRebalanceTooSoon,
+
+    // Creator reward errors
+    #[msg("No creator rewards have accrued")]
+    NoCreatorRewards,
+
+    // Versioning errors
+    #[msg("Account was written by an older program version and needs migrating")]
+    AccountNeedsMigration,
+
+    // Limit order errors
+    #[msg("A limit order is already resting in this market")]
+    LimitOrderActive,
+    #[msg("Mark price has not reached the limit price")]
+    LimitPriceNotReached,
+
+    // Cross margin errors
+    #[msg("Cross-margin positions are not supported by this instruction")]
+    CrossMarginUnsupported,
+    #[msg("Cross margin account and cross vault required for a cross-margin position")]
+    CrossMarginAccountRequired,
+    #[msg("Every other open cross-margin position must be passed")]
+    CrossPositionsMissing,
+
+    // Trailing stop errors
+    #[msg("Mark price has not retraced to the trailing stop")]
+    TrailingStopNotTriggered,
+
+    // Market creation errors
+    #[msg("Market symbol must be non-empty, zero padded and without surrounding whitespace")]
+    InvalidMarketSymbol,
+    #[msg("A market with this symbol already exists")]
+    MarketAlreadyExists,
+
+    // Auto-deleveraging errors
+    #[msg("The insurance fund still covers the market's bad debt")]
+    AdlNotRequired,
+    #[msg("No profitable position on the deleveraged side was passed")]
+    NoAdlCandidates,
+
+    // Creator reward errors
+    #[msg("Amount is zero or more than the creator has accrued")]
+    ExceedsCreatorRewards,
+
+    // Trading fee errors
+    #[msg("Fee destination account required when the fill pays a fee or rebate")]
+    FeeDestinationRequired,
+
+    // Keeper crank errors
+    #[msg("Too many positions passed to one batch liquidation")]
+    BatchTooLarge,
+
+    // Oracle TWAP errors
+    #[msg("The market prices risk at a TWAP; pass the oracle's TWAP account")]
+    OracleTwapRequired,
+
+    // Multi-collateral errors
+    #[msg("Collateral mint is not accepted or is disabled")]
+    CollateralNotAccepted,
+    #[msg("Position is margined with pledged collateral; pass its collateral accounts")]
+    PledgedCollateralRequired,
+    #[msg("Pledged collateral can't be settled on this path")]
+    PledgedCollateralUnsupported,
+    #[msg("Collateral factor must be between 1 and 10000 bps")]
+    InvalidCollateralFactor,
+
+    // Admin handover errors
+    #[msg("No admin handover has been proposed")]
+    NoPendingAdmin,
+
+    // Order expiry errors
+    #[msg("Order has expired")]
+    OrderExpired,
+    #[msg("Order has not expired")]
+    OrderNotExpired,
+    #[msg("Order expiry must be in the future")]
+    InvalidOrderExpiry,
+
+    // Token program errors
+    #[msg("Token program does not own the mint")]
+    TokenProgramMismatch,
+
+    // Holding period errors
+    #[msg("Position cannot be closed before the market's minimum holding period")]
+    HoldingPeriodNotElapsed,
+
+    // Position transfer errors
+    #[msg("Position has resting orders that are not being moved with it")]
+    PositionHasPendingOrders,
 }
 
 impl PerpsError {
@@ -153,6 +299,9 @@ impl PerpsError {
             PerpsError::InsufficientFunds => 6028,
             PerpsError::WouldBeLiquidated => 6029,
             PerpsError::InvalidClosePercentage => 6030,
+            PerpsError::PositionAlreadyOpen => 6031,
+            PerpsError::PositionBeingLiquidated => 6032,
+            PerpsError::PositionPendingSettlement => 6033,
             
             // Oracle errors start at 6040
             PerpsError::BadOracle => 6040,
@@ -168,6 +317,8 @@ impl PerpsError {
             PerpsError::InvalidMarketParameters => 6062,
             PerpsError::InsufficientLiquidity => 6063,
             PerpsError::MarketImpactTooHigh => 6064,
+            PerpsError::PositionMarketMismatch => 6065,
+            PerpsError::DuplicatePositionAccount => 6066,
             
             // Access control start at 6080
             PerpsError::Unauthorized => 6080,
@@ -192,6 +343,8 @@ impl PerpsError {
             PerpsError::FundingRateError => 6140,
             PerpsError::SettlementError => 6141,
             PerpsError::FundingPaymentFailed => 6142,
+            PerpsError::WithdrawalNotReady => 6143,
+            PerpsError::PendingWithdrawalRequired => 6144,
             
             // Protocol state start at 6160
             PerpsError::ProtocolPaused => 6160,
@@ -210,6 +363,45 @@ impl PerpsError {
             PerpsError::WouldCauseLiquidation => 6187,
             PerpsError::InvalidStopLossCondition => 6188,
             PerpsError::StopLossConditionNotMet => 6189,
+            PerpsError::InvalidTakeProfit => 6190,
+            PerpsError::TakeProfitNotTriggered => 6191,
+            PerpsError::UserOrdersRequired => 6192,
+            PerpsError::MarketCloseOnly => 6193,
+            PerpsError::MarketNotExpired => 6194,
+            PerpsError::MarketAlreadySettled => 6195,
+            PerpsError::UserRateLimitRequired => 6196,
+            PerpsError::InsuranceFundRequired => 6197,
+            PerpsError::OracleMarkedDead => 6198,
+            PerpsError::OracleNotMarkedDead => 6199,
+            PerpsError::PositionsNotOpposed => 6200,
+            PerpsError::AmmRebalanceTooSoon => 6201,
+            PerpsError::NoCreatorRewards => 6202,
+            PerpsError::AccountNeedsMigration => 6203,
+            PerpsError::LimitOrderActive => 6204,
+            PerpsError::LimitPriceNotReached => 6205,
+            PerpsError::CrossMarginUnsupported => 6206,
+            PerpsError::CrossMarginAccountRequired => 6207,
+            PerpsError::CrossPositionsMissing => 6208,
+            PerpsError::TrailingStopNotTriggered => 6209,
+            PerpsError::InvalidMarketSymbol => 6210,
+            PerpsError::MarketAlreadyExists => 6211,
+            PerpsError::AdlNotRequired => 6212,
+            PerpsError::NoAdlCandidates => 6213,
+            PerpsError::ExceedsCreatorRewards => 6214,
+            PerpsError::FeeDestinationRequired => 6215,
+            PerpsError::BatchTooLarge => 6216,
+            PerpsError::OracleTwapRequired => 6217,
+            PerpsError::CollateralNotAccepted => 6218,
+            PerpsError::PledgedCollateralRequired => 6219,
+            PerpsError::PledgedCollateralUnsupported => 6220,
+            PerpsError::InvalidCollateralFactor => 6221,
+            PerpsError::NoPendingAdmin => 6222,
+            PerpsError::OrderExpired => 6223,
+            PerpsError::OrderNotExpired => 6224,
+            PerpsError::InvalidOrderExpiry => 6225,
+            PerpsError::TokenProgramMismatch => 6226,
+            PerpsError::HoldingPeriodNotElapsed => 6227,
+            PerpsError::PositionHasPendingOrders => 6228,
         }
     }
 
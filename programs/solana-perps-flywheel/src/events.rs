@@ -9,6 +9,9 @@ pub struct PositionOpened {
     pub entry_price_fp: u128,
     pub leverage: u16,
     pub margin_deposited: u64,
+    pub skew_surcharge_bps: u16,        // Entry surcharge for deepening the skew (0 = none)
+    pub skew_surcharge: u64,            // Paid into the market's insurance fund
+    pub taker_fee: u64,                 // Paid to the fee destination
 }
 
 #[event]
@@ -21,12 +24,108 @@ pub struct PositionClosed {
 }
 
 #[event]
-pub struct Liquidated { 
-    pub user: Pubkey, 
-    pub market: Pubkey, 
-    pub seized_collateral: u64,
-    pub liquidator: Pubkey,
-    pub liquidation_price_fp: u128,
+pub struct CollateralDeposited {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub amount: u64,
+    pub balance: u64,
+}
+
+#[event]
+pub struct CollateralWithdrawn {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub amount: u64,
+    pub balance: u64,
+}
+
+#[event]
+pub struct CollateralPledged {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,                    // Tokens added to the pledge
+    pub margin_value: u64,              // Quote margin they count for
+    pub total_pledged: u64,             // Tokens now behind the position
+}
+
+#[event]
+pub struct PledgedCollateralReleased {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub mint: Pubkey,
+    pub returned: u64,                  // Tokens handed back to the owner
+    pub seized: u64,                    // Tokens kept by the protocol for a loss
+    pub remaining: u64,                 // Tokens still behind the position
+}
+
+#[event]
+pub struct CrossCollateralDeposited {
+    pub user: Pubkey,
+    pub amount: u64,
+    pub balance: u64,
+}
+
+#[event]
+pub struct CrossCollateralWithdrawn {
+    pub user: Pubkey,
+    pub amount: u64,
+    pub balance: u64,
+}
+
+#[event]
+pub struct PositionsNetted {
+    pub user: Pubkey,               // Receives the single settlement
+    pub hedge_owner: Pubkey,        // Owner of the opposing position
+    pub market: Pubkey,
+    pub net_size: u64,              // Size the fee was charged on
+    pub pnl_fp: i128,               // Combined PnL of both legs
+    pub fees_fp: u128,
+    pub settlement_amount: u64,
+}
+
+#[event]
+pub struct OrderNetted {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,              // Side of the order
+    pub order_size: u64,
+    pub closed_size: u64,           // Units of the held position realized
+    pub opened_size: u64,           // Units added on the order's side
+    pub entry_price_fp: u128,       // Position's entry price afterwards
+    pub pnl_fp: i128,               // Realized on the closed units
+    pub fees_paid: u64,
+    pub settlement_amount: u64,
+}
+
+#[event]
+pub struct MakerRebatePaid {
+    pub maker: Pubkey,
+    pub market: Pubkey,
+    pub notional_fp: u128,              // Exit notional the rebate was earned on
+    pub rebate_amount: u64,
+    pub rebate_bps: u16,
+    pub total_rebates_paid: u64,        // Protocol-wide, after this payment
+}
+
+#[allow(deprecated)] // The event's own generated impls name it
+pub use legacy::Liquidated;
+
+#[allow(deprecated)]
+mod legacy {
+    use super::*;
+
+    /// Emitted by the basic `liquidate` path alongside `LiquidationExecuted`
+    /// while indexers move over to the unified event
+    #[deprecated(note = "index LiquidationExecuted, which both liquidation paths emit")]
+    #[event]
+    pub struct Liquidated { 
+        pub user: Pubkey, 
+        pub market: Pubkey, 
+        pub seized_collateral: u64,
+        pub liquidator: Pubkey,
+        pub liquidation_price_fp: u128,
+    }
 }
 
 #[event]
@@ -51,6 +150,14 @@ pub struct OracleUpdated {
     pub old_price_fp: u128,
     pub new_price_fp: u128,
     pub confidence_fp: u128,
+    pub num_publishers: u8,
+}
+
+#[event]
+pub struct OracleSourceUsed {
+    pub market: Pubkey,
+    pub source: crate::state::OracleSource, // First source in the market's priority that passed validation
+    pub price_fp: u128,
 }
 
 #[event]
@@ -145,20 +252,156 @@ pub struct StopLossExecuted {
     pub executor: Pubkey,
 }
 
-// Enhanced Liquidation Events
+#[event]
+pub struct TakeProfitSet {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub trigger_price_fp: u128,
+    pub close_percentage: u8,
+    pub is_long: bool,
+}
+
+#[event]
+pub struct TakeProfitExecuted {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub trigger_price_fp: u128,
+    pub close_percentage: u8,
+    pub executor: Pubkey,
+}
+
+#[event]
+pub struct TrailingStopSet {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub trail_distance_bps: u16,
+    pub high_water_mark_fp: u128,
+    pub close_percentage: u8,
+    pub is_long: bool,
+}
+
+#[event]
+pub struct TrailingStopUpdated {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub high_water_mark_fp: u128,
+    pub trigger_price_fp: u128,
+}
+
+#[event]
+pub struct TrailingStopExecuted {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub high_water_mark_fp: u128,
+    pub trigger_price_fp: u128,
+    pub close_percentage: u8,
+    pub executor: Pubkey,
+}
+
+#[event]
+pub struct LimitOrderPlaced {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub limit_price_fp: u128,
+    pub quote_to_spend: u64,
+    pub leverage: u16,
+    pub margin_escrowed: u64,
+}
+
+#[event]
+pub struct LimitOrderFilled {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub limit_price_fp: u128,
+    pub fill_price_fp: u128,
+    pub base_size: u64,
+    pub margin_deposited: u64,
+    pub skew_surcharge: u64,
+    pub maker_fee: i64,                 // Out of the escrow, or a rebate added to the margin when negative
+    pub executor: Pubkey,
+}
+
+#[event]
+pub struct LimitOrderCancelled {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub margin_refunded: u64,
+}
+
+#[event]
+pub struct ExpiredOrderClosed {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub order: Pubkey,
+    pub margin_refunded: u64,
+    pub executor: Pubkey,
+}
+
+#[event]
+pub struct PositionTransferred {
+    pub market: Pubkey,
+    pub previous_owner: Pubkey,
+    pub new_owner: Pubkey,
+    pub previous_position: Pubkey,
+    pub new_position: Pubkey,
+    pub base_size: i64,
+    pub margin_deposited: u64,
+    pub stop_loss_migrated: bool,
+    pub take_profit_migrated: bool,
+}
+
+#[event]
+pub struct StopLossCancelled {
+    pub user: Pubkey,
+    pub market: Pubkey,
+}
+
+#[event]
+pub struct TakeProfitCancelled {
+    pub user: Pubkey,
+    pub market: Pubkey,
+}
+
+#[event]
+pub struct TrailingStopCancelled {
+    pub user: Pubkey,
+    pub market: Pubkey,
+}
+
+// Liquidation Events
+/// Emitted by both `liquidate` and `enhanced_liquidate`, for full and partial liquidations
 #[event]
 pub struct LiquidationExecuted {
     pub liquidator: Pubkey,
     pub liquidated_user: Pubkey,
+    pub market: Pubkey,
     pub liquidation_size: u64,
+    pub remaining_size: u64,            // 0 once the position is fully liquidated
     pub liquidation_price_fp: u128,
+    pub pnl_fp: i128,                   // PnL realized on the liquidated size
+    pub fees_fp: u128,                  // Liquidation fee charged, at price precision
     pub liquidator_reward: u64,
     pub insurance_fund_contribution: u64,
+    pub returned_to_user: u64,          // Equity paid back to the trader
+    pub requested_percentage: u8,
+    pub enforced_percentage: u8,
+    pub liquidatable_since_ts: i64,
+    pub bad_debt_fp: u128,              // Loss past zero equity the protocol absorbs (0 = solvent)
+}
+
+#[event]
+pub struct KeeperGasReimbursed {
+    pub keeper: Pubkey,
+    pub lamports: u64,
+    pub vault_balance: u64,
 }
 
 #[event]
 pub struct InsuranceFundContribution {
     pub contributor: Pubkey,
+    pub market: Pubkey,
     pub amount: u64,
     pub new_balance: u64,
 }
@@ -183,6 +426,27 @@ pub struct LiquidatorRewardPaid {
     pub reward_percentage: u16,
 }
 
+#[event]
+pub struct InsuranceFundDeficitCovered {
+    pub market: Pubkey,
+    pub fund: Pubkey,                   // Market fund or the global backstop
+    pub amount: u64,
+    pub remaining_balance: u64,
+}
+
+/// One position reduced by `auto_deleverage` to absorb bad debt
+#[event]
+pub struct AutoDeleveraged {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub closed_size: u64,
+    pub remaining_size: u64,
+    pub bankruptcy_price_fp: u128,      // Price the slice was closed at
+    pub absorbed_amount: u64,           // Profit given up towards the bad debt
+    pub settlement_amount: u64,         // Paid to the user for the slice
+    pub remaining_bad_debt: u64,
+}
+
 #[event]
 pub struct InsuranceFundDeposit {
     pub depositor: Pubkey,
@@ -197,3 +461,94 @@ pub struct InsuranceFundWithdrawal {
     pub new_total: u64,
     pub reason: String,
 }
+
+// Withdrawal Queue Events
+#[event]
+pub struct WithdrawalQueued {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub amount: u64,
+    pub total_pending: u64,
+    pub available_at: i64,
+}
+
+#[event]
+pub struct WithdrawalClaimed {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub amount: u64,
+    pub remaining: u64,
+}
+
+// Expiry Events
+#[event]
+pub struct MarketSettled {
+    pub market: Pubkey,
+    pub final_price_fp: u128,
+    pub settled_at: i64,
+}
+
+#[event]
+pub struct EmergencySettled {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub settlement_price_fp: u128,
+    pub pnl_fp: i128,
+    pub settlement_amount: u64,
+}
+
+// Creator Reward Events
+#[event]
+pub struct CreatorRewardsClaimed {
+    pub market: Pubkey,
+    pub creator: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct CreatorRewardsSwept {
+    pub market: Pubkey,
+    pub creator: Pubkey,
+    pub amount: u64,                    // Paid in the creator reward mint
+    pub remaining_accrued: u64,
+}
+
+// AMM Events
+#[event]
+pub struct AmmRebalanced {
+    pub market: Pubkey,
+    pub index_price_fp: u128,
+    pub base_reserve_before_fp: u128,
+    pub quote_reserve_before_fp: u128,
+    pub base_reserve_after_fp: u128,
+    pub quote_reserve_after_fp: u128,
+    pub mark_price_before_fp: u128,
+    pub mark_price_after_fp: u128,
+}
+
+// Auditing Events
+#[event]
+pub struct MarketInvariantChecked {
+    pub market: Pubkey,
+    pub positions_checked: u32,
+    pub market_long_size: u64,
+    pub market_short_size: u64,
+    pub summed_long_size: u64,
+    pub summed_short_size: u64,
+    pub long_discrepancy: i128,         // market total minus summed positions
+    pub short_discrepancy: i128,
+    pub within_tolerance: bool,
+}
+
+// Admin Events
+#[event]
+pub struct AdminProposed {
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey,
+}
+
+#[event]
+pub struct AdminTransferred {
+    pub previous_admin: Pubkey,
+    pub new_admin: Pubkey,
+}
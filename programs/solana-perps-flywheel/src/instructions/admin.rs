@@ -1,7 +1,11 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{Mint, TokenAccount};
+use anchor_lang::system_program;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
 use crate::state::*;
 use crate::errors::PerpsError;
+use crate::events::*;
+use crate::oracle;
+use crate::transfer::{transfer_checked, vault_solvency_check};
 
 pub fn initialize_config(
     ctx: Context<InitializeConfig>, 
@@ -25,12 +29,30 @@ pub fn initialize_config(
     cfg.creator_reward_bps = creator_reward_bps;
     cfg.paused = false;
     cfg.bump = ctx.bumps.config;
+    cfg.price_decimals = PRICE_DECIMALS;
+    cfg.quote_decimals = ctx.accounts.quote_mint.decimals;
+    cfg.version = ACCOUNT_VERSION;
+
+    // Liquidator reward curve: 0.25% -> 1% as liquidations grow from $0 to $1M, $1 floor
+    let one_quote = 10u64.pow(cfg.quote_decimals as u32);
+    cfg.liquidator_reward_tiers = [
+        LiquidatorRewardTier { min_notional: 0, reward_bps: 25 },
+        LiquidatorRewardTier { min_notional: 10_000 * one_quote, reward_bps: 50 },
+        LiquidatorRewardTier { min_notional: 100_000 * one_quote, reward_bps: 75 },
+        LiquidatorRewardTier { min_notional: 1_000_000 * one_quote, reward_bps: 100 },
+    ];
+    cfg.liquidator_reward_floor = one_quote;
     
     // Initialize risk parameters with safe defaults
     cfg.max_positions_per_user = 50;
     cfg.max_total_positions = 10_000;
     cfg.emergency_pause_threshold = 1_000_000; // $1M
     cfg.circuit_breaker_threshold_bps = 1000; // 10%
+    cfg.max_active_orders_per_user = DEFAULT_MAX_ACTIVE_ORDERS_PER_USER;
+
+    // A fresh deployment starts counting from its first open
+    ctx.accounts.protocol_stats.bump = ctx.bumps.protocol_stats;
+    emit!(ctx.accounts.protocol_stats.updated_event());
     
     msg!("Protocol initialized with admin: {}", cfg.admin);
     Ok(())
@@ -42,13 +64,405 @@ pub fn set_fee_destination(ctx: Context<AdminOnly>, new_fee_dest: Pubkey) -> Res
     Ok(()) 
 }
 
+/// First step of an admin handover, e.g. to a multisig. The current admin
+/// stays in charge until `new_admin` signs `accept_admin`.
+pub fn propose_admin(ctx: Context<AdminOnly>, new_admin: Pubkey) -> Result<()> {
+    let cfg = &mut ctx.accounts.config;
+    cfg.propose_admin(new_admin)?;
+    emit!(AdminProposed { admin: cfg.admin, pending_admin: new_admin });
+    msg!("Admin handover proposed to: {}", new_admin);
+    Ok(())
+}
+
+/// Second step: the proposed admin signs to take over
+pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+    let new_admin = ctx.accounts.new_admin.key();
+    let previous_admin = ctx.accounts.config.accept_admin(new_admin)?;
+    emit!(AdminTransferred { previous_admin, new_admin });
+    msg!("Admin transferred from {} to {}", previous_admin, new_admin);
+    Ok(())
+}
+
 pub fn edit_max_position(ctx: Context<AdminOnlyMarket>, new_max_base: u64) -> Result<()> { 
     require!(new_max_base > 0, PerpsError::InvalidMarketParameters);
+    require!(new_max_base >= ctx.accounts.market.min_position_base, PerpsError::InvalidMarketParameters);
     ctx.accounts.market.max_position_base = new_max_base;
     msg!("Max position updated to: {}", new_max_base);
     Ok(()) 
 }
 
+/// Dust floor: opens below `min_position_base` are refused and partial
+/// closes that would leave less close the whole position. 0 turns it off.
+pub fn set_min_position_base(ctx: Context<AdminOnlyMarket>, min_position_base: u64) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    require!(min_position_base <= market.max_position_base, PerpsError::InvalidMarketParameters);
+    market.min_position_base = min_position_base;
+    msg!("Min position size updated to: {}", min_position_base);
+    Ok(())
+}
+
+pub fn set_min_partial_close_pct(ctx: Context<AdminOnlyMarket>, min_partial_close_pct: u8) -> Result<()> {
+    require!(min_partial_close_pct < 100, PerpsError::InvalidMarketParameters);
+    ctx.accounts.market.min_partial_close_pct = min_partial_close_pct;
+    msg!("Min partial close updated to: {}%", min_partial_close_pct);
+    Ok(())
+}
+
+/// Lower the maintenance margin now, or schedule a raise behind the timelock
+pub fn set_maintenance_margin(ctx: Context<AdminOnlyMarket>, maintenance_margin_bps: u16) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    // A solvent position must always be able to pay its close fee, and a
+    // position at the leverage cap must open above maintenance
+    require!(maintenance_margin_bps >= ctx.accounts.config.fee_bps, PerpsError::InvalidMarketParameters);
+    require!(
+        (maintenance_margin_bps as u32) * (market.taker_leverage_cap_x as u32) < 10_000,
+        PerpsError::InvalidMarketParameters
+    );
+
+    let now = Clock::get()?.unix_timestamp;
+    market.schedule_maintenance_margin(maintenance_margin_bps, now)?;
+    if market.pending_maintenance_margin_bps > 0 {
+        msg!("Maintenance margin raise to {} bps scheduled for {}", maintenance_margin_bps, market.maintenance_margin_effective_ts);
+    } else {
+        msg!("Maintenance margin updated to: {} bps", maintenance_margin_bps);
+    }
+    Ok(())
+}
+
+/// Stricter maintenance margin for larger positions. Used tiers come first,
+/// ascending by size and never asking less of a larger position; the rest are
+/// left zeroed. A schedule that raises any size's margin waits out the same
+/// timelock as a maintenance margin raise.
+pub fn set_margin_tiers(ctx: Context<AdminOnlyMarket>, tiers: [MarginTier; MARGIN_TIERS]) -> Result<()> {
+    let used = tiers.iter().take_while(|tier| tier.maintenance_margin_bps > 0).count();
+    require!(
+        tiers[used..].iter().all(|tier| *tier == MarginTier::default()),
+        PerpsError::InvalidMarketParameters
+    );
+    require!(
+        tiers[..used].windows(2).all(|w| w[0].min_base_size < w[1].min_base_size && w[0].maintenance_margin_bps <= w[1].maintenance_margin_bps),
+        PerpsError::InvalidMarketParameters
+    );
+    require!(tiers.iter().all(|tier| tier.maintenance_margin_bps <= 5_000), PerpsError::InvalidMarketParameters);
+
+    let market = &mut ctx.accounts.market;
+    let now = Clock::get()?.unix_timestamp;
+    market.schedule_margin_tiers(tiers, now)?;
+    if market.margin_tiers_effective_ts > 0 {
+        msg!("Margin tiers scheduled for {}", market.margin_tiers_effective_ts);
+    } else {
+        msg!("Margin tiers updated: {} in use", used);
+    }
+    Ok(())
+}
+
+pub fn set_open_protection_seconds(ctx: Context<AdminOnlyMarket>, open_protection_seconds: i64) -> Result<()> {
+    require!(
+        (0..=MAX_OPEN_PROTECTION_SECONDS).contains(&open_protection_seconds),
+        PerpsError::InvalidMarketParameters
+    );
+    ctx.accounts.market.open_protection_seconds = open_protection_seconds;
+    msg!("Open protection updated to: {}s", open_protection_seconds);
+    Ok(())
+}
+
+/// Minimum time a position must sit after its last update before its owner
+/// can close it (0 = off). Liquidations are not held back.
+pub fn set_min_holding_seconds(ctx: Context<AdminOnlyMarket>, min_holding_seconds: i64) -> Result<()> {
+    require!(min_holding_seconds >= 0, PerpsError::InvalidMarketParameters);
+    ctx.accounts.market.min_holding_seconds = min_holding_seconds;
+    msg!("Minimum holding period updated to: {}s", min_holding_seconds);
+    Ok(())
+}
+
+/// Make a market dated: opens stop `close_only_before_expiry_seconds` before
+/// `expiry_ts` and the market settles at expiry. `expiry_ts == 0` makes it perpetual again.
+pub fn set_market_expiry(
+    ctx: Context<AdminOnlyMarket>,
+    expiry_ts: i64,
+    close_only_before_expiry_seconds: i64,
+) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    require!(market.final_settlement_price_fp == 0, PerpsError::MarketAlreadySettled);
+    require!(close_only_before_expiry_seconds >= 0, PerpsError::InvalidMarketParameters);
+    require!(
+        expiry_ts == 0 || expiry_ts > Clock::get()?.unix_timestamp,
+        PerpsError::InvalidMarketParameters
+    );
+    market.expiry_ts = expiry_ts;
+    market.close_only_before_expiry_seconds = close_only_before_expiry_seconds;
+    msg!("Market expiry set to {} (close-only {}s before)", expiry_ts, close_only_before_expiry_seconds);
+    Ok(())
+}
+
+/// Pin an expired market's final price from its oracle. Every later close
+/// or liquidation in the market settles at this price.
+pub fn settle_expired_market(ctx: Context<SettleExpiredMarket>) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    let market = &mut ctx.accounts.market;
+    require!(market.is_expired(now), PerpsError::MarketNotExpired);
+    require!(market.final_settlement_price_fp == 0, PerpsError::MarketAlreadySettled);
+
+    let final_price_fp = oracle::read_market_oracle_fp(market, &ctx.accounts.oracle)?;
+    market.final_settlement_price_fp = final_price_fp;
+
+    emit!(MarketSettled {
+        market: market.key(),
+        final_price_fp,
+        settled_at: now,
+    });
+    msg!("Market {} settled at {}", market.key(), final_price_fp);
+    Ok(())
+}
+
+/// Declare the market's oracle dead (or alive again after a mistake). While
+/// dead nothing prices off the feed; positions leave through
+/// `emergency_settle_position` at the attested `emergency_price_fp`.
+pub fn set_oracle_dead(ctx: Context<AdminOnlyMarket>, oracle_dead: bool, emergency_price_fp: u128) -> Result<()> {
+    require!(!oracle_dead || emergency_price_fp > 0, PerpsError::InvalidPrice);
+    let market = &mut ctx.accounts.market;
+    market.oracle_dead = oracle_dead;
+    market.emergency_price_fp = if oracle_dead { emergency_price_fp } else { 0 };
+    msg!("Market {} oracle dead: {} (emergency price {})", market.key(), oracle_dead, market.emergency_price_fp);
+    Ok(())
+}
+
+/// Close a position in a market whose oracle is dead at the attested price,
+/// with no fee and no liquidation penalty, paying margin plus PnL back to its owner
+pub fn emergency_settle_position(ctx: Context<EmergencySettlePosition>) -> Result<()> {
+    ctx.accounts.user_position.ensure_isolated()?;
+    ctx.accounts.user_position.ensure_quote_margin()?;
+    let now = Clock::get()?.unix_timestamp;
+    let (pnl_fp, settlement_amt) = emergency_settlement(
+        &mut ctx.accounts.config,
+        &mut ctx.accounts.market,
+        &mut ctx.accounts.user_position,
+        ctx.accounts.vault_token.amount,
+        now,
+    )?;
+    ctx.accounts.protocol_stats.record_close();
+    ctx.accounts.user_account.record_close();
+
+    ctx.accounts.user_position.exit(&crate::ID)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+    ctx.accounts.config.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+    ctx.accounts.user_account.exit(&crate::ID)?;
+
+    if settlement_amt > 0 {
+        let config_bump = ctx.accounts.config.bump;
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.quote_mint,
+            ctx.accounts.vault_token.to_account_info(),
+            ctx.accounts.user_token.to_account_info(),
+            ctx.accounts.config.to_account_info(),
+            &[&[CONFIG_SEED, &[config_bump]]],
+            settlement_amt,
+        )?;
+        vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+    }
+
+    emit!(EmergencySettled {
+        user: ctx.accounts.user_position.owner,
+        market: ctx.accounts.market.key(),
+        settlement_price_fp: ctx.accounts.market.emergency_price_fp,
+        pnl_fp,
+        settlement_amount: settlement_amt,
+    });
+    emit!(ctx.accounts.protocol_stats.updated_event());
+    Ok(())
+}
+
+/// Settle `up` in full at the market's attested emergency price. Only
+/// updates state and returns `(pnl_fp, settlement_amt)`; the caller pays out.
+pub(crate) fn emergency_settlement(
+    cfg: &mut Config,
+    market: &mut Market,
+    up: &mut UserPosition,
+    vault_balance: u64,
+    now: i64,
+) -> Result<(i128, u64)> {
+    require!(market.oracle_dead, PerpsError::OracleNotMarkedDead);
+    up.ensure_status(&[PositionStatus::Open, PositionStatus::Liquidating])?;
+
+    let price_fp = market.emergency_price_fp;
+    let size = up.base_size.unsigned_abs();
+    let price_move_fp = price_fp as i128 - up.entry_price_fp as i128;
+    let pnl_fp = up.base_size as i128 * price_move_fp;
+    let settlement = crate::math::close_settlement(cfg.quote_to_fp(up.margin_deposited)?, pnl_fp, size as u128 * price_fp, 0);
+    let payout = cfg.settle_to_quote(settlement.payout_fp)?;
+    let (settlement_amt, _) = cfg.cushion_payout(payout, 0, vault_balance)?;
+
+    market.reduce_open_interest(up.is_long, size);
+    market.release_margin(up.vault_margin())?;
+    market.record_settlement(pnl_fp, 0)?;
+    up.settle_full_close(pnl_fp, 0, now);
+    Ok((pnl_fp, settlement_amt))
+}
+
+/// Carry the market's prices at `price_precision` extra decimals. Sizes and
+/// prices of open positions would change meaning, so only an empty market can switch.
+pub fn set_price_precision(ctx: Context<AdminOnlyMarket>, price_precision: u8) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    require!(price_precision <= MAX_PRICE_PRECISION, PerpsError::InvalidMarketParameters);
+    require!(
+        market.total_long_size == 0 && market.total_short_size == 0,
+        PerpsError::InvalidMarketParameters
+    );
+    market.price_precision = price_precision;
+    // Remembered prices are in the old price space
+    market.recent_index_prices_fp = [0; EMERGENCY_PRICE_SAMPLES];
+    market.recent_index_price_cursor = 0;
+    msg!("Market {} prices carried at {} extra decimals", market.key(), price_precision);
+    Ok(())
+}
+
+/// Largest share of the market's open interest a single position may hold,
+/// on top of the absolute `max_position_base`. 0 turns the check off.
+pub fn set_max_position_oi_fraction(ctx: Context<AdminOnlyMarket>, max_position_oi_fraction_bps: u16) -> Result<()> {
+    require!(max_position_oi_fraction_bps <= 10_000, PerpsError::InvalidMarketParameters);
+    ctx.accounts.market.max_position_oi_fraction_bps = max_position_oi_fraction_bps;
+    msg!("Position concentration capped at {} bps of OI", max_position_oi_fraction_bps);
+    Ok(())
+}
+
+/// Caps on each side's total open interest, in base units. Lowering a cap
+/// below the live OI only blocks new opens on that side. 0 leaves it uncapped.
+pub fn set_open_interest_caps(ctx: Context<AdminOnlyMarket>, max_long_oi: u64, max_short_oi: u64) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    market.max_long_oi = max_long_oi;
+    market.max_short_oi = max_short_oi;
+    msg!("Open interest capped at {} long, {} short", max_long_oi, max_short_oi);
+    Ok(())
+}
+
+/// Window of the oracle TWAP that liquidations and health checks price at, so
+/// one manipulated update can't trigger them. 0 goes back to spot; otherwise
+/// those instructions need the oracle's `OracleTwap`.
+pub fn set_risk_twap_window(ctx: Context<AdminOnlyMarket>, risk_twap_seconds: u32) -> Result<()> {
+    require!(risk_twap_seconds <= MAX_RISK_TWAP_SECONDS, PerpsError::InvalidMarketParameters);
+    ctx.accounts.market.risk_twap_seconds = risk_twap_seconds;
+    msg!("Risk checks price at a {}s TWAP", risk_twap_seconds);
+    Ok(())
+}
+
+/// Price move, in bps, that every open's payout must be backed for by the
+/// market's vault and insurance fund. 0 turns the check off.
+pub fn set_max_favorable_move(ctx: Context<AdminOnlyMarket>, max_favorable_move_bps: u16) -> Result<()> {
+    require!(max_favorable_move_bps <= 10_000, PerpsError::InvalidMarketParameters);
+    ctx.accounts.market.max_favorable_move_bps = max_favorable_move_bps;
+    msg!("Opens must be backed for a {} bps favourable move", max_favorable_move_bps);
+    Ok(())
+}
+
+/// Cap on the immediate surcharge charged to opens that deepen the market's
+/// skew, paid into its insurance fund. 0 turns the surcharge off.
+pub fn set_skew_surcharge(ctx: Context<AdminOnlyMarket>, max_skew_surcharge_bps: u16) -> Result<()> {
+    require!(max_skew_surcharge_bps <= MAX_SKEW_SURCHARGE_BPS, PerpsError::InvalidMarketParameters);
+    ctx.accounts.market.max_skew_surcharge_bps = max_skew_surcharge_bps;
+    msg!("Skew surcharge capped at {} bps", max_skew_surcharge_bps);
+    Ok(())
+}
+
+/// Bound on the market's funding rate per interval, applied to longs and
+/// shorts alike before the cumulative indices move
+pub fn set_max_funding_rate(ctx: Context<AdminOnlyMarket>, max_funding_rate_fp: i128) -> Result<()> {
+    require!(
+        max_funding_rate_fp > 0 && max_funding_rate_fp <= MAX_FUNDING_RATE_CAP_FP,
+        PerpsError::InvalidMarketParameters
+    );
+    ctx.accounts.market.max_funding_rate_fp = max_funding_rate_fp;
+    msg!("Funding rate capped at {}", max_funding_rate_fp);
+    Ok(())
+}
+
+/// Smooth the market's funding rate: each settlement weighs the raw rate at
+/// `funding_ema_alpha_bps` against the last one. 0 (or 10_000) uses the raw rate.
+pub fn set_funding_smoothing(ctx: Context<AdminOnlyMarket>, funding_ema_alpha_bps: u16) -> Result<()> {
+    require!(funding_ema_alpha_bps <= 10_000, PerpsError::InvalidMarketParameters);
+    ctx.accounts.market.funding_ema_alpha_bps = funding_ema_alpha_bps;
+    msg!("Funding smoothing set to {} bps", funding_ema_alpha_bps);
+    Ok(())
+}
+
+/// Order in which the market's price sources are tried, first valid price
+/// wins. All `Unset` keeps the legacy primary/Pyth aggregation.
+pub fn set_oracle_source_priority(
+    ctx: Context<AdminOnlyMarket>,
+    priority: [OracleSource; MAX_ORACLE_SOURCES],
+) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    for (i, source) in priority.iter().enumerate() {
+        if *source == OracleSource::Unset {
+            continue;
+        }
+        require!(!priority[..i].contains(source), PerpsError::InvalidMarketParameters);
+        require!(
+            *source != OracleSource::Pyth || market.pyth_oracle.is_some(),
+            PerpsError::OracleFeedNotFound
+        );
+        require!(
+            *source != OracleSource::Switchboard || market.switchboard_oracle.is_some(),
+            PerpsError::OracleFeedNotFound
+        );
+    }
+    market.oracle_source_priority = priority;
+    msg!("Oracle source priority set to {:?}", priority);
+    Ok(())
+}
+
+pub fn set_confidence_band_liquidation(ctx: Context<AdminOnlyMarket>, enabled: bool) -> Result<()> {
+    ctx.accounts.market.confidence_band_liquidation = enabled;
+    msg!("Confidence band liquidation: {}", enabled);
+    Ok(())
+}
+
+pub fn set_pyth_oracles(
+    ctx: Context<AdminOnlyMarket>,
+    pyth_oracle: Option<Pubkey>,
+    fallbacks: [Pubkey; MAX_PYTH_FALLBACK_FEEDS],
+) -> Result<()> {
+    let used: Vec<&Pubkey> = fallbacks.iter().filter(|key| **key != Pubkey::default()).collect();
+    // Fallbacks only back up a primary feed and must each be distinct
+    require!(pyth_oracle.is_some() || used.is_empty(), PerpsError::InvalidMarketParameters);
+    for (i, key) in used.iter().enumerate() {
+        require!(Some(**key) != pyth_oracle && !used[..i].contains(key), PerpsError::InvalidMarketParameters);
+    }
+
+    let market = &mut ctx.accounts.market;
+    market.pyth_oracle = pyth_oracle;
+    market.pyth_fallback_oracles = fallbacks;
+    msg!("Pyth oracles updated: primary {:?}, {} fallback(s)", pyth_oracle, used.len());
+    Ok(())
+}
+
+/// Register (or with `None`, remove) the market's Switchboard aggregator.
+/// It can't be removed while the source priority still lists it.
+pub fn set_switchboard_oracle(ctx: Context<AdminOnlyMarket>, switchboard_oracle: Option<Pubkey>) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    require!(
+        switchboard_oracle.is_some() || !market.oracle_source_priority.contains(&OracleSource::Switchboard),
+        PerpsError::InvalidMarketParameters
+    );
+    market.switchboard_oracle = switchboard_oracle;
+    msg!("Switchboard oracle updated: {:?}", switchboard_oracle);
+    Ok(())
+}
+
+pub fn set_withdrawal_queue(
+    ctx: Context<AdminOnlyMarket>,
+    threshold: u64,
+    delay_seconds: i64,
+) -> Result<()> {
+    require!(delay_seconds >= 0, PerpsError::InvalidMarketParameters);
+    let market = &mut ctx.accounts.market;
+    market.withdrawal_queue_threshold = threshold;
+    market.withdrawal_delay_seconds = delay_seconds;
+    msg!("Withdrawal queue updated: threshold {}, delay {}s", threshold, delay_seconds);
+    Ok(())
+}
+
 pub fn pause(ctx: Context<AdminOnly>, paused: bool) -> Result<()> { 
     ctx.accounts.config.paused = paused;
     msg!("Protocol pause status: {}", paused);
@@ -59,6 +473,7 @@ pub fn update_risk_parameters(
     ctx: Context<AdminOnly>,
     max_positions_per_user: Option<u32>,
     circuit_breaker_threshold_bps: Option<u64>,
+    max_active_orders_per_user: Option<u32>,
 ) -> Result<()> {
     let cfg = &mut ctx.accounts.config;
     
@@ -68,14 +483,375 @@ pub fn update_risk_parameters(
     }
     
     if let Some(threshold) = circuit_breaker_threshold_bps {
-        require!(threshold >= 100 && threshold <= 5000, PerpsError::InvalidProtocolConfig); // 1-50%
+        require!((100..=5000).contains(&threshold), PerpsError::InvalidProtocolConfig); // 1-50%
         cfg.circuit_breaker_threshold_bps = threshold;
     }
+
+    if let Some(max_orders) = max_active_orders_per_user {
+        require!(max_orders > 0 && max_orders <= 256, PerpsError::InvalidProtocolConfig);
+        cfg.max_active_orders_per_user = max_orders;
+    }
     
     msg!("Risk parameters updated");
     Ok(())
 }
 
+/// Break-glass throttle on how much a single user can take out of the vaults
+/// per `WITHDRAWAL_RATE_LIMIT_WINDOW_SECONDS`. 0 turns it off.
+pub fn set_withdrawal_rate_limit(ctx: Context<AdminOnly>, limit: u64) -> Result<()> {
+    ctx.accounts.config.withdrawal_rate_limit = limit;
+    msg!("Withdrawal rate limit set to {} per {}s", limit, WITHDRAWAL_RATE_LIMIT_WINDOW_SECONDS);
+    Ok(())
+}
+
+/// Rebate whitelisted market makers earn on exit notional in place of the
+/// fee. Capped at the taker fee so makers are never paid more than takers are charged.
+pub fn set_mm_rebate(ctx: Context<AdminOnly>, mm_rebate_bps: u16) -> Result<()> {
+    let cfg = &mut ctx.accounts.config;
+    require!(mm_rebate_bps <= cfg.fee_bps, PerpsError::InvalidProtocolConfig);
+    cfg.mm_rebate_bps = mm_rebate_bps;
+    msg!("Market maker rebate set to {} bps", mm_rebate_bps);
+    Ok(())
+}
+
+/// Entry fees for limit order fills (maker, negative for a rebate) and market
+/// opens (taker). The maker rebate may not exceed the taker fee, so the
+/// protocol never pays out more on a fill than it takes on the other side.
+pub fn set_trading_fees(ctx: Context<AdminOnly>, maker_fee_bps: i16, taker_fee_bps: u16) -> Result<()> {
+    require!(taker_fee_bps <= 1000, PerpsError::InvalidProtocolConfig); // Max 10%, as fee_bps
+    require!((maker_fee_bps as i32).abs() <= 1000, PerpsError::InvalidProtocolConfig);
+    require!(maker_fee_bps as i32 + taker_fee_bps as i32 >= 0, PerpsError::InvalidProtocolConfig);
+    let cfg = &mut ctx.accounts.config;
+    cfg.maker_fee_bps = maker_fee_bps;
+    cfg.taker_fee_bps = taker_fee_bps;
+    msg!("Trading fees set: maker {} bps, taker {} bps", maker_fee_bps, taker_fee_bps);
+    Ok(())
+}
+
+/// Share of the protocol's close and liquidation fees routed into the market's
+/// insurance fund, until its ratio of deposits to claims reaches
+/// `insurance_target_ratio_bps`. 0 bps turns routing off.
+pub fn set_insurance_fee(ctx: Context<AdminOnly>, insurance_fee_bps: u16, insurance_target_ratio_bps: u32) -> Result<()> {
+    require!(insurance_fee_bps <= 10_000, PerpsError::InvalidProtocolConfig);
+    let cfg = &mut ctx.accounts.config;
+    cfg.insurance_fee_bps = insurance_fee_bps;
+    cfg.insurance_target_ratio_bps = insurance_target_ratio_bps;
+    msg!("Insurance fee set to {} bps until a {} bps fund ratio", insurance_fee_bps, insurance_target_ratio_bps);
+    Ok(())
+}
+
+/// Whitelist `maker` for fee-free closes with a rebate
+pub fn register_market_maker(ctx: Context<RegisterMarketMaker>, maker: Pubkey) -> Result<()> {
+    let entry = &mut ctx.accounts.market_maker;
+    entry.owner = maker;
+    entry.bump = ctx.bumps.market_maker;
+    msg!("Market maker {} registered", maker);
+    Ok(())
+}
+
+/// Drop a market maker from the whitelist, returning the rent to the admin
+pub fn remove_market_maker(ctx: Context<RemoveMarketMaker>) -> Result<()> {
+    msg!("Market maker {} removed after {} in rebates",
+         ctx.accounts.market_maker.owner, ctx.accounts.market_maker.total_rebates_paid);
+    Ok(())
+}
+
+/// Create the protocol stats account for a deployment whose config predates it
+/// (`initialize_config` creates it otherwise), seeded with the positions
+/// already open so closes of those don't free slots that were never counted
+pub fn initialize_protocol_stats(ctx: Context<InitializeProtocolStats>, active_positions: u32) -> Result<()> {
+    let stats = &mut ctx.accounts.protocol_stats;
+    stats.active_positions = active_positions;
+    stats.bump = ctx.bumps.protocol_stats;
+    emit!(stats.updated_event());
+    msg!("Protocol stats initialized with {} active positions", active_positions);
+    Ok(())
+}
+
+/// Start keeping an oracle's recent prices for TWAP pricing, seeded with its current price
+pub fn initialize_oracle_twap(ctx: Context<InitializeOracleTwap>) -> Result<()> {
+    let oracle = &ctx.accounts.oracle;
+    let twap = &mut ctx.accounts.oracle_twap;
+    twap.oracle = oracle.key();
+    twap.bump = ctx.bumps.oracle_twap;
+    if oracle.price_fp > 0 {
+        twap.record(oracle.price_fp, oracle.last_updated_ts)?;
+    }
+    msg!("TWAP initialized for oracle {}", oracle.key());
+    Ok(())
+}
+
+/// Smallest shortfall below maintenance (quote tokens) worth liquidating; 0 = any breach
+pub fn set_min_liquidation_deficit(ctx: Context<AdminOnly>, min_liquidation_deficit: u64) -> Result<()> {
+    ctx.accounts.config.min_liquidation_deficit = min_liquidation_deficit;
+    msg!("Minimum liquidation deficit set to {}", min_liquidation_deficit);
+    Ok(())
+}
+
+pub fn set_keeper_gas_reimbursement(ctx: Context<AdminOnly>, lamports_per_liquidation: u64) -> Result<()> {
+    ctx.accounts.config.keeper_gas_reimbursement_lamports = lamports_per_liquidation;
+    msg!("Keeper gas reimbursement set to {} lamports", lamports_per_liquidation);
+    Ok(())
+}
+
+/// Top up the SOL vault that reimburses keepers' transaction fees
+pub fn fund_keeper_gas_vault(ctx: Context<FundKeeperGasVault>, lamports: u64) -> Result<()> {
+    require!(lamports > 0, PerpsError::InvalidProtocolConfig);
+    system_program::transfer(
+        CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.admin.to_account_info(),
+                to: ctx.accounts.keeper_gas_vault.to_account_info(),
+            },
+        ),
+        lamports,
+    )?;
+    msg!("Keeper gas vault funded with {} lamports", lamports);
+    Ok(())
+}
+
+/// Move whole units of accumulated rounding dust out of the vault to the fee destination
+pub fn sweep_rounding_buffer(ctx: Context<SweepRoundingBuffer>, amount: u64) -> Result<()> {
+    require!(amount > 0, PerpsError::InvalidProtocolConfig);
+    ctx.accounts.config.take_rounding_buffer(amount)?;
+    ctx.accounts.config.exit(&crate::ID)?;
+
+    let config_bump = ctx.accounts.config.bump;
+    transfer_checked(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.vault_token.to_account_info(),
+        ctx.accounts.fee_destination_token.to_account_info(),
+        ctx.accounts.config.to_account_info(),
+        &[&[CONFIG_SEED, &[config_bump]]],
+        amount,
+    )?;
+    vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+    msg!("Swept {} from the rounding buffer, {} left", amount, ctx.accounts.config.rounding_buffer()?);
+    Ok(())
+}
+
+/// Move collateral out of the shared `[VAULT_SEED, config]` vault every market
+/// used before each got its own. The admin moves each market's share into its
+/// vault; `None` drains whatever is left into the last one.
+pub fn migrate_legacy_vault(ctx: Context<MigrateLegacyVault>, amount: Option<u64>) -> Result<()> {
+    let amount = legacy_sweep_amount(amount, ctx.accounts.legacy_vault_token.amount)?;
+
+    let config_bump = ctx.accounts.config.bump;
+    transfer_checked(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.legacy_vault_token.to_account_info(),
+        ctx.accounts.vault_token.to_account_info(),
+        ctx.accounts.config.to_account_info(),
+        &[&[CONFIG_SEED, &[config_bump]]],
+        amount,
+    )?;
+    msg!("Moved {} from the legacy vault to {}'s, {} left",
+         amount, ctx.accounts.market.key(), ctx.accounts.legacy_vault_token.amount - amount);
+    Ok(())
+}
+
+/// What a legacy vault sweep moves: the requested amount, or everything left
+fn legacy_sweep_amount(requested: Option<u64>, legacy_balance: u64) -> Result<u64> {
+    let amount = requested.unwrap_or(legacy_balance);
+    require!(amount > 0, PerpsError::InvalidProtocolConfig);
+    require!(amount <= legacy_balance, PerpsError::InsufficientBalance);
+    Ok(amount)
+}
+
+/// Whitelist a mint as isolated margin, priced at `oracle` and counted for
+/// `collateral_factor_bps` of its value, and create the vault pledges go to
+pub fn add_accepted_collateral(ctx: Context<AddAcceptedCollateral>, collateral_factor_bps: u16) -> Result<()> {
+    require!(collateral_factor_bps > 0 && collateral_factor_bps <= 10_000, PerpsError::InvalidCollateralFactor);
+    let accepted = &mut ctx.accounts.accepted_collateral;
+    accepted.mint = ctx.accounts.mint.key();
+    accepted.oracle = ctx.accounts.oracle.key();
+    accepted.collateral_factor_bps = collateral_factor_bps;
+    accepted.decimals = ctx.accounts.mint.decimals;
+    accepted.is_enabled = true;
+    accepted.vault_bump = ctx.bumps.collateral_vault;
+    accepted.bump = ctx.bumps.accepted_collateral;
+    msg!("Accepted {} as collateral at {} bps", accepted.mint, collateral_factor_bps);
+    Ok(())
+}
+
+/// Change an accepted mint's haircut or stop new pledges of it. Open
+/// positions keep the margin they were valued at and still settle.
+pub fn update_accepted_collateral(ctx: Context<UpdateAcceptedCollateral>, collateral_factor_bps: u16, is_enabled: bool) -> Result<()> {
+    require!(collateral_factor_bps > 0 && collateral_factor_bps <= 10_000, PerpsError::InvalidCollateralFactor);
+    let accepted = &mut ctx.accounts.accepted_collateral;
+    accepted.collateral_factor_bps = collateral_factor_bps;
+    accepted.is_enabled = is_enabled;
+    msg!("Collateral {} at {} bps, {}", accepted.mint, collateral_factor_bps, if is_enabled { "enabled" } else { "disabled" });
+    Ok(())
+}
+
+/// Move tokens kept from losing pledged positions out of the collateral vault
+pub fn sweep_seized_collateral(ctx: Context<SweepSeizedCollateral>, amount: u64) -> Result<()> {
+    require!(amount > 0, PerpsError::InvalidProtocolConfig);
+    let accepted = &mut ctx.accounts.accepted_collateral;
+    accepted.total_seized = accepted.total_seized.checked_sub(amount).ok_or(PerpsError::InsufficientFunds)?;
+    accepted.exit(&crate::ID)?;
+
+    let config_bump = ctx.accounts.config.bump;
+    transfer_checked(
+        &ctx.accounts.token_program,
+        &ctx.accounts.mint,
+        ctx.accounts.collateral_vault.to_account_info(),
+        ctx.accounts.treasury_token.to_account_info(),
+        ctx.accounts.config.to_account_info(),
+        &[&[CONFIG_SEED, &[config_bump]]],
+        amount,
+    )?;
+    msg!("Swept {} seized {}, {} left", amount, ctx.accounts.accepted_collateral.mint, ctx.accounts.accepted_collateral.total_seized);
+    Ok(())
+}
+
+/// Layout version that introduced `Market::total_margin_locked`
+const MARGIN_COUNTER_VERSION: u16 = 2;
+
+/// Grow a market account created under an older, shorter layout to the current
+/// `Market::SPACE`. New fields are appended at the end of `Market`, so the
+/// zero-extended bytes decode as their defaults. Stamps the current
+/// `ACCOUNT_VERSION`; otherwise a no-op if already migrated.
+///
+/// A market from before the margin counter starts it at `total_margin_locked`:
+/// the trader funds its vault holds for positions, escrows and idle
+/// collateral, summed off chain while the stale version keeps it closed.
+pub fn migrate_market(ctx: Context<MigrateMarket>, total_margin_locked: u64) -> Result<()> {
+    let market = ctx.accounts.market.to_account_info();
+    {
+        let data = market.try_borrow_data()?;
+        require!(data.len() >= 8 && data[..8] == *Market::DISCRIMINATOR, PerpsError::InvalidAccountOwner);
+    }
+    let old_len = market.data_len();
+    grow_account(&market, &ctx.accounts.admin, &ctx.accounts.system_program, Market::SPACE)?;
+    stamp_version::<Market>(&market, |m| upgrade_market(m, total_margin_locked))?;
+
+    msg!("Market {} migrated: {} -> {} bytes", market.key(), old_len, Market::SPACE);
+    Ok(())
+}
+
+/// `migrate_market` for the protocol config. The admin is read straight from
+/// the raw account, since an old layout can't be deserialized as `Config`.
+pub fn migrate_config(ctx: Context<MigrateConfig>) -> Result<()> {
+    let config = ctx.accounts.config.to_account_info();
+    {
+        let data = config.try_borrow_data()?;
+        require!(data.len() >= 40 && data[..8] == *Config::DISCRIMINATOR, PerpsError::InvalidAccountOwner);
+        // `admin` is the first field after the discriminator
+        require!(data[8..40] == ctx.accounts.admin.key().to_bytes(), PerpsError::UnauthorizedAccess);
+    }
+    let old_len = config.data_len();
+    grow_account(&config, &ctx.accounts.admin, &ctx.accounts.system_program, Config::SPACE)?;
+    stamp_version::<Config>(&config, |cfg| cfg.version = ACCOUNT_VERSION)?;
+
+    msg!("Config migrated: {} -> {} bytes", old_len, Config::SPACE);
+    Ok(())
+}
+
+/// Grow a position created under an older layout to `UserPosition::SPACE` and
+/// stamp the current layout version. Its funding snapshot starts at the
+/// market's current index, so funding from before the migration isn't charged
+/// twice. Anyone may call it; the payer covers the extra rent.
+pub fn migrate_position(ctx: Context<MigratePosition>) -> Result<()> {
+    let position = ctx.accounts.user_position.to_account_info();
+    {
+        let data = position.try_borrow_data()?;
+        require!(data.len() >= 8 && data[..8] == *UserPosition::DISCRIMINATOR, PerpsError::InvalidAccountOwner);
+    }
+    grow_account(&position, &ctx.accounts.payer, &ctx.accounts.system_program, UserPosition::SPACE)?;
+
+    let market = &ctx.accounts.market;
+    let mut market_matches = true;
+    stamp_version::<UserPosition>(&position, |up| {
+        market_matches = up.market == market.key();
+        upgrade_position(up, market);
+    })?;
+    require!(market_matches, PerpsError::InvalidMarketParameters);
+
+    msg!("Position {} migrated to version {}", position.key(), ACCOUNT_VERSION);
+    Ok(())
+}
+
+/// Bring a market's fields up to `ACCOUNT_VERSION`
+fn upgrade_market(m: &mut Market, total_margin_locked: u64) {
+    if m.version < MARGIN_COUNTER_VERSION {
+        m.total_margin_locked = total_margin_locked;
+    }
+    m.version = ACCOUNT_VERSION;
+}
+
+/// Bring a position's fields up to `ACCOUNT_VERSION`. Only a position from
+/// before versioning lacks a funding snapshot; later ones keep theirs, so
+/// funding they owe isn't forgiven.
+fn upgrade_position(up: &mut UserPosition, market: &Market) {
+    if up.version == 0 {
+        up.last_cumulative_funding_fp = market.cumulative_funding_fp(up.is_long);
+    }
+    up.version = ACCOUNT_VERSION;
+}
+
+/// Rewrite an account that has already been grown to the current layout with
+/// `update` applied. Used where the account can't be typed in the context.
+fn stamp_version<T: AccountSerialize + AccountDeserialize>(account: &AccountInfo, update: impl FnOnce(&mut T)) -> Result<()> {
+    let mut data = account.try_borrow_mut_data()?;
+    let mut state = T::try_deserialize(&mut &data[..])?;
+    update(&mut state);
+    state.try_serialize(&mut &mut data[..])
+}
+
+/// Top up rent from `payer` and zero-extend `account` to `space`. No-op if
+/// it is already that large.
+fn grow_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    space: usize,
+) -> Result<()> {
+    if account.data_len() >= space {
+        return Ok(());
+    }
+
+    let rent_due = Rent::get()?.minimum_balance(space).saturating_sub(account.lamports());
+    if rent_due > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.to_account_info(),
+                system_program::Transfer {
+                    from: payer.to_account_info(),
+                    to: account.clone(),
+                },
+            ),
+            rent_due,
+        )?;
+    }
+    account.resize(space)?;
+    Ok(())
+}
+
+pub fn set_liquidator_rewards(
+    ctx: Context<AdminOnly>,
+    tiers: [LiquidatorRewardTier; LIQUIDATOR_REWARD_TIERS],
+    floor: u64,
+) -> Result<()> {
+    for tier in tiers.iter() {
+        require!(tier.reward_bps <= 1_000, PerpsError::InvalidProtocolConfig); // Max 10%
+    }
+    require!(
+        tiers.windows(2).all(|w| w[0].min_notional <= w[1].min_notional),
+        PerpsError::InvalidProtocolConfig
+    );
+
+    let cfg = &mut ctx.accounts.config;
+    cfg.liquidator_reward_tiers = tiers;
+    cfg.liquidator_reward_floor = floor;
+    msg!("Liquidator rewards updated, floor {}", floor);
+    Ok(())
+}
+
 #[derive(Accounts)]
 pub struct InitializeConfig<'info> {
     #[account(
@@ -87,13 +863,22 @@ pub struct InitializeConfig<'info> {
     )]
     pub config: Account<'info, Config>,
     
-    pub quote_mint: Account<'info, Mint>,
+    pub quote_mint: InterfaceAccount<'info, Mint>,
     
     /// CHECK: SPL token account for fee destination (Pump/Pumpswap LP vault)
     pub fee_destination: AccountInfo<'info>,
     
-    pub insurance_vault: Account<'info, TokenAccount>,
-    pub creator_reward_mint: Account<'info, Mint>,
+    pub insurance_vault: InterfaceAccount<'info, TokenAccount>,
+    pub creator_reward_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = ProtocolStats::SPACE,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
     
     #[account(mut)]
     pub admin: Signer<'info>,
@@ -126,3 +911,490 @@ pub struct AdminOnlyMarket<'info> {
     #[account(mut)] 
     pub market: Account<'info, Market> 
 }
+
+#[derive(Accounts)]
+pub struct SettleExpiredMarket<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+        has_one = admin
+    )]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(address = market.oracle @ PerpsError::OracleFeedNotFound)]
+    pub oracle: Account<'info, OraclePrice>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencySettlePosition<'info> {
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+        has_one = admin
+    )]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [POSITION_SEED, user_position.owner.as_ref(), market.key().as_ref()],
+        bump = user_position.bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user_position.owner.as_ref()],
+        bump = user_account.bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user_position.owner @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeOracleTwap<'info> {
+    #[account(
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub oracle: Account<'info, OraclePrice>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = OracleTwap::SPACE,
+        seeds = [ORACLE_TWAP_SEED, oracle.key().as_ref()],
+        bump
+    )]
+    pub oracle_twap: Account<'info, OracleTwap>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeProtocolStats<'info> {
+    #[account(
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = ProtocolStats::SPACE,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(maker: Pubkey)]
+pub struct RegisterMarketMaker<'info> {
+    #[account(
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = MarketMaker::SPACE,
+        seeds = [MARKET_MAKER_SEED, maker.as_ref()],
+        bump
+    )]
+    pub market_maker: Account<'info, MarketMaker>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveMarketMaker<'info> {
+    #[account(
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_MAKER_SEED, market_maker.owner.as_ref()],
+        bump = market_maker.bump,
+        close = admin
+    )]
+    pub market_maker: Account<'info, MarketMaker>,
+}
+
+#[derive(Accounts)]
+pub struct FundKeeperGasVault<'info> {
+    #[account(
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [KEEPER_GAS_VAULT_SEED], bump)]
+    pub keeper_gas_vault: SystemAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SweepRoundingBuffer<'info> {
+    #[account(
+        mut,
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+
+    /// Any market's vault: the buffer is protocol-wide
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = config.fee_destination @ PerpsError::InvalidTokenAccount)]
+    pub fee_destination_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateLegacyVault<'info> {
+    #[account(
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, config.key().as_ref()],
+        bump,
+        constraint = config.is_quote_vault(&config.key(), &legacy_vault_token.owner, &legacy_vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub legacy_vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct AddAcceptedCollateral<'info> {
+    #[account(
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(constraint = mint.key() != config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    /// Quotes one whole token of `mint` in quote
+    pub oracle: Account<'info, OraclePrice>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = AcceptedCollateral::SPACE,
+        seeds = [ACCEPTED_COLLATERAL_SEED, mint.key().as_ref()],
+        bump
+    )]
+    pub accepted_collateral: Account<'info, AcceptedCollateral>,
+
+    #[account(
+        init,
+        payer = admin,
+        seeds = [COLLATERAL_VAULT_SEED, mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = config,
+        token::token_program = token_program,
+    )]
+    pub collateral_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateAcceptedCollateral<'info> {
+    #[account(
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ACCEPTED_COLLATERAL_SEED, accepted_collateral.mint.as_ref()],
+        bump = accepted_collateral.bump
+    )]
+    pub accepted_collateral: Account<'info, AcceptedCollateral>,
+}
+
+#[derive(Accounts)]
+pub struct SweepSeizedCollateral<'info> {
+    #[account(
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ACCEPTED_COLLATERAL_SEED, accepted_collateral.mint.as_ref()],
+        bump = accepted_collateral.bump
+    )]
+    pub accepted_collateral: Account<'info, AcceptedCollateral>,
+
+    #[account(
+        mut,
+        seeds = [COLLATERAL_VAULT_SEED, accepted_collateral.mint.as_ref()],
+        bump = accepted_collateral.vault_bump
+    )]
+    pub collateral_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, constraint = treasury_token.mint == accepted_collateral.mint @ PerpsError::InvalidTokenMint)]
+    pub treasury_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = accepted_collateral.mint @ PerpsError::InvalidTokenMint)]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub new_admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateConfig<'info> {
+    /// CHECK: may predate the current layout, so it cannot be deserialized as
+    /// `Config` yet; the seeds pin it and the handler checks the admin
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump,
+        owner = crate::ID @ PerpsError::InvalidAccountOwner
+    )]
+    pub config: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateMarket<'info> {
+    #[account(
+        has_one = admin,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    /// CHECK: may predate the current layout, so it cannot be deserialized as
+    /// `Market` yet; ownership is checked here and the discriminator in the handler
+    #[account(mut, owner = crate::ID @ PerpsError::InvalidAccountOwner)]
+    pub market: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePosition<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: may predate the current layout, so it cannot be deserialized as
+    /// `UserPosition` yet; ownership is checked here and the discriminator in the handler
+    #[account(mut, owner = crate::ID @ PerpsError::InvalidAccountOwner)]
+    pub user_position: UncheckedAccount<'info>,
+
+    /// Market the position belongs to, for its funding index
+    pub market: Account<'info, Market>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_oracle_blocks_closes_but_emergency_settlement_pays_out() {
+        let mut cfg = Config { price_decimals: 6, quote_decimals: 6, fee_bps: 10, ..Default::default() };
+        let mut market = Market { total_long_size: 10, total_margin_locked: 200_000_000, ..Default::default() };
+        // 10 long from $100 with $200 margin
+        let mut up = UserPosition {
+            base_size: 10,
+            is_long: true,
+            entry_price_fp: 100 * FP,
+            margin_deposited: 200_000_000,
+            status: PositionStatus::Open,
+            ..Default::default()
+        };
+
+        // Settlement needs the feed declared dead first
+        let err = emergency_settlement(&mut cfg, &mut market, &mut up, u64::MAX, 1_000).unwrap_err();
+        assert_eq!(err, PerpsError::OracleNotMarkedDead.into());
+
+        // Once it is, nothing prices off the feed any more
+        market.oracle_dead = true;
+        market.emergency_price_fp = 104 * FP;
+        assert_eq!(market.ensure_oracle_live().unwrap_err(), PerpsError::OracleMarkedDead.into());
+
+        // The owner gets margin plus PnL at the attested price, with no fee taken
+        let (pnl_fp, settlement_amt) = emergency_settlement(&mut cfg, &mut market, &mut up, u64::MAX, 1_000).unwrap();
+        assert_eq!(pnl_fp, 40 * FP as i128);
+        assert_eq!(settlement_amt, 240_000_000);
+        assert_eq!(up.status, PositionStatus::Closed);
+        assert_eq!(up.total_fees_paid, 0);
+        assert_eq!(market.total_long_size, 0);
+
+        // A settled position can't be paid out twice
+        assert!(emergency_settlement(&mut cfg, &mut market, &mut up, u64::MAX, 1_000).is_err());
+    }
+
+    #[test]
+    fn test_legacy_vault_sweep_amount() {
+        // A market's share, then whatever is left for the last one
+        assert_eq!(legacy_sweep_amount(Some(300), 1_000).unwrap(), 300);
+        assert_eq!(legacy_sweep_amount(None, 700).unwrap(), 700);
+
+        // Can't move more than the legacy vault holds, or sweep an empty one
+        assert_eq!(legacy_sweep_amount(Some(701), 700).unwrap_err(), PerpsError::InsufficientBalance.into());
+        assert_eq!(legacy_sweep_amount(None, 0).unwrap_err(), PerpsError::InvalidProtocolConfig.into());
+        assert_eq!(legacy_sweep_amount(Some(0), 700).unwrap_err(), PerpsError::InvalidProtocolConfig.into());
+
+        // The legacy vault is config-seeded, so no market's vault can stand in for it
+        let (config_key, _) = Config::find_pda();
+        let (legacy, _) = Pubkey::find_program_address(&[VAULT_SEED, config_key.as_ref()], &crate::ID);
+        assert_ne!(legacy, Market::find_vault_pda(&Pubkey::new_unique()).0);
+    }
+
+    #[test]
+    fn test_migration_seeds_the_margin_counter_once() {
+        // Version 1 predates the counter, so the admin's total is taken
+        let mut market = Market { version: 1, ..Default::default() };
+        upgrade_market(&mut market, 5_000);
+        assert_eq!((market.version, market.total_margin_locked), (ACCOUNT_VERSION, 5_000));
+
+        // Re-running it on a current market leaves the running counter alone
+        market.lock_margin(100).unwrap();
+        upgrade_market(&mut market, 0);
+        assert_eq!(market.total_margin_locked, 5_100);
+    }
+
+    #[test]
+    fn test_versioned_position_keeps_its_funding_snapshot() {
+        let market = Market { cumulative_funding_long_fp: -700, ..Default::default() };
+
+        // Written before versioning: snapshot at the current index
+        let mut up = UserPosition { is_long: true, ..Default::default() };
+        upgrade_position(&mut up, &market);
+        assert_eq!((up.version, up.last_cumulative_funding_fp), (ACCOUNT_VERSION, -700));
+
+        // A version 1 position still owes what accrued since its snapshot
+        let mut up = UserPosition { version: 1, is_long: true, last_cumulative_funding_fp: -200, ..Default::default() };
+        upgrade_position(&mut up, &market);
+        assert_eq!((up.version, up.last_cumulative_funding_fp), (ACCOUNT_VERSION, -200));
+    }
+}
@@ -1,118 +1,100 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
 
 use crate::state::*;
 use crate::errors::PerpsError;
 use crate::events::*;
 use crate::oracle;
-use crate::math::*;
+use crate::math;
+use crate::instructions::withdrawal_queue::throttle_outflow;
+use crate::instructions::funding::take_funding_share;
+use crate::instructions::trade::CloseOutcome;
+use crate::instructions::collateral::load_pledge;
+use crate::transfer::{transfer_checked, transfer_checked_exact, vault_solvency_check};
 
 // Advanced position management functions
 
 pub fn partial_close_position(
     ctx: Context<PartialClosePosition>,
-    close_percentage: u8, // 1-99 (e.g., 25 = 25%)
-) -> Result<()> {
+    close_percentage: u8, // 1-100 (e.g., 25 = 25%); 100 closes the whole position
+) -> Result<CloseOutcome> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
     require!(
-        close_percentage > 0 && close_percentage < 100,
+        close_percentage > 0 && close_percentage <= 100,
         PerpsError::InvalidClosePercentage
     );
 
     require!(!ctx.accounts.market.is_paused, PerpsError::MarketPaused);
-    require!(ctx.accounts.user_position.base_size != 0, PerpsError::PositionNotFound);
+    ctx.accounts.user_position.ensure_status(&[PositionStatus::Open])?;
+    ctx.accounts.user_position.ensure_isolated()?;
+    require!(ctx.accounts.market.allows_partial_close(close_percentage), PerpsError::PositionTooSmall);
+    ctx.accounts.market.ensure_holding_period(ctx.accounts.user_position.last_updated_ts, Clock::get()?.unix_timestamp)?;
 
     // Get current mark price from oracle
-    let mark_fp = oracle::read_oracle_fp(&ctx.accounts.oracle)?;
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?);
 
-    // Calculate close amounts
-    let original_size = ctx.accounts.user_position.base_size.abs() as u64;
-    let close_size = (original_size as u128 * close_percentage as u128 / 100) as u64;
-    
+    // Calculate close amounts; a slice that would leave dust takes the whole position
+    let position_size = ctx.accounts.user_position.base_size.unsigned_abs();
+    let close_size = ctx.accounts.market.dust_free_close_size(position_size, slice_size(position_size, close_percentage));
     require!(close_size > 0, PerpsError::PositionTooSmall);
 
-    // Calculate PnL for the portion being closed
-    let close_notional_entry_fp = (close_size as u128 * ctx.accounts.user_position.entry_price_fp) / FP;
-    let close_notional_exit_fp = (close_size as u128 * mark_fp) / FP;
-    
-    let pnl_fp = if ctx.accounts.user_position.is_long {
-        close_notional_exit_fp as i128 - close_notional_entry_fp as i128
-    } else {
-        close_notional_entry_fp as i128 - close_notional_exit_fp as i128
-    };
-
-    // Calculate fees (using Config fee_bps)
-    let fee_amt = (close_notional_exit_fp * ctx.accounts.config.fee_bps as u128) / (10_000 * FP);
-    
-    let settlement_amt = if pnl_fp >= 0 {
-        (close_notional_exit_fp - fee_amt) + pnl_fp as u128
-    } else {
-        (close_notional_exit_fp - fee_amt).saturating_sub((-pnl_fp) as u128)
-    };
-
-    // Perform transfers
-    if settlement_amt > 0 {
-        let config_bump = ctx.accounts.config.bump;
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.vault_token.to_account_info(),
-                    to: ctx.accounts.user_token.to_account_info(),
-                    authority: ctx.accounts.config.to_account_info(),
-                },
-                &[&[CONFIG_SEED, &[config_bump]]]
-            ),
-            (settlement_amt / FP) as u64
-        )?;
-    }
-    
-    if fee_amt > 0 {
-        let config_bump = ctx.accounts.config.bump;
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.vault_token.to_account_info(),
-                    to: ctx.accounts.fee_destination_token.to_account_info(),
-                    authority: ctx.accounts.config.to_account_info(),
-                },
-                &[&[CONFIG_SEED, &[config_bump]]]
-            ),
-            (fee_amt / FP) as u64
-        )?;
+    // Effects: shrink the position and market OI before any transfer
+    let now = Clock::get()?.unix_timestamp;
+    let slice = close_slice(
+        &mut ctx.accounts.config,
+        &mut ctx.accounts.market,
+        &mut ctx.accounts.user_position,
+        close_size,
+        mark_fp,
+        ctx.accounts.vault_token.amount,
+        now,
+    )?;
+    let (pnl_fp, settlement_amt, fee_amt, remaining_size) =
+        (slice.pnl_fp, slice.settlement_amt, slice.fee_amt, slice.remaining_size);
+    if remaining_size == 0 {
+        ctx.accounts.protocol_stats.record_close();
+        ctx.accounts.user_account.record_close();
     }
+    ctx.accounts.protocol_stats.record_fees(fee_amt)?;
+    emit!(ctx.accounts.protocol_stats.updated_event());
+    // Whatever stays open must still clear maintenance
+    let health_mark_fp = health_mark_fp(&ctx.accounts.market, &ctx.accounts.oracle, ctx.accounts.oracle_twap.as_deref(), mark_fp)?;
+    oracle::health_check(
+        &ctx.accounts.oracle,
+        ctx.accounts.market.price_precision,
+        health_mark_fp,
+        remaining_size,
+        ctx.accounts.user_position.equity_fp(&ctx.accounts.config, health_mark_fp)?,
+        ctx.accounts.market.maintenance_margin_bps_for(remaining_size, now),
+        now,
+    )?;
+    throttle_outflow(
+        &ctx.accounts.config,
+        ctx.accounts.user_rate_limit.as_mut(),
+        ctx.bumps.user_rate_limit,
+        ctx.accounts.user.key(),
+        settlement_amt,
+        now,
+    )?;
 
-    // Update position
-    let remaining_size = original_size - close_size;
-    ctx.accounts.user_position.base_size = if ctx.accounts.user_position.is_long {
-        ctx.accounts.user_position.base_size - close_size as i64
-    } else {
-        ctx.accounts.user_position.base_size + close_size as i64
-    };
-    
-    // Recalculate margin proportionally
-    let remaining_ratio = remaining_size as u128 * FP / original_size as u128;
-    ctx.accounts.user_position.margin_deposited = 
-        (ctx.accounts.user_position.margin_deposited as u128 * remaining_ratio / FP) as u64;
+    ctx.accounts.user_position.exit(&crate::ID)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+    ctx.accounts.config.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+    ctx.accounts.user_account.exit(&crate::ID)?;
 
-    // Update market totals
-    if ctx.accounts.user_position.is_long {
-        ctx.accounts.market.total_long_size = ctx.accounts.market.total_long_size.saturating_sub(close_size);
-    } else {
-        ctx.accounts.market.total_short_size = ctx.accounts.market.total_short_size.saturating_sub(close_size);
-    }
-
-    // Health check
-        // Health check to ensure position remains valid
-            // Health check after position modification
-    // TODO: Fix health_check lifetime issue
-    // Final health check
-    // TODO: Fix health_check lifetime issue
-    // oracle::health_check(
-    //     &ctx.accounts.oracle.to_account_info(),
-    //     mark_fp,
-    //     ctx.accounts.market.maintenance_margin_bps,
-    // )?;
+    // Interactions
+    pay_out_slice(
+        &ctx.accounts.config,
+        &ctx.accounts.market,
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        &ctx.accounts.vault_token,
+        &ctx.accounts.user_token,
+        &ctx.accounts.fee_destination_token,
+        settlement_amt,
+        slice.fee_forwarded,
+    )?;
 
     emit!(PartialPositionClosed {
         user: ctx.accounts.user.key(),
@@ -121,40 +103,142 @@ pub fn partial_close_position(
         closed_size: close_size,
         remaining_size,
         pnl_fp,
-        settlement_amount: (settlement_amt / FP) as u64,
-        fees_paid: (fee_amt / FP) as u64,
+        settlement_amount: settlement_amt,
+        fees_paid: fee_amt,
     });
 
-    Ok(())
+    Ok(CloseOutcome { pnl_fp, fees_fp: slice.fee_fp, settlement_amount: settlement_amt })
 }
 
 pub fn modify_position_margin(
-    ctx: Context<ModifyPositionMargin>,
+    mut ctx: Context<ModifyPositionMargin>,
     margin_change: i64, // Positive to add, negative to remove
 ) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
     require!(!ctx.accounts.market.is_paused, PerpsError::MarketPaused);
-    require!(ctx.accounts.user_position.base_size != 0, PerpsError::PositionNotFound);
+    // Topping up is how a position under liquidation gets back to health
+    if margin_change < 0 {
+        ctx.accounts.user_position.ensure_status(&[PositionStatus::Open])?;
+    } else {
+        ctx.accounts.user_position.ensure_status(&[PositionStatus::Open, PositionStatus::Liquidating])?;
+    }
+    ctx.accounts.user_position.ensure_isolated()?;
 
-    let mark_fp = oracle::read_oracle_fp(&ctx.accounts.oracle)?;
-    
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?);
+    apply_margin_change(&mut ctx, margin_change, mark_fp)?;
+    post_change_health_check(&ctx, margin_change, mark_fp)?;
+
+    Ok(())
+}
+
+/// Move a position to `target_leverage_x` at its current notional by adding
+/// or removing exactly the margin needed
+pub fn set_position_leverage(
+    mut ctx: Context<ModifyPositionMargin>,
+    target_leverage_x: u16,
+) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+    require!(!ctx.accounts.market.is_paused, PerpsError::MarketPaused);
+    ctx.accounts.user_position.ensure_isolated()?;
+    require!(target_leverage_x > 0, PerpsError::InvalidParameters);
+    require!(target_leverage_x as u64 <= MAX_LEVERAGE_X, PerpsError::LeverageTooHigh);
+    require!(target_leverage_x <= ctx.accounts.market.taker_leverage_cap_x, PerpsError::LeverageTooHigh);
+
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?);
+    let up = &ctx.accounts.user_position;
+    let notional_fp = up.base_size.unsigned_abs() as u128 * mark_fp;
+    let cfg = &ctx.accounts.config;
+    let target_margin = math::margin_for_leverage(notional_fp, target_leverage_x, cfg.price_decimals, cfg.quote_decimals)?;
+    let margin_change: i64 = (target_margin as i128 - up.margin_deposited as i128)
+        .try_into()
+        .map_err(|_| PerpsError::MathOverflow)?;
+
+    // Delevering may rescue a position under liquidation, levering up may not
+    if margin_change < 0 {
+        up.ensure_status(&[PositionStatus::Open])?;
+    } else {
+        up.ensure_status(&[PositionStatus::Open, PositionStatus::Liquidating])?;
+    }
+
+    apply_margin_change(&mut ctx, margin_change, mark_fp)?;
+    post_change_health_check(&ctx, margin_change, mark_fp)?;
+
+    let up = &ctx.accounts.user_position;
+    emit!(PositionMarginModified {
+        user: up.owner,
+        market: up.market,
+        margin_change,
+        new_margin: up.margin_deposited,
+        new_liquidation_price_fp: up.liquidation_price_fp,
+    });
+
+    msg!("Position leverage set to {}x: margin {} ({:+})", target_leverage_x, up.margin_deposited, margin_change);
+    Ok(())
+}
+
+/// Add (positive) or remove (negative) margin, refreshing the liquidation
+/// price and persisting the position before any transfer. Outstanding funding
+/// is settled first so the health checks see it.
+fn apply_margin_change(ctx: &mut Context<ModifyPositionMargin>, margin_change: i64, mark_fp: u128) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    ctx.accounts.user_position.settle_funding(&ctx.accounts.market, now)?;
+    let funding_debt_fp = ctx.accounts.user_position.funding_debt_fp;
+    let vault_margin = ctx.accounts.user_position.vault_margin();
     if margin_change > 0 {
         // Adding margin
         let add_amount = margin_change as u64;
-        // Transfer margin from user to vault
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.user_token.to_account_info(),
-                    to: ctx.accounts.vault_token.to_account_info(),
-                    authority: ctx.accounts.user.to_account_info(),
-                },
-            ),
-            add_amount
-        )?;
-        
         ctx.accounts.user_position.margin_deposited = 
             ctx.accounts.user_position.margin_deposited.saturating_add(add_amount);
+
+        // Back above maintenance: no longer under liquidation
+        let up = &ctx.accounts.user_position;
+        let size = up.base_size.unsigned_abs();
+        let equity_fp = up.equity_fp(&ctx.accounts.config, mark_fp)?;
+        let required_margin_fp = (size as u128 * mark_fp * ctx.accounts.market.maintenance_margin_bps_for(size, now) as u128) / 10_000;
+        if up.status == PositionStatus::Liquidating && equity_fp >= required_margin_fp as i128 {
+            ctx.accounts.user_position.status = PositionStatus::Open;
+            ctx.accounts.user_position.liquidatable_since_ts = 0;
+        }
+        refresh_liquidation_price(ctx)?;
+        ctx.accounts.market.rebook_margin(vault_margin, ctx.accounts.user_position.vault_margin())?;
+        ctx.accounts.user_position.exit(&crate::ID)?;
+        ctx.accounts.market.exit(&crate::ID)?;
+
+        // Transfer margin from user to vault, or pledge its worth in the
+        // mint the position is already margined with
+        if ctx.accounts.user_position.collateral_pledged {
+            let accounts = &mut ctx.accounts;
+            let mut pledge = load_pledge(
+                &accounts.config.key(),
+                &accounts.user.key(),
+                accounts.accepted_collateral.as_mut(),
+                accounts.position_collateral.as_mut(),
+                accounts.collateral_vault.as_ref(),
+                accounts.collateral_user_token.as_ref(),
+                accounts.collateral_mint.as_ref(),
+            )?;
+            let tokens = pledge.add_margin(&accounts.config, accounts.collateral_oracle.as_ref(), add_amount)?;
+            pledge.exit()?;
+            pledge.pull(&accounts.token_program, &accounts.user, tokens)?;
+            emit!(CollateralPledged {
+                user: accounts.user.key(),
+                market: accounts.market.key(),
+                mint: pledge.accepted.mint,
+                amount: tokens,
+                margin_value: add_amount,
+                total_pledged: pledge.position_collateral.amount,
+            });
+        } else {
+            transfer_checked_exact(
+                &ctx.accounts.token_program,
+                &ctx.accounts.quote_mint,
+                ctx.accounts.user_token.to_account_info(),
+                ctx.accounts.vault_token.to_account_info(),
+                ctx.accounts.user.to_account_info(),
+                &[],
+                add_amount,
+            )?;
+        }
         
         emit!(MarginAdded {
             user: ctx.accounts.user.key(),
@@ -163,33 +247,70 @@ pub fn modify_position_margin(
         });
     } else if margin_change < 0 {
         // Removing margin
-        let remove_amount = (-margin_change) as u64;
+        let remove_amount = margin_change.unsigned_abs();
         require!(ctx.accounts.user_position.margin_deposited > remove_amount, PerpsError::InsufficientFunds);
         
         let new_margin = ctx.accounts.user_position.margin_deposited - remove_amount;
         
         // Check if position would still be healthy after margin removal
-        let notional_fp = (ctx.accounts.user_position.base_size.abs() as u128 * mark_fp) / FP;
-        let required_margin = (notional_fp * ctx.accounts.market.maintenance_margin_bps as u128) / 10_000;
+        let size = ctx.accounts.user_position.base_size.unsigned_abs();
+        let notional_fp = size as u128 * mark_fp;
+        let required_margin_fp = (notional_fp * ctx.accounts.market.upcoming_maintenance_margin_bps_for(size) as u128) / 10_000;
         
-        require!(new_margin as u128 >= required_margin, PerpsError::WouldBeLiquidated);
-        
-        // Transfer margin back to user
-        let config_bump = ctx.accounts.config.bump;
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.vault_token.to_account_info(),
-                    to: ctx.accounts.user_token.to_account_info(),
-                    authority: ctx.accounts.config.to_account_info(),
-                },
-                &[&[CONFIG_SEED, &[config_bump]]]
-            ),
-            remove_amount
-        )?;
+        let margin_net_fp = ctx.accounts.config.quote_to_fp(new_margin)? as i128 - funding_debt_fp;
+        require!(margin_net_fp >= required_margin_fp as i128, PerpsError::WouldBeLiquidated);
         
         ctx.accounts.user_position.margin_deposited = new_margin;
+        refresh_liquidation_price(ctx)?;
+        throttle_outflow(
+            &ctx.accounts.config,
+            ctx.accounts.user_rate_limit.as_mut(),
+            ctx.bumps.user_rate_limit,
+            ctx.accounts.user.key(),
+            remove_amount,
+            now,
+        )?;
+        ctx.accounts.market.rebook_margin(vault_margin, ctx.accounts.user_position.vault_margin())?;
+        ctx.accounts.user_position.exit(&crate::ID)?;
+        ctx.accounts.market.exit(&crate::ID)?;
+
+        // Transfer margin back to user; pledged margin frees the same share of its tokens
+        if ctx.accounts.user_position.collateral_pledged {
+            let accounts = &mut ctx.accounts;
+            let mut pledge = load_pledge(
+                &accounts.config.key(),
+                &accounts.user.key(),
+                accounts.accepted_collateral.as_mut(),
+                accounts.position_collateral.as_mut(),
+                accounts.collateral_vault.as_ref(),
+                accounts.collateral_user_token.as_ref(),
+                accounts.collateral_mint.as_ref(),
+            )?;
+            let tokens = pledge.position_collateral.release_share(new_margin + remove_amount, remove_amount);
+            pledge.release(tokens, 0)?;
+            pledge.exit()?;
+            pledge.push(&accounts.token_program, &accounts.config, tokens)?;
+            emit!(PledgedCollateralReleased {
+                user: accounts.user.key(),
+                market: accounts.market.key(),
+                mint: pledge.accepted.mint,
+                returned: tokens,
+                seized: 0,
+                remaining: pledge.position_collateral.amount,
+            });
+        } else {
+            let config_bump = ctx.accounts.config.bump;
+            transfer_checked(
+                &ctx.accounts.token_program,
+                &ctx.accounts.quote_mint,
+                ctx.accounts.vault_token.to_account_info(),
+                ctx.accounts.user_token.to_account_info(),
+                ctx.accounts.config.to_account_info(),
+                &[&[CONFIG_SEED, &[config_bump]]],
+                remove_amount,
+            )?;
+            vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+        }
         
         emit!(MarginRemoved {
             user: ctx.accounts.user.key(),
@@ -197,16 +318,50 @@ pub fn modify_position_margin(
             new_collateral: ctx.accounts.user_position.margin_deposited,
         });
     }
+    Ok(())
+}
+
+/// Revalidate after a margin change. Withdrawals must leave the position above
+/// maintenance; a top-up only needs a sane oracle, since a partial top-up of a
+/// position under liquidation is still allowed.
+fn post_change_health_check(ctx: &Context<ModifyPositionMargin>, margin_change: i64, mark_fp: u128) -> Result<()> {
+    let mark_fp = health_mark_fp(&ctx.accounts.market, &ctx.accounts.oracle, ctx.accounts.oracle_twap.as_deref(), mark_fp)?;
+    let now = Clock::get()?.unix_timestamp;
+    let precision = ctx.accounts.market.price_precision;
+    if margin_change >= 0 {
+        return oracle::check_oracle_sanity(&ctx.accounts.oracle, precision, mark_fp, now);
+    }
+    let up = &ctx.accounts.user_position;
+    oracle::health_check(
+        &ctx.accounts.oracle,
+        precision,
+        mark_fp,
+        up.base_size.unsigned_abs(),
+        up.equity_fp(&ctx.accounts.config, mark_fp)?,
+        ctx.accounts.market.maintenance_margin_bps_for(up.base_size.unsigned_abs(), now),
+        now,
+    )
+}
 
-    // Health check
-    // TODO: Fix health_check lifetime issue
-    // let oracle_info = ctx.accounts.oracle.to_account_info();
-    // oracle::health_check(
-    //     &oracle_info,
-    //     mark_fp,
-    //     ctx.accounts.market.maintenance_margin_bps,
-    // )?;
+/// Mark health checks judge at: the trade's own `mark_fp`, or the TWAP mark
+/// when the market prices risk at one
+fn health_mark_fp(market: &Market, oracle: &Account<OraclePrice>, oracle_twap: Option<&OracleTwap>, mark_fp: u128) -> Result<u128> {
+    if market.risk_twap_seconds == 0 {
+        return Ok(mark_fp);
+    }
+    Ok(market.settlement_mark_fp(oracle::risk_index_price_fp(market, oracle, oracle_twap)?))
+}
 
+fn refresh_liquidation_price(ctx: &mut Context<ModifyPositionMargin>) -> Result<()> {
+    let up = &ctx.accounts.user_position;
+    let liquidation_price_fp = math::liquidation_price_fp(
+        up.entry_price_fp,
+        ctx.accounts.config.quote_to_fp(up.margin_deposited)?,
+        up.base_size.unsigned_abs(),
+        ctx.accounts.market.upcoming_maintenance_margin_bps_for(up.base_size.unsigned_abs()),
+        up.is_long,
+    )?;
+    ctx.accounts.user_position.liquidation_price_fp = liquidation_price_fp;
     Ok(())
 }
 
@@ -214,16 +369,21 @@ pub fn set_stop_loss(
     ctx: Context<SetStopLoss>,
     trigger_price_fp: u128,
     close_percentage: u8, // 1-100 (100 = close entire position)
+    expires_at: Option<i64>,
 ) -> Result<()> {
     require!(
         close_percentage > 0 && close_percentage <= 100,
         PerpsError::InvalidMarketParameters
     );
-    require!(ctx.accounts.user_position.base_size != 0, PerpsError::PositionNotFound);
+    let now = Clock::get()?.unix_timestamp;
+    validate_order_expiry(expires_at, now)?;
+    ctx.accounts.user_position.ensure_status(&[PositionStatus::Open])?;
+    ctx.accounts.user_position.ensure_isolated()?;
+    ctx.accounts.user_position.ensure_quote_margin()?;
     require!(trigger_price_fp > 0, PerpsError::InvalidPrice);
 
     // Validate stop loss direction
-    let current_price_fp = oracle::read_oracle_fp(&ctx.accounts.oracle)?;
+    let current_price_fp = oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?;
     
     if ctx.accounts.user_position.is_long {
         require!(trigger_price_fp < current_price_fp, PerpsError::InvalidStopLoss);
@@ -232,14 +392,25 @@ pub fn set_stop_loss(
     }
 
     // Set stop loss order details
-    ctx.accounts.stop_loss_order.owner = ctx.accounts.user_position.owner;
-    ctx.accounts.stop_loss_order.market = ctx.accounts.market.key();
-    ctx.accounts.stop_loss_order.position_key = ctx.accounts.user_position.key();
-    ctx.accounts.stop_loss_order.trigger_price_fp = trigger_price_fp;
-    ctx.accounts.stop_loss_order.close_percentage = close_percentage;
-    ctx.accounts.stop_loss_order.is_active = true;
-    ctx.accounts.stop_loss_order.created_at = Clock::get()?.unix_timestamp;
-    ctx.accounts.stop_loss_order.executed_at = None;
+    // Re-arming a resting order keeps its slot; a new one takes another
+    let orders = &mut ctx.accounts.user_orders;
+    orders.owner = ctx.accounts.user.key();
+    orders.bump = ctx.bumps.user_orders;
+    if !ctx.accounts.stop_loss_order.is_active {
+        orders.add_order(ctx.accounts.config.max_active_orders_per_user)?;
+    }
+
+    let position_key = ctx.accounts.user_position.key();
+    ctx.accounts.stop_loss_order.arm(
+        ctx.accounts.user_position.owner,
+        ctx.accounts.market.key(),
+        position_key,
+        trigger_price_fp,
+        close_percentage,
+        now,
+        expires_at,
+        ctx.bumps.stop_loss_order,
+    );
 
     emit!(StopLossSet {
         user: ctx.accounts.user_position.owner,
@@ -252,11 +423,250 @@ pub fn set_stop_loss(
     Ok(())
 }
 
+/// Anyone may crank a stop loss once the mark crosses its trigger
+pub fn execute_stop_loss(ctx: Context<ExecuteStopLoss>) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+    require!(ctx.accounts.stop_loss_order.is_active, PerpsError::OrderNotActive);
+    require!(!ctx.accounts.market.is_paused, PerpsError::MarketPaused);
+
+    // An order left behind by a position that was closed out by hand is retired
+    // rather than executed. Failing here would roll the retirement back, and the
+    // order would otherwise still be armed against the next position opened.
+    if ctx.accounts.user_position.orphans_order(ctx.accounts.stop_loss_order.created_at) {
+        ctx.accounts.stop_loss_order.is_active = false;
+        ctx.accounts.user_orders.remove_order();
+        emit!(StopLossCancelled {
+            user: ctx.accounts.user_position.owner,
+            market: ctx.accounts.market.key(),
+        });
+        msg!("Stop loss retired: {}", PerpsError::PositionNotFound);
+        return Ok(());
+    }
+    ctx.accounts.user_position.ensure_status(&[PositionStatus::Open])?;
+    ctx.accounts.user_position.ensure_isolated()?;
+    let now = Clock::get()?.unix_timestamp;
+    require!(!ctx.accounts.stop_loss_order.is_expired(now), PerpsError::OrderExpired);
+
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?);
+    let trigger_price_fp = ctx.accounts.stop_loss_order.trigger_price_fp;
+    let close_percentage = ctx.accounts.stop_loss_order.close_percentage;
+    require!(
+        ctx.accounts.stop_loss_order.is_triggered(ctx.accounts.user_position.is_long, mark_fp),
+        PerpsError::StopLossNotTriggered
+    );
+
+    // Size off the live position; 100% closes it outright
+    let close_size = slice_size(ctx.accounts.user_position.base_size.unsigned_abs(), close_percentage);
+    require!(close_size > 0, PerpsError::PositionTooSmall);
+
+    // Effects
+    let slice = close_slice(
+        &mut ctx.accounts.config,
+        &mut ctx.accounts.market,
+        &mut ctx.accounts.user_position,
+        close_size,
+        mark_fp,
+        ctx.accounts.vault_token.amount,
+        now,
+    )?;
+    if slice.remaining_size == 0 {
+        ctx.accounts.protocol_stats.record_close();
+        ctx.accounts.user_account.record_close();
+    }
+    ctx.accounts.protocol_stats.record_fees(slice.fee_amt)?;
+    emit!(ctx.accounts.protocol_stats.updated_event());
+    let order = &mut ctx.accounts.stop_loss_order;
+    order.is_active = false;
+    order.executed_at = Some(now);
+    ctx.accounts.user_orders.remove_order();
+    throttle_outflow(
+        &ctx.accounts.config,
+        ctx.accounts.user_rate_limit.as_mut(),
+        None,
+        ctx.accounts.user_position.owner,
+        slice.settlement_amt,
+        now,
+    )?;
+
+    ctx.accounts.stop_loss_order.exit(&crate::ID)?;
+    ctx.accounts.user_orders.exit(&crate::ID)?;
+    ctx.accounts.user_position.exit(&crate::ID)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+    ctx.accounts.config.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+    ctx.accounts.user_account.exit(&crate::ID)?;
+
+    // Interactions
+    pay_out_slice(
+        &ctx.accounts.config,
+        &ctx.accounts.market,
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        &ctx.accounts.vault_token,
+        &ctx.accounts.user_token,
+        &ctx.accounts.fee_destination_token,
+        slice.settlement_amt,
+        slice.fee_forwarded,
+    )?;
+
+    emit!(StopLossExecuted {
+        user: ctx.accounts.user_position.owner,
+        market: ctx.accounts.market.key(),
+        trigger_price_fp,
+        close_percentage,
+        executor: ctx.accounts.executor.key(),
+    });
+
+    msg!("Stop loss executed: {} units @ {}, PnL {}, Settlement {}",
+         close_size, mark_fp, slice.pnl_fp, slice.settlement_amt);
+
+    Ok(())
+}
+
+/// Cancel a resting stop loss, freeing its order slot and returning the rent
+pub fn cancel_stop_loss(ctx: Context<CancelStopLoss>) -> Result<()> {
+    if ctx.accounts.stop_loss_order.is_active {
+        ctx.accounts.user_orders.remove_order();
+    }
+
+    emit!(StopLossCancelled {
+        user: ctx.accounts.user.key(),
+        market: ctx.accounts.market.key(),
+    });
+
+    Ok(())
+}
+
+// Helpers
+
+/// Amounts from realizing part (or all) of a position
+#[derive(Debug)]
+pub(crate) struct SliceClose {
+    pub pnl_fp: i128,
+    pub settlement_amt: u64,
+    pub fee_amt: u64,
+    pub fee_fp: u128,       // Exit fee before the vault cushion, as in `PositionClosed`
+    pub fee_forwarded: u64, // fee_amt less the market creator's share
+    pub remaining_size: u64,
+}
+
+/// Units in a `close_percentage` slice of a position of `original_size`,
+/// rounded down; 100% (or more) takes all of it
+pub(crate) fn slice_size(original_size: u64, close_percentage: u8) -> u64 {
+    if close_percentage >= 100 {
+        original_size
+    } else {
+        (original_size as u128 * close_percentage as u128 / 100) as u64
+    }
+}
+
+/// Realize `close_size` of a position at `mark_fp`. The slice takes its share
+/// of margin plus PnL and of the funding owed, net of the fee on its exit
+/// notional; the rest of the margin and funding stays with the remaining
+/// position. Only updates state, the caller does the transfers.
+pub(crate) fn close_slice(
+    cfg: &mut Config,
+    market: &mut Market,
+    up: &mut UserPosition,
+    close_size: u64,
+    mark_fp: u128,
+    vault_balance: u64,
+    now: i64,
+) -> Result<SliceClose> {
+    up.ensure_quote_margin()?;
+    let original_size = up.base_size.unsigned_abs();
+    require!(close_size > 0 && close_size <= original_size, PerpsError::PositionTooSmall);
+    let is_long = up.is_long;
+
+    // The slice realizes its share of funding; the remainder keeps the rest
+    let funding_fp = take_funding_share(up, market, close_size, now)?;
+
+    // Calculate PnL for the portion being closed
+    let close_notional_entry_fp = close_size as u128 * up.entry_price_fp;
+    let close_notional_exit_fp = close_size as u128 * mark_fp;
+    let pnl_fp = if is_long {
+        close_notional_exit_fp as i128 - close_notional_entry_fp as i128
+    } else {
+        close_notional_entry_fp as i128 - close_notional_exit_fp as i128
+    } - funding_fp;
+
+    let margin_share = (up.margin_deposited as u128 * close_size as u128
+        / original_size as u128) as u64;
+    let settlement = math::close_settlement(
+        cfg.quote_to_fp(margin_share)?,
+        pnl_fp,
+        close_notional_exit_fp,
+        cfg.fee_bps,
+    );
+    let payout = cfg.settle_to_quote(settlement.payout_fp)?;
+    let fee = cfg.settle_to_quote(settlement.fee_fp)?;
+    let (settlement_amt, fee_amt) = cfg.cushion_payout(payout, fee, vault_balance)?;
+
+    let vault_margin = up.vault_margin();
+    let remaining_size = original_size - close_size;
+    if remaining_size == 0 {
+        up.settle_full_close(pnl_fp, fee_amt, now);
+    } else {
+        up.base_size = if is_long { remaining_size as i64 } else { -(remaining_size as i64) };
+        up.margin_deposited -= margin_share;
+        up.realized_pnl_fp += pnl_fp;
+        up.total_fees_paid += fee_amt;
+        up.last_updated_ts = now;
+
+        // Recalculate liquidation price for the remaining position
+        up.liquidation_price_fp = math::liquidation_price_fp(
+            up.entry_price_fp,
+            cfg.quote_to_fp(up.margin_deposited)?,
+            remaining_size,
+            market.upcoming_maintenance_margin_bps_for(remaining_size),
+            is_long,
+        )?;
+    }
+    market.reduce_open_interest(is_long, close_size);
+    market.rebook_margin(vault_margin, up.vault_margin())?;
+    market.record_settlement(pnl_fp, settlement.fee_fp)?;
+    let fee_forwarded = market.retain_creator_share(fee_amt, cfg.creator_reward_bps)?;
+
+    Ok(SliceClose { pnl_fp, settlement_amt, fee_amt, fee_fp: settlement.fee_fp, fee_forwarded, remaining_size })
+}
+
+/// Pay a closed slice out of `market`'s vault: settlement to the trader, the
+/// forwarded fee to the fee destination
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn pay_out_slice<'info>(
+    config: &Account<'info, Config>,
+    market: &Market,
+    token_program: &Interface<'info, TokenInterface>,
+    quote_mint: &InterfaceAccount<'info, Mint>,
+    vault_token: &InterfaceAccount<'info, TokenAccount>,
+    user_token: &InterfaceAccount<'info, TokenAccount>,
+    fee_destination_token: &InterfaceAccount<'info, TokenAccount>,
+    settlement_amt: u64,
+    fee_amt: u64,
+) -> Result<()> {
+    let signer_seeds: &[&[&[u8]]] = &[&[CONFIG_SEED, &[config.bump]]];
+    for (to, amount) in [(user_token, settlement_amt), (fee_destination_token, fee_amt)] {
+        if amount > 0 {
+            transfer_checked(
+                token_program,
+                quote_mint,
+                vault_token.to_account_info(),
+                to.to_account_info(),
+                config.to_account_info(),
+                signer_seeds,
+                amount,
+            )?;
+        }
+    }
+    vault_solvency_check(&vault_token.to_account_info(), config, market)
+}
+
 // Context structures
 
 #[derive(Accounts)]
 pub struct PartialClosePosition<'info> {
     #[account(
+        mut,
         seeds = [CONFIG_SEED],
         bump = config.bump,
     )]
@@ -282,22 +692,60 @@ pub struct PartialClosePosition<'info> {
 
     #[account(
         mut,
-        seeds = [VAULT_SEED, config.key().as_ref()],
-        bump = config.bump,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
     )]
-    pub vault_token: Account<'info, TokenAccount>,
+    pub protocol_stats: Account<'info, ProtocolStats>,
 
-    #[account(mut)]
-    pub user_token: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
+        bump = user_account.bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
 
     #[account(
         mut,
-        address = config.fee_destination
+        address = config.fee_destination @ PerpsError::InvalidTokenAccount
     )]
-    pub fee_destination_token: Account<'info, TokenAccount>,
+    pub fee_destination_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// Only needed while withdrawals are rate limited
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRateLimit::SPACE,
+        seeds = [USER_RATE_LIMIT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_rate_limit: Option<Account<'info, UserRateLimit>>,
 
     pub oracle: Account<'info, OraclePrice>,
-    pub token_program: Program<'info, Token>,
+
+    /// The oracle's TWAP ring, required when the market prices risk at a TWAP
+    #[account(seeds = [ORACLE_TWAP_SEED, oracle.key().as_ref()], bump = oracle_twap.bump)]
+    pub oracle_twap: Option<Account<'info, OracleTwap>>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
@@ -309,6 +757,7 @@ pub struct ModifyPositionMargin<'info> {
     pub config: Account<'info, Config>,
 
     #[account(
+        mut,
         seeds = [MARKET_SEED, market.symbol.as_ref()],
         bump = market.bump,
     )]
@@ -327,20 +776,81 @@ pub struct ModifyPositionMargin<'info> {
 
     #[account(
         mut,
-        seeds = [VAULT_SEED, config.key().as_ref()],
-        bump = config.bump,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
     )]
-    pub vault_token: Account<'info, TokenAccount>,
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
 
-    #[account(mut)]
-    pub user_token: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// Only needed while withdrawals are rate limited
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRateLimit::SPACE,
+        seeds = [USER_RATE_LIMIT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_rate_limit: Option<Account<'info, UserRateLimit>>,
 
     pub oracle: Account<'info, OraclePrice>,
-    pub token_program: Program<'info, Token>,
+
+    /// The oracle's TWAP ring, required when the market prices risk at a TWAP
+    #[account(seeds = [ORACLE_TWAP_SEED, oracle.key().as_ref()], bump = oracle_twap.bump)]
+    pub oracle_twap: Option<Account<'info, OracleTwap>>,
+
+    /// The pledge, its mint, price feed and vault and the user's tokens of
+    /// it, required to change the margin of a position margined with pledged collateral
+    #[account(
+        mut,
+        seeds = [ACCEPTED_COLLATERAL_SEED, accepted_collateral.mint.as_ref()],
+        bump = accepted_collateral.bump
+    )]
+    pub accepted_collateral: Option<Account<'info, AcceptedCollateral>>,
+
+    pub collateral_oracle: Option<Account<'info, OraclePrice>>,
+
+    #[account(
+        mut,
+        seeds = [POSITION_COLLATERAL_SEED, user_position.key().as_ref()],
+        bump = position_collateral.bump
+    )]
+    pub position_collateral: Option<Account<'info, PositionCollateral>>,
+
+    #[account(
+        mut,
+        seeds = [COLLATERAL_VAULT_SEED, collateral_vault.mint.as_ref()],
+        bump
+    )]
+    pub collateral_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub collateral_user_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// The pledged mint, checked against accepted_collateral.mint
+    pub collateral_mint: Option<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
 pub struct SetStopLoss<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
     #[account(
         seeds = [MARKET_SEED, market.symbol.as_ref()],
         bump = market.bump,
@@ -366,6 +876,521 @@ pub struct SetStopLoss<'info> {
     )]
     pub stop_loss_order: Account<'info, StopLossOrder>,
 
+    #[account(
+        init_if_needed,
+        payer = user,
+        seeds = [USER_ORDERS_SEED, user.key().as_ref()],
+        bump,
+        space = UserOrders::SPACE,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+
     pub oracle: Account<'info, OraclePrice>,
     pub system_program: Program<'info, System>,
 }
+
+#[derive(Accounts)]
+pub struct ExecuteStopLoss<'info> {
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, market.symbol.as_ref()],
+        bump = market.bump,
+    )]
+    pub market: Account<'info, Market>,
+
+    pub executor: Signer<'info>, // Anyone can crank a triggered stop loss
+
+    #[account(
+        mut,
+        seeds = [POSITION_SEED, user_position.owner.as_ref(), market.key().as_ref()],
+        bump = user_position.bump,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user_position.owner.as_ref()],
+        bump = user_account.bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        seeds = [STOP_LOSS_SEED, user_position.owner.as_ref(), market.key().as_ref()],
+        bump = stop_loss_order.bump,
+        constraint = stop_loss_order.position_key == user_position.key() @ PerpsError::InvalidStopLoss,
+    )]
+    pub stop_loss_order: Account<'info, StopLossOrder>,
+
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_SEED, user_position.owner.as_ref()],
+        bump = user_orders.bump,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user_position.owner @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = config.fee_destination @ PerpsError::InvalidTokenAccount
+    )]
+    pub fee_destination_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// Only needed while withdrawals are rate limited; the owner creates it
+    #[account(
+        mut,
+        seeds = [USER_RATE_LIMIT_SEED, user_position.owner.as_ref()],
+        bump = user_rate_limit.bump,
+    )]
+    pub user_rate_limit: Option<Account<'info, UserRateLimit>>,
+
+    pub oracle: Account<'info, OraclePrice>,
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CancelStopLoss<'info> {
+    #[account(
+        seeds = [MARKET_SEED, market.symbol.as_ref()],
+        bump = market.bump,
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [STOP_LOSS_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump = stop_loss_order.bump,
+        close = user,
+    )]
+    pub stop_loss_order: Account<'info, StopLossOrder>,
+
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_SEED, user.key().as_ref()],
+        bump = user_orders.bump,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_runtime::{run_at, token_balance, Book, Transfer};
+    use std::{cell::RefCell, rc::Rc};
+
+    const PRICE: u128 = 100 * FP;
+
+    fn config() -> Config {
+        Config { price_decimals: 6, quote_decimals: 6, fee_bps: 10, ..Default::default() }
+    }
+
+    #[test]
+    fn test_closes_proceed_into_expiry_at_pinned_price() {
+        let mut cfg = config();
+        let mut market = Market {
+            total_long_size: 10,
+            total_margin_locked: 100_000_000,
+            expiry_ts: 10_000,
+            close_only_before_expiry_seconds: 600,
+            ..Default::default()
+        };
+        // Inside the window opens are refused, but reducing still works
+        assert!(market.is_close_only(9_500));
+        let mut up = long_position(10, 100_000_000);
+        let mark_fp = market.settlement_mark_fp(110 * FP);
+        let slice = close_slice(&mut cfg, &mut market, &mut up, 4, mark_fp, u64::MAX, 9_500).unwrap();
+        assert_eq!(slice.pnl_fp, 40 * FP as i128);
+
+        // After settlement the rest closes at the final price, not the live mark
+        market.final_settlement_price_fp = 105 * FP;
+        let mark_fp = market.settlement_mark_fp(130 * FP);
+        let slice = close_slice(&mut cfg, &mut market, &mut up, 6, mark_fp, u64::MAX, 10_500).unwrap();
+        assert_eq!(slice.pnl_fp, 30 * FP as i128);
+        assert_eq!(up.status, PositionStatus::Closed);
+        assert_eq!(market.total_long_size, 0);
+    }
+
+    fn long_position(size: i64, margin: u64) -> UserPosition {
+        UserPosition {
+            is_long: true,
+            base_size: size,
+            entry_price_fp: PRICE,
+            margin_deposited: margin,
+            status: PositionStatus::Open,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_partial_close_settles_proportional_slice() {
+        let mut cfg = config();
+        let mut market = Market { total_long_size: 10, maintenance_margin_bps: 500, total_margin_locked: 200_000_000, ..Default::default() };
+        // 10 units at $100 with $200 margin, closing 40% at $110
+        let mut up = long_position(10, 200_000_000);
+        let slice = close_slice(&mut cfg, &mut market, &mut up, 4, 110 * FP, u64::MAX, 1_000).unwrap();
+
+        // Slice gets 40% of margin ($80) + $40 PnL - 0.1% of $440
+        assert_eq!(slice.pnl_fp, 40 * FP as i128);
+        assert_eq!(slice.fee_amt, 440_000);
+        assert_eq!(slice.settlement_amt, 80_000_000 + 40_000_000 - 440_000);
+        assert_eq!(slice.remaining_size, 6);
+
+        // Remaining position keeps the other 60% of margin
+        assert_eq!((up.base_size, up.margin_deposited), (6, 120_000_000));
+        assert_eq!(up.realized_pnl_fp, 40 * FP as i128);
+        assert_eq!(up.status, PositionStatus::Open);
+        assert_eq!(market.total_long_size, 6);
+        assert_eq!(market.cumulative_fees_fp, 440_000);
+    }
+
+    #[test]
+    fn test_close_outcome_fits_in_return_data() {
+        let mut cfg = config();
+        let mut market = Market { total_long_size: 10, total_margin_locked: 200_000_000, ..Default::default() };
+        let mut up = long_position(10, 200_000_000);
+        let slice = close_slice(&mut cfg, &mut market, &mut up, 10, 90 * FP, u64::MAX, 1_000).unwrap();
+        let outcome = CloseOutcome { pnl_fp: slice.pnl_fp, fees_fp: slice.fee_fp, settlement_amount: slice.settlement_amt };
+
+        // Negative PnL survives the round trip a CPI caller does with get_return_data
+        let bytes = outcome.try_to_vec().unwrap();
+        assert_eq!(bytes.len(), CloseOutcome::SPACE);
+        assert_eq!(CloseOutcome::try_from_slice(&bytes).unwrap(), outcome);
+        assert_eq!((outcome.pnl_fp, outcome.fees_fp), (-100 * FP as i128, 900_000));
+    }
+
+    #[test]
+    fn test_slices_pay_out_exactly_the_margin_deposited() {
+        // Guards the slice math against drifting from `UserPosition`: margin
+        // lives in `margin_deposited` and nowhere else
+        let mut cfg = config();
+        let mut market = Market { total_long_size: 10, total_margin_locked: 100_000_000, ..Default::default() };
+        let mut up = long_position(10, 100_000_000);
+
+        let mut paid_out = 0;
+        for (close_size, now) in [(3, 1_000), (3, 2_000), (4, 3_000)] {
+            let margin_before = up.margin_deposited;
+            let slice = close_slice(&mut cfg, &mut market, &mut up, close_size, PRICE, u64::MAX, now).unwrap();
+            assert!(up.margin_deposited < margin_before);
+            paid_out += slice.settlement_amt + slice.fee_amt;
+        }
+
+        // At a flat price the slices hand back the deposit to the unit
+        assert_eq!(paid_out, 100_000_000);
+        assert_eq!((up.base_size, up.margin_deposited), (0, 0));
+    }
+
+    #[test]
+    fn test_partial_close_scales_funding_debt_with_the_remainder() {
+        let mut cfg = config();
+        let mut market = Market { total_long_size: 10, total_margin_locked: 200_000_000, ..Default::default() };
+        let mut up = long_position(10, 200_000_000);
+        up.funding_debt_fp = 3 * FP as i128;
+
+        // Closing 40% leaves 60% of the debt on the remaining 6 units
+        close_slice(&mut cfg, &mut market, &mut up, 4, PRICE, u64::MAX, 1_000).unwrap();
+        assert_eq!(up.funding_debt_fp, 1_800_000);
+
+        // Funding credit (negative debt) scales the same way
+        up.funding_debt_fp = -(9 * FP as i128);
+        close_slice(&mut cfg, &mut market, &mut up, 3, PRICE, u64::MAX, 2_000).unwrap();
+        assert_eq!(up.base_size, 3);
+        assert_eq!(up.funding_debt_fp, -(9 * FP as i128) / 2);
+    }
+
+    #[test]
+    fn test_slices_realize_accrued_funding_without_losing_dust() {
+        let mut cfg = config();
+        // The long index fell 0.1 per unit since the position opened, on top
+        // of 1 already owed: 1_000_001 in all
+        let mut market = Market { total_long_size: 10, cumulative_funding_long_fp: -100_000, total_margin_locked: 200_000_000, ..Default::default() };
+        let mut up = long_position(10, 200_000_000);
+        up.funding_debt_fp = 1;
+
+        // A flat-price slice of 3 realizes only its share of the 1_000_001 owed
+        let slice = close_slice(&mut cfg, &mut market, &mut up, 3, PRICE, u64::MAX, 1_000).unwrap();
+        assert_eq!(slice.pnl_fp, -300_000);
+        assert_eq!(up.funding_debt_fp, 700_001);
+        assert_eq!(up.last_cumulative_funding_fp, -100_000);
+
+        // Closing exactly the remaining size takes the rest, rounding dust included
+        let slice = close_slice(&mut cfg, &mut market, &mut up, 7, PRICE, u64::MAX, 2_000).unwrap();
+        assert_eq!(slice.pnl_fp, -700_001);
+        assert_eq!((up.base_size, up.funding_debt_fp), (0, 0));
+    }
+
+    #[test]
+    fn test_partial_close_remainder_must_clear_maintenance() {
+        let cfg = config();
+        let mut market = Market { total_long_size: 20, total_margin_locked: 200_000_000, ..Default::default() };
+
+        // 10 units at 10x: half closed at $95 leaves $25 equity against $23.75 maintenance
+        let mut cfg_a = cfg.clone();
+        let mut up = long_position(10, 100_000_000);
+        close_slice(&mut cfg_a, &mut market, &mut up, 5, 95 * FP, u64::MAX, 1_000).unwrap();
+        let equity_fp = up.equity_fp(&cfg_a, 95 * FP).unwrap();
+        assert!(oracle::check_maintenance(95 * FP, 5, equity_fp, 500).is_ok());
+
+        // At $94 the remaining $20 falls short of $23.50, so the close reverts
+        let mut cfg_b = cfg.clone();
+        let mut up = long_position(10, 100_000_000);
+        close_slice(&mut cfg_b, &mut market, &mut up, 5, 94 * FP, u64::MAX, 1_000).unwrap();
+        let equity_fp = up.equity_fp(&cfg_b, 94 * FP).unwrap();
+        assert_eq!(equity_fp, 20 * FP as i128);
+        assert!(oracle::check_maintenance(94 * FP, 5, equity_fp, 500).is_err());
+    }
+
+    #[test]
+    fn test_margin_removal_into_a_loss_breaches_maintenance() {
+        let cfg = config();
+        // $60 left after removing $40 covers $47.50 maintenance on margin alone,
+        // but the $50 unrealized loss at $95 leaves only $10 of equity
+        let up = long_position(10, 60_000_000);
+        let equity_fp = up.equity_fp(&cfg, 95 * FP).unwrap();
+        assert_eq!(equity_fp, 10 * FP as i128);
+        assert!(oracle::check_maintenance(95 * FP, 10, equity_fp, 500).is_err());
+
+        // Removing $2 instead keeps $48 of equity and passes
+        let up = long_position(10, 98_000_000);
+        let equity_fp = up.equity_fp(&cfg, 95 * FP).unwrap();
+        assert!(oracle::check_maintenance(95 * FP, 10, equity_fp, 500).is_ok());
+        // A fully closed position has nothing to maintain
+        assert!(oracle::check_maintenance(95 * FP, 0, 0, 500).is_ok());
+    }
+
+    #[test]
+    fn test_short_slice_loses_on_rally() {
+        let mut cfg = config();
+        let mut market = Market { total_short_size: 10, total_margin_locked: 100_000_000, ..Default::default() };
+        let mut up = UserPosition { is_long: false, base_size: -10, ..long_position(0, 100_000_000) };
+        let slice = close_slice(&mut cfg, &mut market, &mut up, 5, 104 * FP, u64::MAX, 1_000).unwrap();
+
+        // $50 of margin back less $20 loss and the fee on $520
+        assert_eq!(slice.pnl_fp, -(20 * FP as i128));
+        assert_eq!(slice.settlement_amt, 50_000_000 - 20_000_000 - 520_000);
+        assert_eq!((up.base_size, up.margin_deposited), (-5, 50_000_000));
+        assert_eq!(market.total_short_size, 5);
+    }
+
+    #[test]
+    fn test_full_stop_loss_closes_position() {
+        let mut cfg = config();
+        let mut market = Market { total_long_size: 10, total_margin_locked: 100_000_000, ..Default::default() };
+        let mut up = long_position(10, 100_000_000);
+        let order = StopLossOrder { trigger_price_fp: 95 * FP, ..Default::default() };
+        assert!(order.is_triggered(true, 95 * FP));
+        let slice = close_slice(&mut cfg, &mut market, &mut up, 10, 95 * FP, u64::MAX, 2_000).unwrap();
+
+        assert_eq!(slice.remaining_size, 0);
+        assert_eq!(slice.settlement_amt, 100_000_000 - 50_000_000 - 950_000);
+        assert_eq!((up.base_size, up.margin_deposited), (0, 0));
+        assert_eq!(up.status, PositionStatus::Closed);
+        assert_eq!(market.total_long_size, 0);
+
+        // Can't close more than what's left
+        let mut closed = long_position(3, 1_000);
+        assert!(close_slice(&mut cfg, &mut market, &mut closed, 4, PRICE, u64::MAX, 2_000).is_err());
+    }
+
+    #[test]
+    fn test_hundred_percent_partial_close_is_a_full_close() {
+        let cfg = config();
+        let mut up = long_position(7, 100_000_000);
+        let mut market = Market { total_long_size: 12, total_margin_locked: 100_000_000, ..Default::default() };
+        let close_size = slice_size(7, 100);
+        assert_eq!(close_size, 7);
+        let mut partial_cfg = config();
+        let slice = close_slice(&mut partial_cfg, &mut market, &mut up, close_size, 93 * FP, u64::MAX, 3_000).unwrap();
+
+        // Same settlement close_position computes for the whole position
+        let pnl_fp = 7 * (93 * FP as i128 - PRICE as i128);
+        let full = math::close_settlement(cfg.quote_to_fp(100_000_000).unwrap(), pnl_fp, 7 * 93 * FP, cfg.fee_bps);
+        assert_eq!(slice.pnl_fp, pnl_fp);
+        assert_eq!(slice.settlement_amt, cfg.fp_to_quote(full.payout_fp).unwrap());
+        assert_eq!(slice.fee_amt, cfg.fp_to_quote(full.fee_fp).unwrap());
+
+        // All margin and OI settled, nothing left behind
+        assert_eq!(slice.remaining_size, 0);
+        assert_eq!((up.base_size, up.margin_deposited), (0, 0));
+        assert_eq!(up.status, PositionStatus::Closed);
+        assert_eq!(market.total_long_size, 5);
+    }
+
+    #[test]
+    fn test_partial_close_leaving_dust_closes_the_whole_position() {
+        let mut cfg = config();
+        let mut market = Market { total_long_size: 10, min_position_base: 2, total_margin_locked: 100_000_000, ..Default::default() };
+        let mut up = long_position(10, 100_000_000);
+
+        // 80% leaves 2 units, right at the floor, so it stays partial
+        assert_eq!(market.dust_free_close_size(10, slice_size(10, 80)), 8);
+        // 95% would leave a single unit of dust: the whole position goes instead
+        let close_size = market.dust_free_close_size(10, slice_size(10, 95));
+        assert_eq!(close_size, 10);
+        let slice = close_slice(&mut cfg, &mut market, &mut up, close_size, PRICE, u64::MAX, 2_000).unwrap();
+        assert_eq!(slice.remaining_size, 0);
+        assert_eq!((up.base_size, up.margin_deposited), (0, 0));
+        assert_eq!(up.status, PositionStatus::Closed);
+        assert_eq!(market.total_long_size, 0);
+
+        // With the floor off any remainder is allowed
+        market.min_position_base = 0;
+        assert_eq!(market.dust_free_close_size(10, 9), 9);
+    }
+
+    #[test]
+    fn test_stop_loss_after_partial_close_takes_the_live_size() {
+        let mut cfg = config();
+        let mut market = Market { total_long_size: 10, total_margin_locked: 100_000_000, ..Default::default() };
+        let mut up = long_position(10, 100_000_000);
+        let order = StopLossOrder { trigger_price_fp: 95 * FP, close_percentage: 100, is_active: true, ..Default::default() };
+
+        // Half closed by hand first
+        close_slice(&mut cfg, &mut market, &mut up, slice_size(10, 50), PRICE, u64::MAX, 1_000).unwrap();
+        assert_eq!(up.base_size, 5);
+        assert!(!up.orphans_order(order.created_at));
+
+        // The 100% stop then closes what is actually left, not the original 10
+        let close_size = slice_size(up.base_size.unsigned_abs(), order.close_percentage);
+        assert_eq!(close_size, 5);
+        let slice = close_slice(&mut cfg, &mut market, &mut up, close_size, 95 * FP, u64::MAX, 2_000).unwrap();
+        assert_eq!(slice.remaining_size, 0);
+        assert_eq!(market.total_long_size, 0);
+    }
+
+    #[test]
+    fn test_stop_loss_on_closed_position_is_orphaned() {
+        let mut cfg = config();
+        let mut market = Market { total_long_size: 10, total_margin_locked: 100_000_000, ..Default::default() };
+        let mut up = UserPosition { opened_at_ts: 500, ..long_position(10, 100_000_000) };
+        let armed_at = 600;
+        assert!(!up.orphans_order(armed_at));
+
+        // Closed by hand: nothing left for the order to act on
+        close_slice(&mut cfg, &mut market, &mut up, 10, PRICE, u64::MAX, 1_000).unwrap();
+        assert!(up.orphans_order(armed_at));
+        assert!(UserPosition { status: PositionStatus::PendingSettlement, ..up }.orphans_order(armed_at));
+
+        // Reopened later: the old order still doesn't apply to the new position
+        let reopened = UserPosition { opened_at_ts: 2_000, ..long_position(3, 10_000_000) };
+        assert!(reopened.orphans_order(armed_at));
+        assert!(!reopened.orphans_order(2_000));
+
+        // A position under liquidation is still live
+        let liquidating = UserPosition { status: PositionStatus::Liquidating, opened_at_ts: 500, ..long_position(10, 1) };
+        assert!(!liquidating.orphans_order(armed_at));
+    }
+
+    #[test]
+    fn test_stop_loss_trigger_direction() {
+        let order = StopLossOrder { trigger_price_fp: 95 * FP, ..Default::default() };
+        assert!(order.is_triggered(true, 94 * FP));
+        assert!(!order.is_triggered(true, 96 * FP));
+        let order = StopLossOrder { trigger_price_fp: 105 * FP, ..Default::default() };
+        assert!(order.is_triggered(false, 106 * FP));
+        assert!(!order.is_triggered(false, 104 * FP));
+    }
+
+    #[test]
+    fn test_margin_modify_relevers_to_target() {
+        let cfg = config();
+        // 10 units at $100, $100 margin (10x); target 4x needs $250
+        let notional_fp = 10 * PRICE;
+        let target = math::margin_for_leverage(notional_fp, 4, cfg.price_decimals, cfg.quote_decimals).unwrap();
+        assert_eq!(target, 250_000_000);
+        let margin_change = target as i64 - 100_000_000;
+        assert_eq!(margin_change, 150_000_000);
+
+        // Liquidation price is refreshed from the new margin
+        let liq = math::liquidation_price_fp(PRICE, cfg.quote_to_fp(target).unwrap(), 10, 500, true).unwrap();
+        assert!(liq < PRICE);
+    }
+
+    fn partial_close(book: Book, close_percentage: u8) -> Result<CloseOutcome> {
+        let mut accounts = PartialClosePosition {
+            config: Account::try_from(book.config)?,
+            market: Account::try_from(book.market)?,
+            user: Signer::try_from(book.user)?,
+            user_position: Account::try_from(book.user_position)?,
+            protocol_stats: Account::try_from(book.protocol_stats)?,
+            user_account: Account::try_from(book.user_account)?,
+            vault_token: InterfaceAccount::try_from(book.vault_token)?,
+            user_token: InterfaceAccount::try_from(book.user_token)?,
+            fee_destination_token: InterfaceAccount::try_from(book.fee_destination)?,
+            user_rate_limit: None,
+            oracle: Account::try_from(book.oracle)?,
+            oracle_twap: None,
+            quote_mint: InterfaceAccount::try_from(book.quote_mint)?,
+            token_program: Interface::try_from(book.token_program)?,
+            system_program: Program::try_from(book.system_program)?,
+        };
+        partial_close_position(
+            Context::new(&crate::ID, &mut accounts, &[], PartialClosePositionBumps::default()),
+            close_percentage,
+        )
+    }
+
+    #[test]
+    fn test_partial_close_is_settled_before_the_payout_leaves() {
+        let now = 100_000;
+        let book = Book::new(config(), PRICE, now);
+
+        // The mock token program looks at the books mid-transfer and tries to
+        // close the same position again from inside the payout
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let on_transfer = {
+            let seen = seen.clone();
+            move |transfer: &Transfer| {
+                seen.borrow_mut().push((transfer.amount, book.settled_state(), partial_close(book, 100)));
+            }
+        };
+        let outcome = run_at(now, on_transfer, || partial_close(book, 100)).unwrap();
+
+        // $100 of margin back less the $1 fee on $1,000 of exit notional
+        assert_eq!(outcome.settlement_amount, 99_000_000);
+        assert_eq!(token_balance(book.user_token), 99_000_000);
+        assert_eq!(token_balance(book.fee_destination), 1_000_000);
+        assert_eq!(token_balance(book.vault_token), 0);
+
+        let seen = seen.borrow();
+        assert_eq!(seen.iter().map(|(amount, ..)| *amount).collect::<Vec<_>>(), [99_000_000, 1_000_000]);
+        for (_, (up, market), reentry) in seen.iter() {
+            assert_eq!((up.base_size, up.margin_deposited, up.status), (0, 0, PositionStatus::Closed));
+            assert_eq!((market.total_long_size, market.total_margin_locked), (0, 0));
+            assert_eq!(reentry.as_ref().unwrap_err(), &PerpsError::PositionNotFound.into());
+        }
+    }
+}
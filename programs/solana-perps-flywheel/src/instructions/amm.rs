@@ -0,0 +1,100 @@
+use anchor_lang::prelude::*;
+use crate::errors::PerpsError;
+use crate::events::AmmRebalanced;
+use crate::oracle::read_market_oracle_fp;
+use crate::math::*;
+use crate::state::*;
+
+/// Keeper crank that walks the AMM reserves one bounded step back toward
+/// quoting the oracle price. Rate limited per market so repeated calls can't
+/// drag the mark around within a block.
+pub fn rebalance_amm(ctx: Context<RebalanceAmm>) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[])?;
+    let now = Clock::get()?.unix_timestamp;
+    let m = &mut ctx.accounts.market;
+    require!(
+        now >= m.last_amm_rebalance_ts.saturating_add(AMM_REBALANCE_INTERVAL_SECONDS),
+        PerpsError::AmmRebalanceTooSoon
+    );
+
+    let index_fp = read_market_oracle_fp(m, &ctx.accounts.oracle)?;
+    let (base_before_fp, quote_before_fp) = (m.amm_base_reserve_fp, m.amm_quote_reserve_fp);
+    let mark_before_fp = mark_from_index_fp(m, index_fp);
+
+    let (base_after_fp, quote_after_fp) =
+        rebalanced_amm_reserves(base_before_fp, quote_before_fp, AMM_REBALANCE_MAX_STEP_BPS)?;
+    m.amm_base_reserve_fp = base_after_fp;
+    m.amm_quote_reserve_fp = quote_after_fp;
+    m.last_amm_rebalance_ts = now;
+
+    emit!(AmmRebalanced {
+        market: m.key(),
+        index_price_fp: index_fp,
+        base_reserve_before_fp: base_before_fp,
+        quote_reserve_before_fp: quote_before_fp,
+        base_reserve_after_fp: base_after_fp,
+        quote_reserve_after_fp: quote_after_fp,
+        mark_price_before_fp: mark_before_fp,
+        mark_price_after_fp: mark_from_index_fp(m, index_fp),
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct RebalanceAmm<'info> {
+    pub keeper: Signer<'info>,
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    pub oracle: Account<'info, OraclePrice>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn market_with_reserves(base_fp: u128, quote_fp: u128) -> Market {
+        Market {
+            skew_k_bps: 10_000,
+            amm_base_reserve_fp: base_fp,
+            amm_quote_reserve_fp: quote_fp,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rebalance_walks_the_mark_toward_the_oracle_without_crossing() {
+        let index_fp = 100 * FP;
+        for (base, quote) in [(1_000 * FP, 1_030 * FP), (1_000 * FP, 970 * FP)] {
+            let mut m = market_with_reserves(base, quote);
+            let k = base * quote;
+            let above = mark_from_index_fp(&m, index_fp) > index_fp;
+            let mut gap = mark_from_index_fp(&m, index_fp).abs_diff(index_fp);
+
+            for _ in 0..10 {
+                let (b, q) = rebalanced_amm_reserves(m.amm_base_reserve_fp, m.amm_quote_reserve_fp, AMM_REBALANCE_MAX_STEP_BPS).unwrap();
+                m.amm_base_reserve_fp = b;
+                m.amm_quote_reserve_fp = q;
+                let mark = mark_from_index_fp(&m, index_fp);
+
+                // Each step closes the gap by at most the bound (plus a couple of
+                // units of reserve-ratio rounding) and stays on its side
+                let new_gap = mark.abs_diff(index_fp);
+                assert!(new_gap < gap || new_gap == 0);
+                assert!(gap - new_gap <= index_fp * AMM_REBALANCE_MAX_STEP_BPS / 10_000 + 2 * index_fp / FP);
+                assert!(new_gap == 0 || (mark > index_fp) == above);
+                gap = new_gap;
+
+                // k drifts only by rounding
+                assert!((b * q).abs_diff(k) * 1_000_000 / k <= 1);
+            }
+            // 3% off parity is six 0.5% steps
+            assert_eq!(gap, 0);
+        }
+    }
+}
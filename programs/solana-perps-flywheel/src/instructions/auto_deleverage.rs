@@ -0,0 +1,338 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::*;
+use crate::oracle;
+use crate::math;
+use crate::instructions::funding::take_funding_share;
+use crate::transfer::{transfer_checked, vault_solvency_check};
+
+// Auto-deleveraging: once the insurance funds are drained, bad debt left by
+// liquidations is taken out of the most profitable positions on the winning side
+
+/// Reduce positions on the `deleverage_longs` side until the market's
+/// uncovered bad debt is absorbed. Candidates go in `remaining_accounts` as
+/// (position, user token, user account) triples; they are ranked by
+/// `rank_adl_candidates` and each one taken gives up part of its profit.
+pub fn auto_deleverage<'info>(
+    ctx: Context<'_, '_, 'info, 'info, AutoDeleverage<'info>>,
+    deleverage_longs: bool,
+) -> Result<()> {
+    let shortfall = ctx.accounts.insurance_fund.adl_shortfall();
+    require!(shortfall > 0, PerpsError::AdlNotRequired);
+
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?);
+    let market_key = ctx.accounts.market.key();
+    let now = Clock::get()?.unix_timestamp;
+
+    let remaining = ctx.remaining_accounts;
+    require!(!remaining.is_empty() && remaining.len().is_multiple_of(3), PerpsError::NoAdlCandidates);
+    let mut positions: Vec<Account<'info, UserPosition>> = Vec::with_capacity(remaining.len() / 3);
+    for triple in remaining.chunks(3) {
+        require!(
+            !positions.iter().any(|p| p.key() == *triple[0].key),
+            PerpsError::DuplicatePositionAccount
+        );
+        let position: Account<UserPosition> = Account::try_from(&triple[0])?;
+        ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&position])?;
+        require_keys_eq!(position.market, market_key, PerpsError::PositionMarketMismatch);
+        require!(position.is_long == deleverage_longs, PerpsError::InvalidParameters);
+        position.ensure_status(&[PositionStatus::Open])?;
+        position.ensure_isolated()?;
+        position.ensure_quote_margin()?;
+        positions.push(position);
+    }
+
+    let cfg = &ctx.accounts.config;
+    let candidates = positions
+        .iter()
+        .map(|p| AdlCandidate::new(cfg, p, mark_fp))
+        .collect::<Result<Vec<_>>>()?;
+    let fills = plan_adl_fills(&candidates, cfg.quote_to_fp(shortfall)?);
+    require!(!fills.is_empty(), PerpsError::NoAdlCandidates);
+
+    let mut absorbed_total: u64 = 0;
+    let active_positions_before = ctx.accounts.protocol_stats.active_positions;
+    for fill in fills {
+        let triple = &remaining[fill.index * 3..fill.index * 3 + 3];
+        let user_token: InterfaceAccount<TokenAccount> = InterfaceAccount::try_from(&triple[1])?;
+        let mut user_account: Account<UserAccount> = Account::try_from(&triple[2])?;
+        let up = &mut positions[fill.index];
+        require_keys_eq!(user_token.owner, up.owner, PerpsError::InvalidTokenAccount);
+        require_keys_eq!(user_token.mint, ctx.accounts.config.quote_mint, PerpsError::InvalidTokenMint);
+        require_keys_eq!(
+            user_account.key(),
+            Pubkey::create_program_address(&[USER_ACCOUNT_SEED, up.owner.as_ref(), &[user_account.bump]], &crate::ID)
+                .map_err(|_| PerpsError::InvalidPDA)?,
+            PerpsError::InvalidPDA
+        );
+
+        let original_size = up.base_size.unsigned_abs();
+        let (is_long, vault_margin) = (up.is_long, up.vault_margin());
+        let funding_fp = take_funding_share(up, &ctx.accounts.market, fill.close_size, now)?;
+        let margin_share = (up.margin_deposited as u128 * fill.close_size as u128 / original_size as u128) as u64;
+        let kept_profit_fp = fill.slice_profit_fp - fill.absorbed_fp;
+        let pnl_fp = kept_profit_fp - funding_fp;
+        let payout_fp = (ctx.accounts.config.quote_to_fp(margin_share)? as i128 + pnl_fp).max(0) as u128;
+        let settlement_amt = ctx.accounts.config.settle_to_quote(payout_fp)?;
+
+        let remaining_size = original_size - fill.close_size;
+        if remaining_size == 0 {
+            up.settle_full_close(pnl_fp, 0, now);
+            ctx.accounts.protocol_stats.record_close();
+            user_account.record_close();
+        } else {
+            up.base_size = if is_long { remaining_size as i64 } else { -(remaining_size as i64) };
+            up.margin_deposited -= margin_share;
+            up.realized_pnl_fp += pnl_fp;
+            up.last_updated_ts = now;
+            up.liquidation_price_fp = math::liquidation_price_fp(
+                up.entry_price_fp,
+                ctx.accounts.config.quote_to_fp(up.margin_deposited)?,
+                remaining_size,
+                ctx.accounts.market.upcoming_maintenance_margin_bps_for(remaining_size),
+                is_long,
+            )?;
+        }
+        ctx.accounts.market.reduce_open_interest(is_long, fill.close_size);
+        ctx.accounts.market.rebook_margin(vault_margin, up.vault_margin())?;
+        ctx.accounts.market.record_settlement(pnl_fp, 0)?;
+
+        let absorbed = ctx.accounts.config.fp_to_quote(fill.absorbed_fp as u128)?;
+        absorbed_total += absorbed;
+        let bankruptcy_price_fp = adl_exit_price_fp(up.entry_price_fp, is_long, kept_profit_fp, fill.close_size);
+        let user = up.owner;
+        up.exit(&crate::ID)?;
+        user_account.exit(&crate::ID)?;
+
+        if settlement_amt > 0 {
+            transfer_checked(
+                &ctx.accounts.token_program,
+                &ctx.accounts.quote_mint,
+                ctx.accounts.vault_token.to_account_info(),
+                user_token.to_account_info(),
+                ctx.accounts.config.to_account_info(),
+                &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]],
+                settlement_amt,
+            )?;
+        }
+
+        emit!(AutoDeleveraged {
+            user,
+            market: market_key,
+            closed_size: fill.close_size,
+            remaining_size,
+            bankruptcy_price_fp,
+            absorbed_amount: absorbed,
+            settlement_amount: settlement_amt,
+            remaining_bad_debt: ctx.accounts.insurance_fund.uncovered_bad_debt.saturating_sub(absorbed_total),
+        });
+    }
+
+    if ctx.accounts.protocol_stats.active_positions != active_positions_before {
+        emit!(ctx.accounts.protocol_stats.updated_event());
+    }
+    vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+
+    let fund = &mut ctx.accounts.insurance_fund;
+    fund.uncovered_bad_debt = fund.uncovered_bad_debt.saturating_sub(absorbed_total);
+    msg!("Auto-deleveraged {} of bad debt in market {}, {} left", absorbed_total, market_key, fund.uncovered_bad_debt);
+    Ok(())
+}
+
+/// A position's standing in the deleveraging queue
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AdlCandidate {
+    pub size: u64,
+    pub unrealized_pnl_fp: i128,
+    pub margin_fp: u128,
+    pub notional_fp: u128,
+}
+
+impl AdlCandidate {
+    pub fn new(cfg: &Config, up: &UserPosition, mark_fp: u128) -> Result<Self> {
+        let size = up.base_size.unsigned_abs();
+        let price_move_fp = if up.is_long {
+            mark_fp as i128 - up.entry_price_fp as i128
+        } else {
+            up.entry_price_fp as i128 - mark_fp as i128
+        };
+        Ok(Self {
+            size,
+            unrealized_pnl_fp: size as i128 * price_move_fp,
+            margin_fp: cfg.quote_to_fp(up.margin_deposited)?,
+            notional_fp: size as u128 * mark_fp,
+        })
+    }
+
+    /// Return on margin weighted by leverage, in bps: `pnl / margin * notional
+    /// / margin`. The most profitable and most levered positions go first.
+    pub fn score(&self) -> i128 {
+        if self.margin_fp == 0 {
+            return i128::MAX;
+        }
+        let margin = self.margin_fp as i128;
+        self.unrealized_pnl_fp
+            .saturating_mul(10_000)
+            .checked_div(margin)
+            .and_then(|pnl_bps| pnl_bps.checked_mul(self.notional_fp.min(i128::MAX as u128) as i128))
+            .map_or(i128::MAX, |weighted| weighted / margin)
+    }
+}
+
+/// Indices of the profitable candidates, best score first. Ties keep the
+/// order they were passed in.
+pub fn rank_adl_candidates(candidates: &[AdlCandidate]) -> Vec<usize> {
+    let mut ranked: Vec<usize> = (0..candidates.len())
+        .filter(|&i| candidates[i].unrealized_pnl_fp > 0 && candidates[i].size > 0)
+        .collect();
+    ranked.sort_by_key(|&i| std::cmp::Reverse(candidates[i].score()));
+    ranked
+}
+
+/// One position's share of a deleveraging
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdlFill {
+    pub index: usize,
+    pub close_size: u64,
+    pub slice_profit_fp: i128,          // Unrealized profit on the closed slice
+    pub absorbed_fp: i128,              // Part of it given up to the bad debt
+}
+
+/// Walk the ranking taking profit until `shortfall_fp` is absorbed. Each
+/// position closes the fraction of its size whose profit covers what is still
+/// owed, rounded up to whole units, so it is reduced in proportion to the loss
+/// it takes; profit on the slice beyond that stays with the user.
+pub fn plan_adl_fills(candidates: &[AdlCandidate], shortfall_fp: u128) -> Vec<AdlFill> {
+    let mut owed = shortfall_fp.min(i128::MAX as u128) as i128;
+    let mut fills = Vec::new();
+    for index in rank_adl_candidates(candidates) {
+        if owed == 0 {
+            break;
+        }
+        let c = &candidates[index];
+        let target = owed.min(c.unrealized_pnl_fp);
+        let close_size = ((c.size as u128 * target as u128).div_ceil(c.unrealized_pnl_fp as u128) as u64).min(c.size);
+        let slice_profit_fp = c.unrealized_pnl_fp * close_size as i128 / c.size as i128;
+        let absorbed_fp = owed.min(slice_profit_fp);
+        owed -= absorbed_fp;
+        fills.push(AdlFill { index, close_size, slice_profit_fp, absorbed_fp });
+    }
+    fills
+}
+
+/// Price a deleveraged slice effectively closed at: entry moved by the profit
+/// the user kept per unit
+pub fn adl_exit_price_fp(entry_price_fp: u128, is_long: bool, kept_profit_fp: i128, close_size: u64) -> u128 {
+    let move_fp = (kept_profit_fp / close_size.max(1) as i128).unsigned_abs();
+    if is_long {
+        entry_price_fp + move_fp
+    } else {
+        entry_price_fp.saturating_sub(move_fp)
+    }
+}
+
+#[derive(Accounts)]
+pub struct AutoDeleverage<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+        has_one = admin
+    )]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    pub oracle: Account<'info, OraclePrice>,
+
+    /// The market's own insurance fund, which carries its uncovered bad debt
+    #[account(
+        mut,
+        seeds = [INSURANCE_FUND_SEED, market.key().as_ref()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(size: u64, pnl: i128, margin: u128, notional: u128) -> AdlCandidate {
+        AdlCandidate { size, unrealized_pnl_fp: pnl * FP as i128, margin_fp: margin * FP, notional_fp: notional * FP }
+    }
+
+    #[test]
+    fn test_candidates_rank_by_profit_and_leverage() {
+        let candidates = [
+            candidate(10, 50, 100, 1_000),   // 50% on margin at 10x
+            candidate(10, -20, 100, 1_000),  // losing, never deleveraged
+            candidate(10, 50, 200, 1_000),   // same profit on twice the margin
+            candidate(10, 100, 100, 2_000),  // 100% on margin at 20x
+            candidate(0, 0, 0, 0),           // closed
+        ];
+        assert_eq!(rank_adl_candidates(&candidates), vec![3, 0, 2]);
+        assert!(candidates[0].score() > candidates[2].score());
+    }
+
+    #[test]
+    fn test_fills_take_profit_in_rank_order_until_the_debt_is_absorbed() {
+        let candidates = [
+            candidate(10, 50, 100, 1_000),
+            candidate(10, 100, 100, 2_000),
+        ];
+
+        // $130 of bad debt: the top position gives up all $100, then the next
+        // closes 6 of 10 units ($30 profit) and gives up exactly $30 of it
+        let fills = plan_adl_fills(&candidates, 130 * FP);
+        assert_eq!(fills, vec![
+            AdlFill { index: 1, close_size: 10, slice_profit_fp: 100 * FP as i128, absorbed_fp: 100 * FP as i128 },
+            AdlFill { index: 0, close_size: 6, slice_profit_fp: 30 * FP as i128, absorbed_fp: 30 * FP as i128 },
+        ]);
+
+        // A debt smaller than one unit's profit still closes a whole unit, and
+        // the user keeps what the slice made beyond the debt
+        let fills = plan_adl_fills(&candidates, 3 * FP);
+        assert_eq!(fills, vec![AdlFill { index: 1, close_size: 1, slice_profit_fp: 10 * FP as i128, absorbed_fp: 3 * FP as i128 }]);
+        assert_eq!(adl_exit_price_fp(100 * FP, true, 7 * FP as i128, 1), 107 * FP);
+
+        // More debt than all the profit on offer takes every profitable unit
+        let fills = plan_adl_fills(&candidates, 1_000 * FP);
+        assert_eq!(fills.iter().map(|f| f.close_size).sum::<u64>(), 20);
+        assert_eq!(fills.iter().map(|f| f.absorbed_fp).sum::<i128>(), 150 * FP as i128);
+    }
+
+    #[test]
+    fn test_adl_only_runs_once_the_fund_cannot_cover_the_debt() {
+        let mut fund = InsuranceFund { total_deposits: 500, ..Default::default() };
+        fund.record_bad_debt(300).unwrap();
+        assert_eq!(fund.adl_shortfall(), 0);
+
+        fund.absorb_deficit(400);
+        assert_eq!(fund.adl_shortfall(), 200);
+    }
+}
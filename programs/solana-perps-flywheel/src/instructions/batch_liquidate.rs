@@ -0,0 +1,209 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::math::{self, close_settlement, risk_mark_price_fp};
+use crate::instructions::liquidate::full_liquidation_event;
+use crate::instructions::enhanced_liquidation::route_fee_to_insurance;
+use crate::transfer::{transfer_checked, vault_solvency_check};
+
+/// Most positions one `batch_liquidate` takes, to stay inside the compute budget
+pub const MAX_BATCH_LIQUIDATIONS: usize = 8;
+
+/// Keeper crank for cascades: liquidate every qualifying isolated position of
+/// one market in a single transaction. Positions go in `remaining_accounts`
+/// as (position, user account, user token) triples; any that is healthy,
+/// protected, cross-margin, margined with pledged collateral, already closed
+/// or from another market is skipped.
+/// Each liquidation pays the keeper its reward out of the liquidation fee, and
+/// the rewards go out in one transfer at the end. Returns how many were liquidated.
+pub fn batch_liquidate<'info>(ctx: Context<'_, '_, 'info, 'info, BatchLiquidate<'info>>) -> Result<u32> {
+    let remaining = ctx.remaining_accounts;
+    require!(!remaining.is_empty() && remaining.len().is_multiple_of(3), PerpsError::InvalidParameters);
+    require!(remaining.len() / 3 <= MAX_BATCH_LIQUIDATIONS, PerpsError::BatchTooLarge);
+
+    let now = Clock::get()?.unix_timestamp;
+    ctx.accounts.market.settle_maintenance_margin(now);
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(risk_mark_price_fp(&ctx.accounts.market, &ctx.accounts.oracle, ctx.accounts.oracle_twap.as_deref())?);
+    let market_key = ctx.accounts.market.key();
+    let liquidator = ctx.accounts.liquidator.key();
+
+    let mut vault_available = ctx.accounts.vault_token.amount;
+    let (mut total_reward, mut total_protocol_fee) = (0u64, 0u64);
+    let mut returns: Vec<(AccountInfo<'info>, u64)> = Vec::new();
+    let mut events = Vec::new();
+
+    // Effects: settle every qualifying position before any transfer
+    for triple in remaining.chunks(3) {
+        let mut up: Account<UserPosition> = Account::try_from(&triple[0])?;
+        let cfg = &ctx.accounts.config;
+        let qualifies = up.market == market_key
+            && up.version == ACCOUNT_VERSION
+            && up.margin_mode == MarginMode::Isolated
+            && !up.collateral_pledged
+            && matches!(up.current_status(), PositionStatus::Open | PositionStatus::Liquidating);
+        if !qualifies {
+            msg!("Skipping {}: not an open quote-margined isolated position in this market", triple[0].key);
+            continue;
+        }
+        up.settle_funding(&ctx.accounts.market, now)?;
+        let equity_fp = up.equity_fp(cfg, mark_fp)?;
+        let maintenance_margin_bps = ctx.accounts.market.maintenance_margin_bps_for(up.base_size.unsigned_abs(), now);
+        if !up.is_liquidatable(cfg, mark_fp, maintenance_margin_bps)?
+            || ctx.accounts.market.liquidation_protected(up.opened_at_ts, now, equity_fp)
+        {
+            msg!("Skipping {}: not liquidatable", triple[0].key);
+            continue;
+        }
+
+        let mut user_account: Account<UserAccount> = Account::try_from(&triple[1])?;
+        let user_account_key = Pubkey::create_program_address(&[USER_ACCOUNT_SEED, up.owner.as_ref(), &[user_account.bump]], &crate::ID)
+            .map_err(|_| PerpsError::InvalidPDA)?;
+        require_keys_eq!(user_account.key(), user_account_key, PerpsError::InvalidPDA);
+        let user_token: InterfaceAccount<TokenAccount> = InterfaceAccount::try_from(&triple[2])?;
+        require_keys_eq!(user_token.owner, up.owner, PerpsError::InvalidTokenAccount);
+        require_keys_eq!(user_token.mint, cfg.quote_mint, PerpsError::InvalidTokenMint);
+
+        // Funding owed is realized with the PnL, as on a close
+        let size = up.base_size.unsigned_abs();
+        let notional_fp = size as u128 * mark_fp;
+        let price_move_fp = if up.is_long {
+            mark_fp as i128 - up.entry_price_fp as i128
+        } else {
+            up.entry_price_fp as i128 - mark_fp as i128
+        };
+        let pnl_fp = size as i128 * price_move_fp - std::mem::take(&mut up.funding_debt_fp);
+
+        let cfg = &mut ctx.accounts.config;
+        let settlement = close_settlement(cfg.quote_to_fp(up.margin_deposited)?, pnl_fp, notional_fp, cfg.liq_fee_bps);
+        let payout = cfg.settle_to_quote(settlement.payout_fp)?;
+        let fee = cfg.settle_to_quote(settlement.fee_fp)?;
+        let (returned, seized) = cfg.cushion_payout(payout, fee, vault_available)?;
+        vault_available -= returned + seized;
+        let reward = math::liquidator_reward(cfg.fp_to_quote(notional_fp)?, &cfg.liquidator_reward_tiers, cfg.liquidator_reward_floor)
+            .min(seized);
+        total_reward += reward;
+        total_protocol_fee += seized - reward;
+
+        let mut event = full_liquidation_event(liquidator, &up, mark_fp, pnl_fp, &settlement, returned, now);
+        event.liquidator_reward = reward;
+        events.push(event);
+
+        let (is_long, vault_margin) = (up.is_long, up.vault_margin());
+        up.settle_full_close(pnl_fp, seized, now);
+        up.exit(&crate::ID)?;
+        user_account.record_close();
+        user_account.exit(&crate::ID)?;
+        ctx.accounts.protocol_stats.record_close();
+        ctx.accounts.protocol_stats.record_liquidation(seized - reward)?;
+        let market = &mut ctx.accounts.market;
+        market.reduce_open_interest(is_long, size);
+        market.release_margin(vault_margin)?;
+        market.record_settlement(pnl_fp, settlement.fee_fp)?;
+        if returned > 0 {
+            returns.push((triple[2].clone(), returned));
+        }
+    }
+    ctx.accounts.market.exit(&crate::ID)?;
+    ctx.accounts.config.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+
+    // Interactions: the market's insurance fund takes its slice of the
+    // protocol fee while under target
+    total_protocol_fee -= route_fee_to_insurance(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        &ctx.accounts.config,
+        &ctx.accounts.vault_token,
+        &mut ctx.accounts.insurance_fund,
+        &ctx.accounts.insurance_vault_token,
+        liquidator,
+        total_protocol_fee,
+    )?;
+    let signer_seeds: &[&[&[u8]]] = &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]];
+    let transfers = returns.into_iter().chain([
+        (ctx.accounts.liquidator_reward_token.to_account_info(), total_reward),
+        (ctx.accounts.fee_destination.to_account_info(), total_protocol_fee),
+    ]);
+    for (to, amount) in transfers.filter(|(_, amount)| *amount > 0) {
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.quote_mint,
+            ctx.accounts.vault_token.to_account_info(),
+            to,
+            ctx.accounts.config.to_account_info(),
+            signer_seeds,
+            amount,
+        )?;
+    }
+    vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+
+    let liquidated = events.len() as u32;
+    for event in events {
+        emit!(event);
+    }
+    emit!(ctx.accounts.protocol_stats.updated_event());
+    msg!("Batch liquidated {} of {} positions, keeper reward {}", liquidated, remaining.len() / 3, total_reward);
+    Ok(liquidated)
+}
+
+#[derive(Accounts)]
+pub struct BatchLiquidate<'info> {
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    pub oracle: Account<'info, OraclePrice>,
+
+    /// The oracle's TWAP ring, required when the market prices risk at a TWAP
+    #[account(seeds = [ORACLE_TWAP_SEED, oracle.key().as_ref()], bump = oracle_twap.bump)]
+    pub oracle_twap: Option<Account<'info, OracleTwap>>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, constraint = liquidator_reward_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub liquidator_reward_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Fee destination, must be the configured fee account
+    #[account(mut, address = config.fee_destination @ PerpsError::InvalidTokenAccount)]
+    pub fee_destination: AccountInfo<'info>,
+
+    /// This market's insurance fund, to take its slice of the protocol fee
+    #[account(
+        mut,
+        seeds = [INSURANCE_FUND_SEED, market.key().as_ref()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+
+    #[account(mut, address = insurance_fund.vault_token_account @ PerpsError::InvalidTokenAccount)]
+    pub insurance_vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
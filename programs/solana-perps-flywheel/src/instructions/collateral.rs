@@ -0,0 +1,296 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::*;
+use crate::oracle;
+use crate::instructions::withdrawal_queue::throttle_outflow;
+use crate::transfer::{transfer_checked, transfer_checked_exact, vault_solvency_check};
+
+/// Move quote tokens into the market's vault as unallocated collateral
+pub fn deposit_collateral(ctx: Context<DepositCollateral>, amount: u64) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[])?;
+    require!(amount > 0, PerpsError::InvalidMarketParameters);
+    let collateral = &mut ctx.accounts.collateral_account;
+    collateral.owner = ctx.accounts.user.key();
+    collateral.market = ctx.accounts.market.key();
+    collateral.bump = ctx.bumps.collateral_account;
+    collateral.credit(amount)?;
+    collateral.exit(&crate::ID)?;
+    ctx.accounts.market.lock_margin(amount)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+
+    transfer_checked_exact(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.user_token.to_account_info(),
+        ctx.accounts.vault_token.to_account_info(),
+        ctx.accounts.user.to_account_info(),
+        &[],
+        amount,
+    )?;
+
+    emit!(CollateralDeposited {
+        user: ctx.accounts.user.key(),
+        market: ctx.accounts.market.key(),
+        amount,
+        balance: ctx.accounts.collateral_account.balance,
+    });
+    Ok(())
+}
+
+/// Take unallocated collateral back out of the vault. Amounts the market
+/// would queue are refused rather than queued: withdraw them in smaller parts.
+pub fn withdraw_collateral(ctx: Context<WithdrawCollateral>, amount: u64) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[])?;
+    require!(amount > 0, PerpsError::InvalidMarketParameters);
+    require!(!ctx.accounts.market.queues_withdrawal(amount), PerpsError::ExceedsRiskLimits);
+    require!(ctx.accounts.vault_token.amount >= amount, PerpsError::InsufficientLiquidity);
+
+    let now = Clock::get()?.unix_timestamp;
+    ctx.accounts.collateral_account.debit(amount)?;
+    throttle_outflow(
+        &ctx.accounts.config,
+        ctx.accounts.user_rate_limit.as_mut(),
+        ctx.bumps.user_rate_limit,
+        ctx.accounts.user.key(),
+        amount,
+        now,
+    )?;
+    ctx.accounts.collateral_account.exit(&crate::ID)?;
+    ctx.accounts.market.release_margin(amount)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+
+    let config_bump = ctx.accounts.config.bump;
+    transfer_checked(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.vault_token.to_account_info(),
+        ctx.accounts.user_token.to_account_info(),
+        ctx.accounts.config.to_account_info(),
+        &[&[CONFIG_SEED, &[config_bump]]],
+        amount,
+    )?;
+    vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+
+    emit!(CollateralWithdrawn {
+        user: ctx.accounts.user.key(),
+        market: ctx.accounts.market.key(),
+        amount,
+        balance: ctx.accounts.collateral_account.balance,
+    });
+    Ok(())
+}
+
+/// The accounts a position's pledged collateral moves through, checked
+/// against each other and the position's owner
+pub(crate) struct Pledge<'a, 'info> {
+    pub accepted: &'a mut Account<'info, AcceptedCollateral>,
+    pub position_collateral: &'a mut Account<'info, PositionCollateral>,
+    pub vault: &'a InterfaceAccount<'info, TokenAccount>,
+    pub owner_token: &'a InterfaceAccount<'info, TokenAccount>,
+    pub mint: &'a InterfaceAccount<'info, Mint>,
+}
+
+pub(crate) fn load_pledge<'a, 'info>(
+    config: &Pubkey,
+    owner: &Pubkey,
+    accepted: Option<&'a mut Account<'info, AcceptedCollateral>>,
+    position_collateral: Option<&'a mut Account<'info, PositionCollateral>>,
+    vault: Option<&'a InterfaceAccount<'info, TokenAccount>>,
+    owner_token: Option<&'a InterfaceAccount<'info, TokenAccount>>,
+    mint: Option<&'a InterfaceAccount<'info, Mint>>,
+) -> Result<Pledge<'a, 'info>> {
+    let (Some(accepted), Some(position_collateral), Some(vault), Some(owner_token), Some(mint)) =
+        (accepted, position_collateral, vault, owner_token, mint) else {
+        return err!(PerpsError::PledgedCollateralRequired);
+    };
+    require_keys_eq!(mint.key(), accepted.mint, PerpsError::InvalidTokenMint);
+    require_keys_eq!(vault.owner, *config, PerpsError::InvalidTokenAccount);
+    require_keys_eq!(vault.mint, accepted.mint, PerpsError::InvalidTokenMint);
+    require_keys_eq!(owner_token.owner, *owner, PerpsError::InvalidTokenAccount);
+    require_keys_eq!(owner_token.mint, accepted.mint, PerpsError::InvalidTokenMint);
+    require!(
+        position_collateral.amount == 0 || position_collateral.mint == accepted.mint,
+        PerpsError::InvalidTokenMint
+    );
+    Ok(Pledge { accepted, position_collateral, vault, owner_token, mint })
+}
+
+impl<'info> Pledge<'_, 'info> {
+    /// Book enough tokens to count for `margin` quote at the mint's oracle
+    /// price. Returns how many the owner must move into the vault.
+    pub fn add_margin(&mut self, cfg: &Config, oracle: Option<&Account<'info, OraclePrice>>, margin: u64) -> Result<u64> {
+        require!(self.accepted.is_enabled, PerpsError::CollateralNotAccepted);
+        let oracle = oracle.ok_or(PerpsError::PledgedCollateralRequired)?;
+        require_keys_eq!(oracle.key(), self.accepted.oracle, PerpsError::BadOracle);
+        let tokens = self.accepted.tokens_for_margin(cfg, margin, oracle::read_oracle_fp(oracle)?)?;
+        self.position_collateral.mint = self.accepted.mint;
+        self.position_collateral.amount = self.position_collateral.amount
+            .checked_add(tokens)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.accepted.total_pledged = self.accepted.total_pledged
+            .checked_add(tokens)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(tokens)
+    }
+
+    /// Take `returned` tokens off the pledge for the owner and `seized` for the protocol
+    pub fn release(&mut self, returned: u64, seized: u64) -> Result<()> {
+        let released = returned.checked_add(seized).ok_or(PerpsError::MathOverflow)?;
+        self.position_collateral.amount = self.position_collateral.amount
+            .checked_sub(released)
+            .ok_or(PerpsError::InsufficientFunds)?;
+        self.accepted.total_pledged = self.accepted.total_pledged.saturating_sub(released);
+        self.accepted.total_seized = self.accepted.total_seized
+            .checked_add(seized)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Settle the whole pledge of a position closed out for `payout` quote
+    /// against the `margin` it counted for
+    pub fn settle(&mut self, margin: u64, payout: u64) -> Result<PledgeSettlement> {
+        let split = self.position_collateral.settle(margin, payout);
+        self.release(split.returned, split.seized)?;
+        Ok(split)
+    }
+
+    pub fn exit(&self) -> Result<()> {
+        self.accepted.exit(&crate::ID)?;
+        self.position_collateral.exit(&crate::ID)
+    }
+
+    /// Move `amount` of the owner's tokens into the vault, the owner covering
+    /// any transfer fee so the vault holds what is booked
+    pub fn pull(&self, token_program: &Interface<'info, TokenInterface>, owner: &Signer<'info>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+        transfer_checked_exact(
+            token_program,
+            self.mint,
+            self.owner_token.to_account_info(),
+            self.vault.to_account_info(),
+            owner.to_account_info(),
+            &[],
+            amount,
+        )?;
+        Ok(())
+    }
+
+    /// Hand `amount` tokens back to the owner out of the vault
+    pub fn push(&self, token_program: &Interface<'info, TokenInterface>, config: &Account<'info, Config>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+        transfer_checked(
+            token_program,
+            self.mint,
+            self.vault.to_account_info(),
+            self.owner_token.to_account_info(),
+            config.to_account_info(),
+            &[&[CONFIG_SEED, &[config.bump]]],
+            amount,
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct DepositCollateral<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = CollateralAccount::SPACE,
+        seeds = [COLLATERAL_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub collateral_account: Account<'info, CollateralAccount>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawCollateral<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [COLLATERAL_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump = collateral_account.bump
+    )]
+    pub collateral_account: Account<'info, CollateralAccount>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// Only needed while withdrawals are rate limited
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRateLimit::SPACE,
+        seeds = [USER_RATE_LIMIT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_rate_limit: Option<Account<'info, UserRateLimit>>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
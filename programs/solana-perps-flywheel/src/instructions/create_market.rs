@@ -1,30 +1,102 @@
 use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
 use crate::errors::PerpsError;
+use crate::events::MarketCreated;
 use crate::state::*;
 
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_market(
 ctx: Context<CreateMarket>,
 symbol: [u8; 12], base_decimals: u8, skew_k_bps: u32,
 max_position_base: u64, maintenance_margin_bps: u16, taker_leverage_cap_x: u16,
 amm_base_reserve_fp: u128, amm_quote_reserve_fp: u128,
+max_long_oi: u64, max_short_oi: u64,
 ) -> Result<()> {
+// The market is the PDA of its symbol, so an existing one comes back initialized
+require!(ctx.accounts.market.symbol == [0u8; 12], PerpsError::MarketAlreadyExists);
+validate_market_symbol(&symbol)?;
+require!(taker_leverage_cap_x > 0, PerpsError::InvalidMarketParameters);
 require!(taker_leverage_cap_x as u64 <= MAX_LEVERAGE_X, PerpsError::LeverageTooHigh);
+require!((1..=5_000).contains(&maintenance_margin_bps), PerpsError::InvalidMarketParameters);
+require!(amm_base_reserve_fp > 0 && amm_quote_reserve_fp > 0, PerpsError::InvalidMarketParameters);
+// A solvent position must always be able to pay its close fee
+require!(maintenance_margin_bps >= ctx.accounts.config.fee_bps, PerpsError::InvalidMarketParameters);
 let m = &mut ctx.accounts.market;
+m.bump = ctx.bumps.market;
 m.symbol = symbol; m.base_decimals = base_decimals;
-m.oracle = ctx.accounts.oracle.key();
+m.oracle = ctx.accounts.oracle.key(); m.creator = ctx.accounts.payer.key();
 m.skew_k_bps = skew_k_bps; m.max_position_base = max_position_base;
 m.maintenance_margin_bps = maintenance_margin_bps; m.taker_leverage_cap_x = taker_leverage_cap_x;
 m.amm_base_reserve_fp = amm_base_reserve_fp; m.amm_quote_reserve_fp = amm_quote_reserve_fp;
-m.funding_rate_fp = 0; m.last_funding_ts = Clock::get()?.unix_timestamp; Ok(())
+m.max_long_oi = max_long_oi; m.max_short_oi = max_short_oi;
+m.funding_rate_fp = 0; m.last_funding_ts = Clock::get()?.unix_timestamp;
+m.max_funding_rate_fp = DEFAULT_MAX_FUNDING_RATE_FP;
+m.min_partial_close_pct = DEFAULT_MIN_PARTIAL_CLOSE_PCT;
+m.version = ACCOUNT_VERSION;
+emit!(MarketCreated { market: m.key(), symbol, oracle: m.oracle, max_leverage: taker_leverage_cap_x });
+Ok(())
+}
+
+/// A symbol is its text followed by zero padding: at least one byte, no NUL
+/// inside the text and no leading or trailing whitespace
+pub fn validate_market_symbol(symbol: &[u8; 12]) -> Result<()> {
+let len = symbol.iter().position(|&b| b == 0).unwrap_or(symbol.len());
+let (text, padding) = symbol.split_at(len);
+require!(!text.is_empty() && padding.iter().all(|&b| b == 0), PerpsError::InvalidMarketSymbol);
+require!(!text[0].is_ascii_whitespace() && !text[len - 1].is_ascii_whitespace(), PerpsError::InvalidMarketSymbol);
+Ok(())
+}
+
+
+/// Create the market's own collateral vault. Every trade, liquidation and
+/// payout in the market goes through it, so markets never touch each other's
+/// collateral.
+pub fn initialize_market_vault(ctx: Context<InitializeMarketVault>) -> Result<()> {
+ctx.accounts.market.vault_bump = ctx.bumps.vault_token;
+msg!("Market vault {} initialized for {}", ctx.accounts.vault_token.key(), ctx.accounts.market.key());
+Ok(())
 }
 
 
 #[derive(Accounts)]
+#[instruction(symbol: [u8; 12])]
 pub struct CreateMarket<'info> {
 #[account(mut)] pub config: Account<'info, Config>,
-#[account(init, payer = payer, space = 8 + 256)] pub market: Account<'info, Market>,
+#[account(init_if_needed, payer = payer, space = Market::SPACE, seeds = [MARKET_SEED, symbol.as_ref()], bump)] pub market: Account<'info, Market>,
 pub oracle: Account<'info, OraclePrice>,
 #[account(mut)] pub payer: Signer<'info>,
 pub system_program: Program<'info, System>,
 }
+
+#[derive(Accounts)]
+pub struct InitializeMarketVault<'info> {
+#[account(seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)] pub config: Account<'info, Config>,
+#[account(mut)] pub admin: Signer<'info>,
+#[account(mut)] pub market: Account<'info, Market>,
+#[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)] pub quote_mint: InterfaceAccount<'info, Mint>,
+#[account(init, payer = admin, seeds = [VAULT_SEED, market.key().as_ref()], bump, token::mint = quote_mint, token::authority = config, token::token_program = token_program)] pub vault_token: InterfaceAccount<'info, TokenAccount>,
+pub token_program: Interface<'info, TokenInterface>,
+pub system_program: Program<'info, System>,
+}
+
+#[cfg(test)]
+mod tests {
+use super::*;
+
+fn symbol(text: &[u8]) -> [u8; 12] {
+    let mut s = [0u8; 12];
+    s[..text.len()].copy_from_slice(text);
+    s
+}
+
+#[test]
+fn test_market_symbol_must_be_trimmed_text_then_padding() {
+    assert!(validate_market_symbol(&symbol(b"BTC-PERP")).is_ok());
+    assert!(validate_market_symbol(&symbol(b"ABCDEFGHIJKL")).is_ok());
+
+    for bad in [symbol(b""), symbol(b" BTC"), symbol(b"BTC "), symbol(b"BTC\0X")] {
+        assert_eq!(validate_market_symbol(&bad).unwrap_err(), PerpsError::InvalidMarketSymbol.into());
+    }
+}
+}
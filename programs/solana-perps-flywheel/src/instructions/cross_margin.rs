@@ -0,0 +1,270 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::*;
+use crate::math::current_mark_price_fp;
+use crate::instructions::withdrawal_queue::throttle_outflow;
+use crate::transfer::{transfer_checked, transfer_checked_exact};
+
+/// Create the token account that holds every user's cross-margin collateral
+pub fn initialize_cross_vault(ctx: Context<InitializeCrossVault>) -> Result<()> {
+    msg!("Cross vault {} initialized", ctx.accounts.cross_vault.key());
+    Ok(())
+}
+
+/// Move quote tokens into the cross vault, pooled across the user's cross positions
+pub fn deposit_cross_collateral(ctx: Context<DepositCrossCollateral>, amount: u64) -> Result<()> {
+    require!(amount > 0, PerpsError::InvalidMarketParameters);
+    let account = &mut ctx.accounts.cross_margin_account;
+    account.owner = ctx.accounts.user.key();
+    account.bump = ctx.bumps.cross_margin_account;
+    account.collateral_balance = account.collateral_balance
+        .checked_add(amount)
+        .ok_or(PerpsError::MathOverflow)?;
+    account.exit(&crate::ID)?;
+
+    transfer_checked_exact(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.user_token.to_account_info(),
+        ctx.accounts.cross_vault.to_account_info(),
+        ctx.accounts.user.to_account_info(),
+        &[],
+        amount,
+    )?;
+
+    emit!(CrossCollateralDeposited {
+        user: ctx.accounts.user.key(),
+        amount,
+        balance: ctx.accounts.cross_margin_account.collateral_balance,
+    });
+    Ok(())
+}
+
+/// Take free collateral back out of the cross vault. With cross positions
+/// open, every one of them must be passed in `remaining_accounts` as
+/// (position, market, oracle) and the account must stay above maintenance.
+pub fn withdraw_cross_collateral<'info>(
+    ctx: Context<'_, '_, 'info, 'info, WithdrawCrossCollateral<'info>>,
+    amount: u64,
+) -> Result<()> {
+    require!(amount > 0, PerpsError::InvalidMarketParameters);
+    let account = &ctx.accounts.cross_margin_account;
+    require!(amount <= account.free_collateral(), PerpsError::InsufficientMargin);
+
+    let legs = load_cross_legs(ctx.remaining_accounts, account.owner, None, account.open_positions)?;
+    let after = CrossMarginAccount { collateral_balance: account.collateral_balance - amount, ..(**account).clone() };
+    require!(!after.is_liquidatable(&ctx.accounts.config, &legs)?, PerpsError::InsufficientMargin);
+
+    let now = Clock::get()?.unix_timestamp;
+    ctx.accounts.cross_margin_account.collateral_balance -= amount;
+    throttle_outflow(
+        &ctx.accounts.config,
+        ctx.accounts.user_rate_limit.as_mut(),
+        ctx.bumps.user_rate_limit,
+        ctx.accounts.user.key(),
+        amount,
+        now,
+    )?;
+    ctx.accounts.cross_margin_account.exit(&crate::ID)?;
+
+    let config_bump = ctx.accounts.config.bump;
+    transfer_checked(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.cross_vault.to_account_info(),
+        ctx.accounts.user_token.to_account_info(),
+        ctx.accounts.config.to_account_info(),
+        &[&[CONFIG_SEED, &[config_bump]]],
+        amount,
+    )?;
+
+    emit!(CrossCollateralWithdrawn {
+        user: ctx.accounts.user.key(),
+        amount,
+        balance: ctx.accounts.cross_margin_account.collateral_balance,
+    });
+    Ok(())
+}
+
+/// Price `owner`'s open cross positions from (position, market, oracle)
+/// triples in `accounts`. `skip` is a position the caller prices itself;
+/// exactly `expected` others must be passed, each once.
+pub(crate) fn load_cross_legs<'info>(
+    accounts: &'info [AccountInfo<'info>],
+    owner: Pubkey,
+    skip: Option<Pubkey>,
+    expected: u32,
+) -> Result<Vec<CrossLeg>> {
+    require!(accounts.len().is_multiple_of(3), PerpsError::CrossPositionsMissing);
+    let now = Clock::get()?.unix_timestamp;
+    let mut seen: Vec<Pubkey> = Vec::with_capacity(accounts.len() / 3);
+    let mut legs = Vec::with_capacity(accounts.len() / 3);
+    for triple in accounts.chunks(3) {
+        require!(Some(*triple[0].key) != skip && !seen.contains(triple[0].key), PerpsError::DuplicatePositionAccount);
+        seen.push(*triple[0].key);
+
+        let position: Account<UserPosition> = Account::try_from(&triple[0])?;
+        let market: Account<Market> = Account::try_from(&triple[1])?;
+        let oracle: Account<OraclePrice> = Account::try_from(&triple[2])?;
+        require_keys_eq!(position.owner, owner, PerpsError::UnauthorizedAccess);
+        require_keys_eq!(position.market, market.key(), PerpsError::PositionMarketMismatch);
+        require_keys_eq!(oracle.key(), market.oracle, PerpsError::OracleFeedNotFound);
+        require!(position.margin_mode == MarginMode::Cross, PerpsError::CrossMarginUnsupported);
+        position.ensure_status(&[PositionStatus::Open, PositionStatus::Liquidating])?;
+
+        let mark_fp = market.settlement_mark_fp(current_mark_price_fp(&market, &oracle)?);
+        legs.push(CrossLeg::new(&position, mark_fp, market.maintenance_margin_bps_for(position.base_size.unsigned_abs(), now)));
+    }
+    require!(legs.len() == expected as usize, PerpsError::CrossPositionsMissing);
+    Ok(legs)
+}
+
+/// Move a cross position's settled PnL between its market's vault and the
+/// cross vault: the pool's balance went from `balance_before` to `balance_after`.
+/// Both sides are booked, so the sending vault covers any transfer fee.
+pub(crate) fn settle_cross_balance<'info>(
+    config: &Account<'info, Config>,
+    token_program: &Interface<'info, TokenInterface>,
+    quote_mint: &InterfaceAccount<'info, Mint>,
+    vault_token: &InterfaceAccount<'info, TokenAccount>,
+    cross_vault: &InterfaceAccount<'info, TokenAccount>,
+    balance_before: u64,
+    balance_after: u64,
+) -> Result<()> {
+    let (from, to, amount) = if balance_after > balance_before {
+        (vault_token, cross_vault, balance_after - balance_before)
+    } else {
+        (cross_vault, vault_token, balance_before - balance_after)
+    };
+    if amount == 0 {
+        return Ok(());
+    }
+    transfer_checked_exact(
+        token_program,
+        quote_mint,
+        from.to_account_info(),
+        to.to_account_info(),
+        config.to_account_info(),
+        &[&[CONFIG_SEED, &[config.bump]]],
+        amount,
+    )?;
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeCrossVault<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = admin,
+        seeds = [CROSS_VAULT_SEED],
+        bump,
+        token::mint = quote_mint,
+        token::authority = config,
+        token::token_program = token_program,
+    )]
+    pub cross_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositCrossCollateral<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = CrossMarginAccount::SPACE,
+        seeds = [CROSS_MARGIN_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub cross_margin_account: Account<'info, CrossMarginAccount>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [CROSS_VAULT_SEED],
+        bump,
+    )]
+    pub cross_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawCrossCollateral<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [CROSS_MARGIN_SEED, user.key().as_ref()],
+        bump = cross_margin_account.bump
+    )]
+    pub cross_margin_account: Account<'info, CrossMarginAccount>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [CROSS_VAULT_SEED],
+        bump,
+    )]
+    pub cross_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// Only needed while withdrawals are rate limited
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRateLimit::SPACE,
+        seeds = [USER_RATE_LIMIT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_rate_limit: Option<Account<'info, UserRateLimit>>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
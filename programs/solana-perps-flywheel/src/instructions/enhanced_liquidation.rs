@@ -1,170 +1,250 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+use anchor_lang::system_program;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
 use crate::state::*;
 use crate::errors::*;
 use crate::events::*;
-use crate::math::*;
 use crate::oracle;
+use crate::math;
+use crate::transfer::{transfer_checked, transfer_checked_exact, vault_solvency_check};
 
 /// Enhanced liquidation with partial liquidation support
 pub fn enhanced_liquidate(
     mut ctx: Context<EnhancedLiquidate>,
     max_liquidation_percentage: u8,
 ) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
     require!(
         max_liquidation_percentage > 0 && max_liquidation_percentage <= 100,
         PerpsError::InvalidMarketParameters
     );
 
+    // A scheduled maintenance margin raise only bites once it is due
+    ctx.accounts.market.settle_maintenance_margin(Clock::get()?.unix_timestamp);
+
     // Store values before borrowing mutably
     let market_is_paused = ctx.accounts.market.is_paused;
-    let market_maintenance_margin_bps = ctx.accounts.market.maintenance_margin_bps;
     let market_fee_bps = ctx.accounts.config.fee_bps;
     
     require!(!market_is_paused, PerpsError::MarketPaused);
+    // Cross positions are liquidated against the whole account by `liquidate`
+    ctx.accounts.user_position.ensure_isolated()?;
+    ctx.accounts.user_position.ensure_quote_margin()?;
+    ensure_third_party_liquidator(&ctx.accounts.liquidator.key(), &ctx.accounts.user_position.owner)?;
 
-    let mark_fp = oracle::read_oracle_fp(&ctx.accounts.oracle)?;
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(oracle::risk_index_price_fp(&ctx.accounts.market, &ctx.accounts.oracle, ctx.accounts.oracle_twap.as_deref())?);
     
     // Capture position values before mutations
     let position_is_long = ctx.accounts.user_position.is_long;
     let position_base_size = ctx.accounts.user_position.base_size;
     let position_margin = ctx.accounts.user_position.margin_deposited;
     let position_owner = ctx.accounts.user_position.owner;
-    require!(position_base_size != 0, PerpsError::PositionNotFound);
-
-    // Check if position is actually liquidatable
-    let notional_fp = (position_base_size.abs() as u128 * mark_fp) / FP;
-    let required_margin = (notional_fp * market_maintenance_margin_bps as u128) / 10_000;
-    
-    if position_margin as u128 >= required_margin {
-        return Err(PerpsError::PositionNotLiquidatable.into());
-    }
+    let now = Clock::get()?.unix_timestamp;
+    // Larger positions can sit in a stricter margin tier
+    let market_maintenance_margin_bps = ctx.accounts.market.maintenance_margin_bps_for(position_base_size.unsigned_abs(), now);
 
-    // Store position values
-    let position_base_size = position_base_size;
+    // Check if position is actually liquidatable (equity includes unrealized PnL)
     let position_entry_price_fp = ctx.accounts.user_position.entry_price_fp;
-    let position_is_long = position_is_long;
-    let position_margin_amount = position_margin;
-
-    // Calculate liquidation size (partial or full based on max_liquidation_percentage)
-    let original_size = position_base_size.abs() as u64;
-    let liquidation_size = (original_size as u128 * max_liquidation_percentage as u128 / 100) as u64;
-    
-    // Calculate settlement
-    let liquidation_notional_entry_fp = (liquidation_size as u128 * position_entry_price_fp) / FP;
-    let liquidation_notional_exit_fp = (liquidation_size as u128 * mark_fp) / FP;
-    
-    let pnl_fp = if position_is_long {
-        liquidation_notional_exit_fp as i128 - liquidation_notional_entry_fp as i128
+    let original_size = position_base_size.unsigned_abs();
+    let notional_fp = original_size as u128 * mark_fp;
+    let required_margin_fp = (notional_fp * market_maintenance_margin_bps as u128) / 10_000;
+    let price_move_fp = if position_is_long {
+        mark_fp as i128 - position_entry_price_fp as i128
     } else {
-        liquidation_notional_entry_fp as i128 - liquidation_notional_exit_fp as i128
+        position_entry_price_fp as i128 - mark_fp as i128
     };
+    let margin_fp = ctx.accounts.config.quote_to_fp(position_margin)? as i128;
+    let equity_fp = margin_fp + original_size as i128 * price_move_fp;
 
-    // Calculate fees and liquidator reward
-    let liquidation_fee = (liquidation_notional_exit_fp * market_fee_bps as u128) / (10_000 * FP);
-    let liquidator_reward = liquidation_fee / 2; // 50% of fee goes to liquidator
-    let protocol_fee = liquidation_fee - liquidator_reward;
-
-    // Calculate settlement amount
-    let settlement_base = if pnl_fp >= 0 {
-        liquidation_notional_exit_fp + pnl_fp as u128
-    } else {
-        liquidation_notional_exit_fp - (-pnl_fp) as u128
+    ensure_liquidatable(position_base_size, equity_fp, required_margin_fp)?;
+    ensure_material_deficit(
+        equity_fp,
+        required_margin_fp,
+        ctx.accounts.config.quote_to_fp(ctx.accounts.config.min_liquidation_deficit)?,
+    )?;
+    // Optionally the position must also be underwater at the edge of the
+    // oracle's confidence band that favours it, not just at the point price
+    if ctx.accounts.market.confidence_band_liquidation {
+        let confidence_fp = oracle::scale_to_precision(ctx.accounts.oracle.confidence_fp, ctx.accounts.market.price_precision)?;
+        let band_fp = favorable_band_price_fp(mark_fp, confidence_fp, position_is_long);
+        let (band_equity_fp, band_required_fp) = health_at_fp(
+            original_size, position_entry_price_fp, margin_fp, position_is_long, band_fp, market_maintenance_margin_bps,
+        );
+        ensure_liquidatable(position_base_size, band_equity_fp, band_required_fp)?;
+    }
+    // A momentary wick right after entry doesn't liquidate a solvent position
+    let opened_at_ts = ctx.accounts.user_position.opened_at_ts;
+    require!(
+        !ctx.accounts.market.liquidation_protected(opened_at_ts, now, equity_fp),
+        PerpsError::PositionNotLiquidatable
+    );
+    let liquidatable_since_ts = match ctx.accounts.user_position.liquidatable_since_ts {
+        0 => now,
+        since => since,
     };
-    
-    let net_settlement = settlement_base.saturating_sub(liquidation_fee);
-    let liquidation_deficit = if net_settlement < position_margin_amount as u128 {
-        position_margin_amount as u128 - net_settlement
+
+    // Clamp the liquidator's request to what is needed to restore health
+    let full_close_fee_fp = liquidation_charge_fp(&ctx.accounts.config, notional_fp, market_fee_bps)?.0;
+    let fair_percentage = math::max_allowed_liquidation_pct(
+        equity_fp,
+        required_margin_fp,
+        full_close_fee_fp,
+        math::LIQUIDATION_FAIRNESS_BUFFER_PCT,
+    );
+    // Past the bankruptcy price no slice can restore health, and a partial
+    // one would leave an empty-margined remainder: the whole position goes
+    let bankrupt = math::is_past_bankruptcy(
+        mark_fp,
+        math::bankruptcy_price_fp(position_entry_price_fp, margin_fp as u128, original_size, position_is_long),
+        position_is_long,
+    );
+    let liquidation_size = if bankrupt {
+        original_size
     } else {
-        0
+        calculate_optimal_liquidation_size(
+            &ctx.accounts.user_position,
+            market_maintenance_margin_bps,
+            mark_fp,
+            margin_fp,
+            full_close_fee_fp,
+            max_liquidation_percentage.min(fair_percentage),
+        )?
     };
+    // Report the share actually taken, which the size clamp can bring below the percentage
+    let enforced_percentage = (liquidation_size as u128 * 100).div_ceil(original_size as u128) as u8;
+    let is_full_liquidation = liquidation_size == original_size;
 
-    // Pay liquidator reward
-    if liquidator_reward > 0 {
-        let config_bump = ctx.accounts.config.bump;
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.vault_token.to_account_info(),
-                    to: ctx.accounts.liquidator_reward_token.to_account_info(),
-                    authority: ctx.accounts.config.to_account_info(),
-                },
-                &[&[CONFIG_SEED, &[config_bump]]]
-            ),
-            (liquidator_reward / FP) as u64
-        )?;
-    }
+    // PnL on the liquidated slice is realized into the position's margin
+    let pnl_fp = liquidation_size as i128 * price_move_fp;
 
-    // Pay protocol fee
-    if protocol_fee > 0 {
-        let config_bump = ctx.accounts.config.bump;
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.vault_token.to_account_info(),
-                    to: ctx.accounts.fee_destination.to_account_info(),
-                    authority: ctx.accounts.config.to_account_info(),
-                },
-                &[&[CONFIG_SEED, &[config_bump]]]
-            ),
-            (protocol_fee / FP) as u64
-        )?;
+    // Calculate fees and the size-tiered liquidator reward, paid out of what equity is left
+    let (charge_fp, reward_fp) =
+        liquidation_charge_fp(&ctx.accounts.config, liquidation_size as u128 * mark_fp, market_fee_bps)?;
+    let settlement = settle_liquidation(margin_fp, pnl_fp, charge_fp, reward_fp);
+    let (liquidation_fee, liquidator_reward) = (settlement.fee_fp, settlement.reward_fp);
+    let protocol_fee = liquidation_fee - liquidator_reward;
+    if settlement.bad_debt_fp > 0 {
+        msg!("Liquidated past bankruptcy, bad debt ${}", ctx.accounts.config.to_human_price(settlement.bad_debt_fp as i128));
     }
 
-    // Handle insurance fund if there's a deficit
-    if liquidation_deficit > 0 {
-        contribute_to_insurance_fund(&mut ctx, (liquidation_deficit / FP) as u64)?;
-    }
+    let remaining_margin_fp = settlement.remaining_margin_fp;
+    // The insurance funds cover exactly the loss past zero equity
+    let liquidation_deficit = settlement.bad_debt_fp;
+    // Equity left after a full liquidation is surplus for the market's insurance fund
+    let liquidation_surplus = if is_full_liquidation { remaining_margin_fp } else { 0 };
+
+    let liquidator_reward_amt = ctx.accounts.config.fp_to_quote(liquidator_reward)?;
+    let protocol_fee_amt = ctx.accounts.config.fp_to_quote(protocol_fee)?;
+    let liquidation_deficit_amt = ctx.accounts.config.fp_to_quote(liquidation_deficit)?;
+    let liquidation_surplus_amt = ctx.accounts.config.fp_to_quote(liquidation_surplus)?;
+    // What the position was charged: the liquidator's reward and the protocol's fee
+    let fees_paid = liquidator_reward_amt + protocol_fee_amt;
 
     // Update position
     {
         let up = &mut ctx.accounts.user_position;
-        up.base_size = if max_liquidation_percentage == 100 {
-            0
+        if is_full_liquidation {
+            settle_full_liquidation(up, pnl_fp, fees_paid, now);
         } else {
-            if position_is_long {
+            up.realized_pnl_fp += pnl_fp;
+            up.total_fees_paid += fees_paid;
+            up.base_size = if position_is_long {
                 position_base_size - liquidation_size as i64
             } else {
                 position_base_size + liquidation_size as i64
+            };
+            up.margin_deposited = ctx.accounts.config.fp_to_quote(remaining_margin_fp)?;
+
+            // Stays flagged only if the liquidator took less than needed to restore health
+            let remaining_size = (original_size - liquidation_size) as i128;
+            let remaining_equity_fp = remaining_margin_fp as i128 + remaining_size * price_move_fp;
+            let remaining_required_fp = remaining_size * mark_fp as i128 * market_maintenance_margin_bps as i128 / 10_000;
+            if remaining_equity_fp < remaining_required_fp {
+                up.liquidatable_since_ts = liquidatable_since_ts;
+                up.status = PositionStatus::Liquidating;
+            } else {
+                up.liquidatable_since_ts = 0;
+                up.status = PositionStatus::Open;
             }
-        };
-        
-        if max_liquidation_percentage == 100 || up.base_size == 0 {
-            up.base_size = 0;
-            up.margin_deposited = 0;
-            up.is_long = false;
-            up.entry_price_fp = 0;
-        } else {
-            let remaining_size = original_size - liquidation_size;
-            let remaining_ratio = remaining_size as u128 * FP / original_size as u128;
-            up.margin_deposited = (up.margin_deposited as u128 * remaining_ratio / FP) as u64;
         }
+        up.last_updated_ts = now;
     }
 
+    if is_full_liquidation {
+        ctx.accounts.protocol_stats.record_close();
+        ctx.accounts.user_account.record_close();
+    }
+    ctx.accounts.protocol_stats.record_liquidation(protocol_fee_amt)?;
+
     // Update market
-    {
-        let market_mut = &mut ctx.accounts.market;
-        if position_is_long {
-            market_mut.total_long_size = market_mut.total_long_size.saturating_sub(liquidation_size);
-        } else {
-            market_mut.total_short_size = market_mut.total_short_size.saturating_sub(liquidation_size);
-        }
+    ctx.accounts.market.reduce_open_interest(position_is_long, liquidation_size);
+    ctx.accounts.market.rebook_margin(position_margin, ctx.accounts.user_position.vault_margin())?;
+    ctx.accounts.market.record_settlement(pnl_fp, liquidation_fee)?;
+
+    // Persist settled state before any transfer
+    ctx.accounts.user_position.exit(&crate::ID)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+    ctx.accounts.user_account.exit(&crate::ID)?;
+
+    // Pay liquidator reward, plus SOL for gas when a keeper gas vault is passed
+    transfer_liquidator_reward(&ctx, liquidator_reward_amt)?;
+    reimburse_keeper_gas(&ctx)?;
+
+    // Pay protocol fee, less the insurance fund's slice while it is under target
+    let insurance_fee_amt = route_fee_to_insurance(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        &ctx.accounts.config,
+        &ctx.accounts.vault_token,
+        &mut ctx.accounts.insurance_fund,
+        &ctx.accounts.insurance_vault_token,
+        ctx.accounts.liquidator.key(),
+        protocol_fee_amt,
+    )?;
+    transfer_protocol_fees(&ctx, protocol_fee_amt - insurance_fee_amt)?;
+
+    // Settle against this market's insurance fund (global fund as second loss)
+    if liquidation_surplus_amt > 0 {
+        contribute_to_insurance_fund(&mut ctx, liquidation_surplus_amt)?;
+    }
+    if liquidation_deficit_amt > 0 {
+        let uncovered = cover_deficit_from_insurance(&mut ctx, liquidation_deficit_amt)?;
+        let covered_fp = ctx.accounts.config.quote_to_fp(liquidation_deficit_amt - uncovered)?;
+        ctx.accounts.market.hold_insurance_claims(covered_fp)?;
     }
+    vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
 
     emit!(LiquidationExecuted {
         liquidator: ctx.accounts.liquidator.key(),
         liquidated_user: position_owner,
+        market: ctx.accounts.market.key(),
         liquidation_size,
+        remaining_size: original_size - liquidation_size,
         liquidation_price_fp: mark_fp,
-        liquidator_reward: (liquidator_reward / FP) as u64,
-        insurance_fund_contribution: (liquidation_deficit / FP) as u64,
+        pnl_fp,
+        fees_fp: liquidation_fee,
+        liquidator_reward: liquidator_reward_amt,
+        insurance_fund_contribution: liquidation_deficit_amt,
+        returned_to_user: 0, // surplus equity goes to the insurance fund
+        bad_debt_fp: settlement.bad_debt_fp,
+        requested_percentage: max_liquidation_percentage,
+        enforced_percentage,
+        liquidatable_since_ts,
     });
+    emit!(ctx.accounts.protocol_stats.updated_event());
 
     Ok(())
 }
 
+/// Zero out a fully liquidated position, recording its final PnL and the
+/// fees it was charged in its lifetime stats as a close would
+fn settle_full_liquidation(up: &mut UserPosition, pnl_fp: i128, fees_paid: u64, now: i64) {
+    up.settle_full_close(pnl_fp, fees_paid, now);
+    up.is_long = false;
+    up.entry_price_fp = 0;
+}
+
 /// Deposit to insurance fund
 pub fn deposit_insurance_fund(
     ctx: Context<DepositInsuranceFund>,
@@ -173,22 +253,19 @@ pub fn deposit_insurance_fund(
     require!(amount > 0, PerpsError::InvalidMarketParameters);
 
     // Transfer tokens to insurance fund vault
-    token::transfer(
-        CpiContext::new(
-            ctx.accounts.token_program.to_account_info(),
-            Transfer {
-                from: ctx.accounts.depositor_token.to_account_info(),
-                to: ctx.accounts.insurance_vault_token.to_account_info(),
-                authority: ctx.accounts.depositor.to_account_info(),
-            }
-        ),
-        amount
+    transfer_checked_exact(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.depositor_token.to_account_info(),
+        ctx.accounts.insurance_vault_token.to_account_info(),
+        ctx.accounts.depositor.to_account_info(),
+        &[],
+        amount,
     )?;
 
     // Update insurance fund state
     let fund = &mut ctx.accounts.insurance_fund;
-    fund.total_deposits = fund.total_deposits.checked_add(amount)
-        .ok_or(PerpsError::MathOverflow)?;
+    fund.record_deposit(amount)?;
 
     emit!(InsuranceFundDeposit {
         depositor: ctx.accounts.depositor.key(),
@@ -212,26 +289,24 @@ pub fn withdraw_insurance_fund(
     require!(reason.len() <= 200, PerpsError::InvalidMarketParameters);
 
     let fund = &ctx.accounts.insurance_fund;
-    require!(amount <= fund.total_deposits, PerpsError::InsufficientBalance);
+    require!(amount <= fund.available(), PerpsError::InsufficientBalance);
     
     let fund_bump = fund.bump;
-    let fund_total_before = fund.total_deposits;
+    let fund_market = fund.market;
+    let fund_total_before = fund.available();
 
     // Transfer tokens from insurance fund vault
-    token::transfer(
-        CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            Transfer {
-                from: ctx.accounts.insurance_vault_token.to_account_info(),
-                to: ctx.accounts.recipient_token.to_account_info(),
-                authority: ctx.accounts.insurance_fund.to_account_info(),
-            },
-            &[&[
-                b"insurance_fund",
-                &[fund_bump]
-            ]]
-        ),
-        amount
+    let bump_seed = [fund_bump];
+    let global_seeds: &[&[u8]] = &[INSURANCE_FUND_SEED, &bump_seed];
+    let market_seeds: &[&[u8]] = &[INSURANCE_FUND_SEED, fund_market.as_ref(), &bump_seed];
+    transfer_checked(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.insurance_vault_token.to_account_info(),
+        ctx.accounts.recipient_token.to_account_info(),
+        ctx.accounts.insurance_fund.to_account_info(),
+        &[if fund_market == Pubkey::default() { global_seeds } else { market_seeds }],
+        amount,
     )?;
 
     // Update insurance fund state
@@ -251,117 +326,362 @@ pub fn withdraw_insurance_fund(
     Ok(())
 }
 
-/// Initialize insurance fund
+/// Initialize the global insurance fund, the second-loss backstop for all markets
 pub fn initialize_insurance_fund(ctx: Context<InitializeInsuranceFund>) -> Result<()> {
+    let fund_key = ctx.accounts.insurance_fund.key();
     let fund = &mut ctx.accounts.insurance_fund;
     fund.total_deposits = 0;
     fund.total_claims = 0;
-    fund.vault_authority = ctx.accounts.config.key();
+    fund.vault_authority = fund_key;
     fund.vault_token_account = ctx.accounts.insurance_vault_token.key();
     fund.bump = ctx.bumps.insurance_fund;
+    fund.market = Pubkey::default();
 
     msg!("Insurance fund initialized");
     Ok(())
 }
 
+/// Initialize a market's own insurance fund, which takes that market's first loss
+pub fn initialize_market_insurance_fund(ctx: Context<InitializeMarketInsuranceFund>) -> Result<()> {
+    let fund_key = ctx.accounts.insurance_fund.key();
+    let fund = &mut ctx.accounts.insurance_fund;
+    fund.total_deposits = 0;
+    fund.total_claims = 0;
+    fund.vault_authority = fund_key;
+    fund.vault_token_account = ctx.accounts.insurance_vault_token.key();
+    fund.bump = ctx.bumps.insurance_fund;
+    fund.market = ctx.accounts.market.key();
+
+    msg!("Market insurance fund initialized for {}", fund.market);
+    Ok(())
+}
+
 // Helper functions
+
+/// Gate for keepers racing on the same position. Whatever the reason the
+/// position no longer qualifies -- already closed (`Closed` and
+/// `PendingSettlement` positions have zero size), or restored above
+/// maintenance by another keeper's liquidation -- the loser of the race gets
+/// a clean `PositionNotLiquidatable`.
+pub fn ensure_liquidatable(base_size: i64, equity_fp: i128, maintenance_required_fp: u128) -> Result<()> {
+    require!(
+        base_size != 0 && equity_fp < maintenance_required_fp as i128,
+        PerpsError::PositionNotLiquidatable
+    );
+    Ok(())
+}
+
+/// Refuse dust liquidations: the position must sit at least `min_deficit_fp`
+/// below maintenance, so hairline breaches are left to self-cure (0 = any breach)
+pub fn ensure_material_deficit(equity_fp: i128, maintenance_required_fp: u128, min_deficit_fp: u128) -> Result<()> {
+    let deficit_fp = maintenance_required_fp as i128 - equity_fp;
+    require!(deficit_fp >= min_deficit_fp as i128, PerpsError::PositionNotLiquidatable);
+    Ok(())
+}
+
+/// Owners can't liquidate their own positions: the liquidator reward and
+/// gas reimbursement would only claw back part of their own loss from the
+/// vault. A trader who wants out of an underwater position closes it.
+pub fn ensure_third_party_liquidator(liquidator: &Pubkey, position_owner: &Pubkey) -> Result<()> {
+    require_keys_neq!(*liquidator, *position_owner, PerpsError::UnauthorizedAccess);
+    Ok(())
+}
+
+/// Equity and maintenance requirement of a position valued at `price_fp`,
+/// both at price precision
+pub fn health_at_fp(
+    size: u64,
+    entry_price_fp: u128,
+    margin_fp: i128,
+    is_long: bool,
+    price_fp: u128,
+    maintenance_margin_bps: u16,
+) -> (i128, u128) {
+    let price_move_fp = if is_long {
+        price_fp as i128 - entry_price_fp as i128
+    } else {
+        entry_price_fp as i128 - price_fp as i128
+    };
+    let equity_fp = margin_fp + size as i128 * price_move_fp;
+    let required_fp = size as u128 * price_fp * maintenance_margin_bps as u128 / 10_000;
+    (equity_fp, required_fp)
+}
+
+/// Edge of the oracle's confidence band most favourable to the position:
+/// the top for a long, the bottom for a short
+pub fn favorable_band_price_fp(mark_fp: u128, confidence_fp: u128, is_long: bool) -> u128 {
+    if is_long {
+        mark_fp.saturating_add(confidence_fp)
+    } else {
+        mark_fp.saturating_sub(confidence_fp)
+    }
+}
+
+/// The liquidator's percentage of the position, clamped to the smallest size
+/// that restores health: whole percents would over-liquidate large positions
 fn calculate_optimal_liquidation_size(
     position: &UserPosition,
-    market: &Market,
+    maintenance_margin_bps: u16,
     mark_fp: u128,
+    margin_fp: i128,
+    full_close_fee_fp: u128,
     max_percentage: u8,
 ) -> Result<u64> {
-    let position_size = position.base_size.abs() as u64;
-    let max_liquidation_size = (position_size as u128 * max_percentage as u128 / 100) as u64;
-    
-    // For now, use max allowed percentage, but could be optimized based on:
-    // - Market depth
-    // - Position health
-    // - Insurance fund status
-    Ok(max_liquidation_size.min(position_size))
+    let position_size = position.base_size.unsigned_abs();
+    // Round up so a small fair fraction still closes at least one unit
+    let max_liquidation_size = (position_size as u128 * max_percentage as u128).div_ceil(100) as u64;
+    let restore_size = math::calculate_liquidation_size_to_restore_health(
+        position_size,
+        position.entry_price_fp,
+        mark_fp,
+        position.is_long,
+        margin_fp,
+        maintenance_margin_bps,
+        full_close_fee_fp,
+        math::RESTORE_HEALTH_BUFFER_BPS,
+    );
+    Ok(max_liquidation_size.min(restore_size.max(1)).min(position_size))
+}
+
+/// How a liquidated slice settles against the position, all at price precision
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LiquidationSettlement {
+    pub fee_fp: u128,               // Charged to the position, liquidator reward included
+    pub reward_fp: u128,            // Liquidator's share of the fee
+    pub remaining_margin_fp: u128,  // Margin left after the slice's PnL and fee
+    pub bad_debt_fp: u128,          // Loss past zero equity, for the insurance funds
+}
+
+/// Realize `pnl_fp` into `margin_fp` and take the liquidation charge out of
+/// what is left. Like `close_settlement`, the charge never exceeds the
+/// equity: a bankrupt position pays nothing, and the only deficit left for
+/// the insurance funds is its loss past zero.
+pub fn settle_liquidation(margin_fp: i128, pnl_fp: i128, charge_fp: u128, reward_fp: u128) -> LiquidationSettlement {
+    let equity_fp = margin_fp + pnl_fp;
+    let solvent_fp = equity_fp.max(0) as u128;
+    let fee_fp = charge_fp.min(solvent_fp);
+    LiquidationSettlement {
+        fee_fp,
+        reward_fp: reward_fp.min(fee_fp),
+        remaining_margin_fp: solvent_fp - fee_fp,
+        bad_debt_fp: if equity_fp < 0 { equity_fp.unsigned_abs() } else { 0 },
+    }
+}
+
+/// Total charged to the liquidated position and the keeper's share of it, both
+/// at price precision. The charge is the trading fee, raised to the reward
+/// curve's payout when that is larger (e.g. the floor on small positions).
+fn liquidation_charge_fp(cfg: &Config, notional_fp: u128, fee_bps: u16) -> Result<(u128, u128)> {
+    let fee_fp = notional_fp * fee_bps as u128 / 10_000;
+    let reward = math::liquidator_reward(
+        cfg.fp_to_quote(notional_fp)?,
+        &cfg.liquidator_reward_tiers,
+        cfg.liquidator_reward_floor,
+    );
+    let reward_fp = cfg.quote_to_fp(reward)?;
+    Ok((fee_fp.max(reward_fp), reward_fp))
 }
 
 fn transfer_liquidator_reward(ctx: &Context<EnhancedLiquidate>, amount: u64) -> Result<()> {
     if amount > 0 {
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.vault_token.to_account_info(),
-                    to: ctx.accounts.liquidator_reward_token.to_account_info(),
-                    authority: ctx.accounts.config.to_account_info(),
-                },
-                &[&[
-                    CONFIG_SEED,
-                    &[ctx.accounts.config.bump]
-                ]]
-            ),
-            amount
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.quote_mint,
+            ctx.accounts.vault_token.to_account_info(),
+            ctx.accounts.liquidator_reward_token.to_account_info(),
+            ctx.accounts.config.to_account_info(),
+            &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]],
+            amount,
         )?;
     }
     Ok(())
 }
 
-fn contribute_to_insurance_fund(ctx: &mut Context<EnhancedLiquidate>, amount: u64) -> Result<()> {
-    if amount == 0 {
+fn reimburse_keeper_gas(ctx: &Context<EnhancedLiquidate>) -> Result<()> {
+    let (Some(vault), Some(system)) = (&ctx.accounts.keeper_gas_vault, &ctx.accounts.system_program) else {
+        return Ok(());
+    };
+    let vault_floor = Rent::get()?.minimum_balance(0);
+    let lamports = ctx.accounts.config.keeper_gas_reimbursement(vault.lamports(), vault_floor);
+    if lamports == 0 {
         return Ok(());
     }
 
-        // Try to cover deficit with insurance fund if available
+    let bump = ctx.bumps.keeper_gas_vault.ok_or(PerpsError::InvalidPDA)?;
+    system_program::transfer(
+        CpiContext::new_with_signer(
+            system.to_account_info(),
+            system_program::Transfer {
+                from: vault.to_account_info(),
+                to: ctx.accounts.liquidator.to_account_info(),
+            },
+            &[&[KEEPER_GAS_VAULT_SEED, &[bump]]]
+        ),
+        lamports
+    )?;
+
+    emit!(KeeperGasReimbursed {
+        keeper: ctx.accounts.liquidator.key(),
+        lamports,
+        vault_balance: vault.lamports(),
+    });
+    Ok(())
+}
+
+fn contribute_to_insurance_fund(ctx: &mut Context<EnhancedLiquidate>, amount: u64) -> Result<()> {
     if amount == 0 {
         return Ok(());
     }
 
-    // Update insurance fund
     let fund = &mut ctx.accounts.insurance_fund;
-    fund.total_deposits = fund.total_deposits.saturating_add(amount);
+    fund.record_deposit(amount)?;
+    fund.exit(&crate::ID)?;
+
+    transfer_checked_exact(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.vault_token.to_account_info(),
+        ctx.accounts.insurance_vault_token.to_account_info(),
+        ctx.accounts.config.to_account_info(),
+        &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]],
+        amount,
+    )?;
+
+    let fund = &ctx.accounts.insurance_fund;
     
     emit!(InsuranceFundContribution {
         contributor: ctx.accounts.liquidator.key(),
+        market: fund.market,
         amount,
-        new_balance: fund.total_deposits,
+        new_balance: fund.available(),
     });
     
     Ok(())
-}fn transfer_protocol_fees(ctx: &Context<EnhancedLiquidate>, amount: u64) -> Result<()> {
-    if amount > 0 {
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.vault_token.to_account_info(),
-                    to: ctx.accounts.fee_destination.to_account_info(),
-                    authority: ctx.accounts.config.to_account_info(),
-                },
-                &[&[
-                    CONFIG_SEED,
-                    &[ctx.accounts.config.bump]
-                ]]
-            ),
-            amount
-        )?;
+}
+
+/// Move the insurance fund's slice of a protocol fee (`Config::insurance_fee_bps`,
+/// until the fund reaches `insurance_target_ratio_bps`) from the market vault
+/// into the fund. Returns the amount routed, which the caller holds back from
+/// the fee destination. Call after the other settled accounts are persisted.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn route_fee_to_insurance<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    quote_mint: &InterfaceAccount<'info, Mint>,
+    config: &Account<'info, Config>,
+    vault_token: &InterfaceAccount<'info, TokenAccount>,
+    fund: &mut Account<'info, InsuranceFund>,
+    fund_vault: &InterfaceAccount<'info, TokenAccount>,
+    contributor: Pubkey,
+    fee: u64,
+) -> Result<u64> {
+    require_keys_eq!(fund_vault.key(), fund.vault_token_account, PerpsError::InvalidTokenAccount);
+    let amount = fund.fee_contribution(fee, config.insurance_fee_bps, config.insurance_target_ratio_bps);
+    if amount == 0 {
+        return Ok(0);
     }
-    Ok(())
+    fund.record_deposit(amount)?;
+    fund.exit(&crate::ID)?;
+
+    transfer_checked_exact(
+        token_program,
+        quote_mint,
+        vault_token.to_account_info(),
+        fund_vault.to_account_info(),
+        config.to_account_info(),
+        &[&[CONFIG_SEED, &[config.bump]]],
+        amount,
+    )?;
+
+    emit!(InsuranceFundContribution {
+        contributor,
+        market: fund.market,
+        amount,
+        new_balance: fund.available(),
+    });
+    Ok(amount)
 }
 
-fn calculate_liquidation_price(
-    entry_price_fp: u128,
-    _margin: u64,
-    _base_size: u64,
-    maintenance_margin_bps: u16,
-    is_long: bool,
-) -> Result<u128> {
-    let maintenance_ratio = maintenance_margin_bps as u128;
+/// Pull a liquidation deficit back into the trading vault: the market's own
+/// fund first, then the global fund if one was passed. Returns what is left
+/// uncovered (bad debt).
+fn cover_deficit_from_insurance(ctx: &mut Context<EnhancedLiquidate>, deficit: u64) -> Result<u64> {
+    let market_key = ctx.accounts.market.key();
+    let token_program = &ctx.accounts.token_program;
+    let quote_mint = &ctx.accounts.quote_mint;
+    let vault = ctx.accounts.vault_token.to_account_info();
 
-    let liq_price_fp = if is_long {
-        let numerator = entry_price_fp * (10_000 - maintenance_ratio);
-        numerator.checked_div(10_000).ok_or(PerpsError::DivisionByZero)?
-    } else {
-        let numerator = entry_price_fp * (10_000 + maintenance_ratio);
-        numerator.checked_div(10_000).ok_or(PerpsError::DivisionByZero)?
-    };
+    let fund_info = ctx.accounts.insurance_fund.to_account_info();
+    let fund = &mut ctx.accounts.insurance_fund;
+    let from_market = fund.absorb_deficit(deficit);
+    fund.exit(&crate::ID)?;
+    if from_market > 0 {
+        transfer_checked_exact(
+            token_program,
+            quote_mint,
+            ctx.accounts.insurance_vault_token.to_account_info(),
+            vault.clone(),
+            fund_info,
+            &[&[INSURANCE_FUND_SEED, market_key.as_ref(), &[fund.bump]]],
+            from_market,
+        )?;
+        emit!(InsuranceFundDeficitCovered {
+            market: market_key,
+            fund: fund.key(),
+            amount: from_market,
+            remaining_balance: fund.available(),
+        });
+    }
 
-    Ok(liq_price_fp)
+    let mut uncovered = deficit - from_market;
+    if let (Some(global), Some(global_vault)) = (
+        ctx.accounts.global_insurance_fund.as_mut(),
+        ctx.accounts.global_insurance_vault_token.as_ref(),
+    ) {
+        require_keys_eq!(global_vault.key(), global.vault_token_account, PerpsError::InvalidTokenAccount);
+        let global_info = global.to_account_info();
+        let from_global = global.absorb_deficit(uncovered);
+        global.exit(&crate::ID)?;
+        if from_global > 0 {
+            transfer_checked_exact(
+                token_program,
+                quote_mint,
+                global_vault.to_account_info(),
+                vault,
+                global_info,
+                &[&[INSURANCE_FUND_SEED, &[global.bump]]],
+                from_global,
+            )?;
+            emit!(InsuranceFundDeficitCovered {
+                market: market_key,
+                fund: global.key(),
+                amount: from_global,
+                remaining_balance: global.available(),
+            });
+        }
+        uncovered -= from_global;
+    }
+
+    if uncovered > 0 {
+        // Left for auto_deleverage to take out of the winning side
+        ctx.accounts.insurance_fund.record_bad_debt(uncovered)?;
+        msg!("Liquidation left {} of uncovered bad debt in market {}", uncovered, market_key);
+    }
+    Ok(uncovered)
+}
+
+fn transfer_protocol_fees(ctx: &Context<EnhancedLiquidate>, amount: u64) -> Result<()> {
+    if amount > 0 {
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.quote_mint,
+            ctx.accounts.vault_token.to_account_info(),
+            ctx.accounts.fee_destination.to_account_info(),
+            ctx.accounts.config.to_account_info(),
+            &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]],
+            amount,
+        )?;
+    }
+    Ok(())
 }
 
 // Account contexts
@@ -380,6 +700,10 @@ pub struct EnhancedLiquidate<'info> {
     pub market: Account<'info, Market>,
     
     pub oracle: Account<'info, OraclePrice>,
+
+    /// The oracle's TWAP ring, required when the market prices risk at a TWAP
+    #[account(seeds = [ORACLE_TWAP_SEED, oracle.key().as_ref()], bump = oracle_twap.bump)]
+    pub oracle_twap: Option<Account<'info, OracleTwap>>,
     
     #[account(
         mut,
@@ -387,31 +711,75 @@ pub struct EnhancedLiquidate<'info> {
         bump = user_position.bump
     )]
     pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user_position.owner.as_ref()],
+        bump = user_account.bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
     
+    /// This market's own insurance fund (first loss)
     #[account(
         mut,
-        seeds = [b"insurance_fund"],
+        seeds = [INSURANCE_FUND_SEED, market.key().as_ref()],
         bump = insurance_fund.bump
     )]
     pub insurance_fund: Account<'info, InsuranceFund>,
     
-    #[account(mut)]
-    pub user_token: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_token.owner == user_position.owner @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
     
-    #[account(mut)]
-    pub vault_token: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
     
-    #[account(mut)]
-    pub liquidator_reward_token: Account<'info, TokenAccount>,
+    #[account(mut, constraint = liquidator_reward_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub liquidator_reward_token: InterfaceAccount<'info, TokenAccount>,
     
+    #[account(mut, address = insurance_fund.vault_token_account @ PerpsError::InvalidTokenAccount)]
+    pub insurance_vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// Optional global backstop, drawn only once the market fund is exhausted
+    #[account(
+        mut,
+        seeds = [INSURANCE_FUND_SEED],
+        bump = global_insurance_fund.bump
+    )]
+    pub global_insurance_fund: Option<Account<'info, InsuranceFund>>,
+
     #[account(mut)]
-    pub insurance_vault_token: Account<'info, TokenAccount>,
+    pub global_insurance_vault_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Optional SOL vault reimbursing the keeper's transaction fees
+    #[account(mut, seeds = [KEEPER_GAS_VAULT_SEED], bump)]
+    pub keeper_gas_vault: Option<SystemAccount<'info>>,
+
+    pub system_program: Option<Program<'info, System>>,
     
-    /// CHECK: Fee destination
-    #[account(mut)]
+    /// CHECK: Fee destination, must be the configured fee account
+    #[account(mut, address = config.fee_destination @ PerpsError::InvalidTokenAccount)]
     pub fee_destination: AccountInfo<'info>,
     
-    pub token_program: Program<'info, Token>,
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
@@ -419,20 +787,20 @@ pub struct DepositInsuranceFund<'info> {
     #[account(mut)]
     pub depositor: Signer<'info>,
     
-    #[account(
-        mut,
-        seeds = [b"insurance_fund"],
-        bump = insurance_fund.bump
-    )]
+    /// Global or per-market fund
+    #[account(mut)]
     pub insurance_fund: Account<'info, InsuranceFund>,
     
     #[account(mut)]
-    pub depositor_token: Account<'info, TokenAccount>,
+    pub depositor_token: InterfaceAccount<'info, TokenAccount>,
     
-    #[account(mut)]
-    pub insurance_vault_token: Account<'info, TokenAccount>,
+    #[account(mut, address = insurance_fund.vault_token_account @ PerpsError::InvalidTokenAccount)]
+    pub insurance_vault_token: InterfaceAccount<'info, TokenAccount>,
     
-    pub token_program: Program<'info, Token>,
+    #[account(address = insurance_vault_token.mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
@@ -446,20 +814,20 @@ pub struct WithdrawInsuranceFund<'info> {
     
     pub admin: Signer<'info>,
     
-    #[account(
-        mut,
-        seeds = [b"insurance_fund"],
-        bump = insurance_fund.bump
-    )]
+    /// Global or per-market fund
+    #[account(mut)]
     pub insurance_fund: Account<'info, InsuranceFund>,
     
-    #[account(mut)]
-    pub insurance_vault_token: Account<'info, TokenAccount>,
+    #[account(mut, address = insurance_fund.vault_token_account @ PerpsError::InvalidTokenAccount)]
+    pub insurance_vault_token: InterfaceAccount<'info, TokenAccount>,
     
     #[account(mut)]
-    pub recipient_token: Account<'info, TokenAccount>,
+    pub recipient_token: InterfaceAccount<'info, TokenAccount>,
     
-    pub token_program: Program<'info, Token>,
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
@@ -478,12 +846,217 @@ pub struct InitializeInsuranceFund<'info> {
         init,
         payer = admin,
         space = InsuranceFund::SPACE,
-        seeds = [b"insurance_fund"],
+        seeds = [INSURANCE_FUND_SEED],
+        bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    
+    #[account(
+        constraint = insurance_vault_token.owner == insurance_fund.key() @ PerpsError::InvalidTokenAccount,
+        constraint = insurance_vault_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub insurance_vault_token: InterfaceAccount<'info, TokenAccount>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeMarketInsuranceFund<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+        constraint = config.admin == admin.key() @ PerpsError::UnauthorizedAccess,
+    )]
+    pub config: Account<'info, Config>,
+    
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+    
+    #[account(
+        init,
+        payer = admin,
+        space = InsuranceFund::SPACE,
+        seeds = [INSURANCE_FUND_SEED, market.key().as_ref()],
         bump
     )]
     pub insurance_fund: Account<'info, InsuranceFund>,
     
-    pub insurance_vault_token: Account<'info, TokenAccount>,
+    #[account(
+        constraint = insurance_vault_token.owner == insurance_fund.key() @ PerpsError::InvalidTokenAccount,
+        constraint = insurance_vault_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub insurance_vault_token: InterfaceAccount<'info, TokenAccount>,
     
     pub system_program: Program<'info, System>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_keeper_gets_not_liquidatable() {
+        // 10 units long from $100 at $95 with $40 margin, 5% maintenance
+        let mark_fp = 95 * FP;
+        let mut position = UserPosition { base_size: 10, is_long: true, entry_price_fp: 100 * FP, ..Default::default() };
+        let equity_fp = |p: &UserPosition, margin_fp: i128| {
+            margin_fp + p.base_size as i128 * (mark_fp as i128 - p.entry_price_fp as i128)
+        };
+        let required_fp = |p: &UserPosition| p.base_size.unsigned_abs() as u128 * mark_fp * 500 / 10_000;
+
+        // Keeper A and keeper B both see the position underwater
+        let margin_fp = 40 * FP as i128;
+        assert!(ensure_liquidatable(position.base_size, equity_fp(&position, margin_fp), required_fp(&position)).is_ok());
+
+        // Keeper A lands first and fully liquidates
+        position.base_size = 0;
+        let err = ensure_liquidatable(position.base_size, equity_fp(&position, 0), required_fp(&position)).unwrap_err();
+        assert_eq!(err, PerpsError::PositionNotLiquidatable.into());
+    }
+
+    #[test]
+    fn test_wide_confidence_defers_borderline_liquidation() {
+        // 10 units long from $100 with $60 margin, 5% maintenance, mark at $95.
+        // Equity $10 against a $47.50 requirement: liquidatable at the point price.
+        let (size, entry_fp, margin_fp) = (10, 100 * FP, 60 * FP as i128);
+        let (equity_fp, required_fp) = health_at_fp(size, entry_fp, margin_fp, true, 95 * FP, 500);
+        assert!(ensure_liquidatable(10, equity_fp, required_fp).is_ok());
+
+        // A tight band doesn't save it
+        let band_fp = favorable_band_price_fp(95 * FP, FP / 2, true);
+        let (equity_fp, required_fp) = health_at_fp(size, entry_fp, margin_fp, true, band_fp, 500);
+        assert!(ensure_liquidatable(10, equity_fp, required_fp).is_ok());
+
+        // With +/- $5 of confidence the long may really be at $100: not yet
+        let band_fp = favorable_band_price_fp(95 * FP, 5 * FP, true);
+        assert_eq!(band_fp, 100 * FP);
+        let (equity_fp, required_fp) = health_at_fp(size, entry_fp, margin_fp, true, band_fp, 500);
+        let err = ensure_liquidatable(10, equity_fp, required_fp).unwrap_err();
+        assert_eq!(err, PerpsError::PositionNotLiquidatable.into());
+
+        // A short takes the bottom of the band
+        assert_eq!(favorable_band_price_fp(105 * FP, 5 * FP, false), 100 * FP);
+        assert_eq!(favorable_band_price_fp(FP, 5 * FP, false), 0);
+    }
+
+    #[test]
+    fn test_dust_breach_is_left_below_the_minimum_deficit() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, min_liquidation_deficit: 1_000_000, ..Default::default() };
+        let min_deficit_fp = cfg.quote_to_fp(cfg.min_liquidation_deficit).unwrap();
+        let required_fp = 50 * FP;
+
+        // A hair under maintenance is liquidatable, but not worth a liquidation
+        let barely_fp = required_fp as i128 - 1_000;
+        assert!(ensure_liquidatable(10, barely_fp, required_fp).is_ok());
+        let err = ensure_material_deficit(barely_fp, required_fp, min_deficit_fp).unwrap_err();
+        assert_eq!(err, PerpsError::PositionNotLiquidatable.into());
+
+        // Clearly below the line it goes through
+        let clearly_fp = required_fp as i128 - 5 * FP as i128;
+        assert!(ensure_material_deficit(clearly_fp, required_fp, min_deficit_fp).is_ok());
+
+        // The zero default keeps every breach liquidatable
+        assert!(ensure_material_deficit(barely_fp, required_fp, 0).is_ok());
+    }
+
+    #[test]
+    fn test_owner_cannot_self_liquidate() {
+        let (owner, keeper) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let err = ensure_third_party_liquidator(&owner, &owner).unwrap_err();
+        assert_eq!(err, PerpsError::UnauthorizedAccess.into());
+        assert!(ensure_third_party_liquidator(&keeper, &owner).is_ok());
+    }
+
+    #[test]
+    fn test_restored_position_is_not_liquidatable() {
+        // Partially liquidated back above maintenance by the first keeper
+        let err = ensure_liquidatable(4, 30 * FP as i128, 19 * FP).unwrap_err();
+        assert_eq!(err, PerpsError::PositionNotLiquidatable.into());
+        assert!(ensure_liquidatable(4, 18 * FP as i128, 19 * FP).is_ok());
+    }
+
+    #[test]
+    fn test_solvent_liquidation_pays_its_fee_out_of_equity() {
+        // 10 units long from $100 with $40 margin go bankrupt at $96
+        let margin_fp = 40 * FP;
+        let bankruptcy_fp = math::bankruptcy_price_fp(100 * FP, margin_fp, 10, true);
+        assert_eq!(bankruptcy_fp, 96 * FP);
+
+        // At $97 there is $10 of equity, enough for the $2 charge
+        assert!(!math::is_past_bankruptcy(97 * FP, bankruptcy_fp, true));
+        let pnl_fp = 10 * (97 * FP as i128 - 100 * FP as i128);
+        let settlement = settle_liquidation(margin_fp as i128, pnl_fp, 2 * FP, FP);
+        assert_eq!(settlement, LiquidationSettlement {
+            fee_fp: 2 * FP,
+            reward_fp: FP,
+            remaining_margin_fp: 8 * FP,
+            bad_debt_fp: 0,
+        });
+    }
+
+    #[test]
+    fn test_bankrupt_liquidation_leaves_exactly_the_deficit() {
+        // Short 10 from $100 with $40 margin goes bankrupt at $104
+        let margin_fp = 40 * FP;
+        let bankruptcy_fp = math::bankruptcy_price_fp(100 * FP, margin_fp, 10, false);
+        assert_eq!(bankruptcy_fp, 104 * FP);
+        assert!(!math::is_past_bankruptcy(103 * FP, bankruptcy_fp, false));
+
+        // Right at it equity is zero: no charge, no surplus, no bad debt
+        assert!(math::is_past_bankruptcy(104 * FP, bankruptcy_fp, false));
+        let at_fp = settle_liquidation(margin_fp as i128, -40 * FP as i128, 2 * FP, FP);
+        assert_eq!((at_fp.fee_fp, at_fp.remaining_margin_fp, at_fp.bad_debt_fp), (0, 0, 0));
+
+        // At $105 the user gets nothing, the fee is waived and the insurance
+        // funds cover the $10 past zero, not the fee on top
+        assert!(math::is_past_bankruptcy(105 * FP, bankruptcy_fp, false));
+        let settlement = settle_liquidation(margin_fp as i128, -50 * FP as i128, 2 * FP, FP);
+        assert_eq!(settlement, LiquidationSettlement {
+            fee_fp: 0,
+            reward_fp: 0,
+            remaining_margin_fp: 0,
+            bad_debt_fp: 10 * FP,
+        });
+
+        // A cushion that doesn't divide evenly rounds the bankruptcy price toward entry
+        assert_eq!(math::bankruptcy_price_fp(100 * FP, 10, 3, true), 100 * FP - 4);
+    }
+
+    #[test]
+    fn test_liquidation_records_lifetime_stats_like_a_close() {
+        // Two equivalent longs, 10 units from $100 with $40 margin, both with
+        // history from an earlier partial close
+        let position = UserPosition {
+            is_long: true,
+            base_size: 10,
+            entry_price_fp: 100 * FP,
+            margin_deposited: 40_000_000,
+            realized_pnl_fp: 5 * FP as i128,
+            total_fees_paid: 250_000,
+            last_updated_ts: 1_000,
+            status: PositionStatus::Open,
+            ..Default::default()
+        };
+        let pnl_fp = 10 * (97 * FP as i128 - 100 * FP as i128);
+
+        // One closed by its owner at $97 for a $2 fee
+        let mut closed = position.clone();
+        closed.settle_full_close(pnl_fp, 2_000_000, 2_000);
+
+        // The other liquidated there, charged a $1 reward and $1 protocol fee
+        // (quote and fixed point share 6 decimals)
+        let settlement = settle_liquidation(40 * FP as i128, pnl_fp, 2 * FP, FP);
+        let mut liquidated = position.clone();
+        settle_full_liquidation(&mut liquidated, pnl_fp, settlement.fee_fp as u64, 2_000);
+        assert_eq!((liquidated.is_long, liquidated.entry_price_fp), (false, 0));
+
+        for up in [&closed, &liquidated] {
+            assert_eq!(up.realized_pnl_fp, 5 * FP as i128 - 30 * FP as i128);
+            assert_eq!(up.total_fees_paid, 2_250_000);
+            assert_eq!(up.last_updated_ts, 2_000);
+            assert_eq!((up.base_size, up.margin_deposited, up.status), (0, 0, PositionStatus::Closed));
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::*;
+use crate::transfer::{transfer_checked, vault_solvency_check};
+
+/// Anyone may crank away a lapsed order: pass any of the owner's stop-loss,
+/// take-profit and limit orders in this market, each past its `expires_at`.
+/// Their rent goes back to the owner and a limit order's escrowed margin is
+/// refunded to the owner's quote account.
+pub fn close_expired_order(ctx: Context<CloseExpiredOrder>) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    let (owner, market) = (ctx.accounts.owner.key(), ctx.accounts.market.key());
+    let executor = ctx.accounts.executor.key();
+
+    // (order, active, margin to refund) for each order passed
+    let mut closed = Vec::with_capacity(3);
+    if let Some(order) = ctx.accounts.stop_loss_order.as_ref() {
+        require!(order.is_expired(now), PerpsError::OrderNotExpired);
+        closed.push((order.key(), order.is_active, 0));
+    }
+    if let Some(order) = ctx.accounts.take_profit_order.as_ref() {
+        require!(order.is_expired(now), PerpsError::OrderNotExpired);
+        closed.push((order.key(), order.is_active, 0));
+    }
+    if let Some(order) = ctx.accounts.limit_order.as_ref() {
+        require!(order.is_expired(now), PerpsError::OrderNotExpired);
+        closed.push((order.key(), order.is_active, order.margin_escrowed));
+    }
+    require!(!closed.is_empty(), PerpsError::InvalidParameters);
+
+    // Effects: resting orders give their slot back
+    for _ in closed.iter().filter(|(_, active, _)| *active) {
+        ctx.accounts.user_orders.remove_order();
+    }
+    ctx.accounts.user_orders.exit(&crate::ID)?;
+    let refund: u64 = closed.iter().map(|(_, _, margin)| margin).sum();
+    ctx.accounts.market.release_margin(refund)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+
+    // Interactions: the escrow never backed a position, so it isn't a throttled payout
+    if refund > 0 {
+        let owner_token = ctx.accounts.owner_token.as_ref().ok_or(PerpsError::InvalidTokenAccount)?;
+        let vault_token = ctx.accounts.vault_token.as_ref().ok_or(PerpsError::InvalidTokenAccount)?;
+        let quote_mint = ctx.accounts.quote_mint.as_ref().ok_or(PerpsError::InvalidTokenMint)?;
+        transfer_checked(
+            &ctx.accounts.token_program,
+            quote_mint,
+            vault_token.to_account_info(),
+            owner_token.to_account_info(),
+            ctx.accounts.config.to_account_info(),
+            &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]],
+            refund,
+        )?;
+        vault_solvency_check(&vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+    }
+
+    for (order, _, margin_refunded) in closed {
+        emit!(ExpiredOrderClosed { user: owner, market, order, margin_refunded, executor });
+    }
+    msg!("Closed expired orders of {} in {}, refunded {}", owner, market, refund);
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct CloseExpiredOrder<'info> {
+    pub executor: Signer<'info>, // Anyone can crank an expired order
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    /// Receives the rent of every order closed
+    #[account(mut)]
+    pub owner: SystemAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_SEED, owner.key().as_ref()],
+        bump = user_orders.bump,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+
+    #[account(
+        mut,
+        seeds = [STOP_LOSS_SEED, owner.key().as_ref(), market.key().as_ref()],
+        bump = stop_loss_order.bump,
+        close = owner,
+    )]
+    pub stop_loss_order: Option<Account<'info, StopLossOrder>>,
+
+    #[account(
+        mut,
+        seeds = [TAKE_PROFIT_SEED, owner.key().as_ref(), market.key().as_ref()],
+        bump = take_profit_order.bump,
+        close = owner,
+    )]
+    pub take_profit_order: Option<Account<'info, TakeProfitOrder>>,
+
+    #[account(
+        mut,
+        seeds = [LIMIT_ORDER_SEED, owner.key().as_ref(), market.key().as_ref()],
+        bump = limit_order.bump,
+        close = owner,
+    )]
+    pub limit_order: Option<Account<'info, LimitOrder>>,
+
+    /// The owner's quote account, the market's vault and the quote mint,
+    /// required to refund a limit order's escrow
+    #[account(
+        mut,
+        constraint = owner_token.owner == owner.key() @ PerpsError::InvalidTokenAccount,
+        constraint = owner_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub owner_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: Option<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
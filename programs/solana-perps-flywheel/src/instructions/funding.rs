@@ -1,19 +1,278 @@
 use anchor_lang::prelude::*;
-use crate::oracle::read_oracle_fp;
-use crate::math::current_mark_price_fp;
+use crate::errors::PerpsError;
+use crate::oracle::read_market_oracle_fp;
+use crate::math::*;
 use crate::state::*;
 
 
 pub fn settle_funding(ctx: Context<SettleFunding>) -> Result<()> {
-let now = Clock::get()?.unix_timestamp;
-let m = &mut ctx.accounts.market;
-if now - m.last_funding_ts < 3600 { return Ok(()); }
-let index_fp = read_oracle_fp(&ctx.accounts.oracle)?;
-let mark_fp = current_mark_price_fp(m, &ctx.accounts.oracle)?;
-let premium_fp = ((mark_fp as i128 - index_fp as i128) * (FP as i128)) / index_fp as i128;
-m.funding_rate_fp = premium_fp; m.last_funding_ts = now; Ok(())
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[])?;
+    let now = Clock::get()?.unix_timestamp;
+    // A second crank in the same slot is a harmless no-op
+    if now <= ctx.accounts.market.last_funding_ts { return Ok(()); }
+
+    let index_fp = read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?;
+    let mark_fp = current_mark_price_fp(&ctx.accounts.market, &ctx.accounts.oracle)?;
+    accrue_funding(&mut ctx.accounts.market, now, index_fp, mark_fp)?;
+    Ok(())
+}
+
+/// Advance the market's cumulative funding indices up to `now`.
+/// Returns `false` without touching the market when no time has elapsed.
+pub fn accrue_funding(m: &mut Market, now: i64, index_fp: u128, mark_fp: u128) -> Result<bool> {
+    let elapsed = now - m.last_funding_ts;
+    if elapsed <= 0 { return Ok(false); }
+    require!(index_fp > 0, PerpsError::InvalidPrice);
+
+    let premium_fp = ((mark_fp as i128 - index_fp as i128) * (FP as i128)) / index_fp as i128;
+
+    // Majority side pays an extra skew surcharge on top of the premium
+    let surcharge_fp = skew_surcharge_rate_fp(
+        m.total_long_size, m.total_short_size, m.skew_k_bps, m.max_funding_rate_fp,
+    );
+    // Smoothed against the last settled rate so a market hovering around
+    // balance doesn't flip the sign every crank; the first one seeds it
+    let prev_fp = m.funding_rate_seeded.then_some(m.funding_rate_fp);
+    let rate_fp = smoothed_funding_rate_fp(premium_fp + surcharge_fp, prev_fp, m.funding_ema_alpha_bps, m.max_funding_rate_fp);
+
+    // The carried remainder is owed by whichever side paid last crank
+    let carry = if rate_fp.signum() == m.funding_rate_fp.signum() { m.funding_carry } else { 0 };
+    let deltas = funding_index_deltas(rate_fp, index_fp, elapsed, m.total_long_size, m.total_short_size, carry)?;
+    m.cumulative_funding_long_fp = m.cumulative_funding_long_fp
+        .checked_add(deltas.long_fp)
+        .ok_or(PerpsError::MathOverflow)?;
+    m.cumulative_funding_short_fp = m.cumulative_funding_short_fp
+        .checked_add(deltas.short_fp)
+        .ok_or(PerpsError::MathOverflow)?;
+
+    m.funding_carry = deltas.carry;
+    m.funding_rate_fp = rate_fp;
+    m.funding_rate_seeded = true;
+    m.last_funding_ts = now;
+
+    msg!("Funding settled: rate {} (premium {}, skew surcharge {}), elapsed {}s",
+         rate_fp, premium_fp, surcharge_fp, elapsed);
+    Ok(true)
+}
+
+/// Settle `up`'s funding up to `now` and split its debt for closing
+/// `close_size` units: the closed share is taken off the position and
+/// returned for realizing, the rest stays on the remaining size. Closing all
+/// of it takes the whole debt.
+pub fn take_funding_share(up: &mut UserPosition, market: &Market, close_size: u64, now: i64) -> Result<i128> {
+    up.settle_funding(market, now)?;
+    let size = up.base_size.unsigned_abs();
+    require!(close_size <= size, PerpsError::PositionTooSmall);
+    let closed_fp = if close_size == size {
+        up.funding_debt_fp
+    } else {
+        up.funding_debt_fp
+            .checked_mul(close_size as i128)
+            .ok_or(PerpsError::MathOverflow)?
+            / size as i128
+    };
+    up.funding_debt_fp -= closed_fp;
+    Ok(closed_fp)
 }
 
 
 #[derive(Accounts)]
-pub struct SettleFunding<'info> { #[account(mut)] pub market: Account<'info, Market>, pub oracle: Account<'info, OraclePrice> }
+pub struct SettleFunding<'info> { #[account(seeds = [CONFIG_SEED], bump = config.bump)] pub config: Account<'info, Config>, #[account(mut)] pub market: Account<'info, Market>, pub oracle: Account<'info, OraclePrice> }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account_data<T: AccountSerialize>(value: &T) -> Vec<u8> {
+        let mut data = Vec::new();
+        value.try_serialize(&mut data).unwrap();
+        data
+    }
+
+    /// Run the crank handler against `market` as the program would load it
+    fn crank(market: &Market) -> Result<()> {
+        let keys = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut lamports = [1u64; 3];
+        let mut data = [
+            account_data(&Config { version: ACCOUNT_VERSION, ..Default::default() }),
+            account_data(market),
+            account_data(&OraclePrice { price_fp: 100 * FP, ..Default::default() }),
+        ];
+        let infos: Vec<AccountInfo> = keys.iter()
+            .zip(lamports.iter_mut())
+            .zip(data.iter_mut())
+            .map(|((key, lamports), data)| AccountInfo::new(key, false, true, lamports, data, &crate::ID, false, 0))
+            .collect();
+        let mut accounts = SettleFunding {
+            config: Account::try_from(&infos[0])?,
+            market: Account::try_from(&infos[1])?,
+            oracle: Account::try_from(&infos[2])?,
+        };
+        settle_funding(Context::new(&crate::ID, &mut accounts, &[], SettleFundingBumps::default()))
+    }
+
+    #[test]
+    fn test_crank_refuses_a_market_on_an_old_layout() {
+        let stale = Market { version: ACCOUNT_VERSION - 1, ..skewed_market() };
+        assert_eq!(crank(&stale).unwrap_err(), PerpsError::AccountNeedsMigration.into());
+
+        // A migrated market gets past the gate (and only stops at the clock,
+        // which unit tests don't have)
+        let current = Market { version: ACCOUNT_VERSION, ..skewed_market() };
+        assert_ne!(crank(&current).unwrap_err(), PerpsError::AccountNeedsMigration.into());
+    }
+
+    fn skewed_market() -> Market {
+        Market {
+            total_long_size: 900,
+            total_short_size: 100,
+            skew_k_bps: 100,
+            max_funding_rate_fp: DEFAULT_MAX_FUNDING_RATE_FP,
+            last_funding_ts: 1_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cranking_every_second_charges_the_same_as_one_crank() {
+        // $1 mark at 0.1%: each one-second crank owes under one index unit
+        let mut m = Market {
+            total_long_size: 100,
+            total_short_size: 100,
+            funding_rate_fp: 1_000,
+            funding_rate_seeded: true,
+            max_funding_rate_fp: DEFAULT_MAX_FUNDING_RATE_FP,
+            last_funding_ts: 1_000,
+            ..Default::default()
+        };
+        let mut once = m.clone();
+        let (index, mark) = (FP, FP + 1_000);
+
+        for t in 1..=FUNDING_INTERVAL_SECONDS {
+            assert!(accrue_funding(&mut m, 1_000 + t, index, mark).unwrap());
+        }
+        assert!(accrue_funding(&mut once, 1_000 + FUNDING_INTERVAL_SECONDS, index, mark).unwrap());
+
+        assert_eq!(m.cumulative_funding_long_fp, -1_000);
+        assert_eq!(m.cumulative_funding_long_fp, once.cumulative_funding_long_fp);
+        assert_eq!(m.cumulative_funding_short_fp, once.cumulative_funding_short_fp);
+        assert_eq!(m.funding_carry, 0);
+    }
+
+    #[test]
+    fn test_same_slot_crank_advances_indices_once() {
+        let mut m = skewed_market();
+        let price = 100 * FP;
+
+        assert!(accrue_funding(&mut m, 1_000 + FUNDING_INTERVAL_SECONDS, price, price).unwrap());
+        let (long_idx, short_idx) = (m.cumulative_funding_long_fp, m.cumulative_funding_short_fp);
+        assert!(long_idx < 0 && short_idx > 0);
+
+        // Second keeper in the same slot
+        assert!(!accrue_funding(&mut m, 1_000 + FUNDING_INTERVAL_SECONDS, price, price).unwrap());
+        assert_eq!(m.cumulative_funding_long_fp, long_idx);
+        assert_eq!(m.cumulative_funding_short_fp, short_idx);
+        assert_eq!(m.last_funding_ts, 1_000 + FUNDING_INTERVAL_SECONDS);
+    }
+
+    #[test]
+    fn test_accrual_is_continuous_between_cranks() {
+        let price = 100 * FP;
+        let mut once = skewed_market();
+        accrue_funding(&mut once, 1_000 + FUNDING_INTERVAL_SECONDS, price, price).unwrap();
+
+        let mut twice = skewed_market();
+        accrue_funding(&mut twice, 1_000 + FUNDING_INTERVAL_SECONDS / 2, price, price).unwrap();
+        accrue_funding(&mut twice, 1_000 + FUNDING_INTERVAL_SECONDS, price, price).unwrap();
+
+        assert_eq!(once.cumulative_funding_long_fp, twice.cumulative_funding_long_fp);
+        assert_eq!(once.cumulative_funding_short_fp, twice.cumulative_funding_short_fp);
+    }
+
+    #[test]
+    fn test_positions_settle_funding_from_their_index_snapshot() {
+        let price = 100 * FP;
+        let mut m = skewed_market();
+        let snapshot = |m: &Market, is_long: bool, size: i64| UserPosition {
+            is_long,
+            base_size: if is_long { size } else { -size },
+            last_cumulative_funding_fp: m.cumulative_funding_fp(is_long),
+            ..Default::default()
+        };
+        // All of the market's OI, opened before the first crank
+        let mut long = snapshot(&m, true, 900);
+        let mut short = snapshot(&m, false, 100);
+
+        accrue_funding(&mut m, 1_000 + FUNDING_INTERVAL_SECONDS, price, price).unwrap();
+        let long_paid = long.settle_funding(&m, 5_000).unwrap();
+        let short_paid = short.settle_funding(&m, 5_000).unwrap();
+
+        // The majority pays what the minority receives, and it lands in the debt
+        assert!(long_paid > 0 && short_paid < 0);
+        assert!((long_paid + short_paid).abs() <= 1_000);
+        assert_eq!(long.funding_debt_fp, long_paid);
+        assert_eq!(long.last_funding_settled, 5_000);
+
+        // Settling again without a crank in between moves nothing
+        assert_eq!(long.settle_funding(&m, 6_000).unwrap(), 0);
+        assert_eq!(long.funding_debt_fp, long_paid);
+
+        // A position opened after the crank owes nothing for it
+        let mut late = snapshot(&m, true, 900);
+        assert_eq!(late.settle_funding(&m, 6_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_runaway_funding_is_clamped_in_both_directions() {
+        let index_fp = 100 * FP;
+        let cap = DEFAULT_MAX_FUNDING_RATE_FP;
+
+        // All longs with the mark 50% over index: longs pay, but only the cap
+        let mut long_heavy = Market { total_long_size: 1_000, total_short_size: 0, ..skewed_market() };
+        accrue_funding(&mut long_heavy, 1_000 + FUNDING_INTERVAL_SECONDS, index_fp, 150 * FP).unwrap();
+        assert_eq!(long_heavy.funding_rate_fp, cap);
+
+        // The mirror image is clamped just as hard the other way
+        let mut short_heavy = Market { total_long_size: 0, total_short_size: 1_000, ..skewed_market() };
+        accrue_funding(&mut short_heavy, 1_000 + FUNDING_INTERVAL_SECONDS, index_fp, 50 * FP).unwrap();
+        assert_eq!(short_heavy.funding_rate_fp, -cap);
+
+        // A tighter cap holds the indices to it as well
+        let mut tight = Market { max_funding_rate_fp: cap / 10, ..skewed_market() };
+        accrue_funding(&mut tight, 1_000 + FUNDING_INTERVAL_SECONDS, index_fp, 150 * FP).unwrap();
+        let mut capped = skewed_market();
+        accrue_funding(&mut capped, 1_000 + FUNDING_INTERVAL_SECONDS, index_fp, 150 * FP).unwrap();
+        assert_eq!(tight.funding_rate_fp, cap / 10);
+        assert_eq!(tight.cumulative_funding_long_fp * 10, capped.cumulative_funding_long_fp);
+    }
+
+    #[test]
+    fn test_noisy_skew_produces_a_smoothed_rate_series() {
+        let price = 100 * FP;
+        // OI swinging either side of balance on every crank
+        let skews = [(900, 100), (100, 900), (900, 100), (100, 900), (900, 100), (100, 900)];
+        let settle = |alpha_bps: u16| {
+            let mut m = Market { funding_ema_alpha_bps: alpha_bps, ..skewed_market() };
+            skews.iter().enumerate().map(|(i, &(long, short))| {
+                m.total_long_size = long;
+                m.total_short_size = short;
+                accrue_funding(&mut m, 1_000 + (i as i64 + 1) * FUNDING_INTERVAL_SECONDS, price, price).unwrap();
+                m.funding_rate_fp
+            }).collect::<Vec<_>>()
+        };
+        let raw = settle(0);
+        let smoothed = settle(2_500);
+
+        // Unsmoothed, the sign flips on every crank
+        assert!(raw.windows(2).all(|w| w[0].signum() == -w[1].signum()));
+        // The first settlement has nothing to smooth from and seeds at the raw rate
+        assert_eq!(smoothed[0], raw[0]);
+        // After that each rate is a quarter of the raw one on three quarters of the last
+        for i in 1..skews.len() {
+            assert_eq!(smoothed[i], (raw[i] * 2_500 + smoothed[i - 1] * 7_500) / 10_000);
+            assert!(smoothed[i].abs() < raw[i].abs());
+        }
+        // ...so the noise no longer whipsaws the sign
+        assert!(smoothed.iter().all(|rate| rate.signum() == raw[0].signum()));
+    }
+}
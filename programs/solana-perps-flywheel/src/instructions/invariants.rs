@@ -0,0 +1,121 @@
+use anchor_lang::prelude::*;
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::*;
+
+/// Open interest summed over a set of positions
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenInterestTally {
+    pub positions: u32,
+    pub long_size: u64,
+    pub short_size: u64,
+}
+
+impl OpenInterestTally {
+    pub fn add(&mut self, position: &UserPosition) -> Result<()> {
+        let size = position.base_size.unsigned_abs();
+        if position.base_size > 0 {
+            self.long_size = self.long_size.checked_add(size).ok_or(PerpsError::MathOverflow)?;
+        } else if position.base_size < 0 {
+            self.short_size = self.short_size.checked_add(size).ok_or(PerpsError::MathOverflow)?;
+        }
+        self.positions = self.positions.checked_add(1).ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Market totals minus summed positions, (long, short)
+    pub fn discrepancy(&self, market: &Market) -> (i128, i128) {
+        (
+            market.total_long_size as i128 - self.long_size as i128,
+            market.total_short_size as i128 - self.short_size as i128,
+        )
+    }
+
+    pub fn within_tolerance(&self, market: &Market, tolerance: u64) -> bool {
+        let (long_diff, short_diff) = self.discrepancy(market);
+        long_diff.unsigned_abs() <= tolerance as u128 && short_diff.unsigned_abs() <= tolerance as u128
+    }
+}
+
+/// Read-only check that the market's OI totals match the sum of its open positions.
+/// Every position of the market must be passed in `remaining_accounts`; the result
+/// is reported through `MarketInvariantChecked` rather than by failing the transaction.
+pub fn verify_market_invariants<'info>(
+    ctx: Context<'_, '_, 'info, 'info, VerifyMarketInvariants<'info>>,
+    tolerance: u64,
+) -> Result<()> {
+    let market = &ctx.accounts.market;
+    let market_key = market.key();
+
+    let mut seen: Vec<Pubkey> = Vec::with_capacity(ctx.remaining_accounts.len());
+    let mut tally = OpenInterestTally::default();
+    for info in ctx.remaining_accounts.iter() {
+        require!(!seen.contains(info.key), PerpsError::DuplicatePositionAccount);
+        seen.push(*info.key);
+
+        let position: Account<UserPosition> = Account::try_from(info)?;
+        require_keys_eq!(position.market, market_key, PerpsError::PositionMarketMismatch);
+        tally.add(&position)?;
+    }
+
+    let (long_discrepancy, short_discrepancy) = tally.discrepancy(market);
+    let within_tolerance = tally.within_tolerance(market, tolerance);
+    if !within_tolerance {
+        msg!("Market OI invariant violated: long diff {}, short diff {}", long_discrepancy, short_discrepancy);
+    }
+
+    emit!(MarketInvariantChecked {
+        market: market_key,
+        positions_checked: tally.positions,
+        market_long_size: market.total_long_size,
+        market_short_size: market.total_short_size,
+        summed_long_size: tally.long_size,
+        summed_short_size: tally.short_size,
+        long_discrepancy,
+        short_discrepancy,
+        within_tolerance,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct VerifyMarketInvariants<'info> {
+    pub market: Account<'info, Market>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(base_size: i64) -> UserPosition {
+        UserPosition { base_size, is_long: base_size > 0, ..Default::default() }
+    }
+
+    #[test]
+    fn test_tally_matches_market_totals() {
+        let market = Market { total_long_size: 300, total_short_size: 50, ..Default::default() };
+        let mut tally = OpenInterestTally::default();
+        for size in [100, 200, -50, 0] {
+            tally.add(&position(size)).unwrap();
+        }
+
+        assert_eq!(tally, OpenInterestTally { positions: 4, long_size: 300, short_size: 50 });
+        assert_eq!(tally.discrepancy(&market), (0, 0));
+        assert!(tally.within_tolerance(&market, 0));
+    }
+
+    #[test]
+    fn test_desynced_open_interest_is_reported() {
+        // A close that forgot to decrement the long total leaves 100 of phantom OI,
+        // and a short side that was saturated to zero under-reports by 40
+        let market = Market { total_long_size: 400, total_short_size: 0, ..Default::default() };
+        let mut tally = OpenInterestTally::default();
+        for size in [100, 200, -40] {
+            tally.add(&position(size)).unwrap();
+        }
+
+        assert_eq!(tally.discrepancy(&market), (100, -40));
+        assert!(!tally.within_tolerance(&market, 50));
+        assert!(tally.within_tolerance(&market, 100));
+    }
+}
@@ -0,0 +1,545 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::*;
+use crate::math::*;
+use crate::instructions::trade::{size_entry, take_position_slots, write_open_position};
+use crate::transfer::{transfer_checked, transfer_checked_exact, vault_solvency_check};
+
+// Resting entry orders. The margin is escrowed in the market's vault when the
+// order is placed and a keeper opens the position once the mark crosses the limit.
+
+/// Rest an order to open at `limit_price_fp` or better, escrowing its margin
+pub fn place_limit_order(
+    ctx: Context<PlaceLimitOrder>,
+    is_long: bool,
+    limit_price_fp: u128,
+    quote_to_spend: u64,
+    leverage_x: u16,
+    expires_at: Option<i64>,
+) -> Result<()> {
+    let cfg = &ctx.accounts.config;
+    validate_leverage(leverage_x)?;
+    ensure_current_versions(cfg, &ctx.accounts.market, &[])?;
+
+    require!(!cfg.paused, PerpsError::ProtocolPaused);
+    require!(!ctx.accounts.market.is_paused, PerpsError::MarketPaused);
+    require!(leverage_x <= ctx.accounts.market.taker_leverage_cap_x, PerpsError::LeverageTooHigh);
+    require!(limit_price_fp > 0, PerpsError::InvalidPrice);
+    let now = Clock::get()?.unix_timestamp;
+    require!(!ctx.accounts.market.is_close_only(now), PerpsError::MarketCloseOnly);
+    require!(!ctx.accounts.limit_order.is_active, PerpsError::LimitOrderActive);
+    validate_order_expiry(expires_at, now)?;
+
+    // Escrow exactly what open_position would lock as margin
+    let entry = entry_margin(quote_to_spend, leverage_x)?;
+    require!(entry.margin > 0, PerpsError::InsufficientMargin);
+
+    let owner = ctx.accounts.user.key();
+    let orders = &mut ctx.accounts.user_orders;
+    orders.owner = owner;
+    orders.bump = ctx.bumps.user_orders;
+    orders.add_order(cfg.max_active_orders_per_user)?;
+
+    // The fill writes into these, and the keeper filling it doesn't pay their rent
+    let user_account = &mut ctx.accounts.user_account;
+    if user_account.owner == Pubkey::default() {
+        user_account.owner = owner;
+        user_account.bump = ctx.bumps.user_account;
+    }
+
+    let market_key = ctx.accounts.market.key();
+    let order = &mut ctx.accounts.limit_order;
+    order.owner = owner;
+    order.market = market_key;
+    order.is_long = is_long;
+    order.limit_price_fp = limit_price_fp;
+    order.quote_to_spend = quote_to_spend;
+    order.leverage_x = leverage_x;
+    order.margin_escrowed = entry.margin;
+    order.is_active = true;
+    order.created_at = now;
+    order.expires_at = expires_at;
+    order.bump = ctx.bumps.limit_order;
+    ctx.accounts.market.lock_margin(entry.margin)?;
+
+    transfer_checked_exact(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.user_token.to_account_info(),
+        ctx.accounts.vault_token.to_account_info(),
+        ctx.accounts.user.to_account_info(),
+        &[],
+        entry.margin,
+    )?;
+
+    emit!(LimitOrderPlaced {
+        user: owner,
+        market: market_key,
+        is_long,
+        limit_price_fp,
+        quote_to_spend,
+        leverage: leverage_x,
+        margin_escrowed: entry.margin,
+    });
+
+    Ok(())
+}
+
+/// Anyone may fill a limit order once the mark crosses its limit. The position
+/// opens at the mark, exactly as `open_position` would, on the escrowed margin.
+pub fn fill_limit_order<'info>(ctx: Context<'_, '_, 'info, 'info, FillLimitOrder<'info>>) -> Result<()> {
+    let cfg = &ctx.accounts.config;
+    ensure_current_versions(cfg, &ctx.accounts.market, &[])?;
+    require!(ctx.accounts.limit_order.is_active, PerpsError::OrderNotActive);
+    require!(!cfg.paused, PerpsError::ProtocolPaused);
+    require!(!ctx.accounts.market.is_paused, PerpsError::MarketPaused);
+    let now = Clock::get()?.unix_timestamp;
+    require!(!ctx.accounts.limit_order.is_expired(now), PerpsError::OrderExpired);
+    require!(!ctx.accounts.market.is_close_only(now), PerpsError::MarketCloseOnly);
+    ctx.accounts.user_position.ensure_status(&[PositionStatus::Closed, PositionStatus::PendingSettlement])?;
+
+    let price_fp = checked_mark_price_fp(
+        &mut ctx.accounts.market,
+        &ctx.accounts.oracle,
+        ctx.accounts.pyth_oracle.as_deref(),
+        ctx.accounts.switchboard_oracle.as_deref(),
+        ctx.remaining_accounts,
+    )?;
+    let order = &ctx.accounts.limit_order;
+    require!(order.is_fillable(price_fp), PerpsError::LimitPriceNotReached);
+    let (owner, is_long, limit_price_fp, leverage_x, escrowed) =
+        (order.owner, order.is_long, order.limit_price_fp, order.leverage_x, order.margin_escrowed);
+
+    let market_key = ctx.accounts.market.key();
+    take_position_slots(&mut ctx.accounts.user_account, &mut ctx.accounts.protocol_stats, cfg, market_key)?;
+
+    // A fill that deepens the skew pays the same surcharge as an open. The
+    // owner isn't here to sign for it, so it comes out of the escrow and the
+    // position is sized from what is left at the requested leverage.
+    let gross_notional = escrowed.checked_mul(leverage_x as u64).ok_or(PerpsError::MathOverflow)?;
+    let gross_base = (cfg.quote_to_fp(gross_notional)? / price_fp) as u64;
+    let skew_surcharge_bps = open_skew_surcharge_bps(
+        ctx.accounts.market.total_long_size,
+        ctx.accounts.market.total_short_size,
+        gross_base,
+        is_long,
+        ctx.accounts.market.skew_k_bps,
+        ctx.accounts.market.max_skew_surcharge_bps,
+    );
+    let skew_surcharge = (gross_notional as u128 * skew_surcharge_bps as u128 / 10_000) as u64;
+    // A resting order adds liquidity, so it pays the maker rate: a fee out of
+    // the escrow, or a rebate from the fee pool onto the margin when negative.
+    // Only a pool the program controls can pay a rebate, and never more than it holds.
+    let maker_fee = cfg.entry_fee(gross_notional, true);
+    let fee_charged = maker_fee.max(0) as u64;
+    let rebate = if maker_fee < 0 {
+        let pool = ctx.accounts.fee_destination.as_ref().ok_or(PerpsError::FeeDestinationRequired)?;
+        if pool.owner == cfg.key() { maker_fee.unsigned_abs().min(pool.amount) } else { 0 }
+    } else {
+        0
+    };
+    let deductions = skew_surcharge.checked_add(fee_charged).ok_or(PerpsError::MathOverflow)?;
+    require!(deductions < escrowed, PerpsError::InsufficientMargin);
+    let margin = escrowed - deductions;
+    let mut entry = EntryMargin {
+        margin,
+        notional: margin.checked_mul(leverage_x as u64).ok_or(PerpsError::MathOverflow)?,
+        remainder: 0,
+    };
+    let base_size_units = size_entry(cfg, &ctx.accounts.market, &entry, price_fp)?;
+    // The rebate backs the position without growing it
+    entry.margin += rebate;
+    let margin = entry.margin;
+
+    // The escrow is already in the vault; the surcharge is about to leave it
+    let insurance_liquidity = match (ctx.accounts.insurance_fund.as_ref(), ctx.accounts.insurance_vault_token.as_ref()) {
+        (Some(fund), Some(vault)) if vault.key() == fund.vault_token_account => vault.amount,
+        _ => 0,
+    };
+    ensure_payout_backed(
+        margin,
+        entry.notional,
+        ctx.accounts.market.max_favorable_move_bps,
+        ctx.accounts.vault_token.amount
+            .saturating_sub(deductions)
+            .saturating_add(rebate)
+            .saturating_add(insurance_liquidity),
+    )?;
+    if skew_surcharge > 0 {
+        let fund = ctx.accounts.insurance_fund.as_mut().ok_or(PerpsError::InsuranceFundRequired)?;
+        let fund_vault = ctx.accounts.insurance_vault_token.as_ref().ok_or(PerpsError::InsuranceFundRequired)?;
+        require_keys_eq!(fund_vault.key(), fund.vault_token_account, PerpsError::InvalidTokenAccount);
+        fund.record_deposit(skew_surcharge)?;
+        fund.exit(&crate::ID)?;
+    }
+
+    // Effects: the escrow becomes the position's margin
+    write_open_position(
+        &ctx.accounts.config,
+        &mut ctx.accounts.market,
+        market_key,
+        &mut ctx.accounts.user_position,
+        owner,
+        ctx.bumps.user_position,
+        is_long,
+        base_size_units,
+        &entry,
+        price_fp,
+        now,
+    )?;
+    ctx.accounts.market.rebook_margin(escrowed, margin)?;
+    ctx.accounts.protocol_stats.record_volume(entry.notional, fee_charged)?;
+    let order = &mut ctx.accounts.limit_order;
+    order.is_active = false;
+    order.margin_escrowed = 0;
+    ctx.accounts.user_orders.remove_order();
+
+    ctx.accounts.limit_order.exit(&crate::ID)?;
+    ctx.accounts.user_orders.exit(&crate::ID)?;
+    ctx.accounts.user_position.exit(&crate::ID)?;
+    ctx.accounts.user_account.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+
+    // Interactions
+    if skew_surcharge > 0 {
+        let fund_vault = ctx.accounts.insurance_vault_token.as_ref().ok_or(PerpsError::InsuranceFundRequired)?;
+        let signer_seeds: &[&[&[u8]]] = &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]];
+        transfer_checked_exact(
+            &ctx.accounts.token_program,
+            &ctx.accounts.quote_mint,
+            ctx.accounts.vault_token.to_account_info(),
+            fund_vault.to_account_info(),
+            ctx.accounts.config.to_account_info(),
+            signer_seeds,
+            skew_surcharge,
+        )?;
+    }
+    if fee_charged > 0 || rebate > 0 {
+        let fee_destination = ctx.accounts.fee_destination.as_ref().ok_or(PerpsError::FeeDestinationRequired)?;
+        let vault = ctx.accounts.vault_token.to_account_info();
+        let authority = ctx.accounts.config.to_account_info();
+        let signer_seeds: &[&[&[u8]]] = &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]];
+        // A rebate lands in the vault as margin, so it must arrive whole
+        if fee_charged > 0 {
+            transfer_checked(&ctx.accounts.token_program, &ctx.accounts.quote_mint, vault, fee_destination.to_account_info(), authority, signer_seeds, fee_charged)?;
+        } else {
+            transfer_checked_exact(&ctx.accounts.token_program, &ctx.accounts.quote_mint, fee_destination.to_account_info(), vault, authority, signer_seeds, rebate)?;
+        }
+    }
+    vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+
+    emit!(LimitOrderFilled {
+        user: owner,
+        market: market_key,
+        is_long,
+        limit_price_fp,
+        fill_price_fp: price_fp,
+        base_size: base_size_units,
+        margin_deposited: margin,
+        skew_surcharge,
+        maker_fee: if rebate > 0 { -(rebate as i64) } else { fee_charged as i64 },
+        executor: ctx.accounts.executor.key(),
+    });
+    emit!(ctx.accounts.protocol_stats.updated_event());
+
+    msg!("Limit order filled: {} {} units @ ${} with {}x leverage",
+         if is_long { "Long" } else { "Short" },
+         base_size_units,
+         ctx.accounts.config.to_human_price(price_fp as i128),
+         leverage_x
+    );
+
+    Ok(())
+}
+
+/// Cancel a resting limit order, refunding its escrow and returning the rent
+pub fn cancel_limit_order(ctx: Context<CancelLimitOrder>) -> Result<()> {
+    let order = &ctx.accounts.limit_order;
+    require!(order.is_active, PerpsError::OrderNotActive);
+    let refund = order.margin_escrowed;
+    ctx.accounts.user_orders.remove_order();
+    ctx.accounts.user_orders.exit(&crate::ID)?;
+    ctx.accounts.market.release_margin(refund)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+
+    // The escrow never backed a position, so it isn't a throttled payout
+    if refund > 0 {
+        let signer_seeds: &[&[&[u8]]] = &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]];
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.quote_mint,
+            ctx.accounts.vault_token.to_account_info(),
+            ctx.accounts.user_token.to_account_info(),
+            ctx.accounts.config.to_account_info(),
+            signer_seeds,
+            refund,
+        )?;
+        vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+    }
+
+    emit!(LimitOrderCancelled {
+        user: ctx.accounts.user.key(),
+        market: ctx.accounts.market.key(),
+        margin_refunded: refund,
+    });
+
+    Ok(())
+}
+
+// Context structures
+
+#[derive(Accounts)]
+pub struct PlaceLimitOrder<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = LimitOrder::SPACE,
+        seeds = [LIMIT_ORDER_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub limit_order: Account<'info, LimitOrder>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserOrders::SPACE,
+        seeds = [USER_ORDERS_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+
+    /// Created here so the fill has a position to write into
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserPosition::SPACE,
+        seeds = [POSITION_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserAccount::SPACE,
+        seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_account: Box<Account<'info, UserAccount>>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct FillLimitOrder<'info> {
+    pub executor: Signer<'info>, // Anyone can crank a crossed limit order
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    pub oracle: Account<'info, OraclePrice>,
+
+    /// CHECK: must match market.pyth_oracle when the market has one; parsed in parse_pyth_price.
+    /// Registered fallback feeds may follow in remaining_accounts.
+    pub pyth_oracle: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: must match market.switchboard_oracle when the market has one; parsed in parse_switchboard_price.
+    pub switchboard_oracle: Option<UncheckedAccount<'info>>,
+
+    #[account(
+        mut,
+        seeds = [LIMIT_ORDER_SEED, limit_order.owner.as_ref(), market.key().as_ref()],
+        bump = limit_order.bump,
+    )]
+    pub limit_order: Account<'info, LimitOrder>,
+
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_SEED, limit_order.owner.as_ref()],
+        bump = user_orders.bump,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+
+    #[account(
+        mut,
+        seeds = [POSITION_SEED, limit_order.owner.as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, limit_order.owner.as_ref()],
+        bump = user_account.bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// This market's insurance fund, required when the fill pays a skew surcharge
+    #[account(
+        mut,
+        seeds = [INSURANCE_FUND_SEED, market.key().as_ref()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Option<Box<Account<'info, InsuranceFund>>>,
+
+    #[account(mut)]
+    pub insurance_vault_token: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    /// The configured fee account, required when the fill pays a maker fee or rebate
+    #[account(mut, address = config.fee_destination @ PerpsError::InvalidTokenAccount)]
+    pub fee_destination: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CancelLimitOrder<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [LIMIT_ORDER_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump = limit_order.bump,
+        close = user,
+    )]
+    pub limit_order: Account<'info, LimitOrder>,
+
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_SEED, user.key().as_ref()],
+        bump = user_orders.bump,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_orders_fill_only_through_their_price() {
+        let long = LimitOrder { is_long: true, limit_price_fp: 95 * FP, ..Default::default() };
+        assert!(!long.is_fillable(100 * FP));
+        assert!(long.is_fillable(95 * FP));
+        assert!(long.is_fillable(90 * FP));
+
+        let short = LimitOrder { is_long: false, limit_price_fp: 105 * FP, ..Default::default() };
+        assert!(!short.is_fillable(100 * FP));
+        assert!(short.is_fillable(105 * FP));
+        assert!(short.is_fillable(110 * FP));
+    }
+
+    #[test]
+    fn test_fill_opens_on_the_escrowed_margin() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        let mut market = Market { max_position_base: 1_000, taker_leverage_cap_x: 10, maintenance_margin_bps: 500, ..Default::default() };
+
+        // $1000 at 5x escrows $200, the same margin an open would lock
+        let entry = entry_margin(1_000_000_000, 5).unwrap();
+        let order = LimitOrder { is_long: true, limit_price_fp: 95 * FP, margin_escrowed: entry.margin, leverage_x: 5, ..Default::default() };
+        assert_eq!(order.margin_escrowed, 200_000_000);
+
+        // Filled below the limit: $1000 buys 10 whole units at $95
+        let mark_fp = 94 * FP;
+        assert!(order.is_fillable(mark_fp));
+        let base_size = size_entry(&cfg, &market, &entry, mark_fp).unwrap();
+        assert_eq!(base_size, 10);
+
+        let mut up = UserPosition::default();
+        write_open_position(&cfg, &mut market, Pubkey::default(), &mut up, Pubkey::default(), 255, true, base_size, &entry, mark_fp, 1_000).unwrap();
+        assert_eq!(up.status, PositionStatus::Open);
+        assert_eq!((up.base_size, up.entry_price_fp, up.margin_deposited), (10, mark_fp, 200_000_000));
+        assert_eq!(up.version, ACCOUNT_VERSION);
+        assert!(up.liquidation_price_fp < mark_fp);
+        assert_eq!(market.total_long_size, 10);
+    }
+}
@@ -1,14 +1,25 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
 use crate::events::*;
 use crate::state::*;
-use crate::math::current_mark_price_fp;
+use crate::errors::PerpsError;
+use crate::math::{close_settlement, risk_mark_price_fp, CloseSettlement};
+use crate::instructions::cross_margin::{load_cross_legs, settle_cross_balance};
+use crate::instructions::collateral::load_pledge;
+use crate::instructions::enhanced_liquidation::route_fee_to_insurance;
+use crate::transfer::{transfer_checked, vault_solvency_check};
 
 
-pub fn liquidate(ctx: Context<Liquidate>) -> Result<()> {
+/// Cross positions are judged on the whole account: every other open cross
+/// position of the owner goes in `remaining_accounts` as (position, market, oracle)
+pub fn liquidate<'info>(ctx: Context<'_, '_, 'info, 'info, Liquidate<'info>>) -> Result<()> {
+ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+let now = Clock::get()?.unix_timestamp;
+ctx.accounts.market.settle_maintenance_margin(now);
+let config_key = ctx.accounts.config.key();
 let m = &ctx.accounts.market; 
-let cfg = &ctx.accounts.config;
-let mark_fp = current_mark_price_fp(m, &ctx.accounts.oracle)?;
+let cfg = &mut ctx.accounts.config;
+let mark_fp = m.settlement_mark_fp(risk_mark_price_fp(m, &ctx.accounts.oracle, ctx.accounts.oracle_twap.as_deref())?);
 
 // Read values from user_position first, before borrowing mutably
 let base_size = ctx.accounts.user_position.base_size;
@@ -16,43 +27,123 @@ let entry_price_fp = ctx.accounts.user_position.entry_price_fp;
 let margin_deposited = ctx.accounts.user_position.margin_deposited;
 let user_owner = ctx.accounts.user_position.owner;
 let user_market = ctx.accounts.user_position.market;
+ctx.accounts.user_position.ensure_status(&[PositionStatus::Open, PositionStatus::Liquidating])?;
 
-let notional_fp = (base_size.abs() as u128) * mark_fp / FP;
-let entry_fp = entry_price_fp as u128;
+let notional_fp = (base_size.unsigned_abs() as u128) * mark_fp;
+let entry_fp = entry_price_fp;
 let pnl_fp = if base_size >= 0 {
-    (mark_fp as i128 - entry_fp as i128) * (base_size as i128) / FP as i128
+    (mark_fp as i128 - entry_fp as i128) * (base_size as i128)
 } else {
-    (entry_fp as i128 - mark_fp as i128) * (-(base_size as i128)) / FP as i128
+    (entry_fp as i128 - mark_fp as i128) * (-(base_size as i128))
+};
+let (equity_fp, mm_req_fp, pool_before) = match ctx.accounts.user_position.margin_mode {
+    MarginMode::Isolated => (cfg.quote_to_fp(margin_deposited)? as i128 + pnl_fp, (notional_fp * (m.maintenance_margin_bps_for(base_size.unsigned_abs(), now) as u128)) / 10_000u128, None),
+    MarginMode::Cross => {
+        require!(ctx.accounts.cross_vault.is_some(), PerpsError::CrossMarginAccountRequired);
+        let pool = ctx.accounts.cross_margin_account.as_ref().ok_or(PerpsError::CrossMarginAccountRequired)?;
+        let up = &ctx.accounts.user_position;
+        let mut legs = load_cross_legs(ctx.remaining_accounts, user_owner, Some(up.key()), pool.open_positions.saturating_sub(1))?;
+        legs.push(CrossLeg::new(up, mark_fp, m.maintenance_margin_bps_for(base_size.unsigned_abs(), now)));
+        (pool.equity_fp(cfg, &legs)?, CrossMarginAccount::maintenance_required_fp(&legs), Some(pool.collateral_balance))
+    }
 };
-let equity_fp = (margin_deposited as i128) * FP as i128 + pnl_fp;
-let mm_req_fp = (notional_fp as u128 * (m.maintenance_margin_bps as u128)) / 10_000u128;
 
-if equity_fp < mm_req_fp as i128 {
-    let liq_fee = ((notional_fp as u128) * (cfg.liq_fee_bps as u128) / 10_000) as u64;
-    let seize = margin_deposited.min(liq_fee);
+let liquidator = ctx.accounts.liquidator.key();
+let protected = m.liquidation_protected(ctx.accounts.user_position.opened_at_ts, now, equity_fp);
+if equity_fp < mm_req_fp as i128 && !protected {
+    // Liquidation fee comes out of what equity is left; the trader gets the
+    // rest, or for a cross position it stays in the pool
+    let settlement = close_settlement(cfg.quote_to_fp(pool_before.unwrap_or(margin_deposited))?, pnl_fp, notional_fp, cfg.liq_fee_bps);
+    let payout = cfg.settle_to_quote(settlement.payout_fp)?;
+    let fee = cfg.settle_to_quote(settlement.fee_fp)?;
+    // Pledged margin goes back in kind; the tokens the loss took stay with the protocol
+    let pledge = if ctx.accounts.user_position.collateral_pledged {
+        let mut pledge = load_pledge(&config_key, &user_owner, ctx.accounts.accepted_collateral.as_mut(), ctx.accounts.position_collateral.as_mut(), ctx.accounts.collateral_vault.as_ref(), ctx.accounts.collateral_user_token.as_ref(), ctx.accounts.collateral_mint.as_deref())?;
+        let split = pledge.settle(margin_deposited, payout)?;
+        pledge.exit()?;
+        Some((pledge, split))
+    } else { None };
+    let payout = pledge.as_ref().map_or(payout, |(_, split)| split.quote);
+    let (remaining, seize) = cfg.cushion_payout(if pool_before.is_some() { 0 } else { payout }, fee, ctx.accounts.vault_token.amount)?;
     
-    // Do token transfers before mutating user_position
-    token::transfer(ctx.accounts.transfer_vault_to_fee_dest(), seize)?;
-    let remaining = margin_deposited.saturating_sub(seize);
+    // Settle the position and market before any transfer
+    let up = &mut ctx.accounts.user_position;
+    let event = full_liquidation_event(liquidator, up, mark_fp, pnl_fp, &settlement, remaining, now);
+    let (is_long, vault_margin) = (up.is_long, up.vault_margin());
+    up.settle_full_close(pnl_fp, seize, now);
+    up.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.record_close();
+    ctx.accounts.protocol_stats.record_liquidation(seize)?;
+    ctx.accounts.user_account.record_close();
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+    ctx.accounts.user_account.exit(&crate::ID)?;
+    if let (Some(pool), true) = (ctx.accounts.cross_margin_account.as_mut(), pool_before.is_some()) {
+        pool.release(margin_deposited, payout);
+        pool.exit(&crate::ID)?;
+    }
+    let market = &mut ctx.accounts.market;
+    market.reduce_open_interest(is_long, base_size.unsigned_abs());
+    market.release_margin(vault_margin)?;
+    market.record_settlement(pnl_fp, settlement.fee_fp)?;
+    market.exit(&crate::ID)?;
+    ctx.accounts.config.exit(&crate::ID)?;
+
+    let config_bump = ctx.accounts.config.bump;
+    let signer_seeds: &[&[&[u8]]] = &[&[CONFIG_SEED, &[config_bump]]];
+    if let Some((pledge, split)) = pledge {
+        pledge.push(&ctx.accounts.token_program, &ctx.accounts.config, split.returned)?;
+        emit!(PledgedCollateralReleased { user: user_owner, market: user_market, mint: pledge.accepted.mint, returned: split.returned, seized: split.seized, remaining: pledge.position_collateral.amount });
+    }
+    if let (Some(balance_before), Some(cross_vault)) = (pool_before, ctx.accounts.cross_vault.as_ref()) {
+        settle_cross_balance(&ctx.accounts.config, &ctx.accounts.token_program, &ctx.accounts.quote_mint, &ctx.accounts.vault_token, cross_vault, balance_before, payout)?;
+    }
+    // The market's insurance fund takes its slice of the fee while under target
+    let insurance_fee = route_fee_to_insurance(&ctx.accounts.token_program, &ctx.accounts.quote_mint, &ctx.accounts.config, &ctx.accounts.vault_token, &mut ctx.accounts.insurance_fund, &ctx.accounts.insurance_vault_token, liquidator, seize)?;
+    if seize > insurance_fee {
+        ctx.accounts.transfer_vault_to_fee_dest(signer_seeds, seize - insurance_fee)?;
+    }
     if remaining > 0 { 
-        token::transfer(ctx.accounts.transfer_vault_to_user(), remaining)?; 
+        ctx.accounts.transfer_vault_to_user(signer_seeds, remaining)?; 
     }
+    vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
     
-    // Now we can safely mutate user_position
-    let up = &mut ctx.accounts.user_position;
-    up.base_size = 0; 
-    up.margin_deposited = 0;
-    emit!(Liquidated { 
+    emit!(event);
+    #[allow(deprecated)]
+    let legacy_event = Liquidated { 
         user: user_owner, 
         market: user_market, 
         seized_collateral: seize,
-        liquidator: ctx.accounts.liquidator.key(),
+        liquidator,
         liquidation_price_fp: mark_fp,
-    });
+    };
+    emit!(legacy_event);
+    emit!(ctx.accounts.protocol_stats.updated_event());
 }
 Ok(())
 }
 
+/// The `LiquidationExecuted` for the basic path, which always takes the whole
+/// position and pays the liquidation fee to the fee destination instead of a reward
+pub(crate) fn full_liquidation_event(liquidator: Pubkey, up: &UserPosition, mark_fp: u128, pnl_fp: i128, settlement: &CloseSettlement, returned_to_user: u64, now: i64) -> LiquidationExecuted {
+LiquidationExecuted {
+    liquidator,
+    liquidated_user: up.owner,
+    market: up.market,
+    liquidation_size: up.base_size.unsigned_abs(),
+    remaining_size: 0,
+    liquidation_price_fp: mark_fp,
+    pnl_fp,
+    fees_fp: settlement.fee_fp,
+    liquidator_reward: 0,
+    insurance_fund_contribution: 0,
+    returned_to_user,
+    requested_percentage: 100,
+    enforced_percentage: 100,
+    liquidatable_since_ts: match up.liquidatable_since_ts { 0 => now, since => since },
+    bad_debt_fp: settlement.shortfall_fp,
+}
+}
+
 
 #[derive(Accounts)]
 pub struct Liquidate<'info> {
@@ -60,18 +151,128 @@ pub struct Liquidate<'info> {
 #[account(mut)] pub config: Account<'info, Config>,
 #[account(mut)] pub market: Account<'info, Market>,
 pub oracle: Account<'info, OraclePrice>,
+/// The oracle's TWAP ring, required when the market prices risk at a TWAP
+#[account(seeds = [ORACLE_TWAP_SEED, oracle.key().as_ref()], bump = oracle_twap.bump)] pub oracle_twap: Option<Account<'info, OracleTwap>>,
 #[account(mut, seeds=[b"pos", user_position.owner.as_ref(), market.key().as_ref()], bump)] pub user_position: Account<'info, UserPosition>,
-#[account(mut)] pub user_token: Account<'info, TokenAccount>,
-#[account(mut)] pub vault_token: Account<'info, TokenAccount>,
-/// CHECK
-#[account(mut)] pub fee_destination: AccountInfo<'info>,
-pub token_program: Program<'info, Token>,
+#[account(mut, seeds = [PROTOCOL_STATS_SEED], bump = protocol_stats.bump)] pub protocol_stats: Account<'info, ProtocolStats>,
+#[account(mut, seeds = [USER_ACCOUNT_SEED, user_position.owner.as_ref()], bump = user_account.bump)] pub user_account: Account<'info, UserAccount>,
+#[account(mut, constraint = user_token.owner == user_position.owner @ PerpsError::InvalidTokenAccount, constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint)] pub user_token: InterfaceAccount<'info, TokenAccount>,
+/// Pooled collateral and its vault, required to liquidate a cross-margin position
+#[account(mut, seeds = [CROSS_MARGIN_SEED, user_position.owner.as_ref()], bump = cross_margin_account.bump)] pub cross_margin_account: Option<Account<'info, CrossMarginAccount>>,
+#[account(mut, seeds = [CROSS_VAULT_SEED], bump)] pub cross_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+/// The pledge, its mint and vault and the owner's tokens of it, required to liquidate a position margined with pledged collateral
+#[account(mut, seeds = [ACCEPTED_COLLATERAL_SEED, accepted_collateral.mint.as_ref()], bump = accepted_collateral.bump)] pub accepted_collateral: Option<Account<'info, AcceptedCollateral>>,
+#[account(mut, seeds = [POSITION_COLLATERAL_SEED, user_position.key().as_ref()], bump = position_collateral.bump)] pub position_collateral: Option<Account<'info, PositionCollateral>>,
+#[account(mut, seeds = [COLLATERAL_VAULT_SEED, collateral_vault.mint.as_ref()], bump)] pub collateral_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+#[account(mut)] pub collateral_user_token: Option<InterfaceAccount<'info, TokenAccount>>,
+pub collateral_mint: Option<Box<InterfaceAccount<'info, Mint>>>,
+#[account(mut, seeds = [VAULT_SEED, market.key().as_ref()], bump = market.vault_bump, constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount)] pub vault_token: InterfaceAccount<'info, TokenAccount>,
+/// CHECK: must be the configured fee account
+#[account(mut, address = config.fee_destination @ PerpsError::InvalidTokenAccount)] pub fee_destination: AccountInfo<'info>,
+/// This market's insurance fund and its vault, to take the fund's slice of the fee
+#[account(mut, seeds = [INSURANCE_FUND_SEED, market.key().as_ref()], bump = insurance_fund.bump)] pub insurance_fund: Account<'info, InsuranceFund>,
+#[account(mut, address = insurance_fund.vault_token_account @ PerpsError::InvalidTokenAccount)] pub insurance_vault_token: InterfaceAccount<'info, TokenAccount>,
+#[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)] pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+pub token_program: Interface<'info, TokenInterface>,
 }
 impl<'info> Liquidate<'info> {
-pub fn transfer_vault_to_user(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-CpiContext::new(self.token_program.to_account_info(), Transfer { from: self.vault_token.to_account_info(), to: self.user_token.to_account_info(), authority: self.config.to_account_info() })
+pub fn transfer_vault_to_user(&self, signer_seeds: &[&[&[u8]]], amount: u64) -> Result<u64> {
+transfer_checked(&self.token_program, &self.quote_mint, self.vault_token.to_account_info(), self.user_token.to_account_info(), self.config.to_account_info(), signer_seeds, amount)
+}
+pub fn transfer_vault_to_fee_dest(&self, signer_seeds: &[&[&[u8]]], amount: u64) -> Result<u64> {
+transfer_checked(&self.token_program, &self.quote_mint, self.vault_token.to_account_info(), self.fee_destination.clone(), self.config.to_account_info(), signer_seeds, amount)
 }
-pub fn transfer_vault_to_fee_dest(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-CpiContext::new(self.token_program.to_account_info(), Transfer { from: self.vault_token.to_account_info(), to: self.fee_destination.to_account_info(), authority: self.config.to_account_info() })
+}
+
+#[cfg(test)]
+mod tests {
+use super::*;
+use crate::test_runtime::{run_at, signer, token_balance, Book, Transfer};
+use std::{cell::RefCell, rc::Rc};
+
+#[test]
+fn test_basic_liquidation_reports_a_full_liquidation_executed() {
+    let (liquidator, owner, market) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+    let up = UserPosition { owner, market, is_long: false, base_size: -7, entry_price_fp: 100 * FP, margin_deposited: 50, ..Default::default() };
+    let settlement = CloseSettlement { fee_fp: FP, payout_fp: 3 * FP, shortfall_fp: 0 };
+    let event = full_liquidation_event(liquidator, &up, 110 * FP, -70 * FP as i128, &settlement, 3, 1_000);
+
+    // Same shape enhanced_liquidate emits for a 100% liquidation
+    assert_eq!((event.liquidator, event.liquidated_user, event.market), (liquidator, owner, market));
+    assert_eq!((event.liquidation_size, event.remaining_size), (7, 0));
+    assert_eq!((event.requested_percentage, event.enforced_percentage), (100, 100));
+    assert_eq!((event.pnl_fp, event.fees_fp, event.returned_to_user), (-70 * FP as i128, FP, 3));
+    assert_eq!(event.liquidation_price_fp, 110 * FP);
+    assert_eq!(event.bad_debt_fp, 0);
+
+    // Unflagged positions became liquidatable at the liquidation itself
+    assert_eq!(event.liquidatable_since_ts, 1_000);
+    let flagged = UserPosition { liquidatable_since_ts: 900, ..up };
+    assert_eq!(full_liquidation_event(liquidator, &flagged, 110 * FP, 0, &settlement, 0, 1_000).liquidatable_since_ts, 900);
+}
+
+fn liquidate_book(book: Book) -> Result<()> {
+    let mut accounts = Liquidate {
+        liquidator: Signer::try_from(signer())?,
+        config: Account::try_from(book.config)?,
+        market: Account::try_from(book.market)?,
+        oracle: Account::try_from(book.oracle)?,
+        oracle_twap: None,
+        user_position: Account::try_from(book.user_position)?,
+        protocol_stats: Account::try_from(book.protocol_stats)?,
+        user_account: Account::try_from(book.user_account)?,
+        user_token: InterfaceAccount::try_from(book.user_token)?,
+        cross_margin_account: None,
+        cross_vault: None,
+        accepted_collateral: None,
+        position_collateral: None,
+        collateral_vault: None,
+        collateral_user_token: None,
+        collateral_mint: None,
+        vault_token: InterfaceAccount::try_from(book.vault_token)?,
+        fee_destination: book.fee_destination.clone(),
+        insurance_fund: Account::try_from(book.insurance_fund)?,
+        insurance_vault_token: InterfaceAccount::try_from(book.insurance_vault_token)?,
+        quote_mint: Box::new(InterfaceAccount::try_from(book.quote_mint)?),
+        token_program: Interface::try_from(book.token_program)?,
+    };
+    liquidate(Context::new(&crate::ID, &mut accounts, &[], LiquidateBumps::default()))
+}
+
+#[test]
+fn test_liquidation_is_settled_before_any_tokens_leave_the_vault() {
+    // At $91 the long has $10 of equity against $45.50 of maintenance margin
+    let now = 100_000;
+    let config = Config { liq_fee_bps: 100, insurance_fee_bps: 2_000, ..Default::default() };
+    let book = Book::new(config, 91 * FP, now);
+
+    // The mock token program looks at the books mid-transfer and tries to
+    // liquidate the same position again from inside each transfer
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let on_transfer = {
+        let seen = seen.clone();
+        move |transfer: &Transfer| {
+            seen.borrow_mut().push((transfer.to, transfer.amount, book.settled_state(), liquidate_book(book)));
+        }
+    };
+    run_at(now, on_transfer, || liquidate_book(book)).unwrap();
+
+    // The $9.10 fee on $910 of notional, a fifth of it to the insurance
+    // fund, and the last $0.90 of equity back to the trader
+    let seen = seen.borrow();
+    assert_eq!(
+        seen.iter().map(|(to, amount, ..)| (*to, *amount)).collect::<Vec<_>>(),
+        [
+            (*book.insurance_vault_token.key, 1_820_000),
+            (*book.fee_destination.key, 7_280_000),
+            (*book.user_token.key, 900_000),
+        ]
+    );
+    for (.., (up, market), reentry) in seen.iter() {
+        assert_eq!((up.base_size, up.margin_deposited, up.status), (0, 0, PositionStatus::Closed));
+        assert_eq!((market.total_long_size, market.total_margin_locked), (0, 0));
+        assert_eq!(reentry.as_ref().unwrap_err(), &PerpsError::PositionNotFound.into());
+    }
+    assert_eq!(token_balance(book.vault_token), 90_000_000);
 }
 }
@@ -5,7 +5,22 @@ pub mod liquidate;
 pub mod funding;
 pub mod rewards;
 pub mod advanced_position;
+pub mod take_profit;
+pub mod trailing_stop;
 pub mod enhanced_liquidation;
+pub mod auto_deleverage;
+pub mod batch_liquidate;
+pub mod withdrawal_queue;
+pub mod invariants;
+pub mod position_health;
+pub mod collateral;
+pub mod amm;
+pub mod limit_order;
+pub mod expired_orders;
+pub mod position_transfer;
+pub mod cross_margin;
+#[cfg(feature = "test-helpers")]
+pub mod test_helpers;
 
 pub use admin::*;
 pub use create_market::*;
@@ -14,4 +29,19 @@ pub use liquidate::*;
 pub use funding::*;
 pub use rewards::*;
 pub use advanced_position::*;
+pub use take_profit::*;
+pub use trailing_stop::*;
 pub use enhanced_liquidation::*;
+pub use auto_deleverage::*;
+pub use batch_liquidate::*;
+pub use withdrawal_queue::*;
+pub use invariants::*;
+pub use position_health::*;
+pub use collateral::*;
+pub use amm::*;
+pub use limit_order::*;
+pub use expired_orders::*;
+pub use position_transfer::*;
+pub use cross_margin::*;
+#[cfg(feature = "test-helpers")]
+pub use test_helpers::*;
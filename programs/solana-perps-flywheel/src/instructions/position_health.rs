@@ -0,0 +1,116 @@
+use anchor_lang::prelude::*;
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::math;
+
+/// A position's health as liquidation sees it, handed back as return data so
+/// UIs and keepers read the program's own numbers instead of replicating them
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PositionHealth {
+    pub equity_fp: i128,                // Margin + PnL - funding owed
+    pub maintenance_required_fp: u128,  // At the position's margin tier
+    pub is_liquidatable: bool,
+    pub liquidation_price_fp: u128,     // 0 for an empty position
+    pub unrealized_pnl_fp: i128,        // What a close would realize, funding included
+}
+
+impl PositionHealth {
+    pub const SPACE: usize = 16 + 16 + 1 + 16 + 16;
+}
+
+const _: () = assert!(PositionHealth::SPACE <= anchor_lang::solana_program::program::MAX_RETURN_DATA);
+
+/// Read-only: price `position` at `mark_fp` with its funding settled on a
+/// copy, so the numbers match a close or liquidation in the same slot
+pub fn position_health(cfg: &Config, market: &Market, position: &UserPosition, mark_fp: u128, now: i64) -> Result<PositionHealth> {
+    let mut up = position.clone();
+    up.settle_funding(market, now)?;
+    let size = up.base_size.unsigned_abs();
+    if size == 0 {
+        return Ok(PositionHealth { equity_fp: up.equity_fp(cfg, mark_fp)?, ..Default::default() });
+    }
+
+    let maintenance_margin_bps = market.maintenance_margin_bps_for(size, now);
+    let equity_fp = up.equity_fp(cfg, mark_fp)?;
+    let maintenance_required_fp = size as u128 * mark_fp * maintenance_margin_bps as u128 / 10_000;
+    let price_move_fp = if up.is_long {
+        mark_fp as i128 - up.entry_price_fp as i128
+    } else {
+        up.entry_price_fp as i128 - mark_fp as i128
+    };
+    // Funding owed eats into the margin the liquidation price is spread over
+    let net_margin_fp = (cfg.quote_to_fp(up.margin_deposited)? as i128 - up.funding_debt_fp).max(0) as u128;
+    Ok(PositionHealth {
+        equity_fp,
+        maintenance_required_fp,
+        is_liquidatable: up.is_liquidatable(cfg, mark_fp, maintenance_margin_bps)?,
+        liquidation_price_fp: math::liquidation_price_fp(up.entry_price_fp, net_margin_fp, size, maintenance_margin_bps, up.is_long)?,
+        unrealized_pnl_fp: size as i128 * price_move_fp - up.funding_debt_fp,
+    })
+}
+
+/// Health of an isolated position at the mark liquidations use. Cross
+/// positions are judged on their whole account instead.
+pub fn get_position_health(ctx: Context<GetPositionHealth>) -> Result<PositionHealth> {
+    let market = &ctx.accounts.market;
+    ensure_current_versions(&ctx.accounts.config, market, &[&ctx.accounts.user_position])?;
+    ctx.accounts.user_position.ensure_isolated()?;
+    let mark_fp = market.settlement_mark_fp(math::risk_mark_price_fp(market, &ctx.accounts.oracle, ctx.accounts.oracle_twap.as_deref())?);
+    position_health(&ctx.accounts.config, market, &ctx.accounts.user_position, mark_fp, Clock::get()?.unix_timestamp)
+}
+
+#[derive(Accounts)]
+pub struct GetPositionHealth<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(address = user_position.market @ PerpsError::PositionMarketMismatch)]
+    pub market: Account<'info, Market>,
+
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(address = market.oracle @ PerpsError::OracleFeedNotFound)]
+    pub oracle: Account<'info, OraclePrice>,
+
+    /// The oracle's TWAP ring, required when the market prices risk at a TWAP
+    #[account(seeds = [ORACLE_TWAP_SEED, oracle.key().as_ref()], bump = oracle_twap.bump)]
+    pub oracle_twap: Option<Account<'info, OracleTwap>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_settles_funding_without_touching_the_position() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        // Longs have paid $0.50 per unit of funding since this position last settled
+        let market = Market { maintenance_margin_bps: 500, cumulative_funding_long_fp: -(FP as i128) / 2, ..Default::default() };
+        // 10 long from $100 with $100 margin
+        let position = UserPosition {
+            base_size: 10,
+            is_long: true,
+            entry_price_fp: 100 * FP,
+            margin_deposited: 100_000_000,
+            status: PositionStatus::Open,
+            ..Default::default()
+        };
+
+        // At $95: $100 - $50 - $5 funding = $45 against $47.50 maintenance
+        let health = position_health(&cfg, &market, &position, 95 * FP, 1_000).unwrap();
+        assert_eq!(health.unrealized_pnl_fp, -55 * FP as i128);
+        assert_eq!(health.equity_fp, 45 * FP as i128);
+        assert_eq!(health.maintenance_required_fp, 47_500_000);
+        assert!(health.is_liquidatable);
+        // $95 of margin net of funding over 10 units, less maintenance at entry
+        assert_eq!(health.liquidation_price_fp, math::liquidation_price_fp(100 * FP, 95 * FP, 10, 500, true).unwrap());
+        // The account itself still owes nothing until a real settlement
+        assert_eq!((position.funding_debt_fp, position.last_cumulative_funding_fp), (0, 0));
+
+        // At $100 only the funding is lost and it is healthy
+        let health = position_health(&cfg, &market, &position, 100 * FP, 1_000).unwrap();
+        assert_eq!(health.equity_fp, 95 * FP as i128);
+        assert!(!health.is_liquidatable);
+        assert_eq!(PositionHealth::default().try_to_vec().unwrap().len(), PositionHealth::SPACE);
+    }
+}
@@ -0,0 +1,300 @@
+use anchor_lang::prelude::*;
+
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::*;
+
+/// Hand an open isolated position to `new_owner`. Positions are seeded by
+/// owner, so the account is closed and re-created under the new owner's seed
+/// with the same state. Funding accrued since the last settlement is settled
+/// into it first, so the new owner takes over exactly what the old one owed.
+/// A resting stop-loss or take-profit moves with it and must be passed with
+/// its new account; a resting trailing stop must be cancelled first.
+pub fn transfer_position(ctx: Context<TransferPosition>) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+    let owner = ctx.accounts.owner.key();
+    let new_owner = ctx.accounts.new_owner.key();
+    require_keys_neq!(owner, new_owner, PerpsError::InvalidParameters);
+    let up = &mut ctx.accounts.user_position;
+    up.ensure_status(&[PositionStatus::Open])?;
+    up.ensure_isolated()?;
+    up.ensure_quote_margin()?;
+    // The new owner's seed may hold an old, fully settled position: it is reused
+    ctx.accounts.new_position.ensure_status(&[PositionStatus::Closed])?;
+    if load_order::<TrailingStopOrder>(&ctx.accounts.trailing_stop_order)?.is_some_and(|order| order.is_active) {
+        return err!(PerpsError::PositionHasPendingOrders);
+    }
+
+    let now = Clock::get()?.unix_timestamp;
+    up.settle_funding(&ctx.accounts.market, now)?;
+    let new_position_key = ctx.accounts.new_position.key();
+    let moved = transferred_position(up, new_owner, ctx.bumps.new_position);
+    ctx.accounts.new_position.set_inner(moved);
+
+    // Position slots follow the position
+    ctx.accounts.user_account.record_close();
+    let new_account = &mut ctx.accounts.new_user_account;
+    if new_account.owner == Pubkey::default() {
+        new_account.owner = new_owner;
+        new_account.bump = ctx.bumps.new_user_account;
+    }
+    new_account.record_open(ctx.accounts.config.max_positions_per_user)?;
+
+    // So do resting orders, and the slots they hold
+    let rent_to = ctx.accounts.owner.to_account_info();
+    let migrated = [
+        migrate_order(
+            &ctx.accounts.stop_loss_order,
+            ctx.accounts.new_stop_loss_order.as_mut(),
+            ctx.bumps.new_stop_loss_order,
+            new_owner,
+            new_position_key,
+            rent_to.clone(),
+        )?,
+        migrate_order(
+            &ctx.accounts.take_profit_order,
+            ctx.accounts.new_take_profit_order.as_mut(),
+            ctx.bumps.new_take_profit_order,
+            new_owner,
+            new_position_key,
+            rent_to,
+        )?,
+    ];
+    for replaced_resting in migrated.into_iter().flatten() {
+        let orders = ctx.accounts.user_orders.as_mut().ok_or(PerpsError::PositionHasPendingOrders)?;
+        orders.remove_order();
+        let new_orders = ctx.accounts.new_user_orders.as_mut().ok_or(PerpsError::PositionHasPendingOrders)?;
+        if new_orders.owner == Pubkey::default() {
+            new_orders.owner = new_owner;
+            new_orders.bump = ctx.bumps.new_user_orders.ok_or(PerpsError::InvalidPDA)?;
+        }
+        // An order left resting on the new owner's old position already holds its slot
+        if !replaced_resting {
+            new_orders.add_order(ctx.accounts.config.max_active_orders_per_user)?;
+        }
+    }
+
+    let up = &ctx.accounts.new_position;
+    emit!(PositionTransferred {
+        market: ctx.accounts.market.key(),
+        previous_owner: owner,
+        new_owner,
+        previous_position: ctx.accounts.user_position.key(),
+        new_position: new_position_key,
+        base_size: up.base_size,
+        margin_deposited: up.margin_deposited,
+        stop_loss_migrated: migrated[0].is_some(),
+        take_profit_migrated: migrated[1].is_some(),
+    });
+    msg!("Position in {} transferred from {} to {}", ctx.accounts.market.key(), owner, new_owner);
+    Ok(())
+}
+
+/// `up` as `new_owner` holds it under the PDA bump `bump`, otherwise unchanged
+pub fn transferred_position(up: &UserPosition, new_owner: Pubkey, bump: u8) -> UserPosition {
+    UserPosition { owner: new_owner, bump, ..up.clone() }
+}
+
+/// The orders armed against a position, moved with it to a new owner
+trait PositionOrder: AccountSerialize + AccountDeserialize + Owner + Clone {
+    fn is_resting(&self) -> bool;
+    fn reassign(&mut self, owner: Pubkey, position_key: Pubkey, bump: u8);
+}
+
+impl PositionOrder for StopLossOrder {
+    fn is_resting(&self) -> bool {
+        self.is_active
+    }
+
+    fn reassign(&mut self, owner: Pubkey, position_key: Pubkey, bump: u8) {
+        (self.owner, self.position_key, self.bump) = (owner, position_key, bump);
+    }
+}
+
+impl PositionOrder for TakeProfitOrder {
+    fn is_resting(&self) -> bool {
+        self.is_active
+    }
+
+    fn reassign(&mut self, owner: Pubkey, position_key: Pubkey, bump: u8) {
+        (self.owner, self.position_key, self.bump) = (owner, position_key, bump);
+    }
+}
+
+/// Close the order account at `old`, if one was ever created, returning its
+/// rent to `rent_to`. A resting order is first re-created in `new` for the
+/// new owner. Returns `Some(replaced_resting)` when an order moved, with
+/// whether `new` already held a resting order of its own.
+fn migrate_order<'info, T: PositionOrder>(
+    old: &UncheckedAccount<'info>,
+    new: Option<&mut Account<'info, T>>,
+    new_bump: Option<u8>,
+    new_owner: Pubkey,
+    new_position: Pubkey,
+    rent_to: AccountInfo<'info>,
+) -> Result<Option<bool>> {
+    let Some(mut order) = load_order::<T>(old)? else {
+        return Ok(None);
+    };
+    let moved = if order.is_resting() {
+        let new = new.ok_or(PerpsError::PositionHasPendingOrders)?;
+        let replaced_resting = new.is_resting();
+        order.reassign(new_owner, new_position, new_bump.ok_or(PerpsError::InvalidPDA)?);
+        new.set_inner(order);
+        Some(replaced_resting)
+    } else {
+        None
+    };
+
+    // Close the old account as `close = ` would
+    let lamports = old.lamports();
+    **rent_to.try_borrow_mut_lamports()? = rent_to.lamports().checked_add(lamports).ok_or(PerpsError::MathOverflow)?;
+    **old.try_borrow_mut_lamports()? = 0;
+    old.assign(&anchor_lang::system_program::ID);
+    old.resize(0)?;
+    Ok(moved)
+}
+
+/// The order held at an order PDA, `None` while the PDA was never created
+fn load_order<T: AccountDeserialize + Owner>(account: &UncheckedAccount) -> Result<Option<T>> {
+    if account.data_is_empty() {
+        return Ok(None);
+    }
+    require_keys_eq!(*account.owner, T::owner(), ErrorCode::AccountOwnedByWrongProgram);
+    Ok(Some(T::try_deserialize(&mut &account.try_borrow_data()?[..])?))
+}
+
+#[derive(Accounts)]
+pub struct TransferPosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    /// CHECK: any wallet; it only seeds the new accounts
+    pub new_owner: UncheckedAccount<'info>,
+
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [POSITION_SEED, owner.key().as_ref(), market.key().as_ref()],
+        bump = user_position.bump,
+        has_one = owner @ PerpsError::UnauthorizedAccess,
+        close = owner,
+    )]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserPosition::SPACE,
+        seeds = [POSITION_SEED, new_owner.key().as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub new_position: Box<Account<'info, UserPosition>>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, owner.key().as_ref()],
+        bump = user_account.bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserAccount::SPACE,
+        seeds = [USER_ACCOUNT_SEED, new_owner.key().as_ref()],
+        bump
+    )]
+    pub new_user_account: Account<'info, UserAccount>,
+
+    /// CHECK: the position's stop-loss PDA, passed whether or not it exists so
+    /// a resting order can't be left behind
+    #[account(mut, seeds = [STOP_LOSS_SEED, owner.key().as_ref(), market.key().as_ref()], bump)]
+    pub stop_loss_order: UncheckedAccount<'info>,
+
+    /// CHECK: the position's take-profit PDA, as stop_loss_order
+    #[account(mut, seeds = [TAKE_PROFIT_SEED, owner.key().as_ref(), market.key().as_ref()], bump)]
+    pub take_profit_order: UncheckedAccount<'info>,
+
+    /// CHECK: the position's trailing stop PDA, which must not hold a resting order
+    #[account(seeds = [TRAILING_STOP_SEED, owner.key().as_ref(), market.key().as_ref()], bump)]
+    pub trailing_stop_order: UncheckedAccount<'info>,
+
+    /// Where resting orders move to, required for each one the position has
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = StopLossOrder::SPACE,
+        seeds = [STOP_LOSS_SEED, new_owner.key().as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub new_stop_loss_order: Option<Account<'info, StopLossOrder>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = TakeProfitOrder::SPACE,
+        seeds = [TAKE_PROFIT_SEED, new_owner.key().as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub new_take_profit_order: Option<Account<'info, TakeProfitOrder>>,
+
+    /// Both owners' order counters, required when a resting order moves
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_SEED, owner.key().as_ref()],
+        bump = user_orders.bump
+    )]
+    pub user_orders: Option<Account<'info, UserOrders>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserOrders::SPACE,
+        seeds = [USER_ORDERS_SEED, new_owner.key().as_ref()],
+        bump
+    )]
+    pub new_user_orders: Option<Account<'info, UserOrders>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transferred_position_keeps_its_state() {
+        let (old_owner, new_owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let up = UserPosition {
+            owner: old_owner,
+            market: Pubkey::new_unique(),
+            bump: 254,
+            is_long: true,
+            base_size: 10,
+            entry_price_fp: 100 * FP,
+            margin_deposited: 100_000_000,
+            funding_debt_fp: 1_500_000,
+            opened_at_ts: 1_000,
+            last_updated_ts: 1_200,
+            status: PositionStatus::Open,
+            version: ACCOUNT_VERSION,
+            ..Default::default()
+        };
+        let moved = transferred_position(&up, new_owner, 251);
+        assert_eq!((moved.owner, moved.bump), (new_owner, 251));
+        assert_eq!(UserPosition { owner: old_owner, bump: 254, ..moved }.try_to_vec().unwrap(), up.try_to_vec().unwrap());
+
+        // A moved stop keeps its arming time, so it still counts as armed on
+        // this position rather than being retired as an orphan
+        let mut order = StopLossOrder { owner: old_owner, is_active: true, created_at: 1_100, bump: 250, ..Default::default() };
+        let position_key = Pubkey::new_unique();
+        order.reassign(new_owner, position_key, 249);
+        assert_eq!((order.owner, order.position_key, order.bump, order.created_at), (new_owner, position_key, 249, 1_100));
+        assert!(order.is_resting());
+    }
+}
@@ -1,27 +1,81 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Mint, Transfer};
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
 use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::{CreatorRewardsClaimed, CreatorRewardsSwept};
+use crate::transfer::{transfer_checked, vault_solvency_check};
 
 
+/// Pay up to the market creator's accrued fee share in the creator reward
+/// mint, one reward unit per accrued quote unit, from the protocol's reward
+/// reserve. The quote backing the swept accrual leaves the market vault for
+/// the fee destination.
 pub fn sweep_creator_rewards(ctx: Context<SweepCreatorRewards>, amount: u64) -> Result<()> {
-let cfg = &ctx.accounts.config;
-require_keys_eq!(cfg.creator_reward_mint, ctx.accounts.creator_reward_mint.key());
-token::transfer(ctx.accounts.transfer_rewards_to_fee_dest(), amount * (cfg.creator_reward_bps as u64) / 10_000)?;
+ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[])?;
+ctx.accounts.market.draw_creator_rewards(amount)?;
+require!(ctx.accounts.vault_token.amount >= amount, PerpsError::InsufficientLiquidity);
+require!(ctx.accounts.creator_reward_source.amount >= amount, PerpsError::InsufficientBalance);
+ctx.accounts.market.exit(&crate::ID)?;
+let signer_seeds: &[&[&[u8]]] = &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]];
+ctx.accounts.transfer_rewards_to_creator(signer_seeds, amount)?;
+ctx.accounts.transfer_vault_to_fee_dest(signer_seeds, amount)?;
+vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+emit!(CreatorRewardsSwept { market: ctx.accounts.market.key(), creator: ctx.accounts.creator.key(), amount, remaining_accrued: ctx.accounts.market.creator_rewards_accrued });
 Ok(())
 }
 
 
+/// Pay the market's creator their accrued share of its trading fees out of the market vault
+pub fn claim_creator_rewards(ctx: Context<ClaimCreatorRewards>) -> Result<()> {
+ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[])?;
+let amount = ctx.accounts.market.take_creator_rewards();
+require!(amount > 0, PerpsError::NoCreatorRewards);
+require!(ctx.accounts.vault_token.amount >= amount, PerpsError::InsufficientLiquidity);
+ctx.accounts.market.exit(&crate::ID)?;
+let signer_seeds: &[&[&[u8]]] = &[&[CONFIG_SEED, &[ctx.accounts.config.bump]]];
+ctx.accounts.transfer_vault_to_creator(signer_seeds, amount)?;
+vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+emit!(CreatorRewardsClaimed { market: ctx.accounts.market.key(), creator: ctx.accounts.creator.key(), amount });
+Ok(())
+}
+
+
+#[derive(Accounts)]
+pub struct ClaimCreatorRewards<'info> {
+#[account(seeds = [CONFIG_SEED], bump = config.bump)] pub config: Account<'info, Config>,
+pub creator: Signer<'info>,
+#[account(mut, has_one = creator @ PerpsError::UnauthorizedAccess)] pub market: Account<'info, Market>,
+#[account(mut, seeds = [VAULT_SEED, market.key().as_ref()], bump = market.vault_bump)] pub vault_token: InterfaceAccount<'info, TokenAccount>,
+#[account(mut, constraint = creator_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint)] pub creator_token: InterfaceAccount<'info, TokenAccount>,
+#[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)] pub quote_mint: InterfaceAccount<'info, Mint>,
+pub token_program: Interface<'info, TokenInterface>,
+}
+impl<'info> ClaimCreatorRewards<'info> {
+pub fn transfer_vault_to_creator(&self, signer_seeds: &[&[&[u8]]], amount: u64) -> Result<u64> {
+transfer_checked(&self.token_program, &self.quote_mint, self.vault_token.to_account_info(), self.creator_token.to_account_info(), self.config.to_account_info(), signer_seeds, amount)
+}
+}
+
 #[derive(Accounts)]
 pub struct SweepCreatorRewards<'info> {
-pub config: Account<'info, Config>,
-pub creator_reward_mint: Account<'info, Mint>,
-#[account(mut)] pub creator_reward_source: Account<'info, TokenAccount>,
-/// CHECK
-#[account(mut)] pub fee_destination: AccountInfo<'info>,
-pub token_program: Program<'info, Token>,
+#[account(seeds = [CONFIG_SEED], bump = config.bump)] pub config: Account<'info, Config>,
+pub creator: Signer<'info>,
+#[account(mut, has_one = creator @ PerpsError::UnauthorizedAccess)] pub market: Account<'info, Market>,
+#[account(mut, seeds = [VAULT_SEED, market.key().as_ref()], bump = market.vault_bump)] pub vault_token: InterfaceAccount<'info, TokenAccount>,
+#[account(address = config.creator_reward_mint @ PerpsError::InvalidTokenMint)] pub creator_reward_mint: InterfaceAccount<'info, Mint>,
+/// Protocol-held reserve of the reward token
+#[account(mut, token::mint = creator_reward_mint, token::authority = config, token::token_program = token_program)] pub creator_reward_source: InterfaceAccount<'info, TokenAccount>,
+#[account(mut, constraint = creator_reward_token.mint == creator_reward_mint.key() @ PerpsError::InvalidTokenMint)] pub creator_reward_token: InterfaceAccount<'info, TokenAccount>,
+/// CHECK: must be the configured fee account
+#[account(mut, address = config.fee_destination @ PerpsError::InvalidTokenAccount)] pub fee_destination: AccountInfo<'info>,
+#[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)] pub quote_mint: InterfaceAccount<'info, Mint>,
+pub token_program: Interface<'info, TokenInterface>,
 }
 impl<'info> SweepCreatorRewards<'info> {
-pub fn transfer_rewards_to_fee_dest(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-CpiContext::new(self.token_program.to_account_info(), Transfer { from: self.creator_reward_source.to_account_info(), to: self.fee_destination.to_account_info(), authority: self.config.to_account_info() })
+pub fn transfer_rewards_to_creator(&self, signer_seeds: &[&[&[u8]]], amount: u64) -> Result<u64> {
+transfer_checked(&self.token_program, &self.creator_reward_mint, self.creator_reward_source.to_account_info(), self.creator_reward_token.to_account_info(), self.config.to_account_info(), signer_seeds, amount)
+}
+pub fn transfer_vault_to_fee_dest(&self, signer_seeds: &[&[&[u8]]], amount: u64) -> Result<u64> {
+transfer_checked(&self.token_program, &self.quote_mint, self.vault_token.to_account_info(), self.fee_destination.clone(), self.config.to_account_info(), signer_seeds, amount)
 }
 }
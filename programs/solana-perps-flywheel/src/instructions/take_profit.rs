@@ -0,0 +1,463 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::*;
+use crate::oracle;
+use crate::instructions::advanced_position::{close_slice, pay_out_slice, slice_size};
+use crate::instructions::withdrawal_queue::throttle_outflow;
+
+// Take-profit orders, mirroring the stop-loss system
+
+pub fn set_take_profit(
+    ctx: Context<SetTakeProfit>,
+    trigger_price_fp: u128,
+    close_percentage: u8, // 1-100 (100 = close entire position)
+    expires_at: Option<i64>,
+) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+    require!(
+        close_percentage > 0 && close_percentage <= 100,
+        PerpsError::InvalidMarketParameters
+    );
+    let now = Clock::get()?.unix_timestamp;
+    validate_order_expiry(expires_at, now)?;
+    ctx.accounts.user_position.ensure_status(&[PositionStatus::Open])?;
+    ctx.accounts.user_position.ensure_isolated()?;
+    ctx.accounts.user_position.ensure_quote_margin()?;
+    require!(trigger_price_fp > 0, PerpsError::InvalidPrice);
+
+    // Validate take profit direction
+    let current_price_fp = oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?;
+    let is_long = ctx.accounts.user_position.is_long;
+    validate_bracket(is_long, current_price_fp, 0, trigger_price_fp)?;
+
+    // Re-arming a resting order keeps its slot; a new one takes another
+    let orders = &mut ctx.accounts.user_orders;
+    orders.owner = ctx.accounts.user.key();
+    orders.bump = ctx.bumps.user_orders;
+    if !ctx.accounts.take_profit_order.is_active {
+        orders.add_order(ctx.accounts.config.max_active_orders_per_user)?;
+    }
+
+    let position_key = ctx.accounts.user_position.key();
+    ctx.accounts.take_profit_order.arm(
+        ctx.accounts.user_position.owner,
+        ctx.accounts.market.key(),
+        position_key,
+        trigger_price_fp,
+        close_percentage,
+        now,
+        expires_at,
+        ctx.bumps.take_profit_order,
+    );
+
+    emit!(TakeProfitSet {
+        user: ctx.accounts.user_position.owner,
+        market: ctx.accounts.market.key(),
+        trigger_price_fp,
+        close_percentage,
+        is_long,
+    });
+
+    Ok(())
+}
+
+/// Anyone may crank a take profit once the mark crosses its trigger
+pub fn execute_take_profit(ctx: Context<ExecuteTakeProfit>) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+    require!(ctx.accounts.take_profit_order.is_active, PerpsError::OrderNotActive);
+    require!(!ctx.accounts.market.is_paused, PerpsError::MarketPaused);
+
+    // An order left behind by a position that was closed out by hand is retired
+    // rather than executed. Failing here would roll the retirement back, and the
+    // order would otherwise still be armed against the next position opened.
+    if ctx.accounts.user_position.orphans_order(ctx.accounts.take_profit_order.created_at) {
+        ctx.accounts.take_profit_order.is_active = false;
+        ctx.accounts.user_orders.remove_order();
+        emit!(TakeProfitCancelled {
+            user: ctx.accounts.user_position.owner,
+            market: ctx.accounts.market.key(),
+        });
+        msg!("Take profit retired: {}", PerpsError::PositionNotFound);
+        return Ok(());
+    }
+    ctx.accounts.user_position.ensure_status(&[PositionStatus::Open])?;
+    ctx.accounts.user_position.ensure_isolated()?;
+    let now = Clock::get()?.unix_timestamp;
+    require!(!ctx.accounts.take_profit_order.is_expired(now), PerpsError::OrderExpired);
+
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?);
+    let trigger_price_fp = ctx.accounts.take_profit_order.trigger_price_fp;
+    let close_percentage = ctx.accounts.take_profit_order.close_percentage;
+    require!(
+        ctx.accounts.take_profit_order.is_triggered(ctx.accounts.user_position.is_long, mark_fp),
+        PerpsError::TakeProfitNotTriggered
+    );
+
+    // Size off the live position; 100% closes it outright
+    let close_size = slice_size(ctx.accounts.user_position.base_size.unsigned_abs(), close_percentage);
+    require!(close_size > 0, PerpsError::PositionTooSmall);
+
+    // Effects
+    let slice = close_slice(
+        &mut ctx.accounts.config,
+        &mut ctx.accounts.market,
+        &mut ctx.accounts.user_position,
+        close_size,
+        mark_fp,
+        ctx.accounts.vault_token.amount,
+        now,
+    )?;
+    if slice.remaining_size == 0 {
+        ctx.accounts.protocol_stats.record_close();
+        ctx.accounts.user_account.record_close();
+    }
+    ctx.accounts.protocol_stats.record_fees(slice.fee_amt)?;
+    emit!(ctx.accounts.protocol_stats.updated_event());
+    let order = &mut ctx.accounts.take_profit_order;
+    order.is_active = false;
+    order.executed_at = Some(now);
+    ctx.accounts.user_orders.remove_order();
+    throttle_outflow(
+        &ctx.accounts.config,
+        ctx.accounts.user_rate_limit.as_mut(),
+        None,
+        ctx.accounts.user_position.owner,
+        slice.settlement_amt,
+        now,
+    )?;
+
+    ctx.accounts.take_profit_order.exit(&crate::ID)?;
+    ctx.accounts.user_orders.exit(&crate::ID)?;
+    ctx.accounts.user_position.exit(&crate::ID)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+    ctx.accounts.config.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+    ctx.accounts.user_account.exit(&crate::ID)?;
+
+    // Interactions
+    pay_out_slice(
+        &ctx.accounts.config,
+        &ctx.accounts.market,
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        &ctx.accounts.vault_token,
+        &ctx.accounts.user_token,
+        &ctx.accounts.fee_destination_token,
+        slice.settlement_amt,
+        slice.fee_forwarded,
+    )?;
+
+    emit!(TakeProfitExecuted {
+        user: ctx.accounts.user_position.owner,
+        market: ctx.accounts.market.key(),
+        trigger_price_fp,
+        close_percentage,
+        executor: ctx.accounts.executor.key(),
+    });
+
+    msg!("Take profit executed: {} units @ {}, PnL {}, Settlement {}",
+         close_size, mark_fp, slice.pnl_fp, slice.settlement_amt);
+
+    Ok(())
+}
+
+/// Cancel a resting take profit, freeing its order slot and returning the rent
+pub fn cancel_take_profit(ctx: Context<CancelTakeProfit>) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[])?;
+    if ctx.accounts.take_profit_order.is_active {
+        ctx.accounts.user_orders.remove_order();
+    }
+
+    emit!(TakeProfitCancelled {
+        user: ctx.accounts.user.key(),
+        market: ctx.accounts.market.key(),
+    });
+
+    Ok(())
+}
+
+/// Check that protective prices sit on the right side of `entry_price_fp`:
+/// below it for a long's stop and above for its take profit, the other way
+/// round for a short. A zero price means that leg isn't set.
+pub fn validate_bracket(
+    is_long: bool,
+    entry_price_fp: u128,
+    stop_loss_price_fp: u128,
+    take_profit_price_fp: u128,
+) -> Result<()> {
+    if stop_loss_price_fp > 0 {
+        let valid = if is_long { stop_loss_price_fp < entry_price_fp } else { stop_loss_price_fp > entry_price_fp };
+        require!(valid, PerpsError::InvalidStopLoss);
+    }
+    if take_profit_price_fp > 0 {
+        let valid = if is_long { take_profit_price_fp > entry_price_fp } else { take_profit_price_fp < entry_price_fp };
+        require!(valid, PerpsError::InvalidTakeProfit);
+    }
+    Ok(())
+}
+
+// Context structures
+
+#[derive(Accounts)]
+pub struct SetTakeProfit<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        seeds = [MARKET_SEED, market.symbol.as_ref()],
+        bump = market.bump,
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [POSITION_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump = user_position.bump,
+        constraint = user_position.owner == user.key() @ PerpsError::UnauthorizedAccess,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        seeds = [TAKE_PROFIT_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump,
+        space = TakeProfitOrder::SPACE,
+    )]
+    pub take_profit_order: Account<'info, TakeProfitOrder>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        seeds = [USER_ORDERS_SEED, user.key().as_ref()],
+        bump,
+        space = UserOrders::SPACE,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+
+    pub oracle: Account<'info, OraclePrice>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTakeProfit<'info> {
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, market.symbol.as_ref()],
+        bump = market.bump,
+    )]
+    pub market: Account<'info, Market>,
+
+    pub executor: Signer<'info>, // Anyone can crank a triggered take profit
+
+    #[account(
+        mut,
+        seeds = [POSITION_SEED, user_position.owner.as_ref(), market.key().as_ref()],
+        bump = user_position.bump,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user_position.owner.as_ref()],
+        bump = user_account.bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        seeds = [TAKE_PROFIT_SEED, user_position.owner.as_ref(), market.key().as_ref()],
+        bump = take_profit_order.bump,
+        constraint = take_profit_order.position_key == user_position.key() @ PerpsError::InvalidTakeProfit,
+    )]
+    pub take_profit_order: Account<'info, TakeProfitOrder>,
+
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_SEED, user_position.owner.as_ref()],
+        bump = user_orders.bump,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user_position.owner @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = config.fee_destination @ PerpsError::InvalidTokenAccount
+    )]
+    pub fee_destination_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// Only needed while withdrawals are rate limited; the owner creates it
+    #[account(
+        mut,
+        seeds = [USER_RATE_LIMIT_SEED, user_position.owner.as_ref()],
+        bump = user_rate_limit.bump,
+    )]
+    pub user_rate_limit: Option<Account<'info, UserRateLimit>>,
+
+    pub oracle: Account<'info, OraclePrice>,
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CancelTakeProfit<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        seeds = [MARKET_SEED, market.symbol.as_ref()],
+        bump = market.bump,
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [TAKE_PROFIT_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump = take_profit_order.bump,
+        close = user,
+    )]
+    pub take_profit_order: Account<'info, TakeProfitOrder>,
+
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_SEED, user.key().as_ref()],
+        bump = user_orders.bump,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: u128 = 100 * FP;
+
+    #[test]
+    fn test_bracket_must_straddle_entry() {
+        assert!(validate_bracket(true, ENTRY, 90 * FP, 120 * FP).is_ok());
+        assert!(validate_bracket(false, ENTRY, 110 * FP, 80 * FP).is_ok());
+        // Either leg may be skipped
+        assert!(validate_bracket(true, ENTRY, 0, 120 * FP).is_ok());
+        assert!(validate_bracket(true, ENTRY, 90 * FP, 0).is_ok());
+
+        assert_eq!(validate_bracket(true, ENTRY, 100 * FP, 0).unwrap_err(), PerpsError::InvalidStopLoss.into());
+        assert_eq!(validate_bracket(true, ENTRY, 0, 95 * FP).unwrap_err(), PerpsError::InvalidTakeProfit.into());
+        assert_eq!(validate_bracket(false, ENTRY, 90 * FP, 0).unwrap_err(), PerpsError::InvalidStopLoss.into());
+        assert_eq!(validate_bracket(false, ENTRY, 0, 105 * FP).unwrap_err(), PerpsError::InvalidTakeProfit.into());
+    }
+
+    #[test]
+    fn test_bracketed_long_executes_on_either_bound() {
+        let (owner, market_key, position_key) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let mut stop = StopLossOrder::default();
+        let mut take = TakeProfitOrder::default();
+        validate_bracket(true, ENTRY, 90 * FP, 120 * FP).unwrap();
+        stop.arm(owner, market_key, position_key, 90 * FP, 100, 1_000, None, 254);
+        take.arm(owner, market_key, position_key, 120 * FP, 100, 1_000, None, 253);
+        assert!(stop.is_active && take.is_active);
+        assert_eq!((stop.position_key, take.position_key), (position_key, position_key));
+
+        // Neither fires inside the bracket
+        assert!(!stop.is_triggered(true, 105 * FP) && !take.is_triggered(true, 105 * FP));
+
+        let mut cfg = Config { price_decimals: 6, quote_decimals: 6, fee_bps: 10, ..Default::default() };
+
+        // Rally through the take profit: whole position closes in profit
+        assert!(take.is_triggered(true, 121 * FP));
+        let mut market = Market { total_long_size: 10, total_margin_locked: 100_000_000, ..Default::default() };
+        let mut up = UserPosition {
+            is_long: true, base_size: 10, entry_price_fp: ENTRY, margin_deposited: 100_000_000,
+            status: PositionStatus::Open, ..Default::default()
+        };
+        let slice = close_slice(&mut cfg, &mut market, &mut up, 10, 121 * FP, u64::MAX, 2_000).unwrap();
+        assert_eq!(slice.pnl_fp, 210 * FP as i128);
+        assert_eq!(slice.settlement_amt, 100_000_000 + 210_000_000 - 1_210_000);
+        assert_eq!(up.status, PositionStatus::Closed);
+
+        // Or a drop through the stop: closes at a loss
+        assert!(stop.is_triggered(true, 89 * FP));
+        let mut market = Market { total_long_size: 10, total_margin_locked: 200_000_000, ..Default::default() };
+        let mut up = UserPosition {
+            is_long: true, base_size: 10, entry_price_fp: ENTRY, margin_deposited: 200_000_000,
+            status: PositionStatus::Open, ..Default::default()
+        };
+        let slice = close_slice(&mut cfg, &mut market, &mut up, 10, 89 * FP, u64::MAX, 2_000).unwrap();
+        assert_eq!(slice.pnl_fp, -(110 * FP as i128));
+        assert_eq!(slice.settlement_amt, 200_000_000 - 110_000_000 - 890_000);
+    }
+
+    #[test]
+    fn test_partial_take_profit_leaves_the_rest_open() {
+        let take = TakeProfitOrder { trigger_price_fp: 120 * FP, close_percentage: 50, ..Default::default() };
+        assert!(take.is_triggered(true, 125 * FP));
+
+        let mut cfg = Config { price_decimals: 6, quote_decimals: 6, fee_bps: 10, ..Default::default() };
+        let mut market = Market { total_long_size: 10, total_margin_locked: 100_000_000, ..Default::default() };
+        let mut up = UserPosition {
+            is_long: true, base_size: 10, entry_price_fp: ENTRY, margin_deposited: 100_000_000,
+            status: PositionStatus::Open, ..Default::default()
+        };
+        let close_size = slice_size(10, take.close_percentage);
+        let slice = close_slice(&mut cfg, &mut market, &mut up, close_size, 125 * FP, u64::MAX, 2_000).unwrap();
+
+        // Half the size and half the margin are realized, the rest keeps running
+        assert_eq!(slice.pnl_fp, 125 * FP as i128);
+        assert_eq!(slice.settlement_amt, 50_000_000 + 125_000_000 - 625_000);
+        assert_eq!(slice.remaining_size, 5);
+        assert_eq!((up.base_size, up.margin_deposited), (5, 50_000_000));
+        assert_eq!(up.status, PositionStatus::Open);
+        assert_eq!(market.total_long_size, 5);
+    }
+
+    #[test]
+    fn test_short_take_profit_triggers_below() {
+        let take = TakeProfitOrder { trigger_price_fp: 80 * FP, ..Default::default() };
+        assert!(take.is_triggered(false, 80 * FP));
+        assert!(take.is_triggered(false, 79 * FP));
+        assert!(!take.is_triggered(false, 81 * FP));
+    }
+}
@@ -0,0 +1,61 @@
+//! Localnet/test-only instructions. This module is only compiled with the
+//! `test-helpers` feature, so none of it can ship in a mainnet build.
+
+use anchor_lang::prelude::*;
+use crate::state::*;
+use crate::errors::PerpsError;
+
+/// Create or overwrite an oracle price directly, bypassing the circuit breaker.
+/// `last_updated_ts` defaults to now; pass an old timestamp to simulate staleness.
+pub fn force_set_oracle_price(
+    ctx: Context<ForceSetOraclePrice>,
+    _symbol: [u8; 12],
+    price_fp: u128,
+    confidence_fp: u128,
+    last_updated_ts: Option<i64>,
+) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    let oracle = &mut ctx.accounts.oracle;
+    let old_price = oracle.price_fp;
+
+    oracle.price_fp = price_fp;
+    oracle.confidence_fp = confidence_fp;
+    oracle.last_updated_ts = last_updated_ts.unwrap_or(now);
+    oracle.is_valid = price_fp > 0;
+    oracle.bump = ctx.bumps.oracle;
+    if let Some(twap) = ctx.accounts.oracle_twap.as_mut() {
+        twap.record(price_fp, oracle.last_updated_ts)?;
+    }
+
+    msg!("Oracle force-set: {} -> {} (ts {})", old_price, price_fp, oracle.last_updated_ts);
+    Ok(())
+}
+
+#[derive(Accounts)]
+#[instruction(symbol: [u8; 12])]
+pub struct ForceSetOraclePrice<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+        constraint = config.admin == admin.key() @ PerpsError::UnauthorizedAccess,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = OraclePrice::SPACE,
+        seeds = [ORACLE_SEED, symbol.as_ref()],
+        bump
+    )]
+    pub oracle: Account<'info, OraclePrice>,
+
+    /// The oracle's TWAP ring, fed the forced price when passed
+    #[account(mut, seeds = [ORACLE_TWAP_SEED, oracle.key().as_ref()], bump = oracle_twap.bump)]
+    pub oracle_twap: Option<Account<'info, OracleTwap>>,
+
+    pub system_program: Program<'info, System>,
+}
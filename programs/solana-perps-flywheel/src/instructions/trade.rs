@@ -1,126 +1,483 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
 use crate::state::*;
 use crate::errors::*;
 use crate::events::*;
 use crate::math::*;
+use crate::instructions::take_profit::validate_bracket;
+use crate::instructions::withdrawal_queue::throttle_outflow;
+use crate::instructions::cross_margin::settle_cross_balance;
+use crate::instructions::collateral::load_pledge;
+use crate::instructions::enhanced_liquidation::route_fee_to_insurance;
+use crate::instructions::advanced_position::{close_slice, pay_out_slice, SliceClose};
+use crate::oracle;
+use crate::transfer::{transfer_checked, transfer_checked_exact, vault_solvency_check};
 
-pub fn open_position(
-    ctx: Context<OpenPosition>, 
+/// Open a position, or net the order against the one the user already holds
+/// in this market: the same side adds to it at a size-weighted entry price,
+/// the other side reduces or closes it and any excess flips it the other way.
+#[allow(clippy::too_many_arguments)]
+pub fn open_position<'info>(
+    ctx: Context<'_, '_, 'info, 'info, OpenPosition<'info>>, 
     is_long: bool, 
     quote_to_spend: u64, 
-    leverage_x: u16
+    leverage_x: u16,
+    stop_loss_price_fp: u128,   // 0 = no stop loss
+    take_profit_price_fp: u128, // 0 = no take profit
+    margin_mode: MarginMode,
+    max_slippage_bps: u16,      // Largest accepted gap between the average fill and the pre-trade mark
 ) -> Result<()> {
+    // Reject a bad leverage before any arithmetic uses it
+    validate_leverage(leverage_x)?;
+    // An open position is netted against, so it must be current too. Otherwise
+    // the position is (re)written below and only the config and market need to be.
+    let netting = ctx.accounts.user_position.current_status() == PositionStatus::Open;
+    if netting {
+        ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+    } else {
+        ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[])?;
+    }
     let cfg = &ctx.accounts.config;
-    
+
     // Security checks
     require!(!cfg.paused, PerpsError::ProtocolPaused);
     require!(!ctx.accounts.market.is_paused, PerpsError::MarketPaused);
-    require!(leverage_x as u64 <= MAX_LEVERAGE_X, PerpsError::LeverageTooHigh);
     require!(leverage_x <= ctx.accounts.market.taker_leverage_cap_x, PerpsError::LeverageTooHigh);
     require!(quote_to_spend > 0, PerpsError::InvalidMarketParameters);
+    let now = Clock::get()?.unix_timestamp;
+    if netting {
+        // Netting settles in quote out of this market's vault, like a partial
+        // close. Brackets on the netted position are set on their own.
+        let up = &ctx.accounts.user_position;
+        up.ensure_isolated()?;
+        up.ensure_quote_margin()?;
+        require!(margin_mode == MarginMode::Isolated, PerpsError::CrossMarginUnsupported);
+        require!(ctx.accounts.accepted_collateral.is_none(), PerpsError::PledgedCollateralUnsupported);
+        require!(stop_loss_price_fp == 0 && take_profit_price_fp == 0, PerpsError::InvalidParameters);
+    } else {
+        // A queued payout from the previous position doesn't block reopening
+        ctx.accounts.user_position.ensure_status(&[PositionStatus::Closed, PositionStatus::PendingSettlement])?;
+        // Every open takes a slot of the user's and a protocol-wide one, freed
+        // again by a full close or liquidation
+        let user_account = &mut ctx.accounts.user_account;
+        if user_account.owner == Pubkey::default() {
+            user_account.owner = ctx.accounts.user.key();
+            user_account.bump = ctx.bumps.user_account;
+        }
+        take_position_slots(user_account, &mut ctx.accounts.protocol_stats, cfg, ctx.accounts.market.key())?;
+    }
 
-    // Calculate margin and validate
-    let margin = quote_to_spend.checked_div(leverage_x as u64)
-        .ok_or(PerpsError::MathOverflow)?;
+    // Calculate margin and validate; notional is rebuilt from it so leverage is exact
+    let entry = entry_margin(quote_to_spend, leverage_x)?;
+    require!(entry.margin > 0, PerpsError::InsufficientMargin);
+    if entry.remainder > 0 {
+        msg!("Spend truncated to {} at {}x, {} not taken", entry.notional, leverage_x, entry.remainder);
+    }
+
+    // Get current price and net the order against any held position
+    let last_index_fp = ctx.accounts.market.last_index_price_fp();
+    let price_fp = checked_mark_price_fp(
+        &mut ctx.accounts.market,
+        &ctx.accounts.oracle,
+        ctx.accounts.pyth_oracle.as_deref(),
+        ctx.accounts.switchboard_oracle.as_deref(),
+        ctx.remaining_accounts,
+    )?;
+    let order_size = order_base_size(cfg, &entry, price_fp)?;
+    let net = if netting {
+        net_order(&ctx.accounts.market, &ctx.accounts.user_position, order_size, is_long)
+    } else {
+        NetOrder { close_size: 0, open_size: order_size }
+    };
+    // No opens into a price jump. The refusal rolls back, so the jump is
+    // only taken as the new reference once a close or a calmer price lands.
+    // An order that only reduces goes through it as a close would.
+    if let Some(event) = circuit_breaker_event(&mut ctx.accounts.market, cfg, last_index_fp, price_fp)? {
+        emit!(event);
+        if net.open_size > 0 {
+            return err!(PerpsError::CircuitBreakerTriggered);
+        }
+    }
+    require!(net.open_size == 0 || !ctx.accounts.market.is_close_only(now), PerpsError::MarketCloseOnly);
+
+    // The trade walks the vAMM curve; one that slips too far from the mark,
+    // as a sandwich would make it, or drains the reserves is refused
+    let average_fill_fp = average_fill_price_fp(&ctx.accounts.market, price_fp, order_size, is_long)?;
+    let slippage_bps = check_slippage(price_fp, average_fill_fp, max_slippage_bps)?;
+    msg!("Average fill {} vs mark {} ({}bps)", average_fill_fp, price_fp, slippage_bps);
+
+    // The part of the order offsetting the held position is realized as a
+    // partial close would be, and paid out once everything else is settled
+    let owner = ctx.accounts.user.key();
+    let reduced = if net.close_size > 0 {
+        let market = &mut ctx.accounts.market;
+        market.ensure_holding_period(ctx.accounts.user_position.last_updated_ts, now)?;
+        let close_mark_fp = market.settlement_mark_fp(price_fp);
+        let slice = close_slice(
+            &mut ctx.accounts.config,
+            market,
+            &mut ctx.accounts.user_position,
+            net.close_size,
+            close_mark_fp,
+            ctx.accounts.vault_token.amount,
+            now,
+        )?;
+        // A flip keeps the slots for the side it opens
+        if slice.remaining_size == 0 && net.open_size == 0 {
+            ctx.accounts.protocol_stats.record_close();
+            ctx.accounts.user_account.record_close();
+        }
+        ctx.accounts.protocol_stats.record_fees(slice.fee_amt)?;
+        // Whatever stays open must still clear maintenance
+        oracle::health_check(
+            &ctx.accounts.oracle,
+            ctx.accounts.market.price_precision,
+            close_mark_fp,
+            slice.remaining_size,
+            ctx.accounts.user_position.equity_fp(&ctx.accounts.config, close_mark_fp)?,
+            ctx.accounts.market.maintenance_margin_bps_for(slice.remaining_size, now),
+            now,
+        )?;
+        throttle_outflow(
+            &ctx.accounts.config,
+            ctx.accounts.user_rate_limit.as_deref_mut(),
+            ctx.bumps.user_rate_limit,
+            owner,
+            slice.settlement_amt,
+            now,
+        )?;
+        Some(slice)
+    } else {
+        None
+    };
+    if net.open_size == 0 {
+        let slice = reduced.ok_or(PerpsError::PositionTooSmall)?;
+        ctx.accounts.user_position.exit(&crate::ID)?;
+        ctx.accounts.market.exit(&crate::ID)?;
+        ctx.accounts.config.exit(&crate::ID)?;
+        ctx.accounts.protocol_stats.exit(&crate::ID)?;
+        ctx.accounts.user_account.exit(&crate::ID)?;
+        ctx.accounts.pay_out_reduction(&slice)?;
+        ctx.accounts.emit_order_netted(is_long, order_size, &net, &slice);
+        emit!(ctx.accounts.protocol_stats.updated_event());
+        msg!("Order netted: closed {} units, PnL ${}, Settlement {}",
+             net.close_size, ctx.accounts.config.to_human_price(slice.pnl_fp), slice.settlement_amt);
+        return Ok(());
+    }
+
+    // A flip opens its excess with that share of the order's spend
+    let entry = match reduced {
+        Some(_) => entry_margin((entry.notional as u128 * net.open_size as u128 / order_size as u128) as u64, leverage_x)?,
+        None => entry,
+    };
+    let margin = entry.margin;
     require!(margin > 0, PerpsError::InsufficientMargin);
+    let cfg = &ctx.accounts.config;
+    let base_size_units = size_entry(cfg, &ctx.accounts.market, &entry, price_fp)?;
+    validate_bracket(is_long, price_fp, stop_loss_price_fp, take_profit_price_fp)?;
 
-    // Get current price and calculate position size
-    let price_fp = current_mark_price_fp(&ctx.accounts.market, &ctx.accounts.oracle)?;
-    let base_size_fp: u128 = (quote_to_spend as u128)
-        .checked_mul(FP)
-        .ok_or(PerpsError::MathOverflow)?
-        .checked_div(price_fp)
-        .ok_or(PerpsError::DivisionByZero)?;
-    
-    let base_size_units: u64 = (base_size_fp / FP) as u64;
-    require!(base_size_units > 0, PerpsError::PositionTooSmall);
-    require!(base_size_units <= ctx.accounts.market.max_position_base, PerpsError::MaxPositionExceeded);
+    // A cross position's margin is an allocation of the pooled collateral,
+    // which already sits in the cross vault. Brackets and per-market
+    // collateral settle margin per position, so they stay isolated-only.
+    let cross = margin_mode == MarginMode::Cross;
+    if cross {
+        require!(stop_loss_price_fp == 0 && take_profit_price_fp == 0, PerpsError::CrossMarginUnsupported);
+        require!(ctx.accounts.collateral_account.is_none(), PerpsError::CrossMarginUnsupported);
+        let pool = ctx.accounts.cross_margin_account.as_mut().ok_or(PerpsError::CrossMarginAccountRequired)?;
+        pool.allocate(margin)?;
+        pool.exit(&crate::ID)?;
+    }
+    // Margin can instead be pledged in another accepted mint. Its tokens sit
+    // in that mint's vault, so it is settled per position and only on the
+    // paths that know how to hand the tokens back.
+    let pledged = ctx.accounts.accepted_collateral.is_some();
+    if pledged {
+        require!(!cross && ctx.accounts.collateral_account.is_none(), PerpsError::PledgedCollateralUnsupported);
+        require!(stop_loss_price_fp == 0 && take_profit_price_fp == 0, PerpsError::PledgedCollateralUnsupported);
+    }
 
-    // Calculate liquidation price
-    let liquidation_price_fp = calculate_liquidation_price(
-        price_fp,
+    // The market's vault, plus its insurance fund when passed, must be able to
+    // pay this position out after a large move in its favour
+    let insurance_liquidity = match (ctx.accounts.insurance_fund.as_ref(), ctx.accounts.insurance_vault_token.as_ref()) {
+        (Some(fund), Some(vault)) if vault.key() == fund.vault_token_account => vault.amount,
+        _ => 0,
+    };
+    ensure_payout_backed(
         margin,
-        base_size_units,
-        ctx.accounts.market.maintenance_margin_bps,
-        is_long,
+        entry.notional,
+        ctx.accounts.market.max_favorable_move_bps,
+        ctx.accounts.vault_token.amount
+            .saturating_add(if cross || pledged { 0 } else { margin })
+            .saturating_add(insurance_liquidity),
     )?;
 
-    // Transfer margin from user to vault
-    token::transfer(
-        CpiContext::new(
-            ctx.accounts.token_program.to_account_info(), 
-            Transfer { 
-                from: ctx.accounts.user_token.to_account_info(), 
-                to: ctx.accounts.vault_token.to_account_info(), 
-                authority: ctx.accounts.user.to_account_info() 
-            }
-        ),
-        margin
-    )?;
+    // Opens that deepen the skew pay an immediate surcharge into the market's
+    // insurance fund, on top of the margin
+    let skew_surcharge_bps = open_skew_surcharge_bps(
+        ctx.accounts.market.total_long_size,
+        ctx.accounts.market.total_short_size,
+        base_size_units,
+        is_long,
+        ctx.accounts.market.skew_k_bps,
+        ctx.accounts.market.max_skew_surcharge_bps,
+    );
+    let skew_surcharge = (entry.notional as u128 * skew_surcharge_bps as u128 / 10_000) as u64;
+    if skew_surcharge > 0 {
+        let fund = ctx.accounts.insurance_fund.as_mut().ok_or(PerpsError::InsuranceFundRequired)?;
+        let fund_vault = ctx.accounts.insurance_vault_token.as_ref().ok_or(PerpsError::InsuranceFundRequired)?;
+        require_keys_eq!(fund_vault.key(), fund.vault_token_account, PerpsError::InvalidTokenAccount);
+        fund.record_deposit(skew_surcharge)?;
+        fund.exit(&crate::ID)?;
+    }
+    // A market open takes liquidity, so it pays the taker fee on its notional
+    let taker_fee = ctx.accounts.config.entry_fee(entry.notional, false).max(0) as u64;
+    ctx.accounts.protocol_stats.record_volume(entry.notional, taker_fee)?;
 
-    // Update market state (now we can borrow mutably)
-    let market = &mut ctx.accounts.market;
-    if is_long {
-        market.total_long_size = market.total_long_size
-            .checked_add(base_size_units)
-            .ok_or(PerpsError::MathOverflow)?;
+    // Book the position and the market's open interest
+    let market_key = ctx.accounts.market.key();
+    let booked_margin = ctx.accounts.user_position.vault_margin();
+    let increased = netting && reduced.is_none();
+    if increased {
+        increase_position(
+            &ctx.accounts.config,
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.user_position,
+            base_size_units,
+            &entry,
+            price_fp,
+            now,
+        )?;
     } else {
-        market.total_short_size = market.total_short_size
-            .checked_add(base_size_units)
-            .ok_or(PerpsError::MathOverflow)?;
+        write_open_position(
+            &ctx.accounts.config,
+            &mut ctx.accounts.market,
+            market_key,
+            &mut ctx.accounts.user_position,
+            owner,
+            ctx.bumps.user_position,
+            is_long,
+            base_size_units,
+            &entry,
+            price_fp,
+            now,
+        )?;
     }
-    
-    market.total_volume = market.total_volume
-        .checked_add(quote_to_spend as u128)
-        .ok_or(PerpsError::MathOverflow)?;
-
-    // Initialize user position
-    let up = &mut ctx.accounts.user_position;
-    up.owner = ctx.accounts.user.key();
-    up.market = ctx.accounts.market.key();
-    up.bump = ctx.bumps.user_position;
-    up.is_long = is_long;
-    up.base_size = if is_long { 
-        base_size_units as i64 
-    } else { 
-        -(base_size_units as i64) 
+    ctx.accounts.user_position.margin_mode = margin_mode;
+    ctx.accounts.user_position.collateral_pledged = pledged;
+    let vault_margin = ctx.accounts.user_position.vault_margin();
+    ctx.accounts.market.rebook_margin(booked_margin, vault_margin)?;
+    let pledge = if pledged {
+        let position_key = ctx.accounts.user_position.key();
+        let pledge_bump = ctx.bumps.position_collateral.ok_or(PerpsError::PledgedCollateralRequired)?;
+        let mut pledge = load_pledge(
+            &ctx.accounts.config.key(),
+            &owner,
+            ctx.accounts.accepted_collateral.as_deref_mut(),
+            ctx.accounts.position_collateral.as_deref_mut(),
+            ctx.accounts.collateral_vault.as_deref(),
+            ctx.accounts.collateral_user_token.as_deref(),
+            ctx.accounts.collateral_mint.as_deref(),
+        )?;
+        pledge.position_collateral.position = position_key;
+        pledge.position_collateral.bump = pledge_bump;
+        let tokens = pledge.add_margin(&ctx.accounts.config, ctx.accounts.collateral_oracle.as_deref(), margin)?;
+        pledge.exit()?;
+        Some((pledge, tokens))
+    } else {
+        None
     };
-    up.entry_price_fp = price_fp;
-    up.margin_deposited = margin;
-    up.last_funding_settled = Clock::get()?.unix_timestamp;
-    up.funding_debt_fp = 0;
-    up.liquidation_price_fp = liquidation_price_fp;
-    up.last_updated_ts = Clock::get()?.unix_timestamp;
-    up.realized_pnl_fp = 0;
-    up.total_fees_paid = 0;
+
+    // Cross margin was allocated from the pool above. Pledged margin moves
+    // into its mint's vault. Otherwise margin comes out of deposited
+    // collateral when the user passes it, or is transferred from user to
+    // vault once state is settled
+    if cross {
+        msg!("Margin {} allocated from cross collateral", margin);
+    } else if let Some((pledge, pledged_tokens)) = pledge {
+        pledge.pull(&ctx.accounts.token_program, &ctx.accounts.user, pledged_tokens)?;
+        emit!(CollateralPledged {
+            user: owner,
+            market: market_key,
+            mint: pledge.accepted.mint,
+            amount: pledged_tokens,
+            margin_value: margin,
+            total_pledged: pledge.position_collateral.amount,
+        });
+    } else if let Some(collateral) = ctx.accounts.collateral_account.as_mut() {
+        collateral.debit(margin)?;
+        collateral.exit(&crate::ID)?;
+        ctx.accounts.market.release_margin(margin)?;
+    } else {
+        ctx.accounts.transfer_user_to_vault(margin)?;
+    }
+    if skew_surcharge > 0 {
+        let fund_vault = ctx.accounts.insurance_vault_token.as_ref().ok_or(PerpsError::InsuranceFundRequired)?;
+        transfer_checked_exact(
+            &ctx.accounts.token_program,
+            &ctx.accounts.quote_mint,
+            ctx.accounts.user_token.to_account_info(),
+            fund_vault.to_account_info(),
+            ctx.accounts.user.to_account_info(),
+            &[],
+            skew_surcharge,
+        )?;
+    }
+    if taker_fee > 0 {
+        let fee_destination = ctx.accounts.fee_destination.as_ref().ok_or(PerpsError::FeeDestinationRequired)?;
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.quote_mint,
+            ctx.accounts.user_token.to_account_info(),
+            fee_destination.to_account_info(),
+            ctx.accounts.user.to_account_info(),
+            &[],
+            taker_fee,
+        )?;
+    }
+    // The closed side of a flip is paid out last, once its margin is in
+    if let Some(slice) = reduced.as_ref() {
+        ctx.accounts.pay_out_reduction(slice)?;
+        ctx.accounts.emit_order_netted(is_long, order_size, &net, slice);
+    }
+    let up = &ctx.accounts.user_position;
+    let (owner, market_key, position_key) = (up.owner, up.market, up.key());
 
     // Emit event
-    emit!(PositionOpened { 
-        user: up.owner, 
-        market: up.market, 
-        is_long, 
-        base_size: base_size_units, 
-        entry_price_fp: price_fp,
-        leverage: leverage_x,
-        margin_deposited: margin,
-    });
+    if increased {
+        emit!(OrderNetted {
+            user: owner,
+            market: market_key,
+            is_long,
+            order_size,
+            closed_size: 0,
+            opened_size: base_size_units,
+            entry_price_fp: up.entry_price_fp,
+            pnl_fp: 0,
+            fees_paid: taker_fee,
+            settlement_amount: 0,
+        });
+    } else {
+        emit!(PositionOpened { 
+            user: owner, 
+            market: market_key, 
+            is_long, 
+            base_size: base_size_units, 
+            entry_price_fp: price_fp,
+            leverage: leverage_x,
+            margin_deposited: margin,
+            skew_surcharge_bps,
+            skew_surcharge,
+            taker_fee,
+        });
+    }
+    emit!(ctx.accounts.protocol_stats.updated_event());
+
+    // Protective bracket, live from the moment the position is. Each newly
+    // armed leg takes one of the user's order slots.
+    let mut new_orders = 0;
+    if stop_loss_price_fp > 0 {
+        let bump = ctx.bumps.stop_loss_order.ok_or(PerpsError::InvalidStopLoss)?;
+        let order = ctx.accounts.stop_loss_order.as_mut().ok_or(PerpsError::InvalidStopLoss)?;
+        if !order.is_active {
+            new_orders += 1;
+        }
+        order.arm(owner, market_key, position_key, stop_loss_price_fp, 100, now, None, bump);
+        emit!(StopLossSet {
+            user: owner,
+            market: market_key,
+            trigger_price_fp: stop_loss_price_fp,
+            close_percentage: 100,
+            is_long,
+        });
+    }
+    if take_profit_price_fp > 0 {
+        let bump = ctx.bumps.take_profit_order.ok_or(PerpsError::InvalidTakeProfit)?;
+        let order = ctx.accounts.take_profit_order.as_mut().ok_or(PerpsError::InvalidTakeProfit)?;
+        if !order.is_active {
+            new_orders += 1;
+        }
+        order.arm(owner, market_key, position_key, take_profit_price_fp, 100, now, None, bump);
+        emit!(TakeProfitSet {
+            user: owner,
+            market: market_key,
+            trigger_price_fp: take_profit_price_fp,
+            close_percentage: 100,
+            is_long,
+        });
+    }
+    if new_orders > 0 {
+        let max_active_orders = ctx.accounts.config.max_active_orders_per_user;
+        let bump = ctx.bumps.user_orders.ok_or(PerpsError::UserOrdersRequired)?;
+        let orders = ctx.accounts.user_orders.as_mut().ok_or(PerpsError::UserOrdersRequired)?;
+        orders.owner = owner;
+        orders.bump = bump;
+        for _ in 0..new_orders {
+            orders.add_order(max_active_orders)?;
+        }
+    }
 
-    msg!("Position opened: {} {} units @ ${} with {}x leverage", 
+    msg!("Position {}: {} {} units @ ${} with {}x leverage", 
+         if increased { "increased" } else { "opened" },
          if is_long { "Long" } else { "Short" },
          base_size_units,
-         price_fp / FP,
+         ctx.accounts.config.to_human_price(price_fp as i128),
          leverage_x
     );
 
     Ok(())
 }
 
-pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+/// Run the market's trade-path circuit breaker on a fresh mark. The last
+/// recorded index is marked under the current skew too, so only the oracle's
+/// move counts. Returns the event to emit while the breaker is tripped.
+fn circuit_breaker_event(market: &mut Account<Market>, cfg: &Config, last_index_fp: u128, mark_fp: u128) -> Result<Option<CircuitBreakerTriggered>> {
+    let last_mark_fp = if last_index_fp > 0 { mark_from_index_fp(market, last_index_fp) } else { 0 };
+    let slot = Clock::get()?.slot;
+    let market_key = market.key();
+    Ok(market
+        .check_circuit_breaker(last_mark_fp, mark_fp, cfg.circuit_breaker_threshold_bps, slot)
+        .map(|price_change_bps| CircuitBreakerTriggered {
+            market: market_key,
+            price_change_bps,
+            old_price_fp: last_mark_fp,
+            new_price_fp: mark_fp,
+        }))
+}
+
+/// What a close settled, handed back as return data so CPI callers and
+/// simulating clients can read it without parsing `PositionClosed`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CloseOutcome {
+    pub pnl_fp: i128,                   // Realized PnL, funding included
+    pub fees_fp: u128,                  // Exit fee charged
+    pub settlement_amount: u64,         // Quote paid (or queued) to the trader
+}
+
+impl CloseOutcome {
+    pub const SPACE: usize = 16 + 16 + 8;
+}
+
+const _: () = assert!(CloseOutcome::SPACE <= anchor_lang::solana_program::program::MAX_RETURN_DATA);
+
+pub fn close_position<'info>(ctx: Context<'_, '_, 'info, 'info, ClosePosition<'info>>) -> Result<CloseOutcome> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
     let market = &mut ctx.accounts.market;
     require!(!market.is_paused, PerpsError::MarketPaused);
 
-    let mark_fp = current_mark_price_fp(market, &ctx.accounts.oracle)?;
+    let last_index_fp = market.last_index_price_fp();
+    let mark_fp = checked_mark_price_fp(
+        market,
+        &ctx.accounts.oracle,
+        ctx.accounts.pyth_oracle.as_deref(),
+        ctx.accounts.switchboard_oracle.as_deref(),
+        ctx.remaining_accounts,
+    )?;
+    // Closes still go through a tripped breaker, but leave it tripped for this slot
+    if let Some(event) = circuit_breaker_event(market, &ctx.accounts.config, last_index_fp, mark_fp)? {
+        emit!(event);
+    }
+    // Expired markets settle at their pinned final price
+    let mark_fp = market.settlement_mark_fp(mark_fp);
 
     // Read values from user_position first
     let position = &ctx.accounts.user_position;
@@ -130,83 +487,544 @@ pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
     let user_owner = position.owner;
     let user_market = position.market;
     let is_long = position.is_long;
-    let base_size_abs = signed_base.abs() as u64;
+    let base_size_abs = signed_base.unsigned_abs() as u64;
+    let cross = position.margin_mode == MarginMode::Cross;
+
+    position.ensure_status(&[PositionStatus::Open])?;
 
-    require!(signed_base != 0, PerpsError::PositionNotFound);
+    // A cross position settles against the pooled collateral: its equity is
+    // the pool plus its PnL, and whatever is left stays in the pool
+    let pool_before = if cross {
+        require!(ctx.accounts.cross_vault.is_some(), PerpsError::CrossMarginAccountRequired);
+        let pool = ctx.accounts.cross_margin_account.as_ref().ok_or(PerpsError::CrossMarginAccountRequired)?;
+        Some(pool.collateral_balance)
+    } else {
+        None
+    };
+
+    // Outstanding funding is settled first and realized as part of the PnL
+    let now = Clock::get()?.unix_timestamp;
+    market.ensure_holding_period(ctx.accounts.user_position.last_updated_ts, now)?;
+    ctx.accounts.user_position.settle_funding(market, now)?;
+    let funding_fp = std::mem::take(&mut ctx.accounts.user_position.funding_debt_fp);
 
     // Calculate PnL
-    let notional_entry_fp = (signed_base.abs() as i128) * entry_fp; 
-    let notional_exit_fp = (signed_base.abs() as i128) * (mark_fp as i128);
+    let notional_entry_fp = signed_base.abs() * entry_fp;
+    let notional_exit_fp = signed_base.abs() * (mark_fp as i128);
     let direction = if signed_base >= 0 { 1 } else { -1 };
-    let pnl_fp: i128 = direction * (notional_exit_fp - notional_entry_fp) / FP as i128;
+    let pnl_fp: i128 = direction * (notional_exit_fp - notional_entry_fp) - funding_fp;
 
-    // Calculate fees
-    let fee_fp: u128 = (notional_exit_fp.unsigned_abs() * (ctx.accounts.config.fee_bps as u128)) / 10_000u128;
-    let fee_amt: u64 = (fee_fp / FP) as u64;
-
-    // Calculate settlement amount
-    let mut settle_fp: i128 = (margin_deposited as i128) * (FP as i128) + pnl_fp - (fee_fp as i128);
-    if settle_fp < 0 { settle_fp = 0; }
-    let settle_amt: u64 = (settle_fp as u128 / FP) as u64;
+    // Fee on exit notional, paid out of the position's equity. Whitelisted
+    // market makers pay none and are rebated out of the fee pool instead.
+    let is_maker = ctx.accounts.market_maker.is_some();
+    let cfg = &mut ctx.accounts.config;
+    let settlement = close_settlement(
+        cfg.quote_to_fp(pool_before.unwrap_or(margin_deposited))?,
+        pnl_fp,
+        notional_exit_fp.unsigned_abs(),
+        if is_maker { 0 } else { cfg.fee_bps },
+    );
+    let fee_fp = settlement.fee_fp;
+    let fee_amt: u64 = cfg.settle_to_quote(fee_fp)?;
+    let settle_amt: u64 = cfg.settle_to_quote(settlement.payout_fp)?;
+    if settlement.shortfall_fp > 0 {
+        msg!("Close left a shortfall of ${}", cfg.to_human_price(settlement.shortfall_fp as i128));
+    }
 
-    // Update market state
-    if is_long {
-        market.total_long_size = market.total_long_size.saturating_sub(base_size_abs);
+    // Pledged margin is handed back in kind: only the gain beyond it is paid
+    // in quote, and a loss keeps its share of the tokens
+    let pledge = if ctx.accounts.user_position.collateral_pledged {
+        let mut pledge = load_pledge(
+            &ctx.accounts.config.key(),
+            &user_owner,
+            ctx.accounts.accepted_collateral.as_deref_mut(),
+            ctx.accounts.position_collateral.as_deref_mut(),
+            ctx.accounts.collateral_vault.as_deref(),
+            ctx.accounts.collateral_user_token.as_deref(),
+            ctx.accounts.collateral_mint.as_deref(),
+        )?;
+        let split = pledge.settle(margin_deposited, settle_amt)?;
+        pledge.exit()?;
+        Some((pledge, split))
     } else {
-        market.total_short_size = market.total_short_size.saturating_sub(base_size_abs);
+        None
+    };
+    let settle_amt = pledge.as_ref().map_or(settle_amt, |(_, split)| split.quote);
+
+    // Effects: settle market, position and any queued payout before transferring
+    market.reduce_open_interest(is_long, base_size_abs);
+    market.record_settlement(pnl_fp, fee_fp)?;
+    let queued = !cross && market.queues_withdrawal(settle_amt);
+    let delay = market.withdrawal_delay_seconds;
+    // Rounding can leave the vault a unit short; a queued payout is cushioned
+    // when claimed, and a cross payout never leaves the program
+    let (paid_now, fee_amt) = ctx.accounts.config.cushion_payout(
+        if queued || cross { 0 } else { settle_amt },
+        fee_amt,
+        ctx.accounts.vault_token.amount,
+    )?;
+    let settle_amt = if queued || cross { settle_amt } else { paid_now };
+    let vault_margin = ctx.accounts.user_position.vault_margin();
+    ctx.accounts.user_position.settle_full_close(pnl_fp, fee_amt, now);
+    ctx.accounts.protocol_stats.record_close();
+    ctx.accounts.protocol_stats.record_fees(fee_amt)?;
+    ctx.accounts.user_account.record_close();
+    if let Some(pool) = ctx.accounts.cross_margin_account.as_mut().filter(|_| cross) {
+        pool.release(margin_deposited, settle_amt);
+        pool.exit(&crate::ID)?;
     }
+    let fee_forwarded = ctx.accounts.market.retain_creator_share(fee_amt, ctx.accounts.config.creator_reward_bps)?;
+    // A payout kept as deposited collateral never leaves the vault
+    let to_collateral = !queued && !cross && ctx.accounts.collateral_account.is_some();
+    if let Some(collateral) = ctx.accounts.collateral_account.as_mut().filter(|_| to_collateral) {
+        collateral.credit(settle_amt)?;
+        collateral.exit(&crate::ID)?;
+    }
+    // So does a queued one, until it is claimed
+    ctx.accounts.market.rebook_margin(vault_margin, if queued || to_collateral { settle_amt } else { 0 })?;
+    // A queued payout is throttled when it is claimed
+    if !queued && !to_collateral && !cross {
+        throttle_outflow(
+            &ctx.accounts.config,
+            ctx.accounts.user_rate_limit.as_mut(),
+            ctx.bumps.user_rate_limit,
+            user_owner,
+            settle_amt,
+            now,
+        )?;
+    }
+
+    if queued {
+        let user_bump = ctx.bumps.pending_withdrawal;
+        let pending = ctx.accounts.pending_withdrawal.as_mut()
+            .ok_or(PerpsError::PendingWithdrawalRequired)?;
+        pending.owner = user_owner;
+        pending.market = user_market;
+        pending.bump = user_bump.ok_or(PerpsError::PendingWithdrawalRequired)?;
+        pending.enqueue(settle_amt, now, delay)?;
+        ctx.accounts.user_position.status = PositionStatus::PendingSettlement;
 
-    // Execute transfers
-    if settle_amt > 0 {
-        token::transfer(ctx.accounts.transfer_vault_to_user(), settle_amt)?;
+        emit!(WithdrawalQueued {
+            user: user_owner,
+            market: user_market,
+            amount: settle_amt,
+            total_pending: pending.remaining(),
+            available_at: pending.available_at,
+        });
+        pending.exit(&crate::ID)?;
     }
-    if fee_amt > 0 {
-        token::transfer(ctx.accounts.transfer_vault_to_fee_dest(), fee_amt)?;
+
+    // The rebate can only come out of a fee pool the program controls
+    let config_key = ctx.accounts.config.key();
+    let fee_pool = &ctx.accounts.fee_destination;
+    let rebate_amt = if is_maker && fee_pool.owner == config_key {
+        ctx.accounts.config.maker_rebate(notional_exit_fp.unsigned_abs(), fee_pool.amount)?
+    } else {
+        0
+    };
+    if rebate_amt > 0 {
+        let cfg = &mut ctx.accounts.config;
+        cfg.total_mm_rebates_paid = cfg.total_mm_rebates_paid
+            .checked_add(rebate_amt)
+            .ok_or(PerpsError::MathOverflow)?;
+        if let Some(maker) = ctx.accounts.market_maker.as_mut() {
+            maker.total_rebates_paid = maker.total_rebates_paid
+                .checked_add(rebate_amt)
+                .ok_or(PerpsError::MathOverflow)?;
+            maker.exit(&crate::ID)?;
+        }
     }
 
-    // Update position
-    let up = &mut ctx.accounts.user_position;
-    up.realized_pnl_fp += pnl_fp;
-    up.total_fees_paid += fee_amt;
-    up.base_size = 0; 
-    up.margin_deposited = 0;
-    up.last_updated_ts = Clock::get()?.unix_timestamp;
+    // Persist settled state so nothing reachable from the CPIs sees a stale position
+    ctx.accounts.market.exit(&crate::ID)?;
+    ctx.accounts.user_position.exit(&crate::ID)?;
+    ctx.accounts.config.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+    ctx.accounts.user_account.exit(&crate::ID)?;
+
+    // Interactions: large payouts wait in the market's withdrawal queue
+    if let Some((pledge, split)) = pledge {
+        pledge.push(&ctx.accounts.token_program, &ctx.accounts.config, split.returned)?;
+        emit!(PledgedCollateralReleased {
+            user: user_owner,
+            market: user_market,
+            mint: pledge.accepted.mint,
+            returned: split.returned,
+            seized: split.seized,
+            remaining: pledge.position_collateral.amount,
+        });
+    }
+    let config_bump = ctx.accounts.config.bump;
+    let signer_seeds: &[&[&[u8]]] = &[&[CONFIG_SEED, &[config_bump]]];
+    if !queued && !to_collateral && !cross && settle_amt > 0 {
+        ctx.accounts.transfer_vault_to_user(signer_seeds, settle_amt)?;
+    }
+    if let (Some(balance_before), Some(cross_vault)) = (pool_before, ctx.accounts.cross_vault.as_ref()) {
+        settle_cross_balance(
+            &ctx.accounts.config,
+            &ctx.accounts.token_program,
+            &ctx.accounts.quote_mint,
+            &ctx.accounts.vault_token,
+            cross_vault,
+            balance_before,
+            settle_amt,
+        )?;
+    }
+    // The market's insurance fund takes its slice of the fee while under target
+    let insurance_fee = route_fee_to_insurance(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        &ctx.accounts.config,
+        &ctx.accounts.vault_token,
+        &mut ctx.accounts.insurance_fund,
+        &ctx.accounts.insurance_vault_token,
+        user_owner,
+        fee_forwarded,
+    )?;
+    let fee_forwarded = fee_forwarded - insurance_fee;
+    if fee_forwarded > 0 {
+        ctx.accounts.transfer_vault_to_fee_dest(signer_seeds, fee_forwarded)?;
+    }
+    if rebate_amt > 0 {
+        ctx.accounts.transfer_fee_dest_to_user(signer_seeds, rebate_amt)?;
+        emit!(MakerRebatePaid {
+            maker: user_owner,
+            market: user_market,
+            notional_fp: notional_exit_fp.unsigned_abs(),
+            rebate_amount: rebate_amt,
+            rebate_bps: ctx.accounts.config.mm_rebate_bps,
+            total_rebates_paid: ctx.accounts.config.total_mm_rebates_paid,
+        });
+    }
+    vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
 
+    if funding_fp != 0 {
+        emit!(FundingPaid {
+            user: user_owner,
+            market: user_market,
+            funding_amount_fp: funding_fp,
+            funding_rate_fp: ctx.accounts.market.funding_rate_fp,
+        });
+    }
+    let outcome = CloseOutcome {
+        pnl_fp,
+        fees_fp: fee_fp,
+        settlement_amount: if cross { 0 } else { settle_amt },
+    };
     emit!(PositionClosed { 
         user: user_owner, 
         market: user_market, 
         pnl_fp, 
-        fees_fp: fee_fp,
+        fees_fp: outcome.fees_fp,
+        settlement_amount: outcome.settlement_amount,
+    });
+    emit!(ctx.accounts.protocol_stats.updated_event());
+
+    msg!("Position closed: PnL ${}, Fees {}, Settlement {}", 
+         ctx.accounts.config.to_human_price(pnl_fp), fee_amt, settle_amt);
+
+    Ok(outcome)
+}
+
+/// Close a long and a short in the same market together, e.g. two
+/// subaccounts of one trader. The offsetting size is netted instead of
+/// realized twice: one fee on the net size and one transfer of the pooled
+/// equity to `user`. Payouts large enough to queue go through `close_position`.
+pub fn close_netted<'info>(ctx: Context<'_, '_, 'info, 'info, CloseNetted<'info>>) -> Result<()> {
+    ensure_current_versions(
+        &ctx.accounts.config,
+        &ctx.accounts.market,
+        &[&ctx.accounts.user_position, &ctx.accounts.hedge_position],
+    )?;
+    require!(!ctx.accounts.market.is_paused, PerpsError::MarketPaused);
+    ctx.accounts.user_position.ensure_status(&[PositionStatus::Open])?;
+    ctx.accounts.hedge_position.ensure_status(&[PositionStatus::Open])?;
+    ctx.accounts.user_position.ensure_isolated()?;
+    ctx.accounts.user_position.ensure_quote_margin()?;
+    ctx.accounts.hedge_position.ensure_isolated()?;
+    ctx.accounts.hedge_position.ensure_quote_margin()?;
+    require!(
+        ctx.accounts.user_position.is_long != ctx.accounts.hedge_position.is_long,
+        PerpsError::PositionsNotOpposed
+    );
+    let now = Clock::get()?.unix_timestamp;
+    for position in [&ctx.accounts.user_position, &ctx.accounts.hedge_position] {
+        ctx.accounts.market.ensure_holding_period(position.last_updated_ts, now)?;
+    }
+
+    let mark_fp = checked_mark_price_fp(
+        &mut ctx.accounts.market,
+        &ctx.accounts.oracle,
+        ctx.accounts.pyth_oracle.as_deref(),
+        ctx.accounts.switchboard_oracle.as_deref(),
+        ctx.remaining_accounts,
+    )?;
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(mark_fp);
+
+    let legs = [&ctx.accounts.user_position, &ctx.accounts.hedge_position];
+    let base_sizes = legs.map(|up| up.base_size);
+    let pnls_fp = legs.map(|up| up.base_size as i128 * (mark_fp as i128 - up.entry_price_fp as i128));
+    let cfg = &mut ctx.accounts.config;
+    let margins_fp = [
+        cfg.quote_to_fp(legs[0].margin_deposited)?,
+        cfg.quote_to_fp(legs[1].margin_deposited)?,
+    ];
+    let settlement = netted_close_settlement(base_sizes, margins_fp, pnls_fp, mark_fp, cfg.fee_bps);
+    let net_size = (base_sizes[0] as i128 + base_sizes[1] as i128).unsigned_abs() as u64;
+    let pnl_fp = pnls_fp[0] + pnls_fp[1];
+    let fee_amt = cfg.settle_to_quote(settlement.fee_fp)?;
+    let settle_amt = cfg.settle_to_quote(settlement.payout_fp)?;
+    require!(!ctx.accounts.market.queues_withdrawal(settle_amt), PerpsError::ExceedsRiskLimits);
+    if settlement.shortfall_fp > 0 {
+        msg!("Netted close left a shortfall of ${}", cfg.to_human_price(settlement.shortfall_fp as i128));
+    }
+
+    // Effects: both legs, the market and the protocol count settle before any transfer
+    let (settle_amt, fee_amt) = ctx.accounts.config.cushion_payout(settle_amt, fee_amt, ctx.accounts.vault_token.amount)?;
+    let market = &mut ctx.accounts.market;
+    market.reduce_open_interest(ctx.accounts.user_position.is_long, base_sizes[0].unsigned_abs());
+    market.reduce_open_interest(ctx.accounts.hedge_position.is_long, base_sizes[1].unsigned_abs());
+    market.record_settlement(pnl_fp, settlement.fee_fp)?;
+    let fee_forwarded = market.retain_creator_share(fee_amt, ctx.accounts.config.creator_reward_bps)?;
+    market.release_margin(ctx.accounts.user_position.vault_margin())?;
+    market.release_margin(ctx.accounts.hedge_position.vault_margin())?;
+    ctx.accounts.user_position.settle_full_close(pnls_fp[0], fee_amt, now);
+    ctx.accounts.hedge_position.settle_full_close(pnls_fp[1], 0, now);
+    ctx.accounts.protocol_stats.record_close();
+    ctx.accounts.protocol_stats.record_close();
+    ctx.accounts.protocol_stats.record_fees(fee_amt)?;
+    emit!(ctx.accounts.protocol_stats.updated_event());
+    ctx.accounts.user_account.record_close();
+    ctx.accounts.hedge_user_account.record_close();
+    let user = ctx.accounts.user.key();
+    throttle_outflow(
+        &ctx.accounts.config,
+        ctx.accounts.user_rate_limit.as_mut(),
+        ctx.bumps.user_rate_limit,
+        user,
+        settle_amt,
+        now,
+    )?;
+
+    ctx.accounts.market.exit(&crate::ID)?;
+    ctx.accounts.user_position.exit(&crate::ID)?;
+    ctx.accounts.hedge_position.exit(&crate::ID)?;
+    ctx.accounts.config.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+    ctx.accounts.user_account.exit(&crate::ID)?;
+    ctx.accounts.hedge_user_account.exit(&crate::ID)?;
+
+    // Interactions
+    let config_bump = ctx.accounts.config.bump;
+    let signer_seeds: &[&[&[u8]]] = &[&[CONFIG_SEED, &[config_bump]]];
+    for (to, amount) in [(&ctx.accounts.user_token, settle_amt), (&ctx.accounts.fee_destination, fee_forwarded)] {
+        if amount > 0 {
+            transfer_checked(
+                &ctx.accounts.token_program,
+                &ctx.accounts.quote_mint,
+                ctx.accounts.vault_token.to_account_info(),
+                to.to_account_info(),
+                ctx.accounts.config.to_account_info(),
+                signer_seeds,
+                amount,
+            )?;
+        }
+    }
+    vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+
+    emit!(PositionsNetted {
+        user,
+        hedge_owner: ctx.accounts.hedge_owner.key(),
+        market: ctx.accounts.market.key(),
+        net_size,
+        pnl_fp,
+        fees_fp: settlement.fee_fp,
         settlement_amount: settle_amt,
     });
 
-    msg!("Position closed: PnL ${}, Fees ${}, Settlement ${}", 
-         pnl_fp / FP as i128, fee_amt, settle_amt);
+    msg!("Netted close: net size {}, PnL ${}, Fees {}, Settlement {}",
+         net_size, ctx.accounts.config.to_human_price(pnl_fp), fee_amt, settle_amt);
 
     Ok(())
 }
 
-/// Calculate liquidation price for a position
-fn calculate_liquidation_price(
-    entry_price_fp: u128,
-    _margin: u64,
-    _base_size: u64,
-    maintenance_margin_bps: u16,
+/// Base units `entry.notional` buys at `price_fp`, before any size limit
+pub(crate) fn order_base_size(cfg: &Config, entry: &EntryMargin, price_fp: u128) -> Result<u64> {
+    let notional_fp = cfg.quote_to_fp(entry.notional)?;
+    let base_size_units: u64 = notional_fp
+        .checked_div(price_fp)
+        .ok_or(PerpsError::DivisionByZero)?
+        .try_into()
+        .map_err(|_| PerpsError::MathOverflow)?;
+    require!(base_size_units > 0, PerpsError::PositionTooSmall);
+    Ok(base_size_units)
+}
+
+/// Base units `entry.notional` buys at `price_fp`, held to the market's size
+/// limits. The leverage cap must hold on what was actually filled, not just
+/// on the request.
+pub(crate) fn size_entry(cfg: &Config, market: &Market, entry: &EntryMargin, price_fp: u128) -> Result<u64> {
+    let base_size_units = order_base_size(cfg, entry, price_fp)?;
+    require!(base_size_units > 0 && base_size_units >= market.min_position_base, PerpsError::PositionTooSmall);
+    require!(base_size_units <= market.max_position_base, PerpsError::MaxPositionExceeded);
+    market.ensure_within_concentration(base_size_units)?;
+
+    let leverage_cap_x = market.taker_leverage_cap_x.min(MAX_LEVERAGE_X as u16);
+    ensure_effective_leverage(
+        base_size_units as u128 * price_fp,
+        cfg.quote_to_fp(entry.margin)?,
+        leverage_cap_x,
+        EFFECTIVE_LEVERAGE_TOLERANCE_BPS,
+    )?;
+    Ok(base_size_units)
+}
+
+/// Grow the market's open interest and volume by a fresh position and write
+/// it into `up` from scratch, funding snapshot and liquidation price included
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_open_position(
+    cfg: &Config,
+    market: &mut Market,
+    market_key: Pubkey,
+    up: &mut UserPosition,
+    owner: Pubkey,
+    bump: u8,
     is_long: bool,
-) -> Result<u128> {
-    let maintenance_ratio = maintenance_margin_bps as u128;
+    base_size_units: u64,
+    entry: &EntryMargin,
+    price_fp: u128,
+    now: i64,
+) -> Result<()> {
+    // The position must open above maintenance at the tier its size lands in
+    let maintenance_margin_bps = market.upcoming_maintenance_margin_bps_for(base_size_units);
+    let margin_fp = cfg.quote_to_fp(entry.margin)?;
+    crate::oracle::check_maintenance(price_fp, base_size_units, margin_fp as i128, maintenance_margin_bps)?;
+    let liquidation_price_fp = liquidation_price_fp(
+        price_fp,
+        margin_fp,
+        base_size_units,
+        maintenance_margin_bps,
+        is_long,
+    )?;
 
-    let liq_price_fp = if is_long {
-        // For longs: liq_price = entry_price * (margin - maintenance) / margin
-        let numerator = entry_price_fp * (10_000 - maintenance_ratio);
-        numerator.checked_div(10_000).ok_or(PerpsError::DivisionByZero)?
-    } else {
-        // For shorts: liq_price = entry_price * (margin + maintenance) / margin  
-        let numerator = entry_price_fp * (10_000 + maintenance_ratio);
-        numerator.checked_div(10_000).ok_or(PerpsError::DivisionByZero)?
+    market.increase_open_interest(is_long, base_size_units)?;
+    market.total_volume = market.total_volume
+        .checked_add(entry.notional as u128)
+        .ok_or(PerpsError::MathOverflow)?;
+
+    up.owner = owner;
+    up.market = market_key;
+    up.bump = bump;
+    up.is_long = is_long;
+    up.base_size = if is_long { 
+        base_size_units as i64 
+    } else { 
+        -(base_size_units as i64) 
     };
+    up.entry_price_fp = price_fp;
+    up.margin_deposited = entry.margin;
+    up.last_funding_settled = now;
+    up.funding_debt_fp = 0;
+    up.last_cumulative_funding_fp = market.cumulative_funding_fp(is_long);
+    up.liquidation_price_fp = liquidation_price_fp;
+    up.last_updated_ts = now;
+    up.realized_pnl_fp = 0;
+    up.total_fees_paid = 0;
+    up.liquidatable_since_ts = 0;
+    up.status = PositionStatus::Open;
+    up.opened_at_ts = now;
+    up.version = ACCOUNT_VERSION;
+    up.margin_mode = MarginMode::Isolated;
+    up.collateral_pledged = false;
+    Ok(())
+}
 
-    Ok(liq_price_fp)
+/// How an order nets against the position already open in its market
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct NetOrder {
+    pub close_size: u64, // Units of the held position realized
+    pub open_size: u64,  // Units added on the order's side, past any close
+}
+
+/// Net an order of `order_size` on `order_is_long` against `up`. The same
+/// side adds to it. The other side reduces it, grown to a full close when the
+/// remainder would be dust, and any excess opens the other way. An excess
+/// below `min_position_base` is dropped rather than opened as dust.
+pub(crate) fn net_order(market: &Market, up: &UserPosition, order_size: u64, order_is_long: bool) -> NetOrder {
+    let held = up.base_size.unsigned_abs();
+    if order_is_long == up.is_long {
+        return NetOrder { close_size: 0, open_size: order_size };
+    }
+    if order_size <= held {
+        return NetOrder { close_size: market.dust_free_close_size(held, order_size), open_size: 0 };
+    }
+    let excess = order_size - held;
+    let open_size = if excess >= market.min_position_base.max(1) { excess } else { 0 };
+    NetOrder { close_size: held, open_size }
+}
+
+/// Add `added_size` entered at `price_fp` with `entry`'s margin to the open
+/// position `up`. Funding is settled at the old size first, the entry price
+/// becomes the size-weighted average, and the grown position must clear
+/// maintenance and the market's size limits.
+pub(crate) fn increase_position(
+    cfg: &Config,
+    market: &mut Market,
+    up: &mut UserPosition,
+    added_size: u64,
+    entry: &EntryMargin,
+    price_fp: u128,
+    now: i64,
+) -> Result<()> {
+    let held = up.base_size.unsigned_abs();
+    let new_size = held.checked_add(added_size).ok_or(PerpsError::MathOverflow)?;
+    require!(new_size <= market.max_position_base, PerpsError::MaxPositionExceeded);
+    require!(new_size <= i64::MAX as u64, PerpsError::MathOverflow);
+    market.ensure_increase_within_concentration(held, added_size)?;
+    up.settle_funding(market, now)?;
+
+    let is_long = up.is_long;
+    up.entry_price_fp = average_entry_price_fp(held, up.entry_price_fp, added_size, price_fp, is_long)?;
+    up.base_size = if is_long { new_size as i64 } else { -(new_size as i64) };
+    up.margin_deposited = up.margin_deposited.checked_add(entry.margin).ok_or(PerpsError::MathOverflow)?;
+    let maintenance_margin_bps = market.upcoming_maintenance_margin_bps_for(new_size);
+    crate::oracle::check_maintenance(price_fp, new_size, up.equity_fp(cfg, price_fp)?, maintenance_margin_bps)?;
+    up.liquidation_price_fp = liquidation_price_fp(
+        up.entry_price_fp,
+        cfg.quote_to_fp(up.margin_deposited)?,
+        new_size,
+        maintenance_margin_bps,
+        is_long,
+    )?;
+    up.last_updated_ts = now;
+
+    market.increase_open_interest(is_long, added_size)?;
+    market.total_volume = market.total_volume
+        .checked_add(entry.notional as u128)
+        .ok_or(PerpsError::MathOverflow)?;
+    Ok(())
+}
+
+/// Count an open against `max_positions_per_user` and `max_total_positions`,
+/// reporting whichever limit refuses it
+pub(crate) fn take_position_slots(user_account: &mut UserAccount, stats: &mut ProtocolStats, cfg: &Config, market: Pubkey) -> Result<()> {
+    let user = user_account.owner;
+    let limit_exceeded = |limit_type: &str, current_value: u32, limit_value: u32| emit!(RiskLimitExceeded {
+        user,
+        market,
+        limit_type: limit_type.to_string(),
+        current_value: current_value as u64,
+        limit_value: limit_value as u64,
+    });
+    if let Err(e) = user_account.record_open(cfg.max_positions_per_user) {
+        limit_exceeded("max_positions_per_user", user_account.open_position_count, cfg.max_positions_per_user);
+        return Err(e);
+    }
+    if let Err(e) = stats.record_open(cfg.max_total_positions) {
+        limit_exceeded("max_total_positions", stats.active_positions, cfg.max_total_positions);
+        return Err(e);
+    }
+    Ok(())
 }
 
 #[derive(Accounts)]
@@ -215,6 +1033,7 @@ pub struct OpenPosition<'info> {
     pub user: Signer<'info>,
     
     #[account(
+        mut,
         seeds = [CONFIG_SEED],
         bump = config.bump
     )]
@@ -225,6 +1044,13 @@ pub struct OpenPosition<'info> {
     
     pub oracle: Account<'info, OraclePrice>,
     
+    /// CHECK: must match market.pyth_oracle when the market has one; parsed in parse_pyth_price.
+    /// Registered fallback feeds may follow in remaining_accounts.
+    pub pyth_oracle: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: must match market.switchboard_oracle when the market has one; parsed in parse_switchboard_price.
+    pub switchboard_oracle: Option<UncheckedAccount<'info>>,
+    
     #[account(
         init_if_needed, 
         payer = user, 
@@ -233,28 +1059,194 @@ pub struct OpenPosition<'info> {
         bump
     )]
     pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserAccount::SPACE,
+        seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_account: Box<Account<'info, UserAccount>>,
     
-    #[account(mut)] 
-    pub user_token: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
     
-    #[account(mut)] 
-    pub vault_token: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
     
-    pub token_program: Program<'info, Token>,
+    // Optional bracket legs, required when the matching price is set
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = StopLossOrder::SPACE,
+        seeds = [STOP_LOSS_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub stop_loss_order: Option<Box<Account<'info, StopLossOrder>>>,
+    
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = TakeProfitOrder::SPACE,
+        seeds = [TAKE_PROFIT_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub take_profit_order: Option<Box<Account<'info, TakeProfitOrder>>>,
+    
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserOrders::SPACE,
+        seeds = [USER_ORDERS_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_orders: Option<Box<Account<'info, UserOrders>>>,
+
+    /// This market's insurance fund, required when the open pays a skew surcharge
+    #[account(
+        mut,
+        seeds = [INSURANCE_FUND_SEED, market.key().as_ref()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Option<Box<Account<'info, InsuranceFund>>>,
+
+    #[account(mut)]
+    pub insurance_vault_token: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    /// Deposited collateral to draw the margin from instead of user_token
+    #[account(
+        mut,
+        seeds = [COLLATERAL_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump = collateral_account.bump
+    )]
+    pub collateral_account: Option<Box<Account<'info, CollateralAccount>>>,
+
+    /// Pooled collateral, required to open in cross margin mode
+    #[account(
+        mut,
+        seeds = [CROSS_MARGIN_SEED, user.key().as_ref()],
+        bump = cross_margin_account.bump
+    )]
+    pub cross_margin_account: Option<Box<Account<'info, CrossMarginAccount>>>,
+
+    /// Accepted mint to pledge as margin instead of quote tokens
+    #[account(
+        mut,
+        seeds = [ACCEPTED_COLLATERAL_SEED, accepted_collateral.mint.as_ref()],
+        bump = accepted_collateral.bump
+    )]
+    pub accepted_collateral: Option<Box<Account<'info, AcceptedCollateral>>>,
+
+    /// The accepted mint's price feed, checked against accepted_collateral.oracle
+    pub collateral_oracle: Option<Box<Account<'info, OraclePrice>>>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = PositionCollateral::SPACE,
+        seeds = [POSITION_COLLATERAL_SEED, user_position.key().as_ref()],
+        bump
+    )]
+    pub position_collateral: Option<Box<Account<'info, PositionCollateral>>>,
+
+    #[account(
+        mut,
+        seeds = [COLLATERAL_VAULT_SEED, collateral_vault.mint.as_ref()],
+        bump
+    )]
+    pub collateral_vault: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    #[account(mut)]
+    pub collateral_user_token: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    /// The pledged mint, checked against accepted_collateral.mint
+    pub collateral_mint: Option<Box<InterfaceAccount<'info, Mint>>>,
+
+    /// The configured fee account, required when a taker fee is charged or
+    /// the order reduces a held position
+    #[account(mut, address = config.fee_destination @ PerpsError::InvalidTokenAccount)]
+    pub fee_destination: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    /// Only needed while withdrawals are rate limited and the order reduces a held position
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRateLimit::SPACE,
+        seeds = [USER_RATE_LIMIT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_rate_limit: Option<Box<Account<'info, UserRateLimit>>>,
+    
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
 }
 
 impl<'info> OpenPosition<'info> {
-    pub fn transfer_user_to_vault(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        CpiContext::new(
-            self.token_program.to_account_info(), 
-            Transfer { 
-                from: self.user_token.to_account_info(), 
-                to: self.vault_token.to_account_info(), 
-                authority: self.user.to_account_info() 
-            }
+    /// The vault books the margin, so the user covers any transfer fee on it
+    pub fn transfer_user_to_vault(&self, amount: u64) -> Result<u64> {
+        transfer_checked_exact(
+            &self.token_program,
+            &self.quote_mint,
+            self.user_token.to_account_info(),
+            self.vault_token.to_account_info(),
+            self.user.to_account_info(),
+            &[],
+            amount,
         )
     }
+
+    /// Pay the part of an order that reduced the held position out of the
+    /// vault, as a partial close does
+    pub(crate) fn pay_out_reduction(&self, slice: &SliceClose) -> Result<()> {
+        let fee_destination = self.fee_destination.as_deref().ok_or(PerpsError::FeeDestinationRequired)?;
+        pay_out_slice(
+            &self.config,
+            &self.market,
+            &self.token_program,
+            &self.quote_mint,
+            &self.vault_token,
+            &self.user_token,
+            fee_destination,
+            slice.settlement_amt,
+            slice.fee_forwarded,
+        )
+    }
+
+    /// Report an order that reduced, closed or flipped the held position
+    pub(crate) fn emit_order_netted(&self, is_long: bool, order_size: u64, net: &NetOrder, slice: &SliceClose) {
+        emit!(OrderNetted {
+            user: self.user.key(),
+            market: self.market.key(),
+            is_long,
+            order_size,
+            closed_size: net.close_size,
+            opened_size: net.open_size,
+            entry_price_fp: self.user_position.entry_price_fp,
+            pnl_fp: slice.pnl_fp,
+            fees_paid: slice.fee_amt,
+            settlement_amount: slice.settlement_amt,
+        });
+    }
 }
 
 #[derive(Accounts)]
@@ -263,6 +1255,7 @@ pub struct ClosePosition<'info> {
     pub user: Signer<'info>,
     
     #[account(
+        mut,
         seeds = [CONFIG_SEED],
         bump = config.bump
     )]
@@ -273,46 +1266,480 @@ pub struct ClosePosition<'info> {
     
     pub oracle: Account<'info, OraclePrice>,
     
+    /// CHECK: must match market.pyth_oracle when the market has one; parsed in parse_pyth_price.
+    /// Registered fallback feeds may follow in remaining_accounts.
+    pub pyth_oracle: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: must match market.switchboard_oracle when the market has one; parsed in parse_switchboard_price.
+    pub switchboard_oracle: Option<UncheckedAccount<'info>>,
+    
     #[account(
         mut, 
         seeds = [POSITION_SEED, user.key().as_ref(), market.key().as_ref()], 
         bump = user_position.bump
     )] 
     pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
+        bump = user_account.bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
     
-    #[account(mut)] 
-    pub user_token: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
     
-    #[account(mut)] 
-    pub vault_token: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
     
-    /// CHECK: fees go to Pump/Pumpswap LP token account
-    #[account(mut)] 
-    pub fee_destination: AccountInfo<'info>,
+    /// Fees go to the Pump/Pumpswap LP token account; maker rebates are paid from it
+    #[account(mut, address = config.fee_destination @ PerpsError::InvalidTokenAccount)]
+    pub fee_destination: InterfaceAccount<'info, TokenAccount>,
+
+    /// Present when the user is a whitelisted market maker
+    #[account(
+        mut,
+        seeds = [MARKET_MAKER_SEED, user.key().as_ref()],
+        bump = market_maker.bump,
+    )]
+    pub market_maker: Option<Box<Account<'info, MarketMaker>>>,
+
+    /// Only needed when the market queues large payouts
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = PendingWithdrawal::SPACE,
+        seeds = [PENDING_WITHDRAWAL_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump
+    )]
+    pub pending_withdrawal: Option<Account<'info, PendingWithdrawal>>,
+
+    /// Only needed while withdrawals are rate limited
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRateLimit::SPACE,
+        seeds = [USER_RATE_LIMIT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_rate_limit: Option<Account<'info, UserRateLimit>>,
+
+    /// Deposited collateral to settle the payout into instead of user_token
+    #[account(
+        mut,
+        seeds = [COLLATERAL_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump = collateral_account.bump
+    )]
+    pub collateral_account: Option<Box<Account<'info, CollateralAccount>>>,
+
+    /// Pooled collateral and its vault, required to close a cross-margin position
+    #[account(
+        mut,
+        seeds = [CROSS_MARGIN_SEED, user.key().as_ref()],
+        bump = cross_margin_account.bump
+    )]
+    pub cross_margin_account: Option<Box<Account<'info, CrossMarginAccount>>>,
+
+    #[account(
+        mut,
+        seeds = [CROSS_VAULT_SEED],
+        bump,
+    )]
+    pub cross_vault: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    /// Where pledged margin goes back to, required to close a position margined with it
+    #[account(
+        mut,
+        seeds = [ACCEPTED_COLLATERAL_SEED, accepted_collateral.mint.as_ref()],
+        bump = accepted_collateral.bump
+    )]
+    pub accepted_collateral: Option<Box<Account<'info, AcceptedCollateral>>>,
+
+    #[account(
+        mut,
+        seeds = [POSITION_COLLATERAL_SEED, user_position.key().as_ref()],
+        bump = position_collateral.bump
+    )]
+    pub position_collateral: Option<Box<Account<'info, PositionCollateral>>>,
+
+    #[account(
+        mut,
+        seeds = [COLLATERAL_VAULT_SEED, collateral_vault.mint.as_ref()],
+        bump
+    )]
+    pub collateral_vault: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    #[account(mut)]
+    pub collateral_user_token: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    /// The pledged mint, checked against accepted_collateral.mint
+    pub collateral_mint: Option<Box<InterfaceAccount<'info, Mint>>>,
+
+    /// This market's insurance fund, to take its slice of the fee
+    #[account(
+        mut,
+        seeds = [INSURANCE_FUND_SEED, market.key().as_ref()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Box<Account<'info, InsuranceFund>>,
+
+    #[account(mut, address = insurance_fund.vault_token_account @ PerpsError::InvalidTokenAccount)]
+    pub insurance_vault_token: Box<InterfaceAccount<'info, TokenAccount>>,
     
-    pub token_program: Program<'info, Token>,
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
 }
 
 impl<'info> ClosePosition<'info> {
-    pub fn transfer_vault_to_user(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        CpiContext::new(
-            self.token_program.to_account_info(), 
-            Transfer { 
-                from: self.vault_token.to_account_info(), 
-                to: self.user_token.to_account_info(), 
-                authority: self.config.to_account_info() 
-            }
+    pub fn transfer_vault_to_user(&self, signer_seeds: &[&[&[u8]]], amount: u64) -> Result<u64> {
+        transfer_checked(
+            &self.token_program,
+            &self.quote_mint,
+            self.vault_token.to_account_info(),
+            self.user_token.to_account_info(),
+            self.config.to_account_info(),
+            signer_seeds,
+            amount,
         )
     }
-    
-    pub fn transfer_vault_to_fee_dest(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        CpiContext::new(
-            self.token_program.to_account_info(), 
-            Transfer { 
-                from: self.vault_token.to_account_info(), 
-                to: self.fee_destination.to_account_info(), 
-                authority: self.config.to_account_info() 
-            }
+
+    pub fn transfer_vault_to_fee_dest(&self, signer_seeds: &[&[&[u8]]], amount: u64) -> Result<u64> {
+        transfer_checked(
+            &self.token_program,
+            &self.quote_mint,
+            self.vault_token.to_account_info(),
+            self.fee_destination.to_account_info(),
+            self.config.to_account_info(),
+            signer_seeds,
+            amount,
+        )
+    }
+
+    pub fn transfer_fee_dest_to_user(&self, signer_seeds: &[&[&[u8]]], amount: u64) -> Result<u64> {
+        transfer_checked(
+            &self.token_program,
+            &self.quote_mint,
+            self.fee_destination.to_account_info(),
+            self.user_token.to_account_info(),
+            self.config.to_account_info(),
+            signer_seeds,
+            amount,
         )
     }
 }
+
+#[derive(Accounts)]
+pub struct CloseNetted<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    /// Owner of the opposing position, consenting to it being closed into `user`'s settlement
+    pub hedge_owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    pub oracle: Account<'info, OraclePrice>,
+
+    /// CHECK: must match market.pyth_oracle when the market has one; parsed in parse_pyth_price.
+    /// Registered fallback feeds may follow in remaining_accounts.
+    pub pyth_oracle: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: must match market.switchboard_oracle when the market has one; parsed in parse_switchboard_price.
+    pub switchboard_oracle: Option<UncheckedAccount<'info>>,
+
+    #[account(
+        mut,
+        seeds = [POSITION_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump = user_position.bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [POSITION_SEED, hedge_owner.key().as_ref(), market.key().as_ref()],
+        bump = hedge_position.bump
+    )]
+    pub hedge_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
+        bump = user_account.bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, hedge_owner.key().as_ref()],
+        bump = hedge_user_account.bump
+    )]
+    pub hedge_user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = config.fee_destination @ PerpsError::InvalidTokenAccount)]
+    pub fee_destination: InterfaceAccount<'info, TokenAccount>,
+
+    /// Only needed while withdrawals are rate limited
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRateLimit::SPACE,
+        seeds = [USER_RATE_LIMIT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_rate_limit: Option<Account<'info, UserRateLimit>>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_runtime::{load, program, run_at, token_account, token_account_at, token_balance, Book, Transfer};
+    use std::{cell::RefCell, collections::BTreeSet, rc::Rc};
+
+    fn long_position(base_size: u64, price_fp: u128, market: &mut Market) -> UserPosition {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        let entry = entry_margin(base_size * price_fp as u64, 5).unwrap();
+        let mut up = UserPosition::default();
+        write_open_position(&cfg, market, Pubkey::default(), &mut up, Pubkey::default(), 255, true, base_size, &entry, price_fp, 1_000).unwrap();
+        up
+    }
+
+    #[test]
+    fn test_orders_net_against_the_held_position() {
+        let mut market = Market { max_position_base: 1_000, min_position_base: 3, maintenance_margin_bps: 500, ..Default::default() };
+        let up = long_position(10, 100 * FP, &mut market);
+        let net = |order_size, is_long| net_order(&market, &up, order_size, is_long);
+
+        // The same side adds to the position
+        assert_eq!(net(5, true), NetOrder { close_size: 0, open_size: 5 });
+        // The other side reduces it, or closes it all rather than leave dust
+        assert_eq!(net(4, false), NetOrder { close_size: 4, open_size: 0 });
+        assert_eq!(net(8, false), NetOrder { close_size: 10, open_size: 0 });
+        assert_eq!(net(10, false), NetOrder { close_size: 10, open_size: 0 });
+        // Past the held size it flips, unless the excess would itself be dust
+        assert_eq!(net(12, false), NetOrder { close_size: 10, open_size: 0 });
+        assert_eq!(net(15, false), NetOrder { close_size: 10, open_size: 5 });
+    }
+
+    #[test]
+    fn test_increase_averages_entry_and_adds_margin() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        let mut market = Market { max_position_base: 25, maintenance_margin_bps: 500, ..Default::default() };
+        let mut up = long_position(10, 100 * FP, &mut market);
+        assert_eq!(up.margin_deposited, 200_000_000);
+
+        // $1200 at 5x buys 10 more at $120 on $240 of margin
+        let entry = entry_margin(1_200_000_000, 5).unwrap();
+        increase_position(&cfg, &mut market, &mut up, 10, &entry, 120 * FP, 2_000).unwrap();
+        assert_eq!((up.base_size, up.entry_price_fp, up.margin_deposited), (20, 110 * FP, 440_000_000));
+        assert_eq!(up.liquidation_price_fp, liquidation_price_fp(110 * FP, 440 * FP, 20, 500, true).unwrap());
+        assert_eq!(market.total_long_size, 20);
+        assert_eq!(market.total_volume, 2_200_000_000);
+        // Still the same position: brackets armed on it stay live
+        assert_eq!((up.opened_at_ts, up.last_updated_ts), (1_000, 2_000));
+
+        // The grown position is held to the market's size limit
+        let before = up.clone();
+        assert_eq!(
+            increase_position(&cfg, &mut market, &mut up, 6, &entry, 120 * FP, 3_000).unwrap_err(),
+            PerpsError::MaxPositionExceeded.into()
+        );
+        assert_eq!(up.try_to_vec().unwrap(), before.try_to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_adding_a_unit_at_120_to_one_at_100_enters_at_110() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        let mut market = Market { max_position_base: 1_000, maintenance_margin_bps: 500, ..Default::default() };
+        let mut up = long_position(1, 100 * FP, &mut market);
+        let liquidation_before_fp = up.liquidation_price_fp;
+
+        let entry = entry_margin(120_000_000, 5).unwrap();
+        increase_position(&cfg, &mut market, &mut up, 1, &entry, 120 * FP, 2_000).unwrap();
+        assert_eq!((up.base_size, up.entry_price_fp), (2, 110 * FP));
+        assert_eq!(up.margin_deposited, 20_000_000 + 24_000_000);
+        assert_eq!(up.liquidation_price_fp, liquidation_price_fp(110 * FP, 44 * FP, 2, 500, true).unwrap());
+        assert!(up.liquidation_price_fp > liquidation_before_fp);
+    }
+
+    #[test]
+    fn test_increase_must_leave_the_position_above_maintenance() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        let mut market = Market { max_position_base: 1_000, maintenance_margin_bps: 500, ..Default::default() };
+        let mut up = long_position(10, 100 * FP, &mut market);
+
+        // Doubling a long that is down $190 at $81 on $8.10 more margin leaves
+        // $18.10 of equity against $81 of maintenance
+        let entry = entry_margin(810_000_000, 100).unwrap();
+        assert_eq!(
+            increase_position(&cfg, &mut market, &mut up, 10, &entry, 81 * FP, 2_000).unwrap_err(),
+            PerpsError::WouldBeLiquidated.into()
+        );
+    }
+
+    fn close(book: Book) -> Result<CloseOutcome> {
+        let mut accounts = ClosePosition {
+            user: Signer::try_from(book.user)?,
+            config: Account::try_from(book.config)?,
+            market: Account::try_from(book.market)?,
+            oracle: Account::try_from(book.oracle)?,
+            pyth_oracle: None,
+            switchboard_oracle: None,
+            user_position: Account::try_from(book.user_position)?,
+            protocol_stats: Account::try_from(book.protocol_stats)?,
+            user_account: Account::try_from(book.user_account)?,
+            user_token: InterfaceAccount::try_from(book.user_token)?,
+            vault_token: InterfaceAccount::try_from(book.vault_token)?,
+            fee_destination: InterfaceAccount::try_from(book.fee_destination)?,
+            market_maker: None,
+            pending_withdrawal: None,
+            user_rate_limit: None,
+            collateral_account: None,
+            cross_margin_account: None,
+            cross_vault: None,
+            accepted_collateral: None,
+            position_collateral: None,
+            collateral_vault: None,
+            collateral_user_token: None,
+            collateral_mint: None,
+            insurance_fund: Box::new(Account::try_from(book.insurance_fund)?),
+            insurance_vault_token: Box::new(InterfaceAccount::try_from(book.insurance_vault_token)?),
+            quote_mint: Box::new(InterfaceAccount::try_from(book.quote_mint)?),
+            token_program: Interface::try_from(book.token_program)?,
+            system_program: Program::try_from(book.system_program)?,
+        };
+        close_position(Context::new(&crate::ID, &mut accounts, &[], ClosePositionBumps::default()))
+    }
+
+    #[test]
+    fn test_close_is_settled_before_any_tokens_leave_the_vault() {
+        let now = 100_000;
+        let config = Config { fee_bps: 10, insurance_fee_bps: 2_000, ..Default::default() };
+        let book = Book::new(config, 100 * FP, now);
+
+        // The mock token program looks at the books mid-transfer and tries to
+        // close the same position again from inside each payout
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let on_transfer = {
+            let seen = seen.clone();
+            move |transfer: &Transfer| {
+                seen.borrow_mut().push((transfer.to, transfer.amount, book.settled_state(), close(book)));
+            }
+        };
+        run_at(now, on_transfer, || close(book)).unwrap();
+
+        // $100 back less the $1 fee on $1,000 of exit notional, a fifth of
+        // which goes to the market's insurance fund
+        let seen = seen.borrow();
+        assert_eq!(
+            seen.iter().map(|(to, amount, ..)| (*to, *amount)).collect::<Vec<_>>(),
+            [
+                (*book.user_token.key, 99_000_000),
+                (*book.insurance_vault_token.key, 200_000),
+                (*book.fee_destination.key, 800_000),
+            ]
+        );
+        for (.., (up, market), reentry) in seen.iter() {
+            assert_eq!((up.base_size, up.margin_deposited, up.status), (0, 0, PositionStatus::Closed));
+            assert_eq!((market.total_long_size, market.total_margin_locked), (0, 0));
+            assert_eq!(reentry.as_ref().unwrap_err(), &PerpsError::PositionNotFound.into());
+        }
+        assert_eq!(token_balance(book.vault_token), 0);
+    }
+
+    /// Run `ClosePosition`'s account constraints over `book` at its oracle's
+    /// time, with every optional account left out
+    fn validate_close(book: Book) -> Result<()> {
+        let none = program(crate::ID);
+        let infos: &'static [AccountInfo<'static>] = Box::leak(Box::new([
+            book.user, book.config, book.market, book.oracle, none, none,
+            book.user_position, book.protocol_stats, book.user_account,
+            book.user_token, book.vault_token, book.fee_destination,
+            none, none, none, none, none, none, none, none, none, none, none,
+            book.insurance_fund, book.insurance_vault_token, book.quote_mint,
+            book.token_program, book.system_program,
+        ].map(AccountInfo::clone)));
+        let mut bumps = ClosePositionBumps::default();
+        let now = load::<OraclePrice>(book.oracle).last_updated_ts;
+        run_at(now, |_| {}, || ClosePosition::try_accounts(&crate::ID, &mut &*infos, &[], &mut bumps, &mut BTreeSet::new()))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_close_rejects_token_accounts_the_program_does_not_control() {
+        let book = Book::new(Config::default(), 100 * FP, 100_000);
+        let quote_mint = *book.quote_mint.key;
+        validate_close(book).unwrap();
+
+        // The market's vault address, but held by someone other than the config PDA
+        let vault_key = *book.vault_token.key;
+        let stolen_vault = Book { vault_token: token_account_at(vault_key, quote_mint, Pubkey::new_unique(), 100_000_000), ..book };
+        assert_eq!(validate_close(stolen_vault).unwrap_err(), PerpsError::InvalidTokenAccount.into());
+        // Or held by the config but in another mint
+        let foreign_vault = Book { vault_token: token_account_at(vault_key, Pubkey::new_unique(), *book.config.key, 100_000_000), ..book };
+        assert_eq!(validate_close(foreign_vault).unwrap_err(), PerpsError::InvalidTokenAccount.into());
+
+        // Fees only go to the configured destination
+        let fee_destination = token_account(quote_mint, *book.user.key, 0);
+        assert_eq!(validate_close(Book { fee_destination, ..book }).unwrap_err(), PerpsError::InvalidTokenAccount.into());
+
+        // And the fund's slice only to the fund's own vault
+        let insurance_vault_token = token_account(quote_mint, *book.user.key, 0);
+        assert_eq!(validate_close(Book { insurance_vault_token, ..book }).unwrap_err(), PerpsError::InvalidTokenAccount.into());
+    }
+}
@@ -0,0 +1,443 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::*;
+use crate::oracle;
+use crate::instructions::advanced_position::{close_slice, pay_out_slice, slice_size};
+use crate::instructions::withdrawal_queue::throttle_outflow;
+
+// Trailing stops: a stop loss whose trigger follows the most favorable mark
+
+pub fn set_trailing_stop(
+    ctx: Context<SetTrailingStop>,
+    trail_distance_bps: u16,
+    close_percentage: u8, // 1-100 (100 = close entire position)
+) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+    require!(
+        close_percentage > 0 && close_percentage <= 100,
+        PerpsError::InvalidMarketParameters
+    );
+    require!(trail_distance_bps > 0 && trail_distance_bps < 10_000, PerpsError::InvalidStopLoss);
+    ctx.accounts.user_position.ensure_status(&[PositionStatus::Open])?;
+    ctx.accounts.user_position.ensure_isolated()?;
+    ctx.accounts.user_position.ensure_quote_margin()?;
+
+    // The trail starts from the current mark
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?);
+    let is_long = ctx.accounts.user_position.is_long;
+
+    // Re-arming a resting order keeps its slot; a new one takes another
+    let orders = &mut ctx.accounts.user_orders;
+    orders.owner = ctx.accounts.user.key();
+    orders.bump = ctx.bumps.user_orders;
+    if !ctx.accounts.trailing_stop_order.is_active {
+        orders.add_order(ctx.accounts.config.max_active_orders_per_user)?;
+    }
+
+    let position_key = ctx.accounts.user_position.key();
+    ctx.accounts.trailing_stop_order.arm(
+        ctx.accounts.user_position.owner,
+        ctx.accounts.market.key(),
+        position_key,
+        trail_distance_bps,
+        mark_fp,
+        close_percentage,
+        Clock::get()?.unix_timestamp,
+        ctx.bumps.trailing_stop_order,
+    );
+
+    emit!(TrailingStopSet {
+        user: ctx.accounts.user_position.owner,
+        market: ctx.accounts.market.key(),
+        trail_distance_bps,
+        high_water_mark_fp: mark_fp,
+        close_percentage,
+        is_long,
+    });
+
+    Ok(())
+}
+
+/// Anyone may crank the high-water mark forward to a new favorable extreme.
+/// A mark that isn't one leaves the order as it is.
+pub fn update_trailing_stop(ctx: Context<UpdateTrailingStop>) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+    require!(ctx.accounts.trailing_stop_order.is_active, PerpsError::OrderNotActive);
+    require!(
+        !ctx.accounts.user_position.orphans_order(ctx.accounts.trailing_stop_order.created_at),
+        PerpsError::PositionNotFound
+    );
+
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?);
+    let is_long = ctx.accounts.user_position.is_long;
+    let order = &mut ctx.accounts.trailing_stop_order;
+    if !order.ratchet(is_long, mark_fp) {
+        msg!("Trailing stop unchanged: mark {} is not past {}", mark_fp, order.high_water_mark_fp);
+        return Ok(());
+    }
+
+    emit!(TrailingStopUpdated {
+        user: order.owner,
+        market: order.market,
+        high_water_mark_fp: order.high_water_mark_fp,
+        trigger_price_fp: order.trigger_price_fp(is_long),
+    });
+
+    Ok(())
+}
+
+/// Anyone may crank a trailing stop once the mark retraces past its trigger
+pub fn execute_trailing_stop(ctx: Context<ExecuteTrailingStop>) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+    require!(ctx.accounts.trailing_stop_order.is_active, PerpsError::OrderNotActive);
+    require!(!ctx.accounts.market.is_paused, PerpsError::MarketPaused);
+
+    // An order left behind by a position that was closed out by hand is retired
+    // rather than executed, as for stop losses and take profits
+    if ctx.accounts.user_position.orphans_order(ctx.accounts.trailing_stop_order.created_at) {
+        ctx.accounts.trailing_stop_order.is_active = false;
+        ctx.accounts.user_orders.remove_order();
+        emit!(TrailingStopCancelled {
+            user: ctx.accounts.user_position.owner,
+            market: ctx.accounts.market.key(),
+        });
+        msg!("Trailing stop retired: {}", PerpsError::PositionNotFound);
+        return Ok(());
+    }
+    ctx.accounts.user_position.ensure_status(&[PositionStatus::Open])?;
+    ctx.accounts.user_position.ensure_isolated()?;
+
+    let mark_fp = ctx.accounts.market.settlement_mark_fp(oracle::read_market_oracle_fp(&ctx.accounts.market, &ctx.accounts.oracle)?);
+    let is_long = ctx.accounts.user_position.is_long;
+    let high_water_mark_fp = ctx.accounts.trailing_stop_order.high_water_mark_fp;
+    let trigger_price_fp = ctx.accounts.trailing_stop_order.trigger_price_fp(is_long);
+    let close_percentage = ctx.accounts.trailing_stop_order.close_percentage;
+    require!(
+        ctx.accounts.trailing_stop_order.is_triggered(is_long, mark_fp),
+        PerpsError::TrailingStopNotTriggered
+    );
+
+    // Size off the live position; 100% closes it outright
+    let close_size = slice_size(ctx.accounts.user_position.base_size.unsigned_abs(), close_percentage);
+    require!(close_size > 0, PerpsError::PositionTooSmall);
+
+    // Effects
+    let now = Clock::get()?.unix_timestamp;
+    let slice = close_slice(
+        &mut ctx.accounts.config,
+        &mut ctx.accounts.market,
+        &mut ctx.accounts.user_position,
+        close_size,
+        mark_fp,
+        ctx.accounts.vault_token.amount,
+        now,
+    )?;
+    if slice.remaining_size == 0 {
+        ctx.accounts.protocol_stats.record_close();
+        ctx.accounts.user_account.record_close();
+    }
+    ctx.accounts.protocol_stats.record_fees(slice.fee_amt)?;
+    emit!(ctx.accounts.protocol_stats.updated_event());
+    let order = &mut ctx.accounts.trailing_stop_order;
+    order.is_active = false;
+    order.executed_at = Some(now);
+    ctx.accounts.user_orders.remove_order();
+    throttle_outflow(
+        &ctx.accounts.config,
+        ctx.accounts.user_rate_limit.as_mut(),
+        None,
+        ctx.accounts.user_position.owner,
+        slice.settlement_amt,
+        now,
+    )?;
+
+    ctx.accounts.trailing_stop_order.exit(&crate::ID)?;
+    ctx.accounts.user_orders.exit(&crate::ID)?;
+    ctx.accounts.user_position.exit(&crate::ID)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+    ctx.accounts.config.exit(&crate::ID)?;
+    ctx.accounts.protocol_stats.exit(&crate::ID)?;
+    ctx.accounts.user_account.exit(&crate::ID)?;
+
+    // Interactions
+    pay_out_slice(
+        &ctx.accounts.config,
+        &ctx.accounts.market,
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        &ctx.accounts.vault_token,
+        &ctx.accounts.user_token,
+        &ctx.accounts.fee_destination_token,
+        slice.settlement_amt,
+        slice.fee_forwarded,
+    )?;
+
+    emit!(TrailingStopExecuted {
+        user: ctx.accounts.user_position.owner,
+        market: ctx.accounts.market.key(),
+        high_water_mark_fp,
+        trigger_price_fp,
+        close_percentage,
+        executor: ctx.accounts.executor.key(),
+    });
+
+    msg!("Trailing stop executed: {} units @ {}, PnL {}, Settlement {}",
+         close_size, mark_fp, slice.pnl_fp, slice.settlement_amt);
+
+    Ok(())
+}
+
+/// Cancel a resting trailing stop, freeing its order slot and returning the rent
+pub fn cancel_trailing_stop(ctx: Context<CancelTrailingStop>) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[])?;
+    if ctx.accounts.trailing_stop_order.is_active {
+        ctx.accounts.user_orders.remove_order();
+    }
+
+    emit!(TrailingStopCancelled {
+        user: ctx.accounts.user.key(),
+        market: ctx.accounts.market.key(),
+    });
+
+    Ok(())
+}
+
+// Context structures
+
+#[derive(Accounts)]
+pub struct SetTrailingStop<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        seeds = [MARKET_SEED, market.symbol.as_ref()],
+        bump = market.bump,
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [POSITION_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump = user_position.bump,
+        constraint = user_position.owner == user.key() @ PerpsError::UnauthorizedAccess,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        seeds = [TRAILING_STOP_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump,
+        space = TrailingStopOrder::SPACE,
+    )]
+    pub trailing_stop_order: Account<'info, TrailingStopOrder>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        seeds = [USER_ORDERS_SEED, user.key().as_ref()],
+        bump,
+        space = UserOrders::SPACE,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+
+    pub oracle: Account<'info, OraclePrice>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateTrailingStop<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        seeds = [MARKET_SEED, market.symbol.as_ref()],
+        bump = market.bump,
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        seeds = [POSITION_SEED, user_position.owner.as_ref(), market.key().as_ref()],
+        bump = user_position.bump,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [TRAILING_STOP_SEED, user_position.owner.as_ref(), market.key().as_ref()],
+        bump = trailing_stop_order.bump,
+        constraint = trailing_stop_order.position_key == user_position.key() @ PerpsError::InvalidStopLoss,
+    )]
+    pub trailing_stop_order: Account<'info, TrailingStopOrder>,
+
+    pub oracle: Account<'info, OraclePrice>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTrailingStop<'info> {
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, market.symbol.as_ref()],
+        bump = market.bump,
+    )]
+    pub market: Account<'info, Market>,
+
+    pub executor: Signer<'info>, // Anyone can crank a triggered trailing stop
+
+    #[account(
+        mut,
+        seeds = [POSITION_SEED, user_position.owner.as_ref(), market.key().as_ref()],
+        bump = user_position.bump,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_STATS_SEED],
+        bump = protocol_stats.bump
+    )]
+    pub protocol_stats: Account<'info, ProtocolStats>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user_position.owner.as_ref()],
+        bump = user_account.bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        seeds = [TRAILING_STOP_SEED, user_position.owner.as_ref(), market.key().as_ref()],
+        bump = trailing_stop_order.bump,
+        constraint = trailing_stop_order.position_key == user_position.key() @ PerpsError::InvalidStopLoss,
+    )]
+    pub trailing_stop_order: Account<'info, TrailingStopOrder>,
+
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_SEED, user_position.owner.as_ref()],
+        bump = user_orders.bump,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, market.key().as_ref()],
+        bump = market.vault_bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user_position.owner @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = config.fee_destination @ PerpsError::InvalidTokenAccount
+    )]
+    pub fee_destination_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// Only needed while withdrawals are rate limited; the owner creates it
+    #[account(
+        mut,
+        seeds = [USER_RATE_LIMIT_SEED, user_position.owner.as_ref()],
+        bump = user_rate_limit.bump,
+    )]
+    pub user_rate_limit: Option<Account<'info, UserRateLimit>>,
+
+    pub oracle: Account<'info, OraclePrice>,
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CancelTrailingStop<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        seeds = [MARKET_SEED, market.symbol.as_ref()],
+        bump = market.bump,
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [TRAILING_STOP_SEED, user.key().as_ref(), market.key().as_ref()],
+        bump = trailing_stop_order.bump,
+        close = user,
+    )]
+    pub trailing_stop_order: Account<'info, TrailingStopOrder>,
+
+    #[account(
+        mut,
+        seeds = [USER_ORDERS_SEED, user.key().as_ref()],
+        bump = user_orders.bump,
+    )]
+    pub user_orders: Account<'info, UserOrders>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_trail_only_ratchets_up_and_fires_on_the_retrace() {
+        let mut order = TrailingStopOrder::default();
+        order.arm(Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique(), 500, 100 * FP, 100, 1_000, 255);
+        assert_eq!(order.trigger_price_fp(true), 95 * FP);
+
+        // A new high moves the trigger up with it
+        assert!(order.ratchet(true, 120 * FP));
+        assert_eq!((order.high_water_mark_fp, order.trigger_price_fp(true)), (120 * FP, 114 * FP));
+
+        // A pullback never drags the high-water mark back down
+        assert!(!order.ratchet(true, 110 * FP));
+        assert!(!order.ratchet(true, 120 * FP));
+        assert_eq!(order.high_water_mark_fp, 120 * FP);
+
+        // The old trigger no longer counts; the trailed one does
+        assert!(!order.is_triggered(true, 115 * FP));
+        assert!(order.is_triggered(true, 114 * FP));
+    }
+
+    #[test]
+    fn test_short_trail_follows_the_low() {
+        let mut order = TrailingStopOrder { trail_distance_bps: 1_000, high_water_mark_fp: 100 * FP, ..Default::default() };
+        assert!(order.ratchet(false, 80 * FP));
+        assert!(!order.ratchet(false, 90 * FP));
+        assert_eq!((order.high_water_mark_fp, order.trigger_price_fp(false)), (80 * FP, 88 * FP));
+        assert!(!order.is_triggered(false, 87 * FP));
+        assert!(order.is_triggered(false, 88 * FP));
+    }
+}
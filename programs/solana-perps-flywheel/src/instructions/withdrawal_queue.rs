@@ -0,0 +1,164 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+use crate::state::*;
+use crate::errors::PerpsError;
+use crate::events::*;
+use crate::transfer::{transfer_checked, vault_solvency_check};
+
+/// Claim a queued payout, in full or as much as the vault can cover right now
+pub fn claim_withdrawal(ctx: Context<ClaimWithdrawal>) -> Result<()> {
+    ensure_current_versions(&ctx.accounts.config, &ctx.accounts.market, &[&ctx.accounts.user_position])?;
+    let now = Clock::get()?.unix_timestamp;
+    let pending = &ctx.accounts.pending_withdrawal;
+    require!(pending.remaining() > 0, PerpsError::InsufficientFunds);
+    require!(now >= pending.available_at, PerpsError::WithdrawalNotReady);
+
+    // A vault short by no more than the rounding buffer settles the matured
+    // tranches in full; a real liquidity gap pays what it can and leaves the
+    // rest queued. What it can is what the vault holds beyond everything else
+    // booked in it, including this owner's tranches that haven't matured.
+    let owed = pending.matured(now);
+    let booked_elsewhere = ctx.accounts.market.vault_obligations(&ctx.accounts.config)?.saturating_sub(owed as u128);
+    let available = (ctx.accounts.vault_token.amount as u128).saturating_sub(booked_elsewhere) as u64;
+    let (amount, settled) = match ctx.accounts.config.absorb_rounding_shortfall(owed, available)? {
+        Some(written_off) => (owed - written_off, owed),
+        None => {
+            let amount = pending.claimable(now, available);
+            (amount, amount)
+        }
+    };
+    require!(amount > 0, PerpsError::InsufficientLiquidity);
+    throttle_outflow(
+        &ctx.accounts.config,
+        ctx.accounts.user_rate_limit.as_mut(),
+        ctx.bumps.user_rate_limit,
+        ctx.accounts.user.key(),
+        amount,
+        now,
+    )?;
+
+    // Record the claim before paying it out
+    ctx.accounts.config.exit(&crate::ID)?;
+    let pending = &mut ctx.accounts.pending_withdrawal;
+    pending.claim(settled, now)?;
+    pending.exit(&crate::ID)?;
+    // The queued payout was booked against the vault until claimed
+    ctx.accounts.market.release_margin(settled)?;
+    ctx.accounts.market.exit(&crate::ID)?;
+
+    // Last tranche paid: the closed position is fully settled
+    let up = &mut ctx.accounts.user_position;
+    if pending.remaining() == 0 && up.status == PositionStatus::PendingSettlement {
+        up.status = PositionStatus::Closed;
+        up.exit(&crate::ID)?;
+    }
+
+    let config_bump = ctx.accounts.config.bump;
+    transfer_checked(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_mint,
+        ctx.accounts.vault_token.to_account_info(),
+        ctx.accounts.user_token.to_account_info(),
+        ctx.accounts.config.to_account_info(),
+        &[&[CONFIG_SEED, &[config_bump]]],
+        amount,
+    )?;
+    vault_solvency_check(&ctx.accounts.vault_token.to_account_info(), &ctx.accounts.config, &ctx.accounts.market)?;
+
+    let pending = &ctx.accounts.pending_withdrawal;
+    emit!(WithdrawalClaimed {
+        user: pending.owner,
+        market: pending.market,
+        amount,
+        remaining: pending.remaining(),
+    });
+
+    msg!("Withdrawal claimed: ${}, remaining: ${}", amount, pending.remaining());
+    Ok(())
+}
+
+/// Count a vault-to-user payout against the owner's outflow window while
+/// `Config::withdrawal_rate_limit` is on. `bump` is set when the window
+/// account may have just been created. Liquidation remainders are not
+/// throttled, so an incident can never hold up a liquidation.
+pub(crate) fn throttle_outflow(
+    cfg: &Config,
+    rate_limit: Option<&mut Account<'_, UserRateLimit>>,
+    bump: Option<u8>,
+    owner: Pubkey,
+    amount: u64,
+    now: i64,
+) -> Result<()> {
+    if cfg.withdrawal_rate_limit == 0 || amount == 0 {
+        return Ok(());
+    }
+    let rate_limit = rate_limit.ok_or(PerpsError::UserRateLimitRequired)?;
+    rate_limit.owner = owner;
+    if let Some(bump) = bump {
+        rate_limit.bump = bump;
+    }
+    rate_limit.record_outflow(amount, cfg.withdrawal_rate_limit, now)?;
+    rate_limit.exit(&crate::ID)
+}
+
+#[derive(Accounts)]
+pub struct ClaimWithdrawal<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [PENDING_WITHDRAWAL_SEED, user.key().as_ref(), pending_withdrawal.market.as_ref()],
+        bump = pending_withdrawal.bump,
+        constraint = pending_withdrawal.owner == user.key() @ PerpsError::UnauthorizedAccess,
+    )]
+    pub pending_withdrawal: Account<'info, PendingWithdrawal>,
+
+    #[account(mut, address = pending_withdrawal.market @ PerpsError::PositionMarketMismatch)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [POSITION_SEED, user.key().as_ref(), pending_withdrawal.market.as_ref()],
+        bump = user_position.bump,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        constraint = user_token.owner == user.key() @ PerpsError::InvalidTokenAccount,
+        constraint = user_token.mint == config.quote_mint @ PerpsError::InvalidTokenMint,
+    )]
+    pub user_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, pending_withdrawal.market.as_ref()],
+        bump,
+        constraint = config.is_quote_vault(&config.key(), &vault_token.owner, &vault_token.mint) @ PerpsError::InvalidTokenAccount,
+    )]
+    pub vault_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// Only needed while withdrawals are rate limited
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRateLimit::SPACE,
+        seeds = [USER_RATE_LIMIT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_rate_limit: Option<Account<'info, UserRateLimit>>,
+
+    #[account(address = config.quote_mint @ PerpsError::InvalidTokenMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
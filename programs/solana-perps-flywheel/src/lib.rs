@@ -1,13 +1,31 @@
+
+// `#[program]` expands, at the crate root, to IDL handlers calling
+// AccountInfo::realloc, deprecated in the current anchor-lang. Only that
+// generated code is allowed: every module of ours warns again.
+#![allow(deprecated)]
+
 use anchor_lang::prelude::*;
 
+#[warn(deprecated)]
 pub mod errors;
+#[warn(deprecated)]
 pub mod events;
+#[warn(deprecated)]
 pub mod math;
+#[warn(deprecated)]
 pub mod oracle;
+#[warn(deprecated)]
 pub mod state;
+#[warn(deprecated)]
+pub mod transfer;
+#[warn(deprecated)]
 pub mod instructions;
+#[cfg(test)]
+#[warn(deprecated)]
+mod test_runtime;
 
 use instructions::*;
+use state::{LiquidatorRewardTier, MarginMode, MarginTier, OracleSource, LIQUIDATOR_REWARD_TIERS, MARGIN_TIERS, MAX_ORACLE_SOURCES, MAX_PYTH_FALLBACK_FEEDS};
 
 
 // Program ID
@@ -15,6 +33,8 @@ declare_id!("HSMR7nCvy29baTVaZRUafxZXU9UhfeFrmFtRSJW3r1gj");
 
 
 #[program]
+#[warn(deprecated)]
+#[allow(clippy::too_many_arguments)]
 pub mod solana_perps_flywheel {
 use super::*;
 
@@ -27,12 +47,84 @@ pub fn set_fee_destination(ctx: Context<AdminOnly>, new_fee_dest: Pubkey) -> Res
 instructions::admin::set_fee_destination(ctx, new_fee_dest)
 }
 
+pub fn propose_admin(ctx: Context<AdminOnly>, new_admin: Pubkey) -> Result<()> {
+instructions::admin::propose_admin(ctx, new_admin)
+}
+
+pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+instructions::admin::accept_admin(ctx)
+}
+
 pub fn pause(ctx: Context<AdminOnly>, paused: bool) -> Result<()> { 
 instructions::admin::pause(ctx, paused) 
 }
 
-pub fn update_risk_parameters(ctx: Context<AdminOnly>, max_positions_per_user: Option<u32>, circuit_breaker_threshold_bps: Option<u64>) -> Result<()> {
-instructions::admin::update_risk_parameters(ctx, max_positions_per_user, circuit_breaker_threshold_bps)
+pub fn update_risk_parameters(ctx: Context<AdminOnly>, max_positions_per_user: Option<u32>, circuit_breaker_threshold_bps: Option<u64>, max_active_orders_per_user: Option<u32>) -> Result<()> {
+instructions::admin::update_risk_parameters(ctx, max_positions_per_user, circuit_breaker_threshold_bps, max_active_orders_per_user)
+}
+
+pub fn set_withdrawal_rate_limit(ctx: Context<AdminOnly>, limit: u64) -> Result<()> {
+instructions::admin::set_withdrawal_rate_limit(ctx, limit)
+}
+
+pub fn migrate_config(ctx: Context<MigrateConfig>) -> Result<()> {
+instructions::admin::migrate_config(ctx)
+}
+
+pub fn set_mm_rebate(ctx: Context<AdminOnly>, mm_rebate_bps: u16) -> Result<()> {
+instructions::admin::set_mm_rebate(ctx, mm_rebate_bps)
+}
+
+pub fn register_market_maker(ctx: Context<RegisterMarketMaker>, maker: Pubkey) -> Result<()> {
+instructions::admin::register_market_maker(ctx, maker)
+}
+
+pub fn remove_market_maker(ctx: Context<RemoveMarketMaker>) -> Result<()> {
+instructions::admin::remove_market_maker(ctx)
+}
+
+pub fn initialize_protocol_stats(ctx: Context<InitializeProtocolStats>, active_positions: u32) -> Result<()> {
+instructions::admin::initialize_protocol_stats(ctx, active_positions)
+}
+
+pub fn initialize_oracle_twap(ctx: Context<InitializeOracleTwap>) -> Result<()> {
+instructions::admin::initialize_oracle_twap(ctx)
+}
+
+pub fn set_min_liquidation_deficit(ctx: Context<AdminOnly>, min_liquidation_deficit: u64) -> Result<()> {
+instructions::admin::set_min_liquidation_deficit(ctx, min_liquidation_deficit)
+}
+
+pub fn set_keeper_gas_reimbursement(ctx: Context<AdminOnly>, lamports_per_liquidation: u64) -> Result<()> {
+instructions::admin::set_keeper_gas_reimbursement(ctx, lamports_per_liquidation)
+}
+
+pub fn fund_keeper_gas_vault(ctx: Context<FundKeeperGasVault>, lamports: u64) -> Result<()> {
+instructions::admin::fund_keeper_gas_vault(ctx, lamports)
+}
+
+pub fn sweep_rounding_buffer(ctx: Context<SweepRoundingBuffer>, amount: u64) -> Result<()> {
+instructions::admin::sweep_rounding_buffer(ctx, amount)
+}
+
+pub fn migrate_legacy_vault(ctx: Context<MigrateLegacyVault>, amount: Option<u64>) -> Result<()> {
+instructions::admin::migrate_legacy_vault(ctx, amount)
+}
+
+pub fn add_accepted_collateral(ctx: Context<AddAcceptedCollateral>, collateral_factor_bps: u16) -> Result<()> {
+instructions::admin::add_accepted_collateral(ctx, collateral_factor_bps)
+}
+
+pub fn update_accepted_collateral(ctx: Context<UpdateAcceptedCollateral>, collateral_factor_bps: u16, is_enabled: bool) -> Result<()> {
+instructions::admin::update_accepted_collateral(ctx, collateral_factor_bps, is_enabled)
+}
+
+pub fn sweep_seized_collateral(ctx: Context<SweepSeizedCollateral>, amount: u64) -> Result<()> {
+instructions::admin::sweep_seized_collateral(ctx, amount)
+}
+
+pub fn set_liquidator_rewards(ctx: Context<AdminOnly>, tiers: [LiquidatorRewardTier; LIQUIDATOR_REWARD_TIERS], floor: u64) -> Result<()> {
+instructions::admin::set_liquidator_rewards(ctx, tiers, floor)
 }
 
 // Market management
@@ -46,25 +138,175 @@ maintenance_margin_bps: u16,
 taker_leverage_cap_x: u16,
 amm_base_reserve_fp: u128,
 amm_quote_reserve_fp: u128,
+max_long_oi: u64,
+max_short_oi: u64,
 ) -> Result<()> { 
-instructions::create_market::create_market(ctx, symbol, base_decimals, skew_k_bps, max_position_base, maintenance_margin_bps, taker_leverage_cap_x, amm_base_reserve_fp, amm_quote_reserve_fp) 
+instructions::create_market::create_market(ctx, symbol, base_decimals, skew_k_bps, max_position_base, maintenance_margin_bps, taker_leverage_cap_x, amm_base_reserve_fp, amm_quote_reserve_fp, max_long_oi, max_short_oi) 
+}
+
+pub fn initialize_market_vault(ctx: Context<InitializeMarketVault>) -> Result<()> {
+instructions::create_market::initialize_market_vault(ctx)
+}
+
+pub fn set_min_position_base(ctx: Context<AdminOnlyMarket>, min_position_base: u64) -> Result<()> {
+instructions::admin::set_min_position_base(ctx, min_position_base)
 }
 
 pub fn edit_max_position(ctx: Context<AdminOnlyMarket>, new_max_base: u64) -> Result<()> { 
 instructions::admin::edit_max_position(ctx, new_max_base) 
 }
 
+pub fn migrate_market(ctx: Context<MigrateMarket>, total_margin_locked: u64) -> Result<()> {
+instructions::admin::migrate_market(ctx, total_margin_locked)
+}
+
+pub fn migrate_position(ctx: Context<MigratePosition>) -> Result<()> {
+instructions::admin::migrate_position(ctx)
+}
+
+pub fn set_maintenance_margin(ctx: Context<AdminOnlyMarket>, maintenance_margin_bps: u16) -> Result<()> {
+instructions::admin::set_maintenance_margin(ctx, maintenance_margin_bps)
+}
+
+pub fn set_margin_tiers(ctx: Context<AdminOnlyMarket>, tiers: [MarginTier; MARGIN_TIERS]) -> Result<()> {
+instructions::admin::set_margin_tiers(ctx, tiers)
+}
+
+pub fn set_market_expiry(ctx: Context<AdminOnlyMarket>, expiry_ts: i64, close_only_before_expiry_seconds: i64) -> Result<()> {
+instructions::admin::set_market_expiry(ctx, expiry_ts, close_only_before_expiry_seconds)
+}
+
+pub fn settle_expired_market(ctx: Context<SettleExpiredMarket>) -> Result<()> {
+instructions::admin::settle_expired_market(ctx)
+}
+
+pub fn set_oracle_dead(ctx: Context<AdminOnlyMarket>, oracle_dead: bool, emergency_price_fp: u128) -> Result<()> {
+instructions::admin::set_oracle_dead(ctx, oracle_dead, emergency_price_fp)
+}
+
+pub fn emergency_settle_position(ctx: Context<EmergencySettlePosition>) -> Result<()> {
+instructions::admin::emergency_settle_position(ctx)
+}
+
+pub fn set_price_precision(ctx: Context<AdminOnlyMarket>, price_precision: u8) -> Result<()> {
+instructions::admin::set_price_precision(ctx, price_precision)
+}
+
+pub fn set_max_position_oi_fraction(ctx: Context<AdminOnlyMarket>, max_position_oi_fraction_bps: u16) -> Result<()> {
+instructions::admin::set_max_position_oi_fraction(ctx, max_position_oi_fraction_bps)
+}
+
+pub fn set_open_interest_caps(ctx: Context<AdminOnlyMarket>, max_long_oi: u64, max_short_oi: u64) -> Result<()> {
+instructions::admin::set_open_interest_caps(ctx, max_long_oi, max_short_oi)
+}
+
+pub fn set_risk_twap_window(ctx: Context<AdminOnlyMarket>, risk_twap_seconds: u32) -> Result<()> {
+instructions::admin::set_risk_twap_window(ctx, risk_twap_seconds)
+}
+
+pub fn set_trading_fees(ctx: Context<AdminOnly>, maker_fee_bps: i16, taker_fee_bps: u16) -> Result<()> {
+instructions::admin::set_trading_fees(ctx, maker_fee_bps, taker_fee_bps)
+}
+
+pub fn set_insurance_fee(ctx: Context<AdminOnly>, insurance_fee_bps: u16, insurance_target_ratio_bps: u32) -> Result<()> {
+instructions::admin::set_insurance_fee(ctx, insurance_fee_bps, insurance_target_ratio_bps)
+}
+
+pub fn set_max_favorable_move(ctx: Context<AdminOnlyMarket>, max_favorable_move_bps: u16) -> Result<()> {
+instructions::admin::set_max_favorable_move(ctx, max_favorable_move_bps)
+}
+
+pub fn set_skew_surcharge(ctx: Context<AdminOnlyMarket>, max_skew_surcharge_bps: u16) -> Result<()> {
+instructions::admin::set_skew_surcharge(ctx, max_skew_surcharge_bps)
+}
+
+pub fn set_max_funding_rate(ctx: Context<AdminOnlyMarket>, max_funding_rate_fp: i128) -> Result<()> {
+instructions::admin::set_max_funding_rate(ctx, max_funding_rate_fp)
+}
+
+pub fn set_funding_smoothing(ctx: Context<AdminOnlyMarket>, funding_ema_alpha_bps: u16) -> Result<()> {
+instructions::admin::set_funding_smoothing(ctx, funding_ema_alpha_bps)
+}
+pub fn set_min_holding_seconds(ctx: Context<AdminOnlyMarket>, min_holding_seconds: i64) -> Result<()> {
+instructions::admin::set_min_holding_seconds(ctx, min_holding_seconds)
+}
+
+pub fn set_oracle_source_priority(ctx: Context<AdminOnlyMarket>, priority: [OracleSource; MAX_ORACLE_SOURCES]) -> Result<()> {
+instructions::admin::set_oracle_source_priority(ctx, priority)
+}
+
+pub fn set_confidence_band_liquidation(ctx: Context<AdminOnlyMarket>, enabled: bool) -> Result<()> {
+instructions::admin::set_confidence_band_liquidation(ctx, enabled)
+}
+
+pub fn set_min_partial_close_pct(ctx: Context<AdminOnlyMarket>, min_partial_close_pct: u8) -> Result<()> {
+instructions::admin::set_min_partial_close_pct(ctx, min_partial_close_pct)
+}
+
+pub fn set_open_protection_seconds(ctx: Context<AdminOnlyMarket>, open_protection_seconds: i64) -> Result<()> {
+instructions::admin::set_open_protection_seconds(ctx, open_protection_seconds)
+}
+
+pub fn set_pyth_oracles(ctx: Context<AdminOnlyMarket>, pyth_oracle: Option<Pubkey>, fallbacks: [Pubkey; MAX_PYTH_FALLBACK_FEEDS]) -> Result<()> {
+instructions::admin::set_pyth_oracles(ctx, pyth_oracle, fallbacks)
+}
+
+pub fn set_switchboard_oracle(ctx: Context<AdminOnlyMarket>, switchboard_oracle: Option<Pubkey>) -> Result<()> {
+instructions::admin::set_switchboard_oracle(ctx, switchboard_oracle)
+}
+
+pub fn set_withdrawal_queue(ctx: Context<AdminOnlyMarket>, threshold: u64, delay_seconds: i64) -> Result<()> {
+instructions::admin::set_withdrawal_queue(ctx, threshold, delay_seconds)
+}
+
 // Basic trading
-pub fn open_position(ctx: Context<OpenPosition>, is_long: bool, quote_to_spend: u64, leverage_x: u16) -> Result<()> { 
-instructions::trade::open_position(ctx, is_long, quote_to_spend, leverage_x) 
+pub fn open_position<'info>(ctx: Context<'_, '_, 'info, 'info, OpenPosition<'info>>, is_long: bool, quote_to_spend: u64, leverage_x: u16, stop_loss_price_fp: u128, take_profit_price_fp: u128, margin_mode: MarginMode, max_slippage_bps: u16) -> Result<()> { 
+instructions::trade::open_position(ctx, is_long, quote_to_spend, leverage_x, stop_loss_price_fp, take_profit_price_fp, margin_mode, max_slippage_bps) 
 }
 
-pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> { 
+pub fn close_position<'info>(ctx: Context<'_, '_, 'info, 'info, ClosePosition<'info>>) -> Result<CloseOutcome> { 
 instructions::trade::close_position(ctx) 
 }
 
+pub fn close_netted<'info>(ctx: Context<'_, '_, 'info, 'info, CloseNetted<'info>>) -> Result<()> {
+instructions::trade::close_netted(ctx)
+}
+
+pub fn deposit_collateral(ctx: Context<DepositCollateral>, amount: u64) -> Result<()> {
+instructions::collateral::deposit_collateral(ctx, amount)
+}
+
+pub fn withdraw_collateral(ctx: Context<WithdrawCollateral>, amount: u64) -> Result<()> {
+instructions::collateral::withdraw_collateral(ctx, amount)
+}
+
+pub fn initialize_cross_vault(ctx: Context<InitializeCrossVault>) -> Result<()> {
+instructions::cross_margin::initialize_cross_vault(ctx)
+}
+
+pub fn deposit_cross_collateral(ctx: Context<DepositCrossCollateral>, amount: u64) -> Result<()> {
+instructions::cross_margin::deposit_cross_collateral(ctx, amount)
+}
+
+pub fn withdraw_cross_collateral<'info>(ctx: Context<'_, '_, 'info, 'info, WithdrawCrossCollateral<'info>>, amount: u64) -> Result<()> {
+instructions::cross_margin::withdraw_cross_collateral(ctx, amount)
+}
+
+pub fn claim_withdrawal(ctx: Context<ClaimWithdrawal>) -> Result<()> {
+instructions::withdrawal_queue::claim_withdrawal(ctx)
+}
+
+// Auditing
+pub fn verify_market_invariants<'info>(ctx: Context<'_, '_, 'info, 'info, VerifyMarketInvariants<'info>>, tolerance: u64) -> Result<()> {
+instructions::invariants::verify_market_invariants(ctx, tolerance)
+}
+
+pub fn get_position_health(ctx: Context<GetPositionHealth>) -> Result<PositionHealth> {
+instructions::position_health::get_position_health(ctx)
+}
+
 // Liquidation system
-pub fn liquidate(ctx: Context<Liquidate>) -> Result<()> { 
+pub fn liquidate<'info>(ctx: Context<'_, '_, 'info, 'info, Liquidate<'info>>) -> Result<()> { 
 instructions::liquidate::liquidate(ctx) 
 }
 
@@ -73,12 +315,20 @@ pub fn settle_funding(ctx: Context<SettleFunding>) -> Result<()> {
 instructions::funding::settle_funding(ctx) 
 }
 
+pub fn rebalance_amm(ctx: Context<RebalanceAmm>) -> Result<()> {
+instructions::amm::rebalance_amm(ctx)
+}
+
+pub fn claim_creator_rewards(ctx: Context<ClaimCreatorRewards>) -> Result<()> {
+instructions::rewards::claim_creator_rewards(ctx)
+}
+
 pub fn sweep_creator_rewards(ctx: Context<SweepCreatorRewards>, amount: u64) -> Result<()> { 
 instructions::rewards::sweep_creator_rewards(ctx, amount) 
 }
 
 // Advanced position management
-pub fn partial_close_position(ctx: Context<PartialClosePosition>, close_percentage: u8) -> Result<()> {
+pub fn partial_close_position(ctx: Context<PartialClosePosition>, close_percentage: u8) -> Result<CloseOutcome> {
 instructions::advanced_position::partial_close_position(ctx, close_percentage)
 }
 
@@ -86,8 +336,70 @@ pub fn modify_position_margin(ctx: Context<ModifyPositionMargin>, margin_change:
 instructions::advanced_position::modify_position_margin(ctx, margin_change)
 }
 
-pub fn set_stop_loss(ctx: Context<SetStopLoss>, trigger_price_fp: u128, close_percentage: u8) -> Result<()> {
-instructions::advanced_position::set_stop_loss(ctx, trigger_price_fp, close_percentage)
+pub fn set_position_leverage(ctx: Context<ModifyPositionMargin>, target_leverage_x: u16) -> Result<()> {
+instructions::advanced_position::set_position_leverage(ctx, target_leverage_x)
+}
+
+pub fn set_stop_loss(ctx: Context<SetStopLoss>, trigger_price_fp: u128, close_percentage: u8, expires_at: Option<i64>) -> Result<()> {
+instructions::advanced_position::set_stop_loss(ctx, trigger_price_fp, close_percentage, expires_at)
+}
+
+pub fn execute_stop_loss(ctx: Context<ExecuteStopLoss>) -> Result<()> {
+instructions::advanced_position::execute_stop_loss(ctx)
+}
+
+pub fn cancel_stop_loss(ctx: Context<CancelStopLoss>) -> Result<()> {
+instructions::advanced_position::cancel_stop_loss(ctx)
+}
+
+pub fn set_take_profit(ctx: Context<SetTakeProfit>, trigger_price_fp: u128, close_percentage: u8, expires_at: Option<i64>) -> Result<()> {
+instructions::take_profit::set_take_profit(ctx, trigger_price_fp, close_percentage, expires_at)
+}
+
+pub fn execute_take_profit(ctx: Context<ExecuteTakeProfit>) -> Result<()> {
+instructions::take_profit::execute_take_profit(ctx)
+}
+
+pub fn cancel_take_profit(ctx: Context<CancelTakeProfit>) -> Result<()> {
+instructions::take_profit::cancel_take_profit(ctx)
+}
+
+// Trailing stops
+pub fn set_trailing_stop(ctx: Context<SetTrailingStop>, trail_distance_bps: u16, close_percentage: u8) -> Result<()> {
+instructions::trailing_stop::set_trailing_stop(ctx, trail_distance_bps, close_percentage)
+}
+
+pub fn update_trailing_stop(ctx: Context<UpdateTrailingStop>) -> Result<()> {
+instructions::trailing_stop::update_trailing_stop(ctx)
+}
+
+pub fn execute_trailing_stop(ctx: Context<ExecuteTrailingStop>) -> Result<()> {
+instructions::trailing_stop::execute_trailing_stop(ctx)
+}
+
+pub fn cancel_trailing_stop(ctx: Context<CancelTrailingStop>) -> Result<()> {
+instructions::trailing_stop::cancel_trailing_stop(ctx)
+}
+
+// Limit orders
+pub fn place_limit_order(ctx: Context<PlaceLimitOrder>, is_long: bool, limit_price_fp: u128, quote_to_spend: u64, leverage_x: u16, expires_at: Option<i64>) -> Result<()> {
+instructions::limit_order::place_limit_order(ctx, is_long, limit_price_fp, quote_to_spend, leverage_x, expires_at)
+}
+
+pub fn close_expired_order(ctx: Context<CloseExpiredOrder>) -> Result<()> {
+instructions::expired_orders::close_expired_order(ctx)
+}
+
+pub fn transfer_position(ctx: Context<TransferPosition>) -> Result<()> {
+instructions::position_transfer::transfer_position(ctx)
+}
+
+pub fn fill_limit_order<'info>(ctx: Context<'_, '_, 'info, 'info, FillLimitOrder<'info>>) -> Result<()> {
+instructions::limit_order::fill_limit_order(ctx)
+}
+
+pub fn cancel_limit_order(ctx: Context<CancelLimitOrder>) -> Result<()> {
+instructions::limit_order::cancel_limit_order(ctx)
 }
 
 // Enhanced liquidation system
@@ -95,6 +407,22 @@ pub fn enhanced_liquidate(ctx: Context<EnhancedLiquidate>, max_liquidation_perce
 instructions::enhanced_liquidation::enhanced_liquidate(ctx, max_liquidation_percentage)
 }
 
+pub fn auto_deleverage<'info>(ctx: Context<'_, '_, 'info, 'info, AutoDeleverage<'info>>, deleverage_longs: bool) -> Result<()> {
+instructions::auto_deleverage::auto_deleverage(ctx, deleverage_longs)
+}
+
+pub fn batch_liquidate<'info>(ctx: Context<'_, '_, 'info, 'info, BatchLiquidate<'info>>) -> Result<u32> {
+instructions::batch_liquidate::batch_liquidate(ctx)
+}
+
+pub fn initialize_insurance_fund(ctx: Context<InitializeInsuranceFund>) -> Result<()> {
+instructions::enhanced_liquidation::initialize_insurance_fund(ctx)
+}
+
+pub fn initialize_market_insurance_fund(ctx: Context<InitializeMarketInsuranceFund>) -> Result<()> {
+instructions::enhanced_liquidation::initialize_market_insurance_fund(ctx)
+}
+
 pub fn deposit_insurance_fund(ctx: Context<DepositInsuranceFund>, amount: u64) -> Result<()> {
 instructions::enhanced_liquidation::deposit_insurance_fund(ctx, amount)
 }
@@ -102,4 +430,10 @@ instructions::enhanced_liquidation::deposit_insurance_fund(ctx, amount)
 pub fn withdraw_insurance_fund(ctx: Context<WithdrawInsuranceFund>, amount: u64, reason: String) -> Result<()> {
 instructions::enhanced_liquidation::withdraw_insurance_fund(ctx, amount, reason)
 }
+
+// Localnet/test-only helpers (feature = "test-helpers")
+#[cfg(feature = "test-helpers")]
+pub fn force_set_oracle_price(ctx: Context<ForceSetOraclePrice>, symbol: [u8; 12], price_fp: u128, confidence_fp: u128, last_updated_ts: Option<i64>) -> Result<()> {
+instructions::test_helpers::force_set_oracle_price(ctx, symbol, price_fp, confidence_fp, last_updated_ts)
+}
 }
@@ -1,13 +1,1144 @@
 use anchor_lang::prelude::*;
-use crate::state::{Market, FP};
-use crate::oracle::read_oracle_fp;
+use crate::errors::PerpsError;
+use crate::events::OracleSourceUsed;
+use crate::state::{LiquidatorRewardTier, Market, OracleSource, FP};
+use crate::oracle::{
+    aggregate_oracle_prices, emergency_price_fallback, read_freshest_pyth_price, read_market_oracle_fp,
+    read_oracle_with_config, read_price_with_failover, read_switchboard_price, risk_index_price_fp, OracleConfig,
+};
 
 
 pub fn current_mark_price_fp(m: &Account<Market>, oracle: &Account<crate::state::OraclePrice>) -> Result<u128> {
-let index_fp = read_oracle_fp(oracle)?;
+let index_fp = read_market_oracle_fp(m, oracle)?;
+Ok(mark_from_index_fp(m, index_fp))
+}
+
+/// `current_mark_price_fp` for liquidations, off the oracle TWAP when the market sets a window
+pub fn risk_mark_price_fp(m: &Account<Market>, oracle: &Account<crate::state::OraclePrice>, oracle_twap: Option<&crate::state::OracleTwap>) -> Result<u128> {
+let index_fp = risk_index_price_fp(m, oracle, oracle_twap)?;
+Ok(mark_from_index_fp(m, index_fp))
+}
+
+/// Mark price for the trade path: when the market has a Pyth or Switchboard feed the index
+/// is the median of the primary oracle and those feeds, and the trade fails with
+/// `OraclePriceDeviation` if any two disagree. `pyth_fallbacks` are the market's registered
+/// fallback feeds; the freshest valid feed wins. Markets with a source priority walk that
+/// chain instead. Live index prices are remembered for the emergency moving average.
+pub fn checked_mark_price_fp<'a, 'info>(
+    m: &mut Account<Market>,
+    oracle: &Account<crate::state::OraclePrice>,
+    pyth_oracle: Option<&'a AccountInfo<'info>>,
+    switchboard_oracle: Option<&'a AccountInfo<'info>>,
+    pyth_fallbacks: &'a [AccountInfo<'info>],
+) -> Result<u128> {
+m.ensure_oracle_live()?;
+let (source, index_fp) = if m.has_source_priority() {
+    failover_index_price_fp(m, oracle, pyth_oracle, switchboard_oracle, pyth_fallbacks)?
+} else if m.pyth_oracle.is_none() && m.switchboard_oracle.is_none() {
+    (OracleSource::Push, read_oracle_with_config(oracle, &OracleConfig::for_market(m))?)
+} else {
+    let mut feeds = vec![];
+    if let Some(pyth_key) = m.pyth_oracle {
+        let pyth = pyth_oracle.ok_or(PerpsError::OracleFeedNotFound)?;
+        require_keys_eq!(pyth.key(), pyth_key, PerpsError::OracleFeedNotFound);
+        feeds.push(pyth);
+        for fallback in pyth_fallbacks {
+            require!(m.is_pyth_feed(fallback.key), PerpsError::OracleFeedNotFound);
+            feeds.push(fallback);
+        }
+    }
+    let switchboard = registered_switchboard_feed(m, switchboard_oracle)?;
+    (OracleSource::Push, aggregate_oracle_prices(oracle, &feeds, switchboard, &OracleConfig::for_market(m))?)
+};
+if source != OracleSource::EmergencyMovingAverage {
+    m.record_index_price(index_fp);
+}
+Ok(mark_from_index_fp(m, index_fp))
+}
+
+/// Index price from the first source in the market's priority that passes
+/// validation. Pyth feeds must be registered to the market.
+fn failover_index_price_fp<'a, 'info>(
+    m: &Account<Market>,
+    oracle: &Account<crate::state::OraclePrice>,
+    pyth_oracle: Option<&'a AccountInfo<'info>>,
+    switchboard_oracle: Option<&'a AccountInfo<'info>>,
+    pyth_fallbacks: &'a [AccountInfo<'info>],
+) -> Result<(OracleSource, u128)> {
+let config = OracleConfig::for_market(m);
+let (source, index_fp) = read_price_with_failover(&m.oracle_source_priority, |source| match source {
+    OracleSource::Push => read_oracle_with_config(oracle, &config),
+    OracleSource::Pyth => {
+        let feeds: Vec<&AccountInfo> = pyth_oracle.into_iter().chain(pyth_fallbacks).collect();
+        require!(!feeds.is_empty(), PerpsError::OracleFeedNotFound);
+        for feed in &feeds {
+            require!(m.is_pyth_feed(feed.key), PerpsError::OracleFeedNotFound);
+        }
+        read_freshest_pyth_price(&feeds, &config)
+    }
+    OracleSource::Switchboard => {
+        let feed = registered_switchboard_feed(m, switchboard_oracle)?.ok_or(PerpsError::OracleFeedNotFound)?;
+        read_switchboard_price(feed, &config)
+    }
+    OracleSource::EmergencyMovingAverage => emergency_price_fallback(&m.key(), &m.recent_index_prices_fp),
+    OracleSource::Unset => Err(PerpsError::OracleFeedNotFound.into()),
+})?;
+emit!(OracleSourceUsed { market: m.key(), source, price_fp: index_fp });
+Ok((source, index_fp))
+}
+
+/// The passed Switchboard feed when the market has one registered; it must be that feed
+fn registered_switchboard_feed<'a, 'info>(
+    m: &Market,
+    switchboard_oracle: Option<&'a AccountInfo<'info>>,
+) -> Result<Option<&'a AccountInfo<'info>>> {
+let Some(switchboard_key) = m.switchboard_oracle else { return Ok(None) };
+let feed = switchboard_oracle.ok_or(PerpsError::OracleFeedNotFound)?;
+require_keys_eq!(feed.key(), switchboard_key, PerpsError::OracleFeedNotFound);
+Ok(Some(feed))
+}
+
+/// vAMM spot price, `quote / base` at price precision. Parity (FP) means the
+/// AMM agrees with the index.
+pub fn amm_spot_price_fp(m: &Market) -> u128 {
+if m.amm_base_reserve_fp == 0 { return FP; }
+m.amm_quote_reserve_fp * FP / m.amm_base_reserve_fp
+}
+
+/// Reserves after a trade of `base_units` against the vAMM at constant
+/// `base * quote`. Reserves hold one FP per base unit, so a trader buying
+/// (opening long, or closing short) takes `base_units * FP` out of the base
+/// side and pushes the spot price up; selling does the opposite.
+pub fn amm_reserves_after_trade(base_fp: u128, quote_fp: u128, base_units: u64, trader_buys: bool) -> Result<(u128, u128)> {
+let k = base_fp.checked_mul(quote_fp).ok_or(PerpsError::MathOverflow)?;
+let delta_fp = base_units as u128 * FP;
+let new_base = if trader_buys {
+    require!(delta_fp < base_fp, PerpsError::InsufficientLiquidity);
+    base_fp - delta_fp
+} else {
+    base_fp.checked_add(delta_fp).ok_or(PerpsError::MathOverflow)?
+};
+Ok((new_base, k / new_base))
+}
+
+/// No open may leave a vAMM reserve below this share of its pre-trade depth.
+pub const AMM_RESERVE_FLOOR_BPS: u128 = 1_000;
+
+/// `amm_reserves_after_trade` for an open, refusing one that would leave
+/// either reserve under `AMM_RESERVE_FLOOR_BPS` of what it held before
+pub fn amm_reserves_after_open(base_fp: u128, quote_fp: u128, base_units: u64, trader_buys: bool) -> Result<(u128, u128)> {
+let (new_base, new_quote) = amm_reserves_after_trade(base_fp, quote_fp, base_units, trader_buys)?;
+require!(
+    new_base >= base_fp / 10_000 * AMM_RESERVE_FLOOR_BPS && new_quote >= quote_fp / 10_000 * AMM_RESERVE_FLOOR_BPS,
+    PerpsError::InsufficientLiquidity
+);
+Ok((new_base, new_quote))
+}
+
+/// Average price an open of `base_units` fills at across the vAMM curve,
+/// starting from the pre-trade `mark_fp`. The mark is linear in the AMM's
+/// quote/base ratio, so the average fill is the mark at the average ratio
+/// the trade swapped at: `|d quote| / |d base|`.
+pub fn average_fill_price_fp(m: &Market, mark_fp: u128, base_units: u64, is_long: bool) -> Result<u128> {
+if !m.has_amm_reserves() || base_units == 0 { return Ok(mark_fp); }
+let (base_after, quote_after) = amm_reserves_after_open(m.amm_base_reserve_fp, m.amm_quote_reserve_fp, base_units, is_long)?;
+let avg_ratio_fp = quote_after.abs_diff(m.amm_quote_reserve_fp) * FP / base_after.abs_diff(m.amm_base_reserve_fp);
+let skew_factor_fp = |ratio_fp: u128| FP as i128 + m.skew_k_bps as i128 * (ratio_fp as i128 - FP as i128) / 10_000;
+let before_fp = skew_factor_fp(amm_spot_price_fp(m));
+if before_fp <= 0 { return Ok(mark_fp); }
+Ok((mark_fp as i128 * skew_factor_fp(avg_ratio_fp) / before_fp).max(1) as u128)
+}
+
+/// Reject a fill whose average price sits more than `max_slippage_bps` from
+/// the pre-trade mark. Returns the slippage.
+pub fn check_slippage(mark_fp: u128, average_fill_fp: u128, max_slippage_bps: u16) -> Result<u64> {
+require!(mark_fp > 0, PerpsError::InvalidPrice);
+let slippage_bps = (average_fill_fp.abs_diff(mark_fp) * 10_000 / mark_fp) as u64;
+require!(slippage_bps <= max_slippage_bps as u64, PerpsError::MarketImpactTooHigh);
+Ok(slippage_bps)
+}
+
+/// Apply the AMM skew to an index price: the vAMM spot price sets how far
+/// execution sits from the oracle, scaled by `skew_k_bps`
+pub fn mark_from_index_fp(m: &Market, index_fp: u128) -> u128 {
 let k = m.skew_k_bps as i128; // basis points skew strength
-let ratio_fp = (m.amm_quote_reserve_fp as i128 * FP as i128) / m.amm_base_reserve_fp as i128;
+let ratio_fp = amm_spot_price_fp(m) as i128;
 let skew_term_fp = (k * (ratio_fp - FP as i128)) / 10_000i128;
 let mark_fp = ((index_fp as i128) + ((index_fp as i128 * skew_term_fp) / FP as i128)) as u128;
-Ok(mark_fp.max(1))
+mark_fp.max(1)
+}
+
+/// Largest move of the AMM reserve ratio per `rebalance_amm` call, in bps of parity.
+pub const AMM_REBALANCE_MAX_STEP_BPS: u128 = 50;
+/// Minimum time between two `rebalance_amm` calls on a market.
+pub const AMM_REBALANCE_INTERVAL_SECONDS: i64 = 300;
+
+/// Reserves after one bounded step of the quote/base ratio toward parity,
+/// where `mark_from_index_fp` quotes the index price unchanged. The step is
+/// at most `max_step_bps` of parity and never overshoots it; the constant
+/// product `base * quote` is kept up to rounding.
+pub fn rebalanced_amm_reserves(base_fp: u128, quote_fp: u128, max_step_bps: u128) -> Result<(u128, u128)> {
+    require!(base_fp > 0 && quote_fp > 0, PerpsError::InvalidMarketParameters);
+    let k = base_fp.checked_mul(quote_fp).ok_or(PerpsError::MathOverflow)?;
+    let ratio_fp = quote_fp.checked_mul(FP).ok_or(PerpsError::MathOverflow)? / base_fp;
+    let step_fp = FP * max_step_bps / 10_000;
+    if ratio_fp.abs_diff(FP) <= step_fp {
+        // Landing on parity: equal reserves quote exactly the index
+        let side = k.isqrt();
+        return Ok((side, side));
+    }
+    let target_ratio_fp = if ratio_fp > FP { ratio_fp - step_fp } else { ratio_fp + step_fp };
+    // base * quote = k and quote / base = ratio give base = sqrt(k / ratio)
+    let new_base = (k / target_ratio_fp).checked_mul(FP).ok_or(PerpsError::MathOverflow)?.isqrt();
+    require!(new_base > 0, PerpsError::MathOverflow);
+    Ok((new_base, k / new_base))
+}
+
+/// Length of one funding period; `Market::funding_rate_fp` is quoted per period.
+pub const FUNDING_INTERVAL_SECONDS: i64 = 3600;
+
+/// Per-unit funding accrued by each side during one crank (quote, fixed point).
+/// Positive means the side receives funding, negative means it pays.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FundingIndexDeltas {
+    pub long_fp: i128,
+    pub short_fp: i128,
+    /// Paying side's charge left below one index unit, scaled by
+    /// `FP * FUNDING_INTERVAL_SECONDS`; feed it back into the next crank.
+    pub carry: u128,
+}
+
+/// Skew surcharge rate charged to the majority side, scaled by `skew_k_bps`
+/// and capped at `max_funding_rate_fp`. Positive when longs dominate (longs
+/// pay), negative when shorts dominate.
+///
+/// A one-sided book (e.g. at market launch) gets the capped full-skew
+/// surcharge directly instead of going through a long/short ratio, which is
+/// undefined when one side is empty. It is only charged once the other side
+/// opens and there is someone to pay it to.
+pub fn skew_surcharge_rate_fp(
+    total_long_size: u64,
+    total_short_size: u64,
+    skew_k_bps: u32,
+    max_funding_rate_fp: i128,
+) -> i128 {
+    let full_skew_fp = FP as i128 * skew_k_bps as i128 / 10_000;
+    match (total_long_size, total_short_size) {
+        (0, 0) => 0,
+        (_, 0) => clamp_funding_rate_fp(full_skew_fp, max_funding_rate_fp),
+        (0, _) => clamp_funding_rate_fp(-full_skew_fp, max_funding_rate_fp),
+        (long, short) => {
+            // Imbalance in [-FP, FP]: (long - short) / (long + short)
+            let total_oi = long as i128 + short as i128;
+            let imbalance_fp = (long as i128 - short as i128) * FP as i128 / total_oi;
+            clamp_funding_rate_fp(imbalance_fp * skew_k_bps as i128 / 10_000, max_funding_rate_fp)
+        }
+    }
+}
+
+/// Immediate surcharge, in bps of notional, on an open of `size` that leaves
+/// the opener's side holding a larger share of the book than before. Opens
+/// that shrink the imbalance pay nothing. Scaled by `skew_k_bps` like the
+/// funding surcharge and capped at `cap_bps` (0 = off).
+pub fn open_skew_surcharge_bps(
+    total_long_size: u64,
+    total_short_size: u64,
+    size: u64,
+    is_long: bool,
+    skew_k_bps: u32,
+    cap_bps: u16,
+) -> u16 {
+    // Imbalance in [-FP, FP]: (long - short) / (long + short)
+    let imbalance_fp = |long: u64, short: u64| -> i128 {
+        let total_oi = long as i128 + short as i128;
+        if total_oi == 0 { 0 } else { (long as i128 - short as i128) * FP as i128 / total_oi }
+    };
+    let before_fp = imbalance_fp(total_long_size, total_short_size);
+    let after_fp = if is_long {
+        imbalance_fp(total_long_size.saturating_add(size), total_short_size)
+    } else {
+        -imbalance_fp(total_long_size, total_short_size.saturating_add(size))
+    };
+    if after_fp <= 0 || after_fp <= before_fp.abs() {
+        return 0;
+    }
+    (after_fp as u128 * skew_k_bps as u128 / FP).min(cap_bps as u128) as u16
+}
+
+/// Clamp a funding rate to `[-max_funding_rate_fp, +max_funding_rate_fp]`.
+pub fn clamp_funding_rate_fp(rate_fp: i128, max_funding_rate_fp: i128) -> i128 {
+    let cap = max_funding_rate_fp.abs();
+    rate_fp.clamp(-cap, cap)
+}
+
+/// Exponential moving average of the funding rate, clamped to the cap:
+/// `alpha * raw + (1 - alpha) * prev` with `alpha = alpha_bps / 10_000`.
+/// Without a previous rate, or with `alpha_bps` 0 or 10_000, it is the raw rate.
+pub fn smoothed_funding_rate_fp(raw_fp: i128, prev_fp: Option<i128>, alpha_bps: u16, max_funding_rate_fp: i128) -> i128 {
+    let rate_fp = match prev_fp {
+        Some(prev_fp) if alpha_bps > 0 && alpha_bps < 10_000 => {
+            let alpha = alpha_bps as i128;
+            (raw_fp * alpha + prev_fp * (10_000 - alpha)) / 10_000
+        }
+        _ => raw_fp,
+    };
+    clamp_funding_rate_fp(rate_fp, max_funding_rate_fp)
+}
+
+/// Convert a funding rate into cumulative index increments for both sides.
+///
+/// The paying side is charged `|rate| * price` per unit per funding period,
+/// pro-rated by `elapsed_seconds`. Everything it pays is shared across the
+/// receiving side's open interest, so the minority side collects the
+/// majority's surcharge and funding stays zero-sum. A one-sided book has
+/// nobody to pay, so nothing is charged until the other side opens.
+///
+/// Short cranks charge less than one index unit, so whatever the division
+/// drops is returned as `carry` and added to the next crank's charge instead
+/// of being forgiven. `carry` must come from a crank charging the same side.
+pub fn funding_index_deltas(
+    rate_fp: i128,
+    index_price_fp: u128,
+    elapsed_seconds: i64,
+    total_long_size: u64,
+    total_short_size: u64,
+    carry: u128,
+) -> Result<FundingIndexDeltas> {
+    let (payer_oi, receiver_oi) = if rate_fp > 0 {
+        (total_long_size, total_short_size)
+    } else {
+        (total_short_size, total_long_size)
+    };
+    if rate_fp == 0 || elapsed_seconds <= 0 || payer_oi == 0 || receiver_oi == 0 {
+        return Ok(FundingIndexDeltas { long_fp: 0, short_fp: 0, carry: 0 });
+    }
+
+    let period = FP * FUNDING_INTERVAL_SECONDS as u128;
+    let owed = rate_fp.unsigned_abs()
+        .checked_mul(index_price_fp)
+        .and_then(|v| v.checked_mul(elapsed_seconds as u128))
+        .and_then(|v| v.checked_add(carry))
+        .ok_or(PerpsError::MathOverflow)?;
+    let paid_per_unit_fp = owed / period;
+    let carry = owed % period;
+    let received_per_unit_fp = paid_per_unit_fp
+        .checked_mul(payer_oi as u128)
+        .ok_or(PerpsError::MathOverflow)?
+        / receiver_oi as u128;
+
+    let paid = -i128::try_from(paid_per_unit_fp).map_err(|_| PerpsError::MathOverflow)?;
+    let received = i128::try_from(received_per_unit_fp).map_err(|_| PerpsError::MathOverflow)?;
+
+    Ok(if rate_fp > 0 {
+        FundingIndexDeltas { long_fp: paid, short_fp: received, carry }
+    } else {
+        FundingIndexDeltas { long_fp: received, short_fp: paid, carry }
+    })
+}
+
+/// Render a price (or any quote value at price precision) with `price_decimals`
+/// places, e.g. `to_human_price(60_000_500_000, 6) == "60000.500000"`. Log-only.
+pub fn to_human_price(value_fp: i128, price_decimals: u8) -> String {
+    let scale = 10u128.pow(price_decimals as u32);
+    let sign = if value_fp < 0 { "-" } else { "" };
+    let abs = value_fp.unsigned_abs();
+    if price_decimals == 0 {
+        return format!("{}{}", sign, abs);
+    }
+    format!("{}{}.{:0width$}", sign, abs / scale, abs % scale, width = price_decimals as usize)
+}
+
+/// Rescale between price precision and native quote token units.
+fn rescale_decimals(value: u128, from_decimals: u8, to_decimals: u8) -> Result<u128> {
+    if to_decimals >= from_decimals {
+        10u128.checked_pow((to_decimals - from_decimals) as u32)
+            .and_then(|factor| value.checked_mul(factor))
+            .ok_or(PerpsError::MathOverflow.into())
+    } else {
+        let factor = 10u128.checked_pow((from_decimals - to_decimals) as u32)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(value / factor)
+    }
+}
+
+/// Convert a quote value at price precision (`size * price_fp`) into native
+/// quote token units, rounding down. Only equal to the raw value when the
+/// quote mint happens to use `price_decimals` places.
+pub fn fp_to_quote_amount(value_fp: u128, price_decimals: u8, quote_decimals: u8) -> Result<u64> {
+    let amount = rescale_decimals(value_fp, price_decimals, quote_decimals)?;
+    u64::try_from(amount).map_err(|_| PerpsError::MathOverflow.into())
+}
+
+/// Convert a native quote token amount into a quote value at price precision.
+pub fn quote_amount_to_fp(amount: u64, price_decimals: u8, quote_decimals: u8) -> Result<u128> {
+    rescale_decimals(amount as u128, quote_decimals, price_decimals)
+}
+
+/// Split of a trader's spend at entry, in native quote units.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EntryMargin {
+    pub margin: u64,    // Collateral locked in the position
+    pub notional: u64,  // Exposure opened, always exactly margin * leverage
+    pub remainder: u64, // Part of the spend lost to truncation, never taken from the trader
+}
+
+/// Reject a requested leverage outside `[MIN_LEVERAGE_X, MAX_LEVERAGE_X]`.
+/// Zero is a bad input, not an overflow, and gets reported as one.
+pub fn validate_leverage(leverage_x: u16) -> Result<()> {
+    require!(leverage_x as u64 >= crate::state::MIN_LEVERAGE_X, PerpsError::InvalidParameters);
+    require!(leverage_x as u64 <= crate::state::MAX_LEVERAGE_X, PerpsError::LeverageTooHigh);
+    Ok(())
+}
+
+/// Size an entry from the margin rather than the raw spend. `spend / leverage`
+/// truncates, so sizing off `spend` would open more notional than the locked
+/// margin supports; instead the notional is rebuilt from the margin and the
+/// truncated remainder stays in the trader's wallet.
+pub fn entry_margin(quote_to_spend: u64, leverage_x: u16) -> Result<EntryMargin> {
+    require!(leverage_x > 0, PerpsError::InvalidParameters);
+    let margin = quote_to_spend / leverage_x as u64;
+    let notional = margin.checked_mul(leverage_x as u64).ok_or(PerpsError::MathOverflow)?;
+    Ok(EntryMargin { margin, notional, remainder: quote_to_spend - notional })
+}
+
+/// Margin, in native quote units, that puts `notional_fp` at exactly
+/// `leverage_x` or just under it. Rounds up so the resulting leverage never
+/// exceeds the target.
+pub fn margin_for_leverage(notional_fp: u128, leverage_x: u16, price_decimals: u8, quote_decimals: u8) -> Result<u64> {
+    require!(leverage_x > 0, PerpsError::InvalidParameters);
+    let margin_fp = notional_fp.div_ceil(leverage_x as u128);
+    let margin = fp_to_quote_amount(margin_fp, price_decimals, quote_decimals)?;
+    if quote_amount_to_fp(margin, price_decimals, quote_decimals)? < margin_fp {
+        return Ok(margin.checked_add(1).ok_or(PerpsError::MathOverflow)?);
+    }
+    Ok(margin)
+}
+
+/// Price at which a position's equity falls to its maintenance requirement.
+/// The margin above maintenance (`margin_fp - entry notional * maintenance_margin_bps`)
+/// is spread over `base_size`, so a long liquidates at `entry - buffer / size`
+/// and a short at `entry + buffer / size`; more margin moves it further from entry.
+/// A position already at or under maintenance liquidates at entry.
+pub fn liquidation_price_fp(
+    entry_price_fp: u128,
+    margin_fp: u128,
+    base_size: u64,
+    maintenance_margin_bps: u16,
+    is_long: bool,
+) -> Result<u128> {
+    require!(base_size > 0, PerpsError::DivisionByZero);
+    let notional_fp = entry_price_fp.checked_mul(base_size as u128).ok_or(PerpsError::MathOverflow)?;
+    let maintenance_fp = notional_fp * maintenance_margin_bps as u128 / 10_000;
+    let move_fp = margin_fp.saturating_sub(maintenance_fp) / base_size as u128;
+    Ok(if is_long {
+        entry_price_fp.saturating_sub(move_fp)
+    } else {
+        entry_price_fp.checked_add(move_fp).ok_or(PerpsError::MathOverflow)?
+    })
+}
+
+/// Entry price of `size_a` units entered at `price_a_fp` and `size_b` more at
+/// `price_b_fp`, weighted by size. Rounds against the trader, up for a long
+/// and down for a short, so adding to a position never improves its entry.
+pub fn average_entry_price_fp(size_a: u64, price_a_fp: u128, size_b: u64, price_b_fp: u128, is_long: bool) -> Result<u128> {
+    let total = size_a as u128 + size_b as u128;
+    require!(total > 0, PerpsError::DivisionByZero);
+    let notional_fp = (size_a as u128).checked_mul(price_a_fp)
+        .and_then(|a| a.checked_add((size_b as u128).checked_mul(price_b_fp)?))
+        .ok_or(PerpsError::MathOverflow)?;
+    Ok(if is_long { notional_fp.div_ceil(total) } else { notional_fp / total })
+}
+
+/// Reject an open whose payout after a `max_favorable_move_bps` move in its
+/// favour (margin back plus that move on its notional) is more than the
+/// liquidity behind the market can cover. 0 turns the check off.
+pub fn ensure_payout_backed(margin: u64, notional: u64, max_favorable_move_bps: u16, available_liquidity: u64) -> Result<()> {
+    if max_favorable_move_bps == 0 {
+        return Ok(());
+    }
+    let max_payout = margin as u128 + notional as u128 * max_favorable_move_bps as u128 / 10_000;
+    require!(max_payout <= available_liquidity as u128, PerpsError::InsufficientLiquidity);
+    Ok(())
+}
+
+/// Slack allowed on top of the leverage cap when checking a filled position, in bps of the cap.
+pub const EFFECTIVE_LEVERAGE_TOLERANCE_BPS: u128 = 50;
+
+/// Reject a fill whose realized `notional / margin` exceeds `cap_x` by more
+/// than `tolerance_bps` of the cap. Both values at price precision.
+pub fn ensure_effective_leverage(filled_notional_fp: u128, margin_fp: u128, cap_x: u16, tolerance_bps: u128) -> Result<()> {
+    require!(margin_fp > 0, PerpsError::InsufficientMargin);
+    let max_notional_fp = margin_fp
+        .checked_mul(cap_x as u128 * (10_000 + tolerance_bps))
+        .ok_or(PerpsError::MathOverflow)?
+        / 10_000;
+    require!(filled_notional_fp <= max_notional_fp, PerpsError::LeverageTooHigh);
+    Ok(())
+}
+
+/// Result of settling a closed position, all at price precision.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CloseSettlement {
+    pub fee_fp: u128,       // Fee actually collected
+    pub payout_fp: u128,    // Returned to the trader
+    pub shortfall_fp: u128, // Loss beyond the position's equity (bad debt)
+}
+
+/// Settle a close. Fees are charged on exit notional, as on any venue, and
+/// paid out of the position's equity (margin + PnL). The fee never exceeds
+/// that equity: an underwater position pays what it has left and the rest of
+/// its loss is reported as shortfall instead of the fee being taken from the
+/// vault. Since markets require `fee_bps <= maintenance_margin_bps`, a position
+/// that is still solvent always covers its full fee, so a winning trade can
+/// never be zeroed out by fees alone.
+pub fn close_settlement(margin_fp: u128, pnl_fp: i128, notional_exit_fp: u128, fee_bps: u16) -> CloseSettlement {
+    let equity_fp = margin_fp as i128 + pnl_fp;
+    if equity_fp <= 0 {
+        return CloseSettlement { fee_fp: 0, payout_fp: 0, shortfall_fp: equity_fp.unsigned_abs() };
+    }
+    let equity_fp = equity_fp as u128;
+    let fee_fp = (notional_exit_fp * fee_bps as u128 / 10_000).min(equity_fp);
+    CloseSettlement { fee_fp, payout_fp: equity_fp - fee_fp, shortfall_fp: 0 }
+}
+
+/// Settle two positions in the same market closed together. The size that
+/// offsets between them never leaves the book, so only the net size pays the
+/// exit fee, once; margin and PnL of both legs pool into a single settlement.
+pub fn netted_close_settlement(
+    base_sizes: [i64; 2],
+    margins_fp: [u128; 2],
+    pnls_fp: [i128; 2],
+    mark_fp: u128,
+    fee_bps: u16,
+) -> CloseSettlement {
+    let net_size = (base_sizes[0] as i128 + base_sizes[1] as i128).unsigned_abs();
+    close_settlement(margins_fp[0] + margins_fp[1], pnls_fp[0] + pnls_fp[1], net_size * mark_fp, fee_bps)
+}
+
+/// Price at which a position's equity (margin + PnL) reaches zero. Closing a
+/// long at or below it, or a short at or above it, leaves bad debt. The
+/// cushion per unit rounds up, so a mark past this price always means
+/// `margin + pnl <= 0` exactly.
+pub fn bankruptcy_price_fp(entry_price_fp: u128, margin_fp: u128, size: u64, is_long: bool) -> u128 {
+    let cushion_fp = margin_fp.div_ceil(size.max(1) as u128);
+    if is_long {
+        entry_price_fp.saturating_sub(cushion_fp)
+    } else {
+        entry_price_fp.saturating_add(cushion_fp)
+    }
+}
+
+/// Whether `mark_fp` sits at or past a position's bankruptcy price
+pub fn is_past_bankruptcy(mark_fp: u128, bankruptcy_price_fp: u128, is_long: bool) -> bool {
+    if is_long { mark_fp <= bankruptcy_price_fp } else { mark_fp >= bankruptcy_price_fp }
+}
+
+/// Keeper reward for liquidating `notional` quote tokens: the rate of the
+/// highest tier the notional reaches, never less than `floor`.
+pub fn liquidator_reward(notional: u64, tiers: &[LiquidatorRewardTier], floor: u64) -> u64 {
+    let reward_bps = tiers.iter()
+        .filter(|tier| tier.reward_bps > 0 && notional >= tier.min_notional)
+        .map(|tier| tier.reward_bps)
+        .next_back()
+        .unwrap_or(0);
+    let scaled = (notional as u128 * reward_bps as u128 / 10_000) as u64;
+    scaled.max(floor)
+}
+
+/// Extra percentage points a liquidator may close on top of the bare minimum.
+pub const LIQUIDATION_FAIRNESS_BUFFER_PCT: u8 = 5;
+
+/// Largest share (percent) of a position a liquidator may close: just enough
+/// that the remainder is back above maintenance margin after paying the
+/// liquidation fee, plus `buffer_pct`.
+///
+/// `equity`, `maintenance_required` and `full_close_fee` must share units;
+/// `full_close_fee` is the fee for liquidating the whole position. Equity is
+/// assumed to stay with the remaining position (the closed slice's PnL is
+/// realized into margin), so closing a fraction `f` leaves `equity - f * fee`
+/// against `(1 - f) * maintenance_required`.
+pub fn max_allowed_liquidation_pct(
+    equity: i128,
+    maintenance_required: u128,
+    full_close_fee: u128,
+    buffer_pct: u8,
+) -> u8 {
+    if equity >= maintenance_required as i128 {
+        return 0;
+    }
+    if equity <= 0 || full_close_fee >= maintenance_required {
+        return 100;
+    }
+    let shortfall = maintenance_required - equity as u128;
+    let needed_pct = (shortfall * 100).div_ceil(maintenance_required - full_close_fee);
+    (needed_pct + buffer_pct as u128).min(100) as u8
+}
+
+/// Extra maintenance, in bps of the remaining notional, a health-restoring
+/// liquidation leaves the position with on top of the bare requirement
+pub const RESTORE_HEALTH_BUFFER_BPS: u16 = 50;
+
+/// Smallest base size a liquidator must close for the rest of the position to
+/// clear maintenance plus `buffer_bps` again, at `mark_fp`.
+///
+/// The closed slice's PnL is realized into margin, so closing `x` of `size`
+/// only costs equity its share of `full_close_fee_fp` (the fee for
+/// liquidating everything, at price precision like `margin_fp`). The rest must
+/// cover `(size - x) * mark * (maintenance + buffer)`. Returns 0 for a position
+/// that already clears that, and the whole size when no partial close can
+/// restore it (insolvent, or the fee outruns the requirement it frees).
+#[allow(clippy::too_many_arguments)]
+pub fn calculate_liquidation_size_to_restore_health(
+    size: u64,
+    entry_price_fp: u128,
+    mark_fp: u128,
+    is_long: bool,
+    margin_fp: i128,
+    maintenance_margin_bps: u16,
+    full_close_fee_fp: u128,
+    buffer_bps: u16,
+) -> u64 {
+    let price_move_fp = if is_long {
+        mark_fp as i128 - entry_price_fp as i128
+    } else {
+        entry_price_fp as i128 - mark_fp as i128
+    };
+    let equity_fp = margin_fp + size as i128 * price_move_fp;
+    // Both sides scaled by 10_000 to keep the bps exact
+    let required = size as u128 * mark_fp * (maintenance_margin_bps as u128 + buffer_bps as u128);
+    if equity_fp >= 0 && equity_fp as u128 * 10_000 >= required {
+        return 0;
+    }
+    let fee = full_close_fee_fp * 10_000;
+    if equity_fp <= 0 || fee >= required {
+        return size;
+    }
+    // x * (required - fee) / size >= required - equity * 10_000
+    let shortfall = required - equity_fp as u128 * 10_000;
+    shortfall
+        .checked_mul(size as u128)
+        .map_or(size, |scaled| scaled.div_ceil(required - fee).min(size as u128) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRICE: u128 = 100 * FP;
+
+    #[test]
+    fn test_ten_percent_of_base_reserve_moves_the_quote_side_price() {
+        // A long taking 10% of the base side: 1000 -> 900 base, quote rises to keep k
+        let (base, quote) = amm_reserves_after_trade(1_000 * FP, 1_000 * FP, 100, true).unwrap();
+        assert_eq!((base, quote), (900 * FP, 1_111_111_111));
+
+        let mut m = Market { amm_base_reserve_fp: base, amm_quote_reserve_fp: quote, skew_k_bps: 10_000, ..Default::default() };
+        // Spot rises by 1/0.9^2 - 1, about 23.5%
+        assert_eq!(amm_spot_price_fp(&m), 1_234_567);
+        assert_eq!(mark_from_index_fp(&m, PRICE), 123_456_700);
+        // At 10% skew strength the mark only moves a tenth as far from the index
+        m.skew_k_bps = 1_000;
+        assert_eq!(mark_from_index_fp(&m, PRICE), 102_345_600);
+
+        // A short of the same size pushes it the other way
+        let (base, quote) = amm_reserves_after_trade(1_000 * FP, 1_000 * FP, 100, false).unwrap();
+        assert_eq!((base, quote), (1_100 * FP, 909_090_909));
+        // The AMM can't sell more base than it holds
+        assert_eq!(
+            amm_reserves_after_trade(1_000 * FP, 1_000 * FP, 1_000, true).unwrap_err(),
+            PerpsError::InsufficientLiquidity.into()
+        );
+    }
+
+    #[test]
+    fn test_small_open_fills_within_slippage_and_a_draining_one_is_refused() {
+        let m = Market { amm_base_reserve_fp: 1_000 * FP, amm_quote_reserve_fp: 1_000 * FP, skew_k_bps: 1_000, ..Default::default() };
+
+        // 1% of the base side: the average ratio is 1000/990, about 1.0101,
+        // so at 10% skew strength the fill averages ~0.1% above the mark
+        let fill = average_fill_price_fp(&m, PRICE, 10, true).unwrap();
+        assert_eq!(fill, 100_101_000);
+        assert_eq!(check_slippage(PRICE, fill, 50).unwrap(), 10);
+        assert_eq!(check_slippage(PRICE, fill, 5).unwrap_err(), PerpsError::MarketImpactTooHigh.into());
+        // A short fills below the mark
+        assert!(average_fill_price_fp(&m, PRICE, 10, false).unwrap() < PRICE);
+
+        // Taking 95% of the base side would leave it under the 10% floor
+        assert_eq!(average_fill_price_fp(&m, PRICE, 950, true).unwrap_err(), PerpsError::InsufficientLiquidity.into());
+        let mut drained = m.clone();
+        assert_eq!(drained.increase_open_interest(true, 950).unwrap_err(), PerpsError::InsufficientLiquidity.into());
+
+        // Without reserves there is no curve to slip along
+        assert_eq!(average_fill_price_fp(&Market::default(), PRICE, 10, true).unwrap(), PRICE);
+    }
+
+    #[test]
+    fn test_opens_move_the_mark_and_closes_walk_it_back() {
+        let mut m = Market {
+            amm_base_reserve_fp: 1_000 * FP,
+            amm_quote_reserve_fp: 1_000 * FP,
+            skew_k_bps: 1_000,
+            ..Default::default()
+        };
+        assert_eq!(mark_from_index_fp(&m, PRICE), PRICE);
+
+        m.increase_open_interest(true, 50).unwrap();
+        let after_long = mark_from_index_fp(&m, PRICE);
+        assert!(after_long > PRICE);
+
+        m.increase_open_interest(false, 20).unwrap();
+        let after_short = mark_from_index_fp(&m, PRICE);
+        assert!(PRICE < after_short && after_short < after_long);
+
+        m.reduce_open_interest(true, 50);
+        m.reduce_open_interest(false, 20);
+        assert_eq!((m.total_long_size, m.total_short_size), (0, 0));
+        // Back at the index up to reserve rounding
+        assert!(mark_from_index_fp(&m, PRICE).abs_diff(PRICE) <= 100);
+
+        // A market without reserves isn't touched
+        let mut bare = Market::default();
+        bare.increase_open_interest(true, 50).unwrap();
+        assert_eq!(bare.amm_base_reserve_fp, 0);
+    }
+
+    #[test]
+    fn test_skew_surcharge_sign_follows_majority() {
+        assert!(skew_surcharge_rate_fp(900, 100, 100, 10_000) > 0);
+        assert!(skew_surcharge_rate_fp(100, 900, 100, 10_000) < 0);
+        assert_eq!(skew_surcharge_rate_fp(500, 500, 100, 10_000), 0);
+        assert_eq!(skew_surcharge_rate_fp(0, 0, 100, 10_000), 0);
+        // 80% imbalance at 1% strength
+        assert_eq!(skew_surcharge_rate_fp(900, 100, 100, 10_000), 8_000);
+    }
+
+    #[test]
+    fn test_opening_into_skew_costs_more_than_against_it() {
+        // Long-heavy book (900 vs 100), 1% skew strength, 2% cap
+        let into = open_skew_surcharge_bps(900, 100, 100, true, 100, 200);
+        let against = open_skew_surcharge_bps(900, 100, 100, false, 100, 200);
+        assert!(into > against);
+        // 1000 long vs 100 short after the open: ~82% imbalance at 1%
+        assert_eq!(into, 81);
+        assert_eq!(against, 0);
+
+        // Mirrored for a short-heavy book
+        assert_eq!(open_skew_surcharge_bps(100, 900, 100, false, 100, 200), 81);
+        assert_eq!(open_skew_surcharge_bps(100, 900, 100, true, 100, 200), 0);
+
+        // A minority open that flips the book only pays if the imbalance ends up larger
+        assert_eq!(open_skew_surcharge_bps(900, 100, 1_500, false, 100, 200), 0);
+        assert_eq!(open_skew_surcharge_bps(900, 100, 20_000, false, 100, 200), 91);
+
+        // Capped, and off when the cap is 0
+        assert_eq!(open_skew_surcharge_bps(900, 100, 100, true, 10_000, 50), 50);
+        assert_eq!(open_skew_surcharge_bps(900, 100, 100, true, 100, 0), 0);
+        // Balanced opens leave nothing to charge
+        assert_eq!(open_skew_surcharge_bps(500, 600, 100, true, 100, 50), 0);
+    }
+
+    #[test]
+    fn test_clamp_funding_rate() {
+        assert_eq!(clamp_funding_rate_fp(50_000, 10_000), 10_000);
+        assert_eq!(clamp_funding_rate_fp(-50_000, 10_000), -10_000);
+        assert_eq!(clamp_funding_rate_fp(5_000, 10_000), 5_000);
+    }
+
+    #[test]
+    fn test_long_skewed_market_longs_pay_shorts_receive() {
+        let (long_oi, short_oi) = (900, 100);
+        let rate = clamp_funding_rate_fp(skew_surcharge_rate_fp(long_oi, short_oi, 100, 10_000), 10_000);
+        let deltas = funding_index_deltas(rate, PRICE, FUNDING_INTERVAL_SECONDS, long_oi, short_oi, 0).unwrap();
+
+        assert!(deltas.long_fp < 0);
+        assert!(deltas.short_fp > 0);
+        // 0.8% of $100 per unit for longs, shared by 9x fewer shorts
+        assert_eq!(deltas.long_fp, -800_000);
+        assert_eq!(deltas.short_fp, 7_200_000);
+        // Zero-sum across the book
+        assert_eq!(deltas.long_fp * long_oi as i128 + deltas.short_fp * short_oi as i128, 0);
+    }
+
+    #[test]
+    fn test_surcharge_stacks_on_premium_and_is_capped() {
+        let surcharge = skew_surcharge_rate_fp(900, 100, 5_000, 10_000);
+        let rate = clamp_funding_rate_fp(20_000 + surcharge, 10_000);
+        assert_eq!(rate, 10_000);
+
+        let deltas = funding_index_deltas(rate, PRICE, FUNDING_INTERVAL_SECONDS, 900, 100, 0).unwrap();
+        assert_eq!(deltas.long_fp, -1_000_000); // capped at 1% of price per period
+    }
+
+    #[test]
+    fn test_funding_accrual_is_prorated_by_elapsed_time() {
+        let full = funding_index_deltas(10_000, PRICE, FUNDING_INTERVAL_SECONDS, 100, 100, 0).unwrap();
+        let half = funding_index_deltas(10_000, PRICE, FUNDING_INTERVAL_SECONDS / 2, 100, 100, 0).unwrap();
+        assert_eq!(half.long_fp * 2, full.long_fp);
+        assert_eq!(half.short_fp * 2, full.short_fp);
+    }
+
+    #[test]
+    fn test_sub_unit_funding_is_carried_not_dropped() {
+        // $1 at 0.1% per period owes 0.277 index units per second
+        let one_second = funding_index_deltas(1_000, 1_000_000, 1, 100, 100, 0).unwrap();
+        assert_eq!(one_second.long_fp, 0);
+        assert_eq!(one_second.carry, 1_000_000_000);
+
+        let next = funding_index_deltas(1_000, 1_000_000, 3, 100, 100, one_second.carry).unwrap();
+        assert_eq!(next.long_fp, -1);
+        assert_eq!(next.short_fp, 1);
+        assert_eq!(next.carry, 400_000_000);
+    }
+
+    #[test]
+    fn test_longs_only_market_surcharge_is_capped() {
+        // Launch state: all longs, no shorts, strong skew parameter
+        assert_eq!(skew_surcharge_rate_fp(u64::MAX, 0, 10_000, 10_000), 10_000);
+        assert_eq!(skew_surcharge_rate_fp(1, 0, 50, 10_000), 5_000);
+        assert_eq!(skew_surcharge_rate_fp(0, 1_000, 10_000, 10_000), -10_000);
+    }
+
+    #[test]
+    fn test_longs_only_market_funding_has_no_receivers() {
+        // Nobody on the other side to receive it, so the longs aren't charged
+        let rate = skew_surcharge_rate_fp(1_000_000, 0, 10_000, 10_000);
+        assert_eq!(rate, 10_000);
+        let deltas = funding_index_deltas(rate, PRICE, FUNDING_INTERVAL_SECONDS, 1_000_000, 0, 0).unwrap();
+        assert_eq!(deltas, FundingIndexDeltas { long_fp: 0, short_fp: 0, carry: 0 });
+        // The same for a shorts-only book
+        let deltas = funding_index_deltas(-rate, PRICE, FUNDING_INTERVAL_SECONDS, 0, 1_000_000, 0).unwrap();
+        assert_eq!(deltas, FundingIndexDeltas { long_fp: 0, short_fp: 0, carry: 0 });
+
+        // Once a single short opens, the longs' payment lands on it in full
+        let deltas = funding_index_deltas(rate, PRICE, FUNDING_INTERVAL_SECONDS, 1_000_000, 1, 0).unwrap();
+        assert_eq!(deltas.long_fp * 1_000_000 + deltas.short_fp, 0);
+    }
+
+    #[test]
+    fn test_fair_liquidation_pct_mildly_underwater() {
+        // Maintenance 100, equity 95, full-close fee 10: closing 6% is enough
+        let pct = max_allowed_liquidation_pct(95, 100, 10, LIQUIDATION_FAIRNESS_BUFFER_PCT);
+        // Buffered from 6%, well short of the full close a liquidator would ask for
+        assert_eq!(pct, 11);
+
+        // Remainder after the unbuffered 6% is healthy again
+        let remaining_equity = 95 * 100 - 6 * 10;
+        let remaining_maintenance = (100 - 6) * 100;
+        assert!(remaining_equity >= remaining_maintenance);
+    }
+
+    #[test]
+    fn test_fair_liquidation_pct_bounds() {
+        assert_eq!(max_allowed_liquidation_pct(100, 100, 10, 5), 0);
+        assert_eq!(max_allowed_liquidation_pct(0, 100, 10, 5), 100);
+        assert_eq!(max_allowed_liquidation_pct(-20, 100, 10, 5), 100);
+        assert_eq!(max_allowed_liquidation_pct(40, 100, 10, 5), 72);
+        // Fee at or above maintenance can never restore health partially
+        assert_eq!(max_allowed_liquidation_pct(99, 100, 100, 5), 100);
+    }
+
+    #[test]
+    fn test_restore_health_size_at_several_depths_below_maintenance() {
+        // 1000 units from 100 at 10x: 10,000 margin, 5% maintenance, 1% full-close fee
+        let (size, entry, margin, mm_bps, buffer) = (1_000u64, 100 * FP, 10_000 * FP as i128, 500u16, RESTORE_HEALTH_BUFFER_BPS);
+        let fee_at = |mark: u128| size as u128 * mark / 100;
+        let restore = |mark: u128, is_long: bool| {
+            calculate_liquidation_size_to_restore_health(size, entry, mark, is_long, margin, mm_bps, fee_at(mark), buffer)
+        };
+        // Whether the remainder clears maintenance plus the buffer, scaled by size * 10_000 to stay exact
+        let healthy_after = |mark: u128, is_long: bool, closed: u64| {
+            let price_move = if is_long { mark as i128 - entry as i128 } else { entry as i128 - mark as i128 };
+            let equity = (margin + size as i128 * price_move) * size as i128 * 10_000 - (fee_at(mark) * closed as u128 * 10_000) as i128;
+            let required = (size - closed) as i128 * mark as i128 * (mm_bps + buffer) as i128 * size as i128;
+            equity >= required
+        };
+
+        // At 96 equity is 6,000 against 5,280 with the buffer: nothing to close
+        assert_eq!(restore(96 * FP, true), 0);
+
+        // From just under maintenance at 94 down to 91, the slice grows and is the smallest that works
+        let mut previous = 0;
+        for mark in [94 * FP, 93 * FP, 92 * FP, 91 * FP] {
+            let closed = restore(mark, true);
+            assert!(closed > previous && closed < size, "{closed} at {mark}");
+            assert!(healthy_after(mark, true, closed));
+            assert!(!healthy_after(mark, true, closed - 1));
+            previous = closed;
+        }
+        // At 94: (5,170 buffered requirement - 4,000 equity) / (5.17 - 0.94 fee) per unit
+        assert_eq!(restore(94 * FP, true), 277);
+
+        // Shorts mirror longs
+        let closed = restore(106 * FP, false);
+        assert!(closed > 0 && healthy_after(106 * FP, false, closed) && !healthy_after(106 * FP, false, closed - 1));
+
+        // Insolvent, or a fee eating everything it frees: close the whole position
+        assert_eq!(restore(90 * FP, true), size);
+        assert_eq!(calculate_liquidation_size_to_restore_health(size, entry, 94 * FP, true, margin, mm_bps, fee_at(94 * FP) * 10, buffer), size);
+    }
+
+    #[test]
+    fn test_to_human_price() {
+        assert_eq!(to_human_price(60_000_500_000, 6), "60000.500000");
+        assert_eq!(to_human_price(-1_250_000, 6), "-1.250000");
+        assert_eq!(to_human_price(42, 0), "42");
+    }
+
+    #[test]
+    fn test_settlement_with_quote_decimals_differing_from_price_decimals() {
+        // 2 units long from $100 to $110 with $50 margin, 6-decimal prices
+        let pnl_fp = 2 * (110 * FP as i128 - 100 * FP as i128);
+
+        // 9-decimal quote mint: 50 tokens in, 70 tokens out
+        let margin_fp = quote_amount_to_fp(50_000_000_000, 6, 9).unwrap();
+        assert_eq!(margin_fp, 50 * FP);
+        let settle_fp = (margin_fp as i128 + pnl_fp) as u128;
+        assert_eq!(fp_to_quote_amount(settle_fp, 6, 9).unwrap(), 70_000_000_000);
+
+        // 2-decimal quote mint: same trade, cents out
+        let margin_fp = quote_amount_to_fp(5_000, 6, 2).unwrap();
+        let settle_fp = (margin_fp as i128 + pnl_fp) as u128;
+        assert_eq!(fp_to_quote_amount(settle_fp, 6, 2).unwrap(), 7_000);
+
+        // Matching decimals is the identity, not a division by FP
+        assert_eq!(fp_to_quote_amount(70 * FP, 6, 6).unwrap(), 70_000_000);
+    }
+
+    #[test]
+    fn test_fp_to_quote_rounds_down_and_checks_range() {
+        assert_eq!(fp_to_quote_amount(1_999_999, 6, 0).unwrap(), 1);
+        assert!(fp_to_quote_amount(u128::MAX, 6, 9).is_err());
+    }
+
+    fn reward_curve() -> [LiquidatorRewardTier; 4] {
+        [
+            LiquidatorRewardTier { min_notional: 0, reward_bps: 25 },
+            LiquidatorRewardTier { min_notional: 10_000, reward_bps: 50 },
+            LiquidatorRewardTier { min_notional: 100_000, reward_bps: 75 },
+            LiquidatorRewardTier { min_notional: 1_000_000, reward_bps: 100 },
+        ]
+    }
+
+    #[test]
+    fn test_liquidator_reward_floor_on_small_positions() {
+        let tiers = reward_curve();
+        // 0.25% of 100 is 0; the floor keeps dust worth a keeper's gas
+        assert_eq!(liquidator_reward(100, &tiers, 5), 5);
+        assert_eq!(liquidator_reward(0, &tiers, 5), 5);
+        assert_eq!(liquidator_reward(4_000, &tiers, 5), 10);
+    }
+
+    #[test]
+    fn test_liquidator_reward_scales_with_size() {
+        let tiers = reward_curve();
+        assert_eq!(liquidator_reward(10_000, &tiers, 5), 50);
+        assert_eq!(liquidator_reward(500_000, &tiers, 5), 3_750);
+        assert_eq!(liquidator_reward(2_000_000, &tiers, 5), 20_000);
+
+        let mut last = 0;
+        for notional in [1u64, 9_999, 10_000, 99_999, 100_000, 999_999, 1_000_000, 50_000_000] {
+            let reward = liquidator_reward(notional, &tiers, 5);
+            assert!(reward >= last);
+            last = reward;
+        }
+
+        // An unconfigured curve still pays the floor
+        assert_eq!(liquidator_reward(2_000_000, &[LiquidatorRewardTier::default(); 4], 5), 5);
+    }
+
+    #[test]
+    fn test_close_fee_economics_across_leverage() {
+        // $100 margin, entry $100, exit $101 (+1%), 10 bps fee on exit notional
+        let margin_fp = 100 * FP;
+        for (leverage, expected_payout_fp) in [(2u128, 101_798_000u128), (10, 108_990_000), (40, 135_960_000)] {
+            let size = leverage; // notional = leverage * margin at $100
+            let pnl_fp = (size * FP) as i128;
+            let exit_notional_fp = size * 101 * FP;
+            let settled = close_settlement(margin_fp, pnl_fp, exit_notional_fp, 10);
+
+            // Fee scales with notional, the winner still keeps margin + PnL - fee
+            assert_eq!(settled.fee_fp, exit_notional_fp / 1_000);
+            assert_eq!(settled.payout_fp, expected_payout_fp);
+            assert!(settled.payout_fp > margin_fp);
+            assert_eq!(settled.shortfall_fp, 0);
+        }
+    }
+
+    #[test]
+    fn test_close_fee_never_exceeds_equity() {
+        // 40x flat trade: fee comes out of margin
+        let flat = close_settlement(100 * FP, 0, 4_000 * FP, 10);
+        assert_eq!((flat.fee_fp, flat.payout_fp), (4 * FP, 96 * FP));
+
+        // Nearly wiped out: only the remaining $1 of equity goes to fees
+        let thin = close_settlement(100 * FP, -(99 * FP as i128), 4_000 * FP, 10);
+        assert_eq!((thin.fee_fp, thin.payout_fp, thin.shortfall_fp), (FP, 0, 0));
+
+        // Underwater: no fee, the loss beyond margin is shortfall
+        let under = close_settlement(100 * FP, -(120 * FP as i128), 4_000 * FP, 10);
+        assert_eq!((under.fee_fp, under.payout_fp, under.shortfall_fp), (0, 0, 20 * FP));
+    }
+
+    #[test]
+    fn test_hedged_legs_net_to_one_fee() {
+        // 10 long and 10 short from $100 with $100 margin each, closed at $105
+        let sizes = [10, -10];
+        let margins_fp = [100 * FP, 100 * FP];
+        let pnls_fp = [50 * FP as i128, -(50 * FP as i128)];
+        let mark_fp = 105 * PRICE / 100;
+
+        // Apart, each leg pays 0.1% of its $1_050 exit notional
+        let apart: Vec<_> = (0..2)
+            .map(|i| close_settlement(margins_fp[i], pnls_fp[i], 10 * mark_fp, 10))
+            .collect();
+        assert_eq!(apart[0].fee_fp + apart[1].fee_fp, 2 * 1_050_000);
+
+        // Netted, the PnL cancels and nothing leaves the book to pay a fee on
+        let netted = netted_close_settlement(sizes, margins_fp, pnls_fp, mark_fp, 10);
+        assert_eq!(netted.fee_fp, 0);
+        assert_eq!(netted.payout_fp, 200 * FP);
+
+        // A partial hedge pays a single fee on the 4 units it doesn't offset
+        let netted = netted_close_settlement([10, -6], margins_fp, [50 * FP as i128, -(30 * FP as i128)], mark_fp, 10);
+        assert_eq!(netted.fee_fp, 420_000);
+        assert_eq!(netted.payout_fp, 220 * FP - 420_000);
+    }
+
+    #[test]
+    fn test_entry_notional_matches_margin_times_leverage() {
+        for (spend, leverage) in [(1_000_000u64, 3u16), (99, 100), (1_234_567, 7), (10_000_000, 40), (5, 1)] {
+            let entry = entry_margin(spend, leverage).unwrap();
+            assert_eq!(entry.notional, entry.margin * leverage as u64);
+            assert!(entry.remainder < leverage as u64);
+            assert_eq!(entry.notional + entry.remainder, spend);
+        }
+        // Too small to lock any margin
+        assert_eq!(entry_margin(99, 100).unwrap().margin, 0);
+    }
+
+    #[test]
+    fn test_thin_vault_rejects_a_position_it_could_not_pay_out() {
+        // $100 margin on $5,000 notional, backed for a 50% move: up to $2,600 owed
+        let (margin, notional) = (100_000_000, 5_000_000_000);
+        let move_bps = 5_000;
+
+        let thin = 2_000_000_000;
+        assert_eq!(
+            ensure_payout_backed(margin, notional, move_bps, thin).unwrap_err(),
+            PerpsError::InsufficientLiquidity.into()
+        );
+        let deep = 50_000_000_000;
+        ensure_payout_backed(margin, notional, move_bps, deep).unwrap();
+
+        // Off by default
+        ensure_payout_backed(margin, notional, 0, 0).unwrap();
+    }
+
+    #[test]
+    fn test_zero_leverage_is_invalid_input_not_overflow() {
+        assert_eq!(validate_leverage(0).unwrap_err(), PerpsError::InvalidParameters.into());
+        assert_eq!(entry_margin(1_000_000, 0).unwrap_err(), PerpsError::InvalidParameters.into());
+        assert_eq!(validate_leverage(41).unwrap_err(), PerpsError::LeverageTooHigh.into());
+        validate_leverage(1).unwrap();
+        validate_leverage(40).unwrap();
+    }
+
+    #[test]
+    fn test_effective_leverage_at_entry_tracks_requested() {
+        // USDC-style quote (6 decimals) at a $100 price
+        for (spend, leverage) in [(30_000_000_000u64, 3u16), (123_456_789_012, 7), (50_000_000_001, 40), (10_000_999_999, 10)] {
+            let entry = entry_margin(spend, leverage).unwrap();
+            let notional_fp = quote_amount_to_fp(entry.notional, crate::state::PRICE_DECIMALS, 6).unwrap();
+            let base = notional_fp / PRICE;
+            let margin_fp = quote_amount_to_fp(entry.margin, crate::state::PRICE_DECIMALS, 6).unwrap();
+
+            // base * price / margin, in hundredths of x; base truncation only ever lowers it
+            let effective_x100 = base * PRICE * 100 / margin_fp;
+            let requested_x100 = leverage as u128 * 100;
+            assert!(effective_x100 <= requested_x100);
+            assert!(requested_x100 - effective_x100 <= requested_x100 / 100, "{effective_x100} vs {requested_x100}");
+        }
+    }
+
+    #[test]
+    fn test_margin_for_leverage_levers_up_and_down() {
+        // 10 units at $100 with a 6-decimal quote token: $1,000 notional
+        let notional_fp = 10 * PRICE;
+        let decimals = crate::state::PRICE_DECIMALS;
+        let current = margin_for_leverage(notional_fp, 20, decimals, 6).unwrap();
+        assert_eq!(current, 50_000_000); // $50 at 20x
+
+        // De-risk to 5x: margin goes up
+        let down = margin_for_leverage(notional_fp, 5, decimals, 6).unwrap();
+        assert_eq!(down, 200_000_000);
+        assert_eq!(quote_amount_to_fp(down, decimals, 6).unwrap() * 5, notional_fp);
+
+        // Lever up to 40x: margin comes out
+        let up = margin_for_leverage(notional_fp, 40, decimals, 6).unwrap();
+        assert!(up < current);
+        assert_eq!(notional_fp / quote_amount_to_fp(up, decimals, 6).unwrap(), 40);
+    }
+
+    #[test]
+    fn test_margin_for_leverage_never_overshoots_target() {
+        // Notional that doesn't divide evenly, quote token coarser than price precision
+        let notional_fp = 1_234_567_891;
+        for leverage in [3u16, 7, 13, 40] {
+            let margin = margin_for_leverage(notional_fp, leverage, 6, 2).unwrap();
+            let margin_fp = quote_amount_to_fp(margin, 6, 2).unwrap();
+            assert!(margin_fp * leverage as u128 >= notional_fp);
+            // One unit less would exceed the target
+            let smaller_fp = quote_amount_to_fp(margin - 1, 6, 2).unwrap();
+            assert!(smaller_fp * (leverage as u128) < notional_fp);
+        }
+        assert_eq!(margin_for_leverage(notional_fp, 0, 6, 6).unwrap_err(), PerpsError::InvalidParameters.into());
+    }
+
+    #[test]
+    fn test_doubling_margin_moves_liquidation_away_from_entry() {
+        // 10 units at $100: $1000 notional, $50 maintenance at 5%
+        assert_eq!(liquidation_price_fp(PRICE, 100 * FP, 10, 500, true).unwrap(), 95 * FP);
+        assert_eq!(liquidation_price_fp(PRICE, 100 * FP, 10, 500, false).unwrap(), 105 * FP);
+        // $100 more margin triples the $50 buffer over maintenance
+        assert_eq!(liquidation_price_fp(PRICE, 200 * FP, 10, 500, true).unwrap(), 85 * FP);
+        assert_eq!(liquidation_price_fp(PRICE, 200 * FP, 10, 500, false).unwrap(), 115 * FP);
+        // The same margin behind twice the size sits closer to entry
+        assert_eq!(liquidation_price_fp(PRICE, 200 * FP, 20, 500, true).unwrap(), 95 * FP);
+
+        // At or under maintenance the position liquidates at entry; a long never goes below zero
+        assert_eq!(liquidation_price_fp(PRICE, 40 * FP, 10, 500, true).unwrap(), PRICE);
+        assert_eq!(liquidation_price_fp(PRICE, 5_000 * FP, 10, 500, true).unwrap(), 0);
+        assert!(liquidation_price_fp(PRICE, 100 * FP, 0, 500, true).is_err());
+    }
+
+    #[test]
+    fn test_effective_leverage_cap_on_fill() {
+        let margin_fp = 100 * FP;
+        // Exactly at the 20x cap, and within the 0.5% tolerance
+        assert!(ensure_effective_leverage(2_000 * FP, margin_fp, 20, EFFECTIVE_LEVERAGE_TOLERANCE_BPS).is_ok());
+        assert!(ensure_effective_leverage(2_010 * FP, margin_fp, 20, EFFECTIVE_LEVERAGE_TOLERANCE_BPS).is_ok());
+
+        // Requested 20x, but slippage filled 2% more notional than the margin supports
+        let err = ensure_effective_leverage(2_040 * FP, margin_fp, 20, EFFECTIVE_LEVERAGE_TOLERANCE_BPS).unwrap_err();
+        assert_eq!(err, PerpsError::LeverageTooHigh.into());
+
+        assert_eq!(
+            ensure_effective_leverage(FP, 0, 20, EFFECTIVE_LEVERAGE_TOLERANCE_BPS).unwrap_err(),
+            PerpsError::InsufficientMargin.into()
+        );
+    }
+
+    #[test]
+    fn test_averaged_entry_weights_by_size_and_rounds_against_the_trader() {
+        // 10 at $100 and 30 at $120 average to $115
+        assert_eq!(average_entry_price_fp(10, PRICE, 30, 120 * FP, true).unwrap(), 115 * FP);
+        assert_eq!(average_entry_price_fp(10, PRICE, 30, 120 * FP, false).unwrap(), 115 * FP);
+        // 1 at $100 and 2 at $100.000001 is a third of a unit off exact: a long
+        // rounds up to the higher entry, a short down to the lower one
+        assert_eq!(average_entry_price_fp(1, PRICE, 2, PRICE + 1, true).unwrap(), PRICE + 1);
+        assert_eq!(average_entry_price_fp(1, PRICE, 2, PRICE + 1, false).unwrap(), PRICE);
+        // Adding to nothing takes the new price
+        assert_eq!(average_entry_price_fp(0, 0, 5, 90 * FP, true).unwrap(), 90 * FP);
+        assert_eq!(average_entry_price_fp(0, PRICE, 0, PRICE, true).unwrap_err(), PerpsError::DivisionByZero.into());
+    }
 }
@@ -1,9 +1,10 @@
 use anchor_lang::prelude::*;
 use crate::errors::PerpsError;
-use crate::state::{OraclePrice, FP};
+use crate::events::OracleUpdated;
+use crate::state::{Market, OracleSource, OraclePrice, OracleTwap, PRICE_DECIMALS};
 
 // Pyth Network price account structure
-#[repr(C)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct PythPriceAccount {
     pub magic: u32,
     pub version: u32,
@@ -17,6 +18,61 @@ pub struct PythPriceAccount {
     pub expo: i32,
 }
 
+pub const PYTH_MAGIC: u32 = 0xa1b2c3d4;
+pub const PYTH_VERSION: u32 = 2;
+
+impl PythPriceAccount {
+    /// Serialized length, with the C layout's padding before `expo`
+    pub const LEN: usize = 48;
+
+    /// Read the account from raw data. The length, magic and version are
+    /// checked before any other field is read, and every field is read at a
+    /// bounds-checked offset, so a short or foreign account is `BadOracle`.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        require!(data.len() >= Self::LEN, PerpsError::BadOracle);
+        let magic = u32::from_le_bytes(account_field(data, 0)?);
+        let version = u32::from_le_bytes(account_field(data, 4)?);
+        require!(magic == PYTH_MAGIC && version == PYTH_VERSION, PerpsError::BadOracle);
+        let size = u32::from_le_bytes(account_field(data, 12)?);
+        require!(size as usize >= Self::LEN && size as usize <= data.len(), PerpsError::BadOracle);
+
+        Ok(Self {
+            magic,
+            version,
+            price_type: u32::from_le_bytes(account_field(data, 8)?),
+            size,
+            price: i64::from_le_bytes(account_field(data, 16)?),
+            confidence: u64::from_le_bytes(account_field(data, 24)?),
+            timestamp: i64::from_le_bytes(account_field(data, 32)?),
+            min_publishers: account_field::<1>(data, 40)?[0],
+            num_publishers: account_field::<1>(data, 41)?[0],
+            expo: i32::from_le_bytes(account_field(data, 44)?),
+        })
+    }
+
+    /// The inverse of `parse`, for writing test accounts
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; Self::LEN];
+        data[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        data[4..8].copy_from_slice(&self.version.to_le_bytes());
+        data[8..12].copy_from_slice(&self.price_type.to_le_bytes());
+        data[12..16].copy_from_slice(&self.size.to_le_bytes());
+        data[16..24].copy_from_slice(&self.price.to_le_bytes());
+        data[24..32].copy_from_slice(&self.confidence.to_le_bytes());
+        data[32..40].copy_from_slice(&self.timestamp.to_le_bytes());
+        data[40] = self.min_publishers;
+        data[41] = self.num_publishers;
+        data[44..48].copy_from_slice(&self.expo.to_le_bytes());
+        data
+    }
+}
+
+/// `N` bytes at `offset`, or `BadOracle` if the data is too short
+fn account_field<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N]> {
+    let bytes = data.get(offset..offset + N).ok_or(PerpsError::BadOracle)?;
+    Ok(bytes.try_into().map_err(|_| PerpsError::BadOracle)?)
+}
+
 // Oracle configuration for multiple price sources
 #[derive(Copy, Clone, Debug)]
 pub struct OracleConfig {
@@ -24,6 +80,7 @@ pub struct OracleConfig {
     pub max_confidence_deviation_bps: u64,  // Max confidence as % of price
     pub max_price_deviation_bps: u64,       // Max deviation between sources
     pub min_publishers: u8,
+    pub price_precision: u8,                // Extra decimals prices are read at, see `Market::price_precision`
 }
 
 impl Default for OracleConfig {
@@ -33,15 +90,51 @@ impl Default for OracleConfig {
             max_confidence_deviation_bps: 500,  // 5% max confidence interval
             max_price_deviation_bps: 200,       // 2% max deviation between sources  
             min_publishers: 3,
+            price_precision: 0,
         }
     }
 }
 
+impl OracleConfig {
+    /// Default validation, reading prices into the market's own price space
+    pub fn for_market(market: &Market) -> Self {
+        Self { price_precision: market.price_precision, ..Default::default() }
+    }
+}
+
+/// Move an FP price (or confidence) into a market's price space: per
+/// `10^price_precision` base units instead of per unit
+pub fn scale_to_precision(value_fp: u128, price_precision: u8) -> Result<u128> {
+    let factor = 10u128.checked_pow(price_precision as u32).ok_or(PerpsError::MathOverflow)?;
+    Ok(value_fp.checked_mul(factor).ok_or(PerpsError::MathOverflow)?)
+}
+
 /// Read and validate oracle price with comprehensive checks
 pub fn read_oracle_fp(oracle: &Account<OraclePrice>) -> Result<u128> {
     read_oracle_with_config(oracle, &OracleConfig::default())
 }
 
+/// `read_oracle_fp` for a market's own feed, refused once the feed is marked dead
+pub fn read_market_oracle_fp(market: &Market, oracle: &Account<OraclePrice>) -> Result<u128> {
+    market.ensure_oracle_live()?;
+    read_oracle_with_config(oracle, &OracleConfig::for_market(market))
+}
+
+/// Index price for liquidations and health checks: spot, or the TWAP over
+/// the market's `risk_twap_seconds` when it sets one. The spot read still
+/// gates liveness and staleness, and the TWAP must include the latest update.
+pub fn risk_index_price_fp(market: &Market, oracle: &Account<OraclePrice>, oracle_twap: Option<&OracleTwap>) -> Result<u128> {
+    let spot_fp = read_market_oracle_fp(market, oracle)?;
+    if market.risk_twap_seconds == 0 {
+        return Ok(spot_fp);
+    }
+    let twap = oracle_twap.ok_or(PerpsError::OracleTwapRequired)?;
+    require_keys_eq!(twap.oracle, oracle.key(), PerpsError::BadOracle);
+    require!(twap.last_update_ts() >= oracle.last_updated_ts, PerpsError::BadOracle);
+    let now = Clock::get()?.unix_timestamp;
+    scale_to_precision(twap.twap_fp(now, market.risk_twap_seconds)?, market.price_precision)
+}
+
 /// Read oracle price with custom configuration
 pub fn read_oracle_with_config(oracle: &Account<OraclePrice>, config: &OracleConfig) -> Result<u128> {
     let now = Clock::get()?.unix_timestamp;
@@ -54,37 +147,51 @@ pub fn read_oracle_with_config(oracle: &Account<OraclePrice>, config: &OracleCon
     require!(oracle.price_fp > 0, PerpsError::BadOracle);
     
     msg!("Oracle price validated: {} (age: {}s)", oracle.price_fp, age);
-    Ok(oracle.price_fp)
+    scale_to_precision(oracle.price_fp, config.price_precision)
+}
+
+/// A validated Pyth price, its confidence interval and when it was published
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PythReading {
+    pub price_fp: u128,
+    pub confidence_fp: u128,
+    pub publish_ts: i64,
+}
+
+/// One source's price and confidence width, both in the market's price space.
+/// A zero confidence means the source did not report one.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourcePrice {
+    pub price_fp: u128,
+    pub confidence_fp: u128,
 }
 
 /// Read Pyth Network price feed with validation
 pub fn read_pyth_price(pyth_account: &AccountInfo, config: &OracleConfig) -> Result<u128> {
-    let pyth_data = pyth_account.try_borrow_data()?;
-    require!(pyth_data.len() >= std::mem::size_of::<PythPriceAccount>(), PerpsError::BadOracle);
-    
-    let pyth_price: &PythPriceAccount = unsafe {
-        &*(pyth_data.as_ptr() as *const PythPriceAccount)
-    };
-    
+    let now = Clock::get()?.unix_timestamp;
+    let reading = parse_pyth_price(&pyth_account.try_borrow_data()?, now, config)?;
+    Ok(reading.price_fp)
+}
+
+/// Validate raw Pyth account data as of `now`
+pub fn parse_pyth_price(pyth_data: &[u8], now: i64, config: &OracleConfig) -> Result<PythReading> {
+    let pyth_price = PythPriceAccount::parse(pyth_data)?;
+
     // Validate Pyth data
-    require!(pyth_price.magic == 0xa1b2c3d4, PerpsError::BadOracle);
     require!(pyth_price.num_publishers >= config.min_publishers, PerpsError::OracleConfidenceLow);
     
-    let now = Clock::get()?.unix_timestamp;
     require!(now - pyth_price.timestamp <= config.max_staleness_seconds, PerpsError::BadOracle);
     
-    // Convert Pyth price to fixed point
-    let price_scaled = if pyth_price.expo >= 0 {
-        pyth_price.price * (10_i64.pow(pyth_price.expo as u32))
-    } else {
-        pyth_price.price / (10_i64.pow((-pyth_price.expo) as u32))
-    };
-    
-    require!(price_scaled > 0, PerpsError::BadOracle);
-    let price_fp = (price_scaled as u128) * FP / 1_000_000; // Normalize to 1e6 precision
+    // Convert Pyth's price * 10^expo to our fixed point precision. The
+    // market's extra decimals come straight off the mantissa, so a micro-priced
+    // asset keeps resolution it would lose going through FP first.
+    require!(pyth_price.price > 0, PerpsError::BadOracle);
+    let expo = pyth_price.expo + config.price_precision as i32;
+    let price_fp = pyth_to_fp(pyth_price.price as u128, expo)?;
+    require!(price_fp > 0, PerpsError::BadOracle);
     
     // Check confidence interval
-    let confidence_fp = (pyth_price.confidence as u128) * FP / 1_000_000;
+    let confidence_fp = pyth_to_fp(pyth_price.confidence as u128, expo)?;
     let confidence_ratio_bps = (confidence_fp * 10_000) / price_fp;
     require!(
         confidence_ratio_bps <= config.max_confidence_deviation_bps as u128, 
@@ -92,45 +199,240 @@ pub fn read_pyth_price(pyth_account: &AccountInfo, config: &OracleConfig) -> Res
     );
     
     msg!("Pyth price: {} (confidence: {}bps)", price_fp, confidence_ratio_bps);
-    Ok(price_fp)
+    Ok(PythReading { price_fp, confidence_fp, publish_ts: pyth_price.timestamp })
+}
+
+/// Rescale a Pyth mantissa with exponent `expo` to PRICE_DECIMALS
+fn pyth_to_fp(value: u128, expo: i32) -> Result<u128> {
+    let shift = expo + PRICE_DECIMALS as i32;
+    let factor = 10u128.checked_pow(shift.unsigned_abs()).ok_or(PerpsError::MathOverflow)?;
+    if shift >= 0 {
+        Ok(value.checked_mul(factor).ok_or(PerpsError::MathOverflow)?)
+    } else {
+        Ok(value / factor)
+    }
+}
+
+/// Most recently published of the valid readings; stale or malformed feeds are skipped
+pub fn freshest_pyth_reading(readings: impl IntoIterator<Item = Result<PythReading>>) -> Option<PythReading> {
+    readings.into_iter()
+        .filter_map(|reading| reading.ok())
+        .max_by_key(|reading| reading.publish_ts)
+}
+
+/// Read several Pyth feeds for the same asset and use the freshest valid one
+pub fn read_freshest_pyth_price(pyth_accounts: &[&AccountInfo], config: &OracleConfig) -> Result<u128> {
+    Ok(read_freshest_pyth_reading(pyth_accounts, config)?.price_fp)
+}
+
+/// `read_freshest_pyth_price`, keeping the feed's confidence
+pub fn read_freshest_pyth_reading(pyth_accounts: &[&AccountInfo], config: &OracleConfig) -> Result<PythReading> {
+    let now = Clock::get()?.unix_timestamp;
+    let readings = pyth_accounts.iter().map(|account| {
+        parse_pyth_price(&account.try_borrow_data()?, now, config)
+    });
+    Ok(freshest_pyth_reading(readings).ok_or(PerpsError::BadOracle)?)
 }
 
-/// Aggregate multiple oracle sources for robust pricing
+/// Switchboard V2 `AggregatorAccountData` discriminator
+pub const SWITCHBOARD_AGGREGATOR_DISCRIMINATOR: [u8; 8] = [217, 230, 65, 101, 201, 162, 27, 125];
+
+/// The parts of a Switchboard V2 aggregator we price from. Offsets are into
+/// its packed layout: `min_oracle_results` sits after the name, metadata,
+/// reserved bytes, queue key and batch size, and `latest_confirmed_round`
+/// after the update schedule and crank key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SwitchboardAggregator {
+    pub min_oracle_results: u32,
+    pub num_success: u32,
+    pub round_open_timestamp: i64,
+    pub result_mantissa: i128,
+    pub result_scale: u32,
+    pub std_deviation_mantissa: i128,
+    pub std_deviation_scale: u32,
+}
+
+impl SwitchboardAggregator {
+    const MIN_ORACLE_RESULTS_OFFSET: usize = 236;
+    const ROUND_OFFSET: usize = 341;
+    /// Bytes needed to reach the end of the latest round's standard deviation
+    pub const MIN_LEN: usize = Self::ROUND_OFFSET + 65;
+
+    /// Read the latest confirmed round, checking the discriminator and
+    /// length first, as `PythPriceAccount::parse` does
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        require!(data.len() >= Self::MIN_LEN, PerpsError::BadOracle);
+        require!(account_field::<8>(data, 0)? == SWITCHBOARD_AGGREGATOR_DISCRIMINATOR, PerpsError::BadOracle);
+        let round = Self::ROUND_OFFSET;
+        Ok(Self {
+            min_oracle_results: u32::from_le_bytes(account_field(data, Self::MIN_ORACLE_RESULTS_OFFSET)?),
+            num_success: u32::from_le_bytes(account_field(data, round)?),
+            round_open_timestamp: i64::from_le_bytes(account_field(data, round + 17)?),
+            result_mantissa: i128::from_le_bytes(account_field(data, round + 25)?),
+            result_scale: u32::from_le_bytes(account_field(data, round + 41)?),
+            std_deviation_mantissa: i128::from_le_bytes(account_field(data, round + 45)?),
+            std_deviation_scale: u32::from_le_bytes(account_field(data, round + 61)?),
+        })
+    }
+
+    /// The inverse of `parse` for the fields it reads, for writing test accounts
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let round = Self::ROUND_OFFSET;
+        let mut data = vec![0u8; Self::MIN_LEN];
+        data[0..8].copy_from_slice(&SWITCHBOARD_AGGREGATOR_DISCRIMINATOR);
+        data[Self::MIN_ORACLE_RESULTS_OFFSET..Self::MIN_ORACLE_RESULTS_OFFSET + 4].copy_from_slice(&self.min_oracle_results.to_le_bytes());
+        data[round..round + 4].copy_from_slice(&self.num_success.to_le_bytes());
+        data[round + 17..round + 25].copy_from_slice(&self.round_open_timestamp.to_le_bytes());
+        data[round + 25..round + 41].copy_from_slice(&self.result_mantissa.to_le_bytes());
+        data[round + 41..round + 45].copy_from_slice(&self.result_scale.to_le_bytes());
+        data[round + 45..round + 61].copy_from_slice(&self.std_deviation_mantissa.to_le_bytes());
+        data[round + 61..round + 65].copy_from_slice(&self.std_deviation_scale.to_le_bytes());
+        data
+    }
+}
+
+/// Read a Switchboard aggregator's latest confirmed result with validation
+pub fn read_switchboard_price(switchboard_account: &AccountInfo, config: &OracleConfig) -> Result<u128> {
+    Ok(read_switchboard_reading(switchboard_account, config)?.price_fp)
+}
+
+/// `read_switchboard_price`, keeping the round's standard deviation
+pub fn read_switchboard_reading(switchboard_account: &AccountInfo, config: &OracleConfig) -> Result<SourcePrice> {
+    let now = Clock::get()?.unix_timestamp;
+    parse_switchboard_reading(&switchboard_account.try_borrow_data()?, now, config)
+}
+
+/// Validate raw Switchboard aggregator data as of `now`
+pub fn parse_switchboard_price(data: &[u8], now: i64, config: &OracleConfig) -> Result<u128> {
+    Ok(parse_switchboard_reading(data, now, config)?.price_fp)
+}
+
+/// `parse_switchboard_price` with the round's standard deviation, which
+/// stands in for Pyth's confidence
+pub fn parse_switchboard_reading(data: &[u8], now: i64, config: &OracleConfig) -> Result<SourcePrice> {
+    let aggregator = SwitchboardAggregator::parse(data)?;
+    require!(
+        aggregator.num_success > 0 && aggregator.num_success >= aggregator.min_oracle_results,
+        PerpsError::OracleConfidenceLow
+    );
+    require!(now - aggregator.round_open_timestamp <= config.max_staleness_seconds, PerpsError::BadOracle);
+
+    // A decimal is mantissa * 10^-scale, i.e. a Pyth exponent of -scale
+    require!(aggregator.result_mantissa > 0 && aggregator.std_deviation_mantissa >= 0, PerpsError::BadOracle);
+    let price_expo = config.price_precision as i32 - aggregator.result_scale as i32;
+    let price_fp = pyth_to_fp(aggregator.result_mantissa as u128, price_expo)?;
+    require!(price_fp > 0, PerpsError::BadOracle);
+
+    let deviation_expo = config.price_precision as i32 - aggregator.std_deviation_scale as i32;
+    let confidence_fp = pyth_to_fp(aggregator.std_deviation_mantissa as u128, deviation_expo)?;
+    let confidence_ratio_bps = (confidence_fp * 10_000) / price_fp;
+    require!(
+        confidence_ratio_bps <= config.max_confidence_deviation_bps as u128,
+        PerpsError::OracleConfidenceLow
+    );
+
+    msg!("Switchboard price: {} (confidence: {}bps)", price_fp, confidence_ratio_bps);
+    Ok(SourcePrice { price_fp, confidence_fp })
+}
+
+/// Aggregate multiple oracle sources for robust pricing. `pyth_accounts` are
+/// alternative feeds for the same asset; the freshest valid one is used. A
+/// secondary source that fails validation is left out, and the index weights
+/// the sources that remain by their confidence (see `confidence_weighted_price`).
 pub fn aggregate_oracle_prices(
     primary_oracle: &Account<OraclePrice>,
-    pyth_account: Option<&AccountInfo>,
+    pyth_accounts: &[&AccountInfo],
+    switchboard_account: Option<&AccountInfo>,
     config: &OracleConfig,
 ) -> Result<u128> {
-    let primary_price = read_oracle_with_config(primary_oracle, config)?;
-    
-    // If no secondary source, return primary
-    let Some(pyth) = pyth_account else {
-        return Ok(primary_price);
-    };
-    
-    // Get Pyth price
-    let pyth_price = match read_pyth_price(pyth, config) {
-        Ok(price) => price,
-        Err(_) => {
-            msg!("Pyth oracle failed, using primary only");
-            return Ok(primary_price);
+    let mut sources = vec![SourcePrice {
+        price_fp: read_oracle_with_config(primary_oracle, config)?,
+        confidence_fp: scale_to_precision(primary_oracle.confidence_fp, config.price_precision)?,
+    }];
+
+    if !pyth_accounts.is_empty() {
+        match read_freshest_pyth_reading(pyth_accounts, config) {
+            Ok(reading) => sources.push(SourcePrice { price_fp: reading.price_fp, confidence_fp: reading.confidence_fp }),
+            Err(_) => msg!("Pyth oracles failed, leaving them out"),
+        }
+    }
+    if let Some(switchboard) = switchboard_account {
+        match read_switchboard_reading(switchboard, config) {
+            Ok(source) => sources.push(source),
+            Err(_) => msg!("Switchboard oracle failed, leaving it out"),
         }
+    }
+
+    let aggregated_price = confidence_weighted_price(&sources, config.max_price_deviation_bps)?;
+    msg!("Aggregated price: {} from {:?}", aggregated_price, sources);
+    Ok(aggregated_price)
+}
+
+/// Resolution of the relative source weights in `confidence_weighted_price`
+const CONFIDENCE_WEIGHT_SCALE: u128 = 1_000_000;
+
+/// Average of the source prices weighted inversely to their confidence
+/// width, so a tight feed outweighs a wide one. Sources must agree pairwise
+/// within `max_deviation_bps` as for the median, which is used instead when
+/// any source has no confidence to weigh.
+pub fn confidence_weighted_price(sources: &[SourcePrice], max_deviation_bps: u64) -> Result<u128> {
+    let prices: Vec<u128> = sources.iter().map(|source| source.price_fp).collect();
+    let Some(tightest) = sources.iter().map(|source| source.confidence_fp).min().filter(|c| *c > 0) else {
+        return median_source_price(&prices, max_deviation_bps);
     };
-    
-    // Check deviation between sources
-    let deviation_bps = calculate_deviation_bps(primary_price, pyth_price);
-    if deviation_bps > config.max_price_deviation_bps {
+    median_source_price(&prices, max_deviation_bps)?;
+
+    // The tightest source weighs CONFIDENCE_WEIGHT_SCALE, the rest proportionally less
+    let (mut weighted_sum, mut total_weight) = (0u128, 0u128);
+    for source in sources {
+        let weight = tightest * CONFIDENCE_WEIGHT_SCALE / source.confidence_fp;
+        weighted_sum = weighted_sum
+            .checked_add(source.price_fp.checked_mul(weight).ok_or(PerpsError::MathOverflow)?)
+            .ok_or(PerpsError::MathOverflow)?;
+        total_weight += weight;
+    }
+    Ok(weighted_sum / total_weight)
+}
+
+/// Median of the source prices once every pair agrees within
+/// `max_deviation_bps`; two sources meet halfway
+pub fn median_source_price(prices: &[u128], max_deviation_bps: u64) -> Result<u128> {
+    require!(!prices.is_empty(), PerpsError::OracleFeedNotFound);
+    for (i, a) in prices.iter().enumerate() {
+        for b in &prices[i + 1..] {
+            check_source_deviation(*a, *b, max_deviation_bps)?;
+        }
+    }
+    let mut sorted = prices.to_vec();
+    sorted.sort_unstable();
+    let mid = sorted.len() / 2;
+    Ok(if sorted.len().is_multiple_of(2) { (sorted[mid - 1] + sorted[mid]) / 2 } else { sorted[mid] })
+}
+
+/// Walk a market's source priority and use the first source that yields a
+/// valid price. `read` prices a single source; failures are logged and the
+/// next source is tried. Returns the source used and its price.
+pub fn read_price_with_failover(
+    priority: &[OracleSource],
+    mut read: impl FnMut(OracleSource) -> Result<u128>,
+) -> Result<(OracleSource, u128)> {
+    for &source in priority.iter().filter(|source| **source != OracleSource::Unset) {
+        match read(source) {
+            Ok(price_fp) => return Ok((source, price_fp)),
+            Err(err) => msg!("Oracle source {:?} failed: {}", source, err),
+        }
+    }
+    Err(PerpsError::OracleFeedNotFound.into())
+}
+
+/// Reject when two price sources disagree by more than `max_deviation_bps`
+pub fn check_source_deviation(primary_price: u128, secondary_price: u128, max_deviation_bps: u64) -> Result<u64> {
+    let deviation_bps = calculate_deviation_bps(primary_price, secondary_price);
+    if deviation_bps > max_deviation_bps {
         msg!("Oracle deviation too high: {}bps", deviation_bps);
         return Err(PerpsError::OraclePriceDeviation.into());
     }
-    
-    // Use weighted average (70% primary, 30% Pyth for now)
-    let aggregated_price = (primary_price * 70 + pyth_price * 30) / 100;
-    
-    msg!("Aggregated price: {} (primary: {}, pyth: {})", 
-         aggregated_price, primary_price, pyth_price);
-    
-    Ok(aggregated_price)
+    Ok(deviation_bps)
 }
 
 /// Calculate percentage deviation between two prices
@@ -163,41 +465,95 @@ pub fn emergency_price_fallback(
 /// Update oracle price with validation and circuit breaker logic
 pub fn update_oracle_price(
     oracle: &mut Account<OraclePrice>,
+    oracle_twap: Option<&mut OracleTwap>,
     new_price_fp: u128,
+    confidence_fp: u128,
     max_price_change_bps: u64,
 ) -> Result<()> {
-    let old_price = oracle.price_fp;
     let now = Clock::get()?.unix_timestamp;
+    let oracle_key = oracle.key();
+    let event = apply_oracle_update(oracle, oracle_key, new_price_fp, confidence_fp, max_price_change_bps, now)?;
+    if let Some(twap) = oracle_twap {
+        require_keys_eq!(twap.oracle, oracle_key, PerpsError::BadOracle);
+        twap.record(new_price_fp, now)?;
+    }
+    emit!(event);
+    Ok(())
+}
+
+/// Write a new price into `oracle` unless it trips the circuit breaker, and
+/// return the `OracleUpdated` to emit. A rejected update changes nothing.
+pub fn apply_oracle_update(
+    oracle: &mut OraclePrice,
+    oracle_key: Pubkey,
+    new_price_fp: u128,
+    confidence_fp: u128,
+    max_price_change_bps: u64,
+    now: i64,
+) -> Result<OracleUpdated> {
+    let old_price = oracle.price_fp;
     
     // Circuit breaker: check for extreme price movements
-    if old_price > 0 {
-        let deviation_bps = calculate_deviation_bps(old_price, new_price_fp);
-        if deviation_bps > max_price_change_bps {
-            msg!("Price change too large: {}bps, triggering circuit breaker", deviation_bps);
-            return Err(PerpsError::CircuitBreakerTriggered.into());
-        }
+    let change_bps = if old_price > 0 { calculate_deviation_bps(old_price, new_price_fp) } else { 0 };
+    if change_bps > max_price_change_bps {
+        msg!("Price change too large: {}bps, triggering circuit breaker", change_bps);
+        return Err(PerpsError::CircuitBreakerTriggered.into());
     }
     
     oracle.price_fp = new_price_fp;
+    oracle.confidence_fp = confidence_fp;
     oracle.last_updated_ts = now;
     
-    msg!("Oracle updated: {} -> {} (change: {}bps)", 
-         old_price, new_price_fp, 
-         if old_price > 0 { calculate_deviation_bps(old_price, new_price_fp) } else { 0 });
+    msg!("Oracle updated: {} -> {} (change: {}bps)", old_price, new_price_fp, change_bps);
     
+    Ok(OracleUpdated {
+        oracle: oracle_key,
+        old_price_fp: old_price,
+        new_price_fp,
+        confidence_fp,
+        num_publishers: oracle.num_publishers,
+    })
+}
+
+/// Performs health checks after position changes to ensure system stability:
+/// the oracle must be sane and a position of `base_size` with `equity_fp` must
+/// still clear maintenance at `current_price_fp`. A closed-out position
+/// (`base_size` 0) needs no margin.
+pub fn health_check(
+    oracle_account: &OraclePrice,
+    price_precision: u8,
+    current_price_fp: u128,
+    base_size: u64,
+    equity_fp: i128,
+    maintenance_margin_bps: u16,
+    now: i64,
+) -> Result<()> {
+    check_oracle_sanity(oracle_account, price_precision, current_price_fp, now)?;
+    check_maintenance(current_price_fp, base_size, equity_fp, maintenance_margin_bps)
+}
+
+/// Require `equity_fp` to cover maintenance on `base_size` at `current_price_fp`
+pub fn check_maintenance(
+    current_price_fp: u128,
+    base_size: u64,
+    equity_fp: i128,
+    maintenance_margin_bps: u16,
+) -> Result<()> {
+    let required_fp = base_size as u128 * current_price_fp * maintenance_margin_bps as u128 / 10_000;
+    require!(equity_fp >= required_fp as i128, PerpsError::WouldBeLiquidated);
     Ok(())
 }
 
-/// Performs health checks after position changes to ensure system stability
-pub fn health_check<'info>(
-    oracle: &'info AccountInfo<'info>,
+/// The oracle half of `health_check`: fresh, non-zero and not wildly off the
+/// stored price. Enough on its own for changes that only add margin.
+/// `current_price_fp` is in the market's price space, so the stored per-unit
+/// price is scaled by `price_precision` before the two are compared.
+pub fn check_oracle_sanity(
+    oracle_account: &OraclePrice,
+    price_precision: u8,
     current_price_fp: u128,
-    _maintenance_margin_bps: u16,
+    now: i64,
 ) -> Result<()> {
-    // Check if oracle price is still valid
-    let oracle_account = Account::<OraclePrice>::try_from(oracle)?;
-    let now = Clock::get()?.unix_timestamp;
-    
     // Ensure oracle is not stale (max 5 minutes old for health checks)
     let max_staleness = 300; // 5 minutes
     require!(
@@ -210,7 +566,8 @@ pub fn health_check<'info>(
     
     // Check for extreme price movements (basic sanity check)
     if oracle_account.price_fp > 0 {
-        let deviation_bps = calculate_deviation_bps(oracle_account.price_fp, current_price_fp);
+        let oracle_price_fp = scale_to_precision(oracle_account.price_fp, price_precision)?;
+        let deviation_bps = calculate_deviation_bps(oracle_price_fp, current_price_fp);
         
         // Warn if price moved more than 10% since last update
         if deviation_bps > 1000 {
@@ -227,14 +584,269 @@ pub fn health_check<'info>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::state::FP;
     
     #[test]
     fn test_deviation_calculation() {
-        assert_eq!(calculate_deviation_bps(100_000_000, 105_000_000), 476); // ~4.76%
-        assert_eq!(calculate_deviation_bps(100_000_000, 95_000_000), 526);  // ~5.26%
+        // The gap is taken as a share of the higher price, whichever
+        // argument it is, so the order of the two prices doesn't matter
+        assert_eq!(calculate_deviation_bps(100_000_000, 105_000_000), 476); // 5 of 105, ~4.76%
+        assert_eq!(calculate_deviation_bps(105_000_000, 100_000_000), 476);
+        assert_eq!(calculate_deviation_bps(100_000_000, 95_000_000), 500);  // 5 of 100
         assert_eq!(calculate_deviation_bps(100_000_000, 100_000_000), 0);   // 0%
     }
     
+    #[test]
+    fn test_manipulated_primary_rejected_against_pyth() {
+        let max_bps = OracleConfig::default().max_price_deviation_bps;
+        let pyth = 100_000_000u128; // $100
+        // Primary pushed 10% above Pyth
+        let err = check_source_deviation(110_000_000, pyth, max_bps).unwrap_err();
+        assert_eq!(err, PerpsError::OraclePriceDeviation.into());
+        // Small disagreement is tolerated
+        assert_eq!(check_source_deviation(101_000_000, pyth, max_bps).unwrap(), 99);
+    }
+
+    fn pyth_data(price: i64, timestamp: i64) -> Vec<u8> {
+        pyth_data_with_expo(price, -6, timestamp)
+    }
+
+    fn pyth_data_with_expo(price: i64, expo: i32, timestamp: i64) -> Vec<u8> {
+        let account = PythPriceAccount {
+            magic: PYTH_MAGIC,
+            version: PYTH_VERSION,
+            price_type: 1,
+            size: PythPriceAccount::LEN as u32,
+            price,
+            confidence: 10_000,
+            timestamp,
+            min_publishers: 3,
+            num_publishers: 5,
+            expo,
+        };
+        account.to_bytes()
+    }
+
+    #[test]
+    fn test_switchboard_result_read_into_fixed_point() {
+        let config = OracleConfig::default();
+        let now = 10_000;
+        // $65.1234 from 5 of at least 3 oracles, spread $0.01
+        let aggregator = SwitchboardAggregator {
+            min_oracle_results: 3,
+            num_success: 5,
+            round_open_timestamp: now - 10,
+            result_mantissa: 651_234,
+            result_scale: 4,
+            std_deviation_mantissa: 1,
+            std_deviation_scale: 2,
+        };
+        let data = aggregator.to_bytes();
+        assert_eq!(SwitchboardAggregator::parse(&data).unwrap(), aggregator);
+        assert_eq!(parse_switchboard_price(&data, now, &config).unwrap(), 65_123_400);
+
+        let short_round = SwitchboardAggregator { num_success: 2, ..aggregator.clone() }.to_bytes();
+        assert_eq!(parse_switchboard_price(&short_round, now, &config).unwrap_err(), PerpsError::OracleConfidenceLow.into());
+        let stale = SwitchboardAggregator { round_open_timestamp: now - 61, ..aggregator }.to_bytes();
+        for bad in [&stale[..], &data[..data.len() - 1], &pyth_data(100_000_000, now)[..]] {
+            assert_eq!(parse_switchboard_price(bad, now, &config).unwrap_err(), PerpsError::BadOracle.into());
+        }
+    }
+
+    #[test]
+    fn test_index_is_median_of_sources_that_agree_pairwise() {
+        let max_bps = OracleConfig::default().max_price_deviation_bps;
+        assert_eq!(median_source_price(&[100 * FP], max_bps).unwrap(), 100 * FP);
+        assert_eq!(median_source_price(&[100 * FP, 101 * FP], max_bps).unwrap(), 100 * FP + FP / 2);
+        assert_eq!(median_source_price(&[101 * FP, 100 * FP, 102 * FP], max_bps).unwrap(), 101 * FP);
+
+        // Each source is within 2% of the middle one, but the outer pair is not
+        let spread = [100 * FP, 102 * FP, 104 * FP];
+        assert_eq!(median_source_price(&spread, max_bps).unwrap_err(), PerpsError::OraclePriceDeviation.into());
+        assert_eq!(median_source_price(&[], max_bps).unwrap_err(), PerpsError::OracleFeedNotFound.into());
+    }
+
+    #[test]
+    fn test_tighter_confidence_source_weighs_more() {
+        let max_bps = OracleConfig::default().max_price_deviation_bps;
+        // Primary at 100 with a $1 band, Pyth at 101 with a 10c band
+        let primary = SourcePrice { price_fp: 100 * FP, confidence_fp: FP };
+        let pyth = SourcePrice { price_fp: 101 * FP, confidence_fp: FP / 10 };
+        let price = confidence_weighted_price(&[primary, pyth], max_bps).unwrap();
+        // (100 * 1 + 101 * 10) / 11
+        assert_eq!(price, 100_909_090);
+        assert!(101 * FP - price < price - 100 * FP);
+
+        // Equal bands split the difference; a source without one falls back to the median
+        let even = SourcePrice { confidence_fp: FP / 10, ..primary };
+        assert_eq!(confidence_weighted_price(&[even, pyth], max_bps).unwrap(), 100 * FP + FP / 2);
+        let unreported = SourcePrice { confidence_fp: 0, ..primary };
+        let switchboard = SourcePrice { price_fp: 100_200_000, confidence_fp: FP / 20 };
+        assert_eq!(confidence_weighted_price(&[unreported, pyth, switchboard], max_bps).unwrap(), 100_200_000);
+
+        // The deviation guard still applies, however tight the outlier
+        let outlier = SourcePrice { price_fp: 105 * FP, confidence_fp: 1 };
+        assert_eq!(confidence_weighted_price(&[primary, outlier], max_bps).unwrap_err(), PerpsError::OraclePriceDeviation.into());
+    }
+
+    #[test]
+    fn test_truncated_or_foreign_pyth_account_is_bad_oracle() {
+        let config = OracleConfig::default();
+        let now = 10_000;
+        let data = pyth_data(100_000_000, now);
+        assert_eq!(PythPriceAccount::parse(&data).unwrap().to_bytes(), data);
+
+        // Every short prefix is rejected, down to an empty account
+        for len in 0..data.len() {
+            assert_eq!(parse_pyth_price(&data[..len], now, &config).unwrap_err(), PerpsError::BadOracle.into());
+        }
+
+        let mut wrong_version = data.clone();
+        wrong_version[4..8].copy_from_slice(&3u32.to_le_bytes());
+        let mut oversized = data.clone();
+        oversized[12..16].copy_from_slice(&64u32.to_le_bytes());
+        for bad in [wrong_version, oversized] {
+            assert_eq!(parse_pyth_price(&bad, now, &config).unwrap_err(), PerpsError::BadOracle.into());
+        }
+    }
+
+    #[test]
+    fn test_stale_primary_pyth_falls_back_to_fresh_secondary() {
+        let config = OracleConfig::default();
+        let now = 10_000;
+        let stale_primary = pyth_data(100_000_000, now - 120);
+        let fresh_secondary = pyth_data(101_000_000, now - 5);
+
+        assert_eq!(
+            parse_pyth_price(&stale_primary, now, &config).unwrap_err(),
+            PerpsError::BadOracle.into()
+        );
+        let reading = freshest_pyth_reading([
+            parse_pyth_price(&stale_primary, now, &config),
+            parse_pyth_price(&fresh_secondary, now, &config),
+        ]).unwrap();
+        assert_eq!(reading, PythReading { price_fp: 101_000_000, confidence_fp: 10_000, publish_ts: now - 5 });
+    }
+
+    #[test]
+    fn test_freshest_of_valid_pyth_feeds_wins() {
+        let config = OracleConfig::default();
+        let now = 10_000;
+        let feeds = [pyth_data(100_000_000, now - 30), pyth_data(100_500_000, now - 2), pyth_data(99_000_000, now - 10)];
+        let reading = freshest_pyth_reading(feeds.iter().map(|data| parse_pyth_price(data, now, &config))).unwrap();
+        assert_eq!(reading.price_fp, 100_500_000);
+
+        // All stale: nothing to use
+        let stale = [pyth_data(100_000_000, now - 61)];
+        assert!(freshest_pyth_reading(stale.iter().map(|data| parse_pyth_price(data, now, &config))).is_none());
+    }
+
+    #[test]
+    fn test_pyth_exponent_rescaled_to_price_precision() {
+        assert_eq!(pyth_to_fp(6_512_345_678, -8).unwrap(), 65_123_456); // $65.12345678
+        assert_eq!(pyth_to_fp(100_000_000, -6).unwrap(), 100_000_000);  // $100
+        assert_eq!(pyth_to_fp(3, 2).unwrap(), 300_000_000);             // $300
+    }
+
+    #[test]
+    fn test_sub_cent_asset_keeps_its_resolution_with_price_precision() {
+        let now = 10_000;
+        // $0.0000004 and a 10% rally from there
+        let entry = pyth_data_with_expo(4_000_000, -13, now);
+        let rally = pyth_data_with_expo(4_400_000, -13, now);
+
+        // At plain FP the price rounds to nothing
+        let err = parse_pyth_price(&entry, now, &OracleConfig::default()).unwrap_err();
+        assert_eq!(err, PerpsError::BadOracle.into());
+
+        // Six extra decimals price it per million units instead
+        let market = Market { price_precision: 6, ..Default::default() };
+        let config = OracleConfig::for_market(&market);
+        let entry_fp = parse_pyth_price(&entry, now, &config).unwrap().price_fp;
+        let exit_fp = parse_pyth_price(&rally, now, &config).unwrap().price_fp;
+        assert_eq!((entry_fp, exit_fp), (400_000, 440_000));
+
+        // $10 of notional buys a whole number of lots and the rally is worth exactly 10%
+        let notional_fp = 10 * FP;
+        let base_size = notional_fp / entry_fp;
+        assert_eq!(base_size, 25);
+        assert_eq!(base_size * entry_fp, notional_fp);
+        assert_eq!(base_size * (exit_fp - entry_fp), FP);
+
+        // The push feed lands in the same price space
+        assert_eq!(scale_to_precision(FP / 1_000, 6).unwrap(), 1_000 * FP);
+    }
+
+    #[test]
+    fn test_health_check_compares_in_the_market_price_space() {
+        let now = 10_000;
+        // The push feed stores $0.001 per unit; the market prices per 10^3 units
+        let oracle = OraclePrice { price_fp: FP / 1_000, last_updated_ts: now, ..Default::default() };
+        let mark_fp = scale_to_precision(oracle.price_fp, 3).unwrap();
+        assert_eq!(mark_fp, FP);
+
+        // 10 lots at $1 with $2 of equity clear 5% maintenance
+        assert!(health_check(&oracle, 3, mark_fp, 10, 2 * FP as i128, 500, now).is_ok());
+        assert!(check_oracle_sanity(&oracle, 3, mark_fp * 11 / 10, now).is_ok());
+
+        // Compared per unit instead, the mark looks 1000x off the feed
+        let err = health_check(&oracle, 0, mark_fp, 10, 2 * FP as i128, 500, now).unwrap_err();
+        assert_eq!(err, PerpsError::OraclePriceDeviation.into());
+
+        // A real move is still caught in the market's price space
+        let err = check_oracle_sanity(&oracle, 3, mark_fp * 3, now).unwrap_err();
+        assert_eq!(err, PerpsError::OraclePriceDeviation.into());
+    }
+
+    #[test]
+    fn test_oracle_update_reports_old_and_new_price_unless_the_breaker_trips() {
+        let key = Pubkey::new_unique();
+        let mut oracle = OraclePrice { price_fp: 100 * FP, num_publishers: 5, ..Default::default() };
+
+        let event = apply_oracle_update(&mut oracle, key, 105 * FP, FP / 10, 1_000, 1_000).unwrap();
+        assert_eq!(event.oracle, key);
+        assert_eq!((event.old_price_fp, event.new_price_fp), (100 * FP, 105 * FP));
+        assert_eq!((event.confidence_fp, event.num_publishers), (FP / 10, 5));
+        assert_eq!((oracle.price_fp, oracle.last_updated_ts), (105 * FP, 1_000));
+
+        // A 20% jump trips the breaker: no event and the old price stands
+        let err = apply_oracle_update(&mut oracle, key, 126 * FP, FP / 10, 1_000, 2_000).map(|_| ()).unwrap_err();
+        assert_eq!(err, PerpsError::CircuitBreakerTriggered.into());
+        assert_eq!((oracle.price_fp, oracle.last_updated_ts), (105 * FP, 1_000));
+    }
+
+    #[test]
+    fn test_failover_uses_next_source_when_primary_fails() {
+        let priority = [OracleSource::Push, OracleSource::Pyth, OracleSource::EmergencyMovingAverage, OracleSource::Unset];
+        let mut tried = Vec::new();
+        let used = read_price_with_failover(&priority, |source| {
+            tried.push(source);
+            match source {
+                OracleSource::Push => Err(PerpsError::BadOracle.into()),
+                _ => Ok(101_000_000),
+            }
+        }).unwrap();
+        assert_eq!(used, (OracleSource::Pyth, 101_000_000));
+        assert_eq!(tried, [OracleSource::Push, OracleSource::Pyth]);
+
+        // Unset slots are skipped, not read
+        let priority = [OracleSource::Unset, OracleSource::Switchboard, OracleSource::Unset, OracleSource::Push];
+        let used = read_price_with_failover(&priority, |source| match source {
+            OracleSource::Push => Ok(99_000_000),
+            OracleSource::Switchboard => Err(PerpsError::OracleFeedNotFound.into()),
+            _ => panic!("unset slot read"),
+        }).unwrap();
+        assert_eq!(used, (OracleSource::Push, 99_000_000));
+    }
+
+    #[test]
+    fn test_failover_errors_when_every_source_fails() {
+        let priority = [OracleSource::Push, OracleSource::Pyth, OracleSource::Switchboard, OracleSource::EmergencyMovingAverage];
+        let err = read_price_with_failover(&priority, |_| Err(PerpsError::BadOracle.into())).unwrap_err();
+        assert_eq!(err, PerpsError::OracleFeedNotFound.into());
+        assert!(read_price_with_failover(&[OracleSource::Unset; 4], |_| Ok(1)).is_err());
+    }
+
     #[test]
     fn test_aggregate_weights() {
         let primary = 100_000_000u128; // $100
@@ -1,7 +1,29 @@
 use anchor_lang::prelude::*;
+use crate::errors::PerpsError;
+use crate::events::ProtocolStatsUpdated;
 
 pub const FP: u128 = 1_000_000; // fixed point 1e6
+pub const PRICE_DECIMALS: u8 = 6; // decimal places of every *_fp price, FP == 10^PRICE_DECIMALS
+pub const MIN_LEVERAGE_X: u64 = 1;
+pub const ACCOUNT_VERSION: u16 = 2; // layout version stamped on Config, Market and UserPosition
 pub const MAX_LEVERAGE_X: u64 = 40;
+pub const DEFAULT_MAX_FUNDING_RATE_FP: i128 = 10_000; // 1% per funding interval
+pub const MAX_FUNDING_RATE_CAP_FP: i128 = 100_000; // the funding cap can't be raised past 10% per interval
+pub const MAX_PRICE_PRECISION: u8 = 6; // extra price decimals a market can carry, see Market::price_precision
+pub const LIQUIDATOR_REWARD_TIERS: usize = 4;
+pub const DEFAULT_MIN_PARTIAL_CLOSE_PCT: u8 = 5;
+pub const MAX_OPEN_PROTECTION_SECONDS: i64 = 600; // liquidation grace after entry is capped at 10 minutes
+pub const MAINTENANCE_MARGIN_TIMELOCK_SECONDS: i64 = 24 * 60 * 60; // notice traders get before a maintenance margin raise
+pub const MAX_PYTH_FALLBACK_FEEDS: usize = 2;
+pub const MAX_ORACLE_SOURCES: usize = 4;
+pub const EMERGENCY_PRICE_SAMPLES: usize = 5; // live index prices kept for the emergency moving average
+pub const DEFAULT_MAX_ACTIVE_ORDERS_PER_USER: u32 = 32;
+pub const MAX_SKEW_SURCHARGE_BPS: u16 = 100; // entry skew surcharge is capped at 1% of notional
+pub const WITHDRAWAL_RATE_LIMIT_WINDOW_SECONDS: i64 = 24 * 60 * 60; // window a user's vault outflow is capped over
+pub const TWAP_SAMPLES: usize = 16; // oracle updates kept in an OracleTwap ring
+pub const MARGIN_TIERS: usize = 4; // steps in a market's size-tiered maintenance margin
+pub const MAX_RISK_TWAP_SECONDS: u32 = 60 * 60; // longest TWAP window liquidations can price at
+pub const MAX_WITHDRAWAL_TRANCHES: usize = 8; // queued payouts a PendingWithdrawal keeps on their own clocks
 
 // PDA seed constants for secure account derivation
 pub const CONFIG_SEED: &[u8] = b"config";
@@ -9,10 +31,28 @@ pub const MARKET_SEED: &[u8] = b"market";
 pub const POSITION_SEED: &[u8] = b"position";
 pub const VAULT_SEED: &[u8] = b"vault";
 pub const ORACLE_SEED: &[u8] = b"oracle";
+pub const ORACLE_TWAP_SEED: &[u8] = b"oracle_twap";
 pub const STOP_LOSS_SEED: &[u8] = b"stop_loss";
+pub const TAKE_PROFIT_SEED: &[u8] = b"take_profit";
+pub const TRAILING_STOP_SEED: &[u8] = b"trailing_stop";
 pub const INSURANCE_FUND_SEED: &[u8] = b"insurance_fund";
+pub const PENDING_WITHDRAWAL_SEED: &[u8] = b"pending_withdrawal";
+pub const KEEPER_GAS_VAULT_SEED: &[u8] = b"keeper_gas_vault";
+pub const USER_ORDERS_SEED: &[u8] = b"user_orders";
+pub const USER_RATE_LIMIT_SEED: &[u8] = b"user_rate_limit";
+pub const MARKET_MAKER_SEED: &[u8] = b"market_maker";
+pub const PROTOCOL_STATS_SEED: &[u8] = b"protocol_stats";
+pub const COLLATERAL_SEED: &[u8] = b"collateral";
+pub const USER_ACCOUNT_SEED: &[u8] = b"user_account";
+pub const LIMIT_ORDER_SEED: &[u8] = b"limit_order";
+pub const CROSS_MARGIN_SEED: &[u8] = b"cross_margin";
+pub const CROSS_VAULT_SEED: &[u8] = b"cross_vault";
+pub const ACCEPTED_COLLATERAL_SEED: &[u8] = b"accepted_collateral";
+pub const COLLATERAL_VAULT_SEED: &[u8] = b"collateral_vault";
+pub const POSITION_COLLATERAL_SEED: &[u8] = b"position_collateral";
 
 #[account]
+#[derive(Default)]
 pub struct Config {
     pub admin: Pubkey,
     pub quote_mint: Pubkey,
@@ -30,6 +70,64 @@ pub struct Config {
     pub max_total_positions: u32,        // Maximum total protocol positions
     pub emergency_pause_threshold: u64,  // Auto-pause threshold
     pub circuit_breaker_threshold_bps: u64, // Price movement threshold
+
+    // Decimal scales, kept apart: prices vs. the quote token's native units
+    pub price_decimals: u8,              // Decimal places of *_fp prices and values
+    pub quote_decimals: u8,              // Decimal places of the quote mint
+
+    // Liquidator incentives
+    pub liquidator_reward_tiers: [LiquidatorRewardTier; LIQUIDATOR_REWARD_TIERS], // Ascending by min_notional
+    pub liquidator_reward_floor: u64,    // Minimum reward per liquidation (quote tokens)
+    pub keeper_gas_reimbursement_lamports: u64, // SOL paid to keepers per liquidation (0 = off)
+
+    // Settlement rounding
+    pub rounding_buffer_fp: u128,        // Dust kept in the vault by rounding payouts down, at price precision
+
+    pub max_active_orders_per_user: u32, // Cap on a user's resting orders across markets (0 = no cap)
+
+    // Break-glass outflow throttle
+    pub withdrawal_rate_limit: u64,      // Max quote tokens a user may take out of the vaults per window (0 = disabled)
+
+    // Liquidity incentives
+    pub mm_rebate_bps: u16,              // Rebate on exit notional paid to whitelisted market makers instead of the fee
+    pub total_mm_rebates_paid: u64,      // Rebates paid out of the fee pool (quote tokens)
+
+    pub min_liquidation_deficit: u64,    // Shortfall below maintenance (quote tokens) a liquidation needs (0 = any)
+
+    pub version: u16,                    // Layout version, see ACCOUNT_VERSION (0 = not yet migrated)
+
+    // Entry fees by liquidity role (appended; older configs grow through `migrate_config`)
+    pub maker_fee_bps: i16,              // Limit order fills; negative pays a rebate
+    pub taker_fee_bps: u16,              // Market opens
+
+    pub pending_admin: Option<Pubkey>,   // Proposed successor, promoted once it signs `accept_admin`
+
+    // Insurance fund top-up out of trading fees
+    pub insurance_fee_bps: u16,          // Share of the protocol's fee routed to the market's insurance fund (0 = off)
+    pub insurance_target_ratio_bps: u32, // Fund ratio (deposits / claims) at which routing stops
+}
+
+/// Reject an operation on accounts written under another layout version.
+/// Accounts from before versioning read 0 and must go through
+/// `migrate_config`, `migrate_market` or `migrate_position` first.
+pub fn ensure_current_versions(config: &Config, market: &Market, positions: &[&UserPosition]) -> Result<()> {
+    let versions = [config.version, market.version].into_iter().chain(positions.iter().map(|up| up.version));
+    for version in versions {
+        require!(version == ACCOUNT_VERSION, PerpsError::AccountNeedsMigration);
+    }
+    Ok(())
+}
+
+/// One step of the liquidator reward curve: liquidations of at least
+/// `min_notional` quote tokens pay `reward_bps` of the liquidated notional.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LiquidatorRewardTier {
+    pub min_notional: u64,              // Notional threshold (quote tokens)
+    pub reward_bps: u16,                // Reward share of liquidated notional
+}
+
+impl LiquidatorRewardTier {
+    pub const SPACE: usize = 8 + 2;
 }
 
 impl Config {
@@ -48,7 +146,23 @@ impl Config {
         4 +  // max_total_positions
         8 +  // emergency_pause_threshold
         8 +  // circuit_breaker_threshold_bps
-        32;  // padding for future upgrades
+        1 +  // price_decimals
+        1 +  // quote_decimals
+        LiquidatorRewardTier::SPACE * LIQUIDATOR_REWARD_TIERS + // liquidator_reward_tiers
+        8 +  // liquidator_reward_floor
+        8 +  // keeper_gas_reimbursement_lamports
+        16 + // rounding_buffer_fp
+        4 +  // max_active_orders_per_user
+        8 +  // withdrawal_rate_limit
+        2 +  // mm_rebate_bps
+        8 +  // total_mm_rebates_paid
+        8 +  // min_liquidation_deficit
+        2 +  // version, in what was the padding
+        2 +  // maker_fee_bps
+        2 +  // taker_fee_bps
+        33 + // pending_admin (Option<Pubkey>)
+        2 +  // insurance_fee_bps
+        4;   // insurance_target_ratio_bps
 
     /// Generate PDA for the protocol config
     pub fn find_pda() -> (Pubkey, u8) {
@@ -57,9 +171,119 @@ impl Config {
             &crate::ID
         )
     }
+
+    /// Start handing the protocol over to `new_admin`, replacing any earlier
+    /// proposal. Nothing changes until the new key accepts.
+    pub fn propose_admin(&mut self, new_admin: Pubkey) -> Result<()> {
+        require_keys_neq!(new_admin, Pubkey::default(), PerpsError::InvalidParameters);
+        self.pending_admin = Some(new_admin);
+        Ok(())
+    }
+
+    /// Promote the pending admin, which must be `signer`. Returns the admin it replaced.
+    pub fn accept_admin(&mut self, signer: Pubkey) -> Result<Pubkey> {
+        let pending = self.pending_admin.ok_or(PerpsError::NoPendingAdmin)?;
+        require_keys_eq!(signer, pending, PerpsError::UnauthorizedAccess);
+        self.pending_admin = None;
+        Ok(std::mem::replace(&mut self.admin, pending))
+    }
+
+    /// Native quote token amount -> quote value at price precision
+    pub fn quote_to_fp(&self, amount: u64) -> Result<u128> {
+        crate::math::quote_amount_to_fp(amount, self.price_decimals, self.quote_decimals)
+    }
+
+    /// Quote value at price precision (e.g. `size * price_fp`) -> native quote token amount
+    pub fn fp_to_quote(&self, value_fp: u128) -> Result<u64> {
+        crate::math::fp_to_quote_amount(value_fp, self.price_decimals, self.quote_decimals)
+    }
+
+    /// `fp_to_quote` for a payout out of the vault: the part of `value_fp`
+    /// lost to rounding down stays in the vault and is banked in the rounding buffer
+    pub fn settle_to_quote(&mut self, value_fp: u128) -> Result<u64> {
+        let amount = self.fp_to_quote(value_fp)?;
+        let dust_fp = value_fp - self.quote_to_fp(amount)?;
+        self.rounding_buffer_fp = self.rounding_buffer_fp
+            .checked_add(dust_fp)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(amount)
+    }
+
+    /// Whole quote units accumulated in the rounding buffer
+    pub fn rounding_buffer(&self) -> Result<u64> {
+        self.fp_to_quote(self.rounding_buffer_fp)
+    }
+
+    /// Write off a vault shortfall on `owed` against the rounding buffer.
+    /// Returns the units written off (0 when `available` covers `owed`), or
+    /// `None` when the gap is larger than the buffer and isn't rounding dust.
+    pub fn absorb_rounding_shortfall(&mut self, owed: u64, available: u64) -> Result<Option<u64>> {
+        let shortfall = owed.saturating_sub(available);
+        if shortfall == 0 {
+            return Ok(Some(0));
+        }
+        let shortfall_fp = self.quote_to_fp(shortfall)?;
+        if shortfall_fp > self.rounding_buffer_fp {
+            return Ok(None);
+        }
+        self.rounding_buffer_fp -= shortfall_fp;
+        Ok(Some(shortfall))
+    }
+
+    /// Trim a settlement and fee to what the vault holds, absorbing the gap in
+    /// the rounding buffer. The fee gives way before the trader's settlement.
+    pub fn cushion_payout(&mut self, settlement: u64, fee: u64, available: u64) -> Result<(u64, u64)> {
+        let owed = settlement.checked_add(fee).ok_or(PerpsError::MathOverflow)?;
+        let written_off = self.absorb_rounding_shortfall(owed, available)?
+            .ok_or(PerpsError::InsufficientLiquidity)?;
+        let from_fee = written_off.min(fee);
+        Ok((settlement - (written_off - from_fee), fee - from_fee))
+    }
+
+    /// Release `amount` whole units of the rounding buffer for an admin sweep
+    pub fn take_rounding_buffer(&mut self, amount: u64) -> Result<()> {
+        let amount_fp = self.quote_to_fp(amount)?;
+        require!(amount_fp <= self.rounding_buffer_fp, PerpsError::InsufficientFunds);
+        self.rounding_buffer_fp -= amount_fp;
+        Ok(())
+    }
+
+    /// Whether a token account held by `authority` in `mint` can act as the
+    /// program vault: it must be under the config PDA and in the quote mint
+    pub fn is_quote_vault(&self, config_key: &Pubkey, authority: &Pubkey, mint: &Pubkey) -> bool {
+        authority == config_key && *mint == self.quote_mint
+    }
+
+    /// Market maker rebate on a close of `notional_fp`, capped at what the
+    /// fee pool holds so paying it can never overdraw the pool
+    pub fn maker_rebate(&self, notional_fp: u128, pool_balance: u64) -> Result<u64> {
+        let rebate = self.fp_to_quote(notional_fp * self.mm_rebate_bps as u128 / 10_000)?;
+        Ok(rebate.min(pool_balance))
+    }
+
+    /// Entry fee on `notional` quote tokens at the maker or taker rate.
+    /// Positive is charged to the trader, negative is a rebate paid to them;
+    /// both round towards zero, so a rebate never exceeds the matching taker fee.
+    pub fn entry_fee(&self, notional: u64, is_maker: bool) -> i64 {
+        let bps = if is_maker { self.maker_fee_bps as i128 } else { self.taker_fee_bps as i128 };
+        (notional as i128 * bps / 10_000) as i64
+    }
+
+    /// Lamports to reimburse a keeper from a gas vault holding `vault_lamports`,
+    /// leaving at least `vault_floor` (its rent-exempt minimum) behind
+    pub fn keeper_gas_reimbursement(&self, vault_lamports: u64, vault_floor: u64) -> u64 {
+        self.keeper_gas_reimbursement_lamports
+            .min(vault_lamports.saturating_sub(vault_floor))
+    }
+
+    /// Human-readable rendering of a price or quote value for logs
+    pub fn to_human_price(&self, value_fp: i128) -> String {
+        crate::math::to_human_price(value_fp, self.price_decimals)
+    }
 }
 
-#[account]  
+#[account]
+#[derive(Default)]
 pub struct Market {
     pub symbol: [u8; 12],               // Market symbol (e.g. "BTC", "ETH")
     pub base_decimals: u8,              // Base token decimal places
@@ -92,6 +316,110 @@ pub struct Market {
     // Risk management
     pub max_funding_rate_fp: i128,      // Maximum allowed funding rate
     pub max_skew_ratio: u32,            // Maximum skew ratio (long/short)
+
+    // Withdrawal queue for large closes
+    pub withdrawal_queue_threshold: u64, // Payouts above this are queued (0 = disabled)
+    pub withdrawal_delay_seconds: i64,  // Delay before a queued payout is claimable
+
+    pub min_partial_close_pct: u8,      // Smallest allowed partial close (full closes always allowed)
+
+    // Accounting (appended after the original layout; see `migrate_market`)
+    pub cumulative_trader_pnl_fp: i128, // Realized trader PnL across closes and liquidations
+    pub cumulative_fees_fp: u128,       // Fees collected across closes and liquidations
+
+    pub open_protection_seconds: i64,   // Grace after entry before a solvent position can be liquidated (0 = off)
+
+    pub pyth_fallback_oracles: [Pubkey; MAX_PYTH_FALLBACK_FEEDS], // Extra Pyth feeds for the asset (default key = unused)
+
+    pub pending_maintenance_margin_bps: u16, // Scheduled maintenance margin raise (0 = none)
+    pub maintenance_margin_effective_ts: i64, // When the scheduled raise takes effect
+
+    pub vault_bump: u8,                 // Bump of the market's collateral vault PDA
+
+    pub confidence_band_liquidation: bool, // Only liquidate if underwater at the favourable edge of the oracle band
+
+    // Dated markets (expiry_ts == 0 for a perpetual)
+    pub expiry_ts: i64,                 // When the market stops trading and settles
+    pub close_only_before_expiry_seconds: i64, // Window before expiry in which opens are blocked
+    pub final_settlement_price_fp: u128, // Price pinned by settle_expired_market (0 = not settled)
+
+    pub max_skew_surcharge_bps: u16,    // Cap on the entry surcharge for opens that deepen the skew (0 = off)
+
+    // Oracle failover (all Unset = legacy primary/Pyth aggregation)
+    pub oracle_source_priority: [OracleSource; MAX_ORACLE_SOURCES], // Tried in order, first valid price wins
+    pub recent_index_prices_fp: [u128; EMERGENCY_PRICE_SAMPLES], // Last live index prices, a ring
+    pub recent_index_price_cursor: u8,  // Next slot to overwrite in recent_index_prices_fp
+
+    // Dead-oracle escape hatch
+    pub oracle_dead: bool,              // Feed abandoned: positions only leave through emergency settlement
+    pub emergency_price_fp: u128,       // Admin-attested final price emergency settlement pays out at
+
+    // Prices in this market are per 10^price_precision base units and base
+    // sizes count those lots, so a micro-priced asset keeps resolution that FP
+    // alone would round away. Notionals (size * price) are unchanged; the
+    // cap keeps price intermediates such as `price * FP` and `price * 10_000`
+    // far inside u128/i128 for the sub-dollar assets worth scaling.
+    pub price_precision: u8,            // Extra price decimals (0 = plain FP)
+
+    pub max_position_oi_fraction_bps: u16, // Largest share of total OI one position may hold (0 = off)
+    pub last_amm_rebalance_ts: i64,     // Last rebalance_amm crank
+    pub creator: Pubkey,                // Who launched the market, paid creator_reward_bps of its trading fees
+    pub creator_rewards_accrued: u64,   // Creator's unclaimed share, held in the market vault
+    pub max_favorable_move_bps: u16,    // Price move an open's payout must be backed for (0 = off)
+    pub version: u16,                   // Layout version, see ACCOUNT_VERSION (0 = not yet migrated)
+    pub switchboard_oracle: Option<Pubkey>, // Optional Switchboard aggregator
+
+    // Aggregate open interest caps, on top of the per-position max_position_base.
+    // These take the old padding plus 8 bytes: markets created before them
+    // need `migrate_market` to grow.
+    pub max_long_oi: u64,               // Cap on total_long_size (0 = uncapped)
+    pub max_short_oi: u64,              // Cap on total_short_size (0 = uncapped)
+
+    pub risk_twap_seconds: u32,         // Liquidations and health checks price at the oracle TWAP over this window (0 = spot)
+
+    pub circuit_breaker_slot: u64,      // Slot the trade-path circuit breaker last tripped in (0 = clear)
+
+    // Size-tiered maintenance margin, on top of maintenance_margin_bps
+    pub margin_tiers: [MarginTier; MARGIN_TIERS], // Ascending by min_base_size, unused tiers zeroed at the end
+    pub pending_margin_tiers: [MarginTier; MARGIN_TIERS], // Scheduled stricter schedule
+    pub margin_tiers_effective_ts: i64, // When the scheduled tiers take effect (0 = none scheduled)
+
+    pub min_position_base: u64,         // Dust floor: smallest position that may be opened or left open (0 = off)
+
+    // Funding smoothing
+    pub funding_ema_alpha_bps: u16,     // Weight of each raw rate in funding_rate_fp (0 = no smoothing)
+    pub funding_rate_seeded: bool,      // funding_rate_fp holds a settled rate to smooth from
+
+    pub min_holding_seconds: i64,       // Shortest time from a position's last update to a user close (0 = off)
+
+    pub total_margin_locked: u64,       // Trader funds booked in the vault: isolated margin, limit escrows and idle collateral
+
+    pub funding_carry: u128,            // Funding the paying side owes below one index unit (x FP * FUNDING_INTERVAL_SECONDS)
+
+    pub insurance_claims_held_fp: u128, // Insurance paid into the vault for deficits, held for the winning side's PnL
+}
+
+/// One step of a market's maintenance margin schedule: positions of at least
+/// `min_base_size` base units need `maintenance_margin_bps`. The market-wide
+/// rate still applies below the first tier; a zero `maintenance_margin_bps`
+/// marks the tier unused.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MarginTier {
+    pub min_base_size: u64,             // Smallest position the tier applies to (base units)
+    pub maintenance_margin_bps: u16,
+}
+
+impl MarginTier {
+    pub const SPACE: usize = 8 + 2;
+
+    /// Highest rate of the tiers `base_size` reaches (0 = none)
+    pub fn bps_for(tiers: &[MarginTier], base_size: u64) -> u16 {
+        tiers.iter()
+            .filter(|tier| tier.maintenance_margin_bps > 0 && base_size >= tier.min_base_size)
+            .map(|tier| tier.maintenance_margin_bps)
+            .max()
+            .unwrap_or(0)
+    }
 }
 
 impl Market {
@@ -117,7 +445,48 @@ impl Market {
         1 +  // is_paused
         16 + // max_funding_rate_fp
         4 +  // max_skew_ratio
-        32;  // padding
+        8 +  // withdrawal_queue_threshold
+        8 +  // withdrawal_delay_seconds
+        1 +  // min_partial_close_pct
+        16 + // cumulative_trader_pnl_fp
+        16 + // cumulative_fees_fp
+        8 +  // open_protection_seconds
+        32 * MAX_PYTH_FALLBACK_FEEDS + // pyth_fallback_oracles
+        2 +  // pending_maintenance_margin_bps
+        8 +  // maintenance_margin_effective_ts
+        1 +  // vault_bump
+        1 +  // confidence_band_liquidation
+        8 +  // expiry_ts
+        8 +  // close_only_before_expiry_seconds
+        16 + // final_settlement_price_fp
+        2 +  // max_skew_surcharge_bps
+        MAX_ORACLE_SOURCES + // oracle_source_priority
+        16 * EMERGENCY_PRICE_SAMPLES + // recent_index_prices_fp
+        1 +  // recent_index_price_cursor
+        1 +  // oracle_dead
+        16 + // emergency_price_fp
+        1 +  // price_precision
+        2 +  // max_position_oi_fraction_bps
+        8 +  // last_amm_rebalance_ts
+        32 + // creator
+        8 +  // creator_rewards_accrued
+        2 +  // max_favorable_move_bps
+        2 +  // version
+        33 + // switchboard_oracle (Option<Pubkey>)
+        8 +  // max_long_oi
+        8 +  // max_short_oi
+        4 +  // risk_twap_seconds
+        8 +  // circuit_breaker_slot
+        MarginTier::SPACE * MARGIN_TIERS + // margin_tiers
+        MarginTier::SPACE * MARGIN_TIERS + // pending_margin_tiers
+        8 +  // margin_tiers_effective_ts
+        8 +  // min_position_base
+        2 +  // funding_ema_alpha_bps
+        1 +  // funding_rate_seeded
+        8 +  // min_holding_seconds
+        8 +  // total_margin_locked
+        16 + // funding_carry
+        16;  // insurance_claims_held_fp
 
     /// Generate PDA for a market account
     pub fn find_pda(symbol: &[u8]) -> (Pubkey, u8) {
@@ -127,22 +496,434 @@ impl Market {
         )
     }
 
-    /// Calculate current skew ratio
+    /// Whether the market prices through an explicit failover chain
+    pub fn has_source_priority(&self) -> bool {
+        self.oracle_source_priority.iter().any(|source| *source != OracleSource::Unset)
+    }
+
+    /// Remember a live index price for the emergency moving average
+    pub fn record_index_price(&mut self, price_fp: u128) {
+        let slot = self.recent_index_price_cursor as usize % EMERGENCY_PRICE_SAMPLES;
+        self.recent_index_prices_fp[slot] = price_fp;
+        self.recent_index_price_cursor = ((slot + 1) % EMERGENCY_PRICE_SAMPLES) as u8;
+    }
+
+    /// The live index price recorded last (0 before the first)
+    pub fn last_index_price_fp(&self) -> u128 {
+        let last = (self.recent_index_price_cursor as usize + EMERGENCY_PRICE_SAMPLES - 1) % EMERGENCY_PRICE_SAMPLES;
+        self.recent_index_prices_fp[last]
+    }
+
+    /// Trade-path circuit breaker. A move from `last_mark_fp` to `mark_fp`
+    /// over `threshold_bps` trips it for the rest of `slot`; it clears on a
+    /// later slot once the move is back within threshold. Returns the move in
+    /// bps while tripped, None when opens may go ahead.
+    pub fn check_circuit_breaker(&mut self, last_mark_fp: u128, mark_fp: u128, threshold_bps: u64, slot: u64) -> Option<u64> {
+        let change_bps = match last_mark_fp.max(mark_fp) {
+            0 => 0,
+            higher => (last_mark_fp.abs_diff(mark_fp) * 10_000 / higher) as u64,
+        };
+        if threshold_bps > 0 && change_bps > threshold_bps {
+            self.circuit_breaker_slot = slot;
+            return Some(change_bps);
+        }
+        if self.circuit_breaker_slot != 0 && slot <= self.circuit_breaker_slot {
+            return Some(change_bps);
+        }
+        self.circuit_breaker_slot = 0;
+        None
+    }
+
+    /// Generate PDA for a market's collateral vault. Each market's margin and
+    /// payouts go through its own vault, held by the config PDA.
+    pub fn find_vault_pda(market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[VAULT_SEED, market.as_ref()],
+            &crate::ID
+        )
+    }
+
+    /// Calculate current skew ratio (long/short in basis points).
+    /// An empty book is balanced; a book with no shorts returns the `u32::MAX`
+    /// sentinel, so callers must not do arithmetic on the result.
     pub fn skew_ratio(&self) -> u32 {
         if self.total_short_size == 0 {
-            return u32::MAX;
+            return if self.total_long_size == 0 { 10_000 } else { u32::MAX };
+        }
+        let ratio = (self.total_long_size as u128 * 10_000) / self.total_short_size as u128;
+        ratio.min(u32::MAX as u128) as u32
+    }
+
+    /// True when open interest exists on only one side of the book
+    pub fn is_one_sided(&self) -> bool {
+        (self.total_long_size == 0) != (self.total_short_size == 0)
+    }
+
+    /// Whether a close paying out `settlement_amount` goes through the withdrawal queue
+    pub fn queues_withdrawal(&self, settlement_amount: u64) -> bool {
+        self.withdrawal_queue_threshold > 0 && settlement_amount > self.withdrawal_queue_threshold
+    }
+
+    /// Add newly opened size to the side's open interest and trade it
+    /// against the vAMM, so longs push the mark up and shorts push it down.
+    /// Fails if the side would pass its open interest cap or the trade would
+    /// take the AMM below its reserve floor.
+    pub fn increase_open_interest(&mut self, is_long: bool, base_size: u64) -> Result<()> {
+        let (side, cap) = if is_long {
+            (&mut self.total_long_size, self.max_long_oi)
+        } else {
+            (&mut self.total_short_size, self.max_short_oi)
+        };
+        let new_oi = side.checked_add(base_size).ok_or(PerpsError::MathOverflow)?;
+        require!(cap == 0 || new_oi <= cap, PerpsError::ExceedsRiskLimits);
+        *side = new_oi;
+        if self.has_amm_reserves() {
+            (self.amm_base_reserve_fp, self.amm_quote_reserve_fp) = crate::math::amm_reserves_after_open(
+                self.amm_base_reserve_fp, self.amm_quote_reserve_fp, base_size, is_long,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Remove closed or liquidated size from the side's open interest and
+    /// trade it back against the vAMM. Closing is never blocked by AMM depth:
+    /// an unwind the curve can't absorb leaves the reserves as they were.
+    pub fn reduce_open_interest(&mut self, is_long: bool, base_size: u64) {
+        if is_long {
+            self.total_long_size = self.total_long_size.saturating_sub(base_size);
+        } else {
+            self.total_short_size = self.total_short_size.saturating_sub(base_size);
+        }
+        if self.has_amm_reserves() {
+            if let Ok(reserves) = crate::math::amm_reserves_after_trade(
+                self.amm_base_reserve_fp, self.amm_quote_reserve_fp, base_size, !is_long,
+            ) {
+                (self.amm_base_reserve_fp, self.amm_quote_reserve_fp) = reserves;
+            }
+        }
+    }
+
+    /// Cumulative funding index of one side, per unit of base
+    pub fn cumulative_funding_fp(&self, is_long: bool) -> i128 {
+        if is_long { self.cumulative_funding_long_fp } else { self.cumulative_funding_short_fp }
+    }
+
+    /// Markets created without reserves quote the index unchanged
+    pub fn has_amm_reserves(&self) -> bool {
+        self.amm_base_reserve_fp > 0 && self.amm_quote_reserve_fp > 0
+    }
+
+    /// Add a close, partial close or liquidation to the market's running totals.
+    /// A profit draws down the insurance money held for it; anything past
+    /// that is paid out of the losing side's settled margin.
+    pub fn record_settlement(&mut self, trader_pnl_fp: i128, fees_fp: u128) -> Result<()> {
+        self.cumulative_trader_pnl_fp = self.cumulative_trader_pnl_fp
+            .checked_add(trader_pnl_fp)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.cumulative_fees_fp = self.cumulative_fees_fp
+            .checked_add(fees_fp)
+            .ok_or(PerpsError::MathOverflow)?;
+        if trader_pnl_fp > 0 {
+            self.insurance_claims_held_fp = self.insurance_claims_held_fp.saturating_sub(trader_pnl_fp as u128);
+        }
+        Ok(())
+    }
+
+    /// Book insurance money paid into the vault for a liquidation deficit. It
+    /// stands in for the bankrupt position's loss, so it stays owed to the
+    /// winning side until their profitable settlements draw it down.
+    pub fn hold_insurance_claims(&mut self, amount_fp: u128) -> Result<()> {
+        self.insurance_claims_held_fp = self.insurance_claims_held_fp
+            .checked_add(amount_fp)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Keep the creator's `creator_reward_bps` share of a trading fee in the
+    /// vault for them to claim, returning what goes on to the fee destination.
+    /// Markets from before creators were recorded forward the whole fee.
+    pub fn retain_creator_share(&mut self, fee_amt: u64, creator_reward_bps: u16) -> Result<u64> {
+        if self.creator == Pubkey::default() {
+            return Ok(fee_amt);
+        }
+        let share = (fee_amt as u128 * creator_reward_bps as u128 / 10_000) as u64;
+        self.creator_rewards_accrued = self.creator_rewards_accrued
+            .checked_add(share)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(fee_amt - share)
+    }
+
+    /// Hand out everything the creator has accrued, resetting the balance
+    pub fn take_creator_rewards(&mut self) -> u64 {
+        std::mem::take(&mut self.creator_rewards_accrued)
+    }
+
+    /// Draw `amount` off the creator's accrual, refusing more than has accrued
+    pub fn draw_creator_rewards(&mut self, amount: u64) -> Result<()> {
+        require!(self.creator_rewards_accrued > 0, PerpsError::NoCreatorRewards);
+        require!(amount > 0 && amount <= self.creator_rewards_accrued, PerpsError::ExceedsCreatorRewards);
+        self.creator_rewards_accrued -= amount;
+        Ok(())
+    }
+
+    /// Book `amount` of trader funds as held in the market's vault
+    pub fn lock_margin(&mut self, amount: u64) -> Result<()> {
+        self.total_margin_locked = self.total_margin_locked
+            .checked_add(amount)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Take `amount` of trader funds off the vault's books. Releasing more
+    /// than was booked means the counter has drifted from the positions, so
+    /// it fails rather than hiding it.
+    pub fn release_margin(&mut self, amount: u64) -> Result<()> {
+        self.total_margin_locked = self.total_margin_locked
+            .checked_sub(amount)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Rebook trader funds in the vault that went from `before` to `after`
+    pub fn rebook_margin(&mut self, before: u64, after: u64) -> Result<()> {
+        self.release_margin(before)?;
+        self.lock_margin(after)
+    }
+
+    /// What the vault must keep covering: trader funds, the creator's
+    /// unclaimed rewards and insurance money held for the winning side
+    pub fn vault_obligations(&self, cfg: &Config) -> Result<u128> {
+        Ok(self.total_margin_locked as u128
+            + self.creator_rewards_accrued as u128
+            + cfg.fp_to_quote(self.insurance_claims_held_fp)? as u128)
+    }
+
+    /// Whether a partial close of `close_percentage` meets the market's minimum increment
+    pub fn allows_partial_close(&self, close_percentage: u8) -> bool {
+        close_percentage >= self.min_partial_close_pct
+    }
+
+    /// `close_size` of a position of `position_size`, grown to the whole
+    /// position when the remainder would fall under `min_position_base`:
+    /// dust is too small to maintain or to liquidate economically
+    pub fn dust_free_close_size(&self, position_size: u64, close_size: u64) -> u64 {
+        let remaining = position_size.saturating_sub(close_size);
+        if remaining > 0 && remaining < self.min_position_base {
+            position_size
+        } else {
+            close_size
+        }
+    }
+
+    /// Whether `key` is one of the market's registered Pyth feeds
+    pub fn is_pyth_feed(&self, key: &Pubkey) -> bool {
+        self.pyth_oracle == Some(*key)
+            || (*key != Pubkey::default() && self.pyth_fallback_oracles.contains(key))
+    }
+
+    /// Maintenance margin in force at `now`: a scheduled raise only counts
+    /// once its timelock has passed
+    pub fn maintenance_margin_bps_at(&self, now: i64) -> u16 {
+        if self.pending_maintenance_margin_bps > 0 && now >= self.maintenance_margin_effective_ts {
+            self.pending_maintenance_margin_bps
+        } else {
+            self.maintenance_margin_bps
+        }
+    }
+
+    /// Stricter of the live and any scheduled maintenance margin. Used when a
+    /// trader adds risk, so nobody opens or withdraws straight into a raise.
+    pub fn upcoming_maintenance_margin_bps(&self) -> u16 {
+        self.maintenance_margin_bps.max(self.pending_maintenance_margin_bps)
+    }
+
+    /// `maintenance_margin_bps_at` for a position of `base_size`: the
+    /// highest margin tier it reaches when that is stricter
+    pub fn maintenance_margin_bps_for(&self, base_size: u64, now: i64) -> u16 {
+        let tiers = if self.margin_tiers_effective_ts > 0 && now >= self.margin_tiers_effective_ts {
+            &self.pending_margin_tiers
+        } else {
+            &self.margin_tiers
+        };
+        self.maintenance_margin_bps_at(now).max(MarginTier::bps_for(tiers, base_size))
+    }
+
+    /// `upcoming_maintenance_margin_bps` for a position of `base_size`,
+    /// counting scheduled tiers as well
+    pub fn upcoming_maintenance_margin_bps_for(&self, base_size: u64) -> u16 {
+        self.upcoming_maintenance_margin_bps()
+            .max(MarginTier::bps_for(&self.margin_tiers, base_size))
+            .max(MarginTier::bps_for(&self.pending_margin_tiers, base_size))
+    }
+
+    /// Promote a scheduled maintenance margin raise or tier schedule once it is due
+    pub fn settle_maintenance_margin(&mut self, now: i64) {
+        if self.pending_maintenance_margin_bps > 0 && now >= self.maintenance_margin_effective_ts {
+            self.maintenance_margin_bps = self.pending_maintenance_margin_bps;
+            self.pending_maintenance_margin_bps = 0;
+            self.maintenance_margin_effective_ts = 0;
+        }
+        if self.margin_tiers_effective_ts > 0 && now >= self.margin_tiers_effective_ts {
+            self.margin_tiers = std::mem::take(&mut self.pending_margin_tiers);
+            self.margin_tiers_effective_ts = 0;
+        }
+    }
+
+    /// Replace the margin tiers. A schedule that asks no size for more margin
+    /// than the current one applies at once and drops any scheduled one;
+    /// anything stricter waits out `MAINTENANCE_MARGIN_TIMELOCK_SECONDS`.
+    pub fn schedule_margin_tiers(&mut self, tiers: [MarginTier; MARGIN_TIERS], now: i64) -> Result<()> {
+        self.settle_maintenance_margin(now);
+        // Both schedules are step functions that only change at a tier's
+        // min_base_size, so comparing at every threshold covers every size
+        let loosens = self.margin_tiers.iter().chain(tiers.iter()).all(|tier| {
+            MarginTier::bps_for(&tiers, tier.min_base_size) <= MarginTier::bps_for(&self.margin_tiers, tier.min_base_size)
+        });
+        if loosens {
+            self.margin_tiers = tiers;
+            self.pending_margin_tiers = Default::default();
+            self.margin_tiers_effective_ts = 0;
+        } else {
+            self.pending_margin_tiers = tiers;
+            self.margin_tiers_effective_ts = now
+                .checked_add(MAINTENANCE_MARGIN_TIMELOCK_SECONDS)
+                .ok_or(PerpsError::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    /// Change the maintenance margin. Lowering it applies at once and drops any
+    /// scheduled raise; raising it only takes effect after
+    /// `MAINTENANCE_MARGIN_TIMELOCK_SECONDS`, so traders can add margin first.
+    pub fn schedule_maintenance_margin(&mut self, new_bps: u16, now: i64) -> Result<()> {
+        self.settle_maintenance_margin(now);
+        if new_bps <= self.maintenance_margin_bps {
+            self.maintenance_margin_bps = new_bps;
+            self.pending_maintenance_margin_bps = 0;
+            self.maintenance_margin_effective_ts = 0;
+        } else {
+            self.pending_maintenance_margin_bps = new_bps;
+            self.maintenance_margin_effective_ts = now
+                .checked_add(MAINTENANCE_MARGIN_TIMELOCK_SECONDS)
+                .ok_or(PerpsError::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a dated market has reached expiry
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expiry_ts > 0 && now >= self.expiry_ts
+    }
+
+    /// Whether a dated market only accepts closes and reductions: from
+    /// `close_only_before_expiry_seconds` ahead of expiry onwards
+    pub fn is_close_only(&self, now: i64) -> bool {
+        self.expiry_ts > 0
+            && now >= self.expiry_ts.saturating_sub(self.close_only_before_expiry_seconds)
+    }
+
+    /// Refuse an open that would leave a position of `position_size` holding
+    /// more than `max_position_oi_fraction_bps` of the market's OI, counted
+    /// after the open. An empty market has nothing to measure against, so its
+    /// first position is exempt.
+    pub fn ensure_within_concentration(&self, position_size: u64) -> Result<()> {
+        self.ensure_increase_within_concentration(0, position_size)
+    }
+
+    /// As `ensure_within_concentration`, for adding `added` to a position of
+    /// `held` that is already counted in the market's OI
+    pub fn ensure_increase_within_concentration(&self, held: u64, added: u64) -> Result<()> {
+        let oi_before = self.total_long_size as u128 + self.total_short_size as u128;
+        if self.max_position_oi_fraction_bps == 0 || oi_before == 0 {
+            return Ok(());
+        }
+        let oi_after = oi_before + added as u128;
+        let position_size = held as u128 + added as u128;
+        require!(
+            position_size * 10_000 <= oi_after * self.max_position_oi_fraction_bps as u128,
+            PerpsError::ConcentrationLimitExceeded
+        );
+        Ok(())
+    }
+
+    /// Refuse to price anything off a feed the admin has declared dead
+    pub fn ensure_oracle_live(&self) -> Result<()> {
+        require!(!self.oracle_dead, PerpsError::OracleMarkedDead);
+        Ok(())
+    }
+
+    /// Price positions close at: the pinned final price once an expired
+    /// market has been settled, otherwise the live `mark_fp`
+    pub fn settlement_mark_fp(&self, mark_fp: u128) -> u128 {
+        if self.final_settlement_price_fp > 0 {
+            self.final_settlement_price_fp
+        } else {
+            mark_fp
         }
-        (self.total_long_size as u32 * 10_000) / self.total_short_size as u32
+    }
+
+    /// Whether a position opened at `opened_at_ts` is still inside its entry
+    /// grace period. Insolvent positions (no equity left) are never protected.
+    pub fn liquidation_protected(&self, opened_at_ts: i64, now: i64, equity_fp: i128) -> bool {
+        self.open_protection_seconds > 0
+            && equity_fp > 0
+            && now < opened_at_ts.saturating_add(self.open_protection_seconds)
+    }
+
+    /// Reject a user close of a position last updated at `last_updated_ts`
+    /// until the market's minimum holding period has passed, so a position
+    /// can't be opened and closed around a known oracle update.
+    /// Liquidations don't call this.
+    pub fn ensure_holding_period(&self, last_updated_ts: i64, now: i64) -> Result<()> {
+        require!(
+            now >= last_updated_ts.saturating_add(self.min_holding_seconds),
+            PerpsError::HoldingPeriodNotElapsed
+        );
+        Ok(())
     }
 
     /// Check if market is balanced (skew within acceptable range)
     pub fn is_balanced(&self) -> bool {
         let skew = self.skew_ratio();
-        skew >= 5_000 && skew <= 15_000 // Between 0.5x and 1.5x ratio
+        (5_000..=15_000).contains(&skew) // Between 0.5x and 1.5x ratio
     }
 }
 
+/// A price source in a market's failover chain
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OracleSource {
+    #[default]
+    Unset,                              // Empty slot, skipped
+    Push,                               // The market's pushed OraclePrice account
+    Pyth,                               // Registered Pyth feeds, freshest valid one
+    Switchboard,                        // Switchboard aggregator
+    EmergencyMovingAverage,             // Average of the market's last live index prices
+}
+
+/// Lifecycle of a position account. `Closed` is the zero value so a freshly
+/// created (or never used) position account reads as closed.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PositionStatus {
+    #[default]
+    Closed,                             // No exposure, free to open
+    Open,                               // Live position
+    Liquidating,                        // Partially liquidated and still below maintenance
+    PendingSettlement,                  // Closed, payout waiting in the withdrawal queue
+}
+
+/// How a position's margin is held. Isolated positions carry their own margin
+/// in their market's vault. Cross positions draw theirs from the owner's
+/// `CrossMarginAccount` and are only liquidated when the account as a whole
+/// falls below maintenance.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MarginMode {
+    #[default]
+    Isolated,                           // Own margin, own liquidation
+    Cross,                              // Pooled collateral, liquidated on account health
+}
+
 #[account]
+#[derive(Default)]
 pub struct UserPosition {
     pub owner: Pubkey,                  // Position owner
     pub market: Pubkey,                 // Market this position belongs to
@@ -165,6 +946,21 @@ pub struct UserPosition {
     // Position history
     pub realized_pnl_fp: i128,          // Total realized PnL
     pub total_fees_paid: u64,           // Total fees paid on this position
+
+    // Liquidation tracking
+    pub liquidatable_since_ts: i64,     // First seen below maintenance by a keeper (0 = healthy)
+
+    // Lifecycle
+    pub status: PositionStatus,         // Explicit state instead of inferring from base_size
+    pub opened_at_ts: i64,              // When the current position was opened
+    pub version: u16,                   // Layout version, see ACCOUNT_VERSION (0 = not yet migrated)
+
+    // Funding
+    pub last_cumulative_funding_fp: i128, // Side's cumulative funding index when funding was last settled
+
+    // Margin
+    pub margin_mode: MarginMode,        // Isolated (zeroed padding) unless opened against a CrossMarginAccount
+    pub collateral_pledged: bool,       // Margin is non-quote collateral held in a PositionCollateral
 }
 
 impl UserPosition {
@@ -182,7 +978,14 @@ impl UserPosition {
         8 +  // last_updated_ts
         16 + // realized_pnl_fp
         8 +  // total_fees_paid
-        32;  // padding
+        8 +  // liquidatable_since_ts
+        1 +  // status
+        8 +  // opened_at_ts
+        2 +  // version
+        16 + // last_cumulative_funding_fp
+        1 +  // margin_mode
+        1 +  // collateral_pledged
+        11;  // padding
 
     /// Generate PDA for a user position
     pub fn find_pda(owner: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
@@ -192,45 +995,125 @@ impl UserPosition {
         )
     }
 
-    /// Calculate unrealized PnL
-    pub fn unrealized_pnl_fp(&self, current_price_fp: u128) -> i128 {
-        if self.base_size == 0 {
-            return 0;
+    /// Zero out a fully closed position, recording its final PnL and fees
+    pub fn settle_full_close(&mut self, pnl_fp: i128, fees_paid: u64, now: i64) {
+        self.realized_pnl_fp += pnl_fp;
+        self.total_fees_paid += fees_paid;
+        self.base_size = 0;
+        self.margin_deposited = 0;
+        self.liquidatable_since_ts = 0;
+        self.last_updated_ts = now;
+        self.status = PositionStatus::Closed;
+    }
+
+    /// Move funding accrued since the last settlement into `funding_debt_fp`:
+    /// `(side index now - index at last settlement) * size`, paid when the
+    /// index fell and received when it rose. Returns the amount settled,
+    /// positive when the position paid.
+    pub fn settle_funding(&mut self, market: &Market, now: i64) -> Result<i128> {
+        let index_fp = market.cumulative_funding_fp(self.is_long);
+        let owed_fp = (self.last_cumulative_funding_fp - index_fp)
+            .checked_mul(self.base_size.unsigned_abs() as i128)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.funding_debt_fp = self.funding_debt_fp.checked_add(owed_fp).ok_or(PerpsError::MathOverflow)?;
+        self.last_cumulative_funding_fp = index_fp;
+        self.last_funding_settled = now;
+        Ok(owed_fp)
+    }
+
+    /// Whether a stop-loss or take-profit armed at `order_created_at` has
+    /// outlived the position it was set on: the position has since been closed
+    /// out, or closed and reopened
+    pub fn orphans_order(&self, order_created_at: i64) -> bool {
+        self.base_size == 0
+            || matches!(self.current_status(), PositionStatus::Closed | PositionStatus::PendingSettlement)
+            || order_created_at < self.opened_at_ts
+    }
+
+    /// Current lifecycle state. Positions opened before the status field existed
+    /// read as `Closed` from the zeroed padding, so a live size means `Open`.
+    pub fn current_status(&self) -> PositionStatus {
+        match self.status {
+            PositionStatus::Closed if self.base_size != 0 => PositionStatus::Open,
+            status => status,
         }
+    }
 
-        let position_size_abs = self.base_size.abs() as u128;
-        let entry_notional_fp = position_size_abs * self.entry_price_fp;
-        let current_notional_fp = position_size_abs * current_price_fp;
-        
-        if self.is_long {
-            (current_notional_fp as i128 - entry_notional_fp as i128) / FP as i128
-        } else {
-            (entry_notional_fp as i128 - current_notional_fp as i128) / FP as i128
+    /// Reject the operation unless the position is in one of the `allowed` states
+    pub fn ensure_status(&self, allowed: &[PositionStatus]) -> Result<()> {
+        let status = self.current_status();
+        if allowed.contains(&status) {
+            return Ok(());
         }
+        Err(match status {
+            PositionStatus::Closed => PerpsError::PositionNotFound,
+            PositionStatus::Open => PerpsError::PositionAlreadyOpen,
+            PositionStatus::Liquidating => PerpsError::PositionBeingLiquidated,
+            PositionStatus::PendingSettlement => PerpsError::PositionPendingSettlement,
+        }.into())
+    }
+
+    /// Reject cross-margin positions on paths that settle margin per position
+    pub fn ensure_isolated(&self) -> Result<()> {
+        require!(self.margin_mode == MarginMode::Isolated, PerpsError::CrossMarginUnsupported);
+        Ok(())
+    }
+
+    /// Reject positions margined with pledged collateral on paths that only pay out quote
+    pub fn ensure_quote_margin(&self) -> Result<()> {
+        require!(!self.collateral_pledged, PerpsError::PledgedCollateralUnsupported);
+        Ok(())
     }
 
-    /// Calculate total equity (margin + unrealized PnL)
-    pub fn equity_fp(&self, current_price_fp: u128) -> i128 {
-        let margin_fp = (self.margin_deposited as i128) * FP as i128;
-        let unrealized_pnl = self.unrealized_pnl_fp(current_price_fp);
-        margin_fp + unrealized_pnl - self.funding_debt_fp
+    /// Margin this position keeps in its market's vault: cross margin sits
+    /// in the cross vault and pledged margin in its mint's vault
+    pub fn vault_margin(&self) -> u64 {
+        if self.margin_mode == MarginMode::Isolated && !self.collateral_pledged {
+            self.margin_deposited
+        } else {
+            0
+        }
     }
 
-    /// Check if position is liquidatable
-    pub fn is_liquidatable(&self, current_price_fp: u128, maintenance_margin_bps: u16) -> bool {
+    /// Unrealized PnL: `size * (current - entry) / FP`. The price difference
+    /// is taken first, in signed math, so the intermediate stays small instead
+    /// of subtracting two full notionals cast from `u128`.
+    pub fn unrealized_pnl_fp(&self, current_price_fp: u128) -> i128 {
         if self.base_size == 0 {
-            return false;
+            return 0;
         }
 
-        let equity = self.equity_fp(current_price_fp);
-        let notional_fp = (self.base_size.abs() as u128 * current_price_fp) / FP;
-        let maintenance_required_fp = (notional_fp * maintenance_margin_bps as u128) / 10_000;
+        let price_move_fp = current_price_fp as i128 - self.entry_price_fp as i128;
+        let directional_move_fp = if self.is_long { price_move_fp } else { -price_move_fp };
+        self.base_size.unsigned_abs() as i128 * directional_move_fp / FP as i128
+    }
+
+    /// Margin plus PnL at `current_price_fp`, less the funding owed, at price
+    /// precision like the notional (`size * price`) it is held against
+    pub fn equity_fp(&self, cfg: &Config, current_price_fp: u128) -> Result<i128> {
+        let price_move_fp = if self.is_long {
+            current_price_fp as i128 - self.entry_price_fp as i128
+        } else {
+            self.entry_price_fp as i128 - current_price_fp as i128
+        };
+        Ok(cfg.quote_to_fp(self.margin_deposited)? as i128
+            + self.base_size.unsigned_abs() as i128 * price_move_fp
+            - self.funding_debt_fp)
+    }
 
-        equity < maintenance_required_fp as i128
+    /// Whether equity has fallen below maintenance at `current_price_fp`
+    pub fn is_liquidatable(&self, cfg: &Config, current_price_fp: u128, maintenance_margin_bps: u16) -> Result<bool> {
+        if self.base_size == 0 {
+            return Ok(false);
+        }
+        let notional_fp = self.base_size.unsigned_abs() as u128 * current_price_fp;
+        let maintenance_required_fp = notional_fp * maintenance_margin_bps as u128 / 10_000;
+        Ok(self.equity_fp(cfg, current_price_fp)? < maintenance_required_fp as i128)
     }
 }
 
 #[account]
+#[derive(Default)]
 pub struct OraclePrice { 
     pub price_fp: u128,                 // Current price (fixed point)
     pub last_updated_ts: i64,           // Last update timestamp
@@ -259,7 +1142,91 @@ impl OraclePrice {
     }
 }
 
+/// One oracle update as kept in an `OracleTwap`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TwapSample {
+    pub price_fp: u128,                 // Oracle price as pushed (fixed point)
+    pub ts: i64,                        // When it was pushed
+}
+
+/// Recent prices of one `OraclePrice`, so risk checks can price at a
+/// time-weighted average that one manipulated update barely moves
+#[account]
+#[derive(Default)]
+pub struct OracleTwap {
+    pub oracle: Pubkey,                 // The OraclePrice these samples come from
+    pub samples: [TwapSample; TWAP_SAMPLES], // Ring of the latest updates
+    pub cursor: u8,                     // Next slot to overwrite in samples
+    pub count: u8,                      // Slots filled so far, up to TWAP_SAMPLES
+    pub bump: u8,                       // PDA bump seed
+}
+
+impl OracleTwap {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // oracle
+        24 * TWAP_SAMPLES + // samples
+        1 +  // cursor
+        1 +  // count
+        1 +  // bump
+        16;  // padding
+
+    fn latest(&self) -> Option<&TwapSample> {
+        (self.count > 0).then(|| &self.samples[(self.cursor as usize + TWAP_SAMPLES - 1) % TWAP_SAMPLES])
+    }
+
+    /// Remember an oracle update. A second update in the same second replaces
+    /// the first, since it held the price for no time.
+    pub fn record(&mut self, price_fp: u128, ts: i64) -> Result<()> {
+        if let Some(latest) = self.latest() {
+            require!(ts >= latest.ts, PerpsError::BadOracle);
+            if ts == latest.ts {
+                let slot = (self.cursor as usize + TWAP_SAMPLES - 1) % TWAP_SAMPLES;
+                self.samples[slot].price_fp = price_fp;
+                return Ok(());
+            }
+        }
+        let slot = self.cursor as usize % TWAP_SAMPLES;
+        self.samples[slot] = TwapSample { price_fp, ts };
+        self.cursor = ((slot + 1) % TWAP_SAMPLES) as u8;
+        self.count = (self.count + 1).min(TWAP_SAMPLES as u8);
+        Ok(())
+    }
+
+    /// When the latest sample was pushed (0 before any)
+    pub fn last_update_ts(&self) -> i64 {
+        self.latest().map_or(0, |sample| sample.ts)
+    }
+
+    /// Time-weighted average over the `window_seconds` before `now`, each
+    /// price counting for as long as it stood. A window reaching past the
+    /// oldest sample averages only the part the ring covers.
+    pub fn twap_fp(&self, now: i64, window_seconds: u32) -> Result<u128> {
+        let latest = *self.latest().ok_or(PerpsError::OracleFeedNotFound)?;
+        let start = now - window_seconds as i64;
+        let (mut weighted_fp, mut covered) = (0u128, 0u128);
+        let mut end = now;
+        for back in 1..=self.count as usize {
+            let sample = self.samples[(self.cursor as usize + TWAP_SAMPLES - back) % TWAP_SAMPLES];
+            let from = sample.ts.max(start);
+            if end > from {
+                let held = (end - from) as u128;
+                weighted_fp = weighted_fp
+                    .checked_add(sample.price_fp.checked_mul(held).ok_or(PerpsError::MathOverflow)?)
+                    .ok_or(PerpsError::MathOverflow)?;
+                covered += held;
+            }
+            if sample.ts <= start {
+                break;
+            }
+            end = sample.ts;
+        }
+        // Nothing has stood for any time yet: the latest price is all there is
+        Ok(weighted_fp.checked_div(covered).unwrap_or(latest.price_fp))
+    }
+}
+
 #[account]
+#[derive(Default)]
 pub struct StopLossOrder {
     pub owner: Pubkey,                  // Order owner
     pub market: Pubkey,                 // Market this order belongs to
@@ -270,6 +1237,7 @@ pub struct StopLossOrder {
     pub created_at: i64,                // Order creation timestamp
     pub executed_at: Option<i64>,       // Execution timestamp
     pub bump: u8,                       // PDA bump seed
+    pub expires_at: Option<i64>,        // No execution after this time (None = good till cancelled)
 }
 
 impl StopLossOrder {
@@ -283,7 +1251,8 @@ impl StopLossOrder {
         8 +  // created_at
         9 +  // executed_at (Option<i64>)
         1 +  // bump
-        16;  // padding
+        9 +  // expires_at (Option<i64>), in what was the padding
+        7;   // padding
 
     /// Generate PDA for stop-loss order
     pub fn find_pda(owner: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
@@ -292,17 +1261,907 @@ impl StopLossOrder {
             &crate::ID
         )
     }
+
+    /// (Re)arm the order for a position
+    #[allow(clippy::too_many_arguments)]
+    pub fn arm(
+        &mut self,
+        owner: Pubkey,
+        market: Pubkey,
+        position_key: Pubkey,
+        trigger_price_fp: u128,
+        close_percentage: u8,
+        now: i64,
+        expires_at: Option<i64>,
+        bump: u8,
+    ) {
+        self.owner = owner;
+        self.market = market;
+        self.position_key = position_key;
+        self.trigger_price_fp = trigger_price_fp;
+        self.close_percentage = close_percentage;
+        self.is_active = true;
+        self.created_at = now;
+        self.executed_at = None;
+        self.expires_at = expires_at;
+        self.bump = bump;
+    }
+
+    /// Whether the order has lapsed and may no longer execute
+    pub fn is_expired(&self, now: i64) -> bool {
+        order_expired(self.expires_at, now)
+    }
+
+    /// A long's stop triggers at or below its price, a short's at or above
+    pub fn is_triggered(&self, is_long: bool, mark_fp: u128) -> bool {
+        if is_long {
+            mark_fp <= self.trigger_price_fp
+        } else {
+            mark_fp >= self.trigger_price_fp
+        }
+    }
 }
 
 #[account]
-pub struct InsuranceFund {
-    pub total_deposits: u64,            // Total insurance fund deposits
-    pub total_claims: u64,              // Total claims paid out
-    pub vault_authority: Pubkey,        // Authority for the vault
-    pub vault_token_account: Pubkey,    // Token account holding funds
-    pub bump: u8,                       // PDA bump seed
+#[derive(Default)]
+pub struct TakeProfitOrder {
+    pub owner: Pubkey,                  // Order owner
+    pub market: Pubkey,                 // Market this order belongs to
+    pub position_key: Pubkey,           // Associated position account
+    pub trigger_price_fp: u128,         // Price at which to trigger
+    pub close_percentage: u8,           // Percentage to close (1-100)
+    pub is_active: bool,                // Whether order is active
+    pub created_at: i64,                // Order creation timestamp
+    pub executed_at: Option<i64>,       // Execution timestamp
+    pub bump: u8,                       // PDA bump seed
+    pub expires_at: Option<i64>,        // No execution after this time (None = good till cancelled)
+}
+
+impl TakeProfitOrder {
+    pub const SPACE: usize = StopLossOrder::SPACE; // same layout
+
+    /// Generate PDA for take-profit order
+    pub fn find_pda(owner: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[TAKE_PROFIT_SEED, owner.as_ref(), market.as_ref()],
+            &crate::ID
+        )
+    }
+
+    /// (Re)arm the order for a position
+    #[allow(clippy::too_many_arguments)]
+    pub fn arm(
+        &mut self,
+        owner: Pubkey,
+        market: Pubkey,
+        position_key: Pubkey,
+        trigger_price_fp: u128,
+        close_percentage: u8,
+        now: i64,
+        expires_at: Option<i64>,
+        bump: u8,
+    ) {
+        self.owner = owner;
+        self.market = market;
+        self.position_key = position_key;
+        self.trigger_price_fp = trigger_price_fp;
+        self.close_percentage = close_percentage;
+        self.is_active = true;
+        self.created_at = now;
+        self.executed_at = None;
+        self.expires_at = expires_at;
+        self.bump = bump;
+    }
+
+    /// Whether the order has lapsed and may no longer execute
+    pub fn is_expired(&self, now: i64) -> bool {
+        order_expired(self.expires_at, now)
+    }
+
+    /// A long takes profit at or above its price, a short at or below
+    pub fn is_triggered(&self, is_long: bool, mark_fp: u128) -> bool {
+        if is_long {
+            mark_fp >= self.trigger_price_fp
+        } else {
+            mark_fp <= self.trigger_price_fp
+        }
+    }
+}
+
+/// A stop that follows the price. `high_water_mark_fp` is the most favorable
+/// mark seen since the order was armed: the highest for a long, the lowest
+/// for a short. The trigger sits `trail_distance_bps` behind it.
+#[account]
+#[derive(Default)]
+pub struct TrailingStopOrder {
+    pub owner: Pubkey,                  // Order owner
+    pub market: Pubkey,                 // Market this order belongs to
+    pub position_key: Pubkey,           // Associated position account
+    pub trail_distance_bps: u16,        // Distance of the trigger behind the extreme
+    pub high_water_mark_fp: u128,       // Most favorable mark seen
+    pub close_percentage: u8,           // Percentage to close (1-100)
+    pub is_active: bool,                // Whether order is active
+    pub created_at: i64,                // Order creation timestamp
+    pub executed_at: Option<i64>,       // Execution timestamp
+    pub bump: u8,                       // PDA bump seed
+}
+
+impl TrailingStopOrder {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // owner
+        32 + // market
+        32 + // position_key
+        2 +  // trail_distance_bps
+        16 + // high_water_mark_fp
+        1 +  // close_percentage
+        1 +  // is_active
+        8 +  // created_at
+        9 +  // executed_at (Option<i64>)
+        1 +  // bump
+        16;  // padding
+
+    /// Generate PDA for trailing stop order
+    pub fn find_pda(owner: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[TRAILING_STOP_SEED, owner.as_ref(), market.as_ref()],
+            &crate::ID
+        )
+    }
+
+    /// (Re)arm the order for a position, trailing from the current mark
+    #[allow(clippy::too_many_arguments)]
+    pub fn arm(
+        &mut self,
+        owner: Pubkey,
+        market: Pubkey,
+        position_key: Pubkey,
+        trail_distance_bps: u16,
+        mark_fp: u128,
+        close_percentage: u8,
+        now: i64,
+        bump: u8,
+    ) {
+        self.owner = owner;
+        self.market = market;
+        self.position_key = position_key;
+        self.trail_distance_bps = trail_distance_bps;
+        self.high_water_mark_fp = mark_fp;
+        self.close_percentage = close_percentage;
+        self.is_active = true;
+        self.created_at = now;
+        self.executed_at = None;
+        self.bump = bump;
+    }
+
+    /// Move the high-water mark to `mark_fp` if it is a new favorable extreme.
+    /// It only ever ratchets: up for a long, down for a short.
+    pub fn ratchet(&mut self, is_long: bool, mark_fp: u128) -> bool {
+        let improved = if is_long {
+            mark_fp > self.high_water_mark_fp
+        } else {
+            mark_fp < self.high_water_mark_fp
+        };
+        if improved {
+            self.high_water_mark_fp = mark_fp;
+        }
+        improved
+    }
+
+    /// Effective stop price, `trail_distance_bps` behind the high-water mark
+    pub fn trigger_price_fp(&self, is_long: bool) -> u128 {
+        let bps = self.trail_distance_bps as u128;
+        if is_long {
+            self.high_water_mark_fp * (10_000 - bps) / 10_000
+        } else {
+            self.high_water_mark_fp * (10_000 + bps) / 10_000
+        }
+    }
+
+    /// A long's trailing stop fires once the mark retraces to or below the
+    /// trigger, a short's once it rises to or above it
+    pub fn is_triggered(&self, is_long: bool, mark_fp: u128) -> bool {
+        let trigger_fp = self.trigger_price_fp(is_long);
+        if is_long {
+            mark_fp <= trigger_fp
+        } else {
+            mark_fp >= trigger_fp
+        }
+    }
+}
+
+/// A resting entry order. The margin is escrowed in the market's vault when it
+/// is placed and becomes the position's margin when a keeper fills it.
+#[account]
+#[derive(Default)]
+pub struct LimitOrder {
+    pub owner: Pubkey,                  // Order owner
+    pub market: Pubkey,                 // Market the position opens in
+    pub is_long: bool,                  // Side of the position
+    pub limit_price_fp: u128,           // Worst mark the order fills at
+    pub quote_to_spend: u64,            // Requested spend, as for open_position
+    pub leverage_x: u16,                // Requested leverage
+    pub margin_escrowed: u64,           // Quote tokens held in the vault for this order
+    pub is_active: bool,                // Whether the order is resting
+    pub created_at: i64,                // Order creation timestamp
+    pub bump: u8,                       // PDA bump seed
+    pub expires_at: Option<i64>,        // No fill after this time (None = good till cancelled)
+}
+
+impl LimitOrder {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // owner
+        32 + // market
+        1 +  // is_long
+        16 + // limit_price_fp
+        8 +  // quote_to_spend
+        2 +  // leverage_x
+        8 +  // margin_escrowed
+        1 +  // is_active
+        8 +  // created_at
+        1 +  // bump
+        9 +  // expires_at (Option<i64>), in what was the padding
+        23;  // padding
+
+    /// Generate PDA for a user's limit order in a market
+    pub fn find_pda(owner: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[LIMIT_ORDER_SEED, owner.as_ref(), market.as_ref()],
+            &crate::ID
+        )
+    }
+
+    /// A long fills at or below its limit, a short at or above
+    pub fn is_fillable(&self, mark_fp: u128) -> bool {
+        if self.is_long {
+            mark_fp <= self.limit_price_fp
+        } else {
+            mark_fp >= self.limit_price_fp
+        }
+    }
+
+    /// Whether the order has lapsed and may no longer fill
+    pub fn is_expired(&self, now: i64) -> bool {
+        order_expired(self.expires_at, now)
+    }
+}
+
+/// An order with `expires_at` set is live up to and including that second
+pub fn order_expired(expires_at: Option<i64>, now: i64) -> bool {
+    expires_at.is_some_and(|expires_at| now > expires_at)
+}
+
+/// An expiry given when an order is placed must still be ahead of `now`
+pub fn validate_order_expiry(expires_at: Option<i64>, now: i64) -> Result<()> {
+    require!(expires_at.is_none_or(|expires_at| expires_at > now), PerpsError::InvalidOrderExpiry);
+    Ok(())
+}
+
+/// Resting orders a user has across all markets, so keepers only ever scan a
+/// bounded set
+#[account]
+#[derive(Default)]
+pub struct UserOrders {
+    pub owner: Pubkey,                  // Order owner
+    pub active_orders: u32,             // Armed stop-loss, take-profit and limit orders
+    pub bump: u8,                       // PDA bump seed
+}
+
+impl UserOrders {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // owner
+        4 +  // active_orders
+        1 +  // bump
+        16;  // padding
+
+    /// Generate PDA for a user's order counter
+    pub fn find_pda(owner: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[USER_ORDERS_SEED, owner.as_ref()],
+            &crate::ID
+        )
+    }
+
+    /// Take a slot for a newly armed order, refusing it once `max_active`
+    /// orders are resting (0 = no cap)
+    pub fn add_order(&mut self, max_active: u32) -> Result<()> {
+        require!(
+            max_active == 0 || self.active_orders < max_active,
+            PerpsError::ExceedsPositionLimits
+        );
+        self.active_orders = self.active_orders
+            .checked_add(1)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Free the slot of an executed or cancelled order
+    pub fn remove_order(&mut self) {
+        self.active_orders = self.active_orders.saturating_sub(1);
+    }
+}
+
+/// Whitelist entry for a market maker: closes by `owner` pay no fee and earn
+/// `Config::mm_rebate_bps` out of the fee pool instead
+#[account]
+#[derive(Default)]
+pub struct MarketMaker {
+    pub owner: Pubkey,                  // Whitelisted wallet
+    pub total_rebates_paid: u64,        // Rebates this maker has received (quote tokens)
+    pub bump: u8,                       // PDA bump seed
+}
+
+impl MarketMaker {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // owner
+        8 +  // total_rebates_paid
+        1 +  // bump
+        16;  // padding
+
+    /// Generate PDA for a market maker's whitelist entry
+    pub fn find_pda(owner: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[MARKET_MAKER_SEED, owner.as_ref()],
+            &crate::ID
+        )
+    }
+}
+
+/// Protocol-wide counters. `active_positions` is held under
+/// `Config::max_total_positions` to bound the state the program has to carry.
+#[account]
+#[derive(Default)]
+pub struct ProtocolStats {
+    pub active_positions: u32,          // Open positions across every market
+    pub bump: u8,                       // PDA bump seed
+    // Totals across every market, carved out of the padding
+    pub total_volume: u128,             // Notional opened (quote tokens), as Market::total_volume
+    pub total_fees_collected: u64,      // Trading and liquidation fees kept by the protocol (quote tokens)
+    pub total_liquidations: u32,        // Liquidations, partial ones included
+}
+
+impl ProtocolStats {
+    pub const SPACE: usize = 8 + // discriminator
+        4 +  // active_positions
+        1 +  // bump
+        16 + // total_volume
+        8 +  // total_fees_collected
+        4 +  // total_liquidations
+        36;  // padding
+
+    /// Generate PDA for the protocol stats
+    pub fn find_pda() -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[PROTOCOL_STATS_SEED],
+            &crate::ID
+        )
+    }
+
+    /// Count a freshly opened position, refusing one past `max_total_positions`
+    pub fn record_open(&mut self, max_total_positions: u32) -> Result<()> {
+        require!(self.active_positions < max_total_positions, PerpsError::ExceedsRiskLimits);
+        self.active_positions += 1;
+        Ok(())
+    }
+
+    /// Free the slot of a position that was closed or liquidated in full
+    pub fn record_close(&mut self) {
+        self.active_positions = self.active_positions.saturating_sub(1);
+    }
+
+    /// Count an open's notional and the entry fee it paid
+    pub fn record_volume(&mut self, notional: u64, fee: u64) -> Result<()> {
+        self.total_volume = self.total_volume
+            .checked_add(notional as u128)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.record_fees(fee)
+    }
+
+    /// Count fees the protocol kept
+    pub fn record_fees(&mut self, fee: u64) -> Result<()> {
+        self.total_fees_collected = self.total_fees_collected
+            .checked_add(fee)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Count a liquidation and the protocol's share of its fee
+    pub fn record_liquidation(&mut self, protocol_fee: u64) -> Result<()> {
+        self.total_liquidations = self.total_liquidations
+            .checked_add(1)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.record_fees(protocol_fee)
+    }
+
+    /// The `ProtocolStatsUpdated` indexers follow, emitted after each change
+    pub fn updated_event(&self) -> ProtocolStatsUpdated {
+        ProtocolStatsUpdated {
+            total_volume: self.total_volume,
+            total_fees_collected: self.total_fees_collected,
+            active_positions: self.active_positions,
+            total_liquidations: self.total_liquidations,
+        }
+    }
+}
+
+/// Per-user counters across all markets. Positions are one PDA per user per
+/// market, so `open_position_count` is what holds a user under
+/// `Config::max_positions_per_user`.
+#[account]
+#[derive(Default)]
+pub struct UserAccount {
+    pub owner: Pubkey,                  // Account owner
+    pub open_position_count: u32,       // Positions open across every market
+    pub bump: u8,                       // PDA bump seed
+}
+
+impl UserAccount {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // owner
+        4 +  // open_position_count
+        1 +  // bump
+        32;  // padding
+
+    /// Generate PDA for a user's account
+    pub fn find_pda(owner: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[USER_ACCOUNT_SEED, owner.as_ref()],
+            &crate::ID
+        )
+    }
+
+    /// Count a freshly opened position, refusing one past `max_positions_per_user`
+    pub fn record_open(&mut self, max_positions_per_user: u32) -> Result<()> {
+        require!(self.open_position_count < max_positions_per_user, PerpsError::ExceedsPositionLimits);
+        self.open_position_count += 1;
+        Ok(())
+    }
+
+    /// Free the slot of a position that was closed or liquidated in full
+    pub fn record_close(&mut self) {
+        self.open_position_count = self.open_position_count.saturating_sub(1);
+    }
+}
+
+/// Collateral a user pools across all their cross-margin positions. The
+/// tokens sit in the protocol's cross vault; open cross positions each hold
+/// an allocation of it as their initial margin.
+#[account]
+#[derive(Default)]
+pub struct CrossMarginAccount {
+    pub owner: Pubkey,                  // Account owner
+    pub collateral_balance: u64,        // Quote tokens held for the owner in the cross vault
+    pub margin_in_use: u64,             // Initial margin allocated to open cross positions
+    pub open_positions: u32,            // Cross positions currently open
+    pub bump: u8,                       // PDA bump seed
+}
+
+/// One open cross position, priced for its account's health check
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CrossLeg {
+    pub size: u64,
+    pub is_long: bool,
+    pub entry_price_fp: u128,
+    pub mark_fp: u128,
+    pub funding_debt_fp: i128,
+    pub maintenance_margin_bps: u16,
+}
+
+impl CrossLeg {
+    pub fn new(up: &UserPosition, mark_fp: u128, maintenance_margin_bps: u16) -> Self {
+        Self {
+            size: up.base_size.unsigned_abs(),
+            is_long: up.is_long,
+            entry_price_fp: up.entry_price_fp,
+            mark_fp,
+            funding_debt_fp: up.funding_debt_fp,
+            maintenance_margin_bps,
+        }
+    }
+
+    /// PnL at the mark net of funding owed, on the same scale as a close
+    pub fn pnl_fp(&self) -> i128 {
+        let price_move_fp = self.mark_fp as i128 - self.entry_price_fp as i128;
+        let directional_move_fp = if self.is_long { price_move_fp } else { -price_move_fp };
+        self.size as i128 * directional_move_fp - self.funding_debt_fp
+    }
+
+    pub fn maintenance_fp(&self) -> u128 {
+        self.size as u128 * self.mark_fp * self.maintenance_margin_bps as u128 / 10_000
+    }
+}
+
+impl CrossMarginAccount {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // owner
+        8 +  // collateral_balance
+        8 +  // margin_in_use
+        4 +  // open_positions
+        1 +  // bump
+        32;  // padding
+
+    /// Generate PDA for a user's cross margin account
+    pub fn find_pda(owner: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[CROSS_MARGIN_SEED, owner.as_ref()],
+            &crate::ID
+        )
+    }
+
+    /// Collateral not allocated to an open position
+    pub fn free_collateral(&self) -> u64 {
+        self.collateral_balance.saturating_sub(self.margin_in_use)
+    }
+
+    /// Allocate initial margin to a new cross position
+    pub fn allocate(&mut self, margin: u64) -> Result<()> {
+        require!(margin <= self.free_collateral(), PerpsError::InsufficientMargin);
+        self.margin_in_use += margin;
+        self.open_positions = self.open_positions.checked_add(1).ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Release a closed or liquidated position's allocation. Its PnL, net of
+    /// fees, has already been folded into `balance_after`.
+    pub fn release(&mut self, margin: u64, balance_after: u64) {
+        self.margin_in_use = self.margin_in_use.saturating_sub(margin);
+        self.open_positions = self.open_positions.saturating_sub(1);
+        self.collateral_balance = balance_after;
+    }
+
+    /// Pooled collateral plus PnL across every open cross position
+    pub fn equity_fp(&self, cfg: &Config, legs: &[CrossLeg]) -> Result<i128> {
+        let pnl_fp: i128 = legs.iter().map(CrossLeg::pnl_fp).sum();
+        Ok(cfg.quote_to_fp(self.collateral_balance)? as i128 + pnl_fp)
+    }
+
+    /// Maintenance summed over every open cross position
+    pub fn maintenance_required_fp(legs: &[CrossLeg]) -> u128 {
+        legs.iter().map(CrossLeg::maintenance_fp).sum()
+    }
+
+    /// Whether the account as a whole is below maintenance. A leg in profit
+    /// props up the others, so no position is judged on its own.
+    pub fn is_liquidatable(&self, cfg: &Config, legs: &[CrossLeg]) -> Result<bool> {
+        if legs.is_empty() {
+            return Ok(false);
+        }
+        Ok(self.equity_fp(cfg, legs)? < Self::maintenance_required_fp(legs) as i128)
+    }
+}
+
+/// Quote tokens a user holds in a market's vault without a position behind
+/// them. Opens can draw their margin from it and closes can settle back into
+/// it, so a trader moves tokens once instead of on every trade. Kept per
+/// market because each market's collateral stays in its own vault.
+#[account]
+#[derive(Default)]
+pub struct CollateralAccount {
+    pub owner: Pubkey,                  // Depositor
+    pub market: Pubkey,                 // Market whose vault holds the balance
+    pub balance: u64,                   // Unallocated quote tokens
+    pub bump: u8,                       // PDA bump seed
+}
+
+impl CollateralAccount {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // owner
+        32 + // market
+        8 +  // balance
+        1 +  // bump
+        16;  // padding
+
+    /// Generate PDA for a user's collateral in a market
+    pub fn find_pda(owner: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[COLLATERAL_SEED, owner.as_ref(), market.as_ref()],
+            &crate::ID
+        )
+    }
+
+    pub fn credit(&mut self, amount: u64) -> Result<()> {
+        self.balance = self.balance.checked_add(amount).ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn debit(&mut self, amount: u64) -> Result<()> {
+        self.balance = self.balance.checked_sub(amount).ok_or(PerpsError::InsufficientFunds)?;
+        Ok(())
+    }
+}
+
+/// A non-quote token whitelisted as isolated margin. Pledged tokens sit in the
+/// mint's collateral vault and count for `amount * price * collateral_factor_bps`
+/// of margin, valued when they are pledged.
+#[account]
+#[derive(Default)]
+pub struct AcceptedCollateral {
+    pub mint: Pubkey,                   // Accepted token
+    pub oracle: Pubkey,                 // OraclePrice quoting one whole token in quote
+    pub collateral_factor_bps: u16,     // Share of market value counted as margin
+    pub decimals: u8,                   // Mint decimals
+    pub is_enabled: bool,               // New pledges refused while false; existing ones still settle
+    pub total_pledged: u64,             // Tokens backing open positions
+    pub total_seized: u64,              // Tokens kept from losing positions, awaiting a sweep
+    pub vault_bump: u8,                 // Bump of the mint's collateral vault PDA
+    pub bump: u8,                       // PDA bump seed
+}
+
+impl AcceptedCollateral {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // mint
+        32 + // oracle
+        2 +  // collateral_factor_bps
+        1 +  // decimals
+        1 +  // is_enabled
+        8 +  // total_pledged
+        8 +  // total_seized
+        1 +  // vault_bump
+        1 +  // bump
+        32;  // padding
+
+    /// Generate PDA for an accepted collateral mint
+    pub fn find_pda(mint: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[ACCEPTED_COLLATERAL_SEED, mint.as_ref()],
+            &crate::ID
+        )
+    }
+
+    fn token_unit(&self) -> Result<u128> {
+        Ok(10u128.checked_pow(self.decimals as u32).ok_or(PerpsError::MathOverflow)?)
+    }
+
+    /// Margin, in quote tokens, that `amount` tokens count for at `price_fp`
+    /// per whole token: market value less the haircut, rounded down
+    pub fn margin_value(&self, cfg: &Config, amount: u64, price_fp: u128) -> Result<u64> {
+        let value_fp = (amount as u128)
+            .checked_mul(price_fp)
+            .and_then(|v| v.checked_mul(self.collateral_factor_bps as u128))
+            .ok_or(PerpsError::MathOverflow)?
+            / (self.token_unit()? * 10_000);
+        cfg.fp_to_quote(value_fp)
+    }
+
+    /// Fewest tokens that count for at least `margin` quote at `price_fp`
+    pub fn tokens_for_margin(&self, cfg: &Config, margin: u64, price_fp: u128) -> Result<u64> {
+        let per_token_fp = price_fp * self.collateral_factor_bps as u128;
+        require!(per_token_fp > 0, PerpsError::CollateralNotAccepted);
+        let needed = cfg.quote_to_fp(margin)?
+            .checked_mul(self.token_unit()? * 10_000)
+            .ok_or(PerpsError::MathOverflow)?
+            .div_ceil(per_token_fp);
+        Ok(u64::try_from(needed).map_err(|_| PerpsError::MathOverflow)?)
+    }
+}
+
+/// Collateral pledged as one isolated position's margin. The position's
+/// `margin_deposited` is what the tokens were worth when pledged.
+#[account]
+#[derive(Default)]
+pub struct PositionCollateral {
+    pub position: Pubkey,               // Position the tokens back
+    pub mint: Pubkey,                   // Pledged mint
+    pub amount: u64,                    // Tokens held in the mint's collateral vault
+    pub bump: u8,                       // PDA bump seed
+}
+
+/// Where a pledge goes once its position settles
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PledgeSettlement {
+    pub quote: u64,                     // Payout beyond the pledge's margin, paid from the quote vault
+    pub returned: u64,                  // Tokens handed back to the owner
+    pub seized: u64,                    // Tokens kept by the protocol for the loss
+}
+
+impl PositionCollateral {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // position
+        32 + // mint
+        8 +  // amount
+        1 +  // bump
+        16;  // padding
+
+    /// Generate PDA for the collateral behind a position
+    pub fn find_pda(position: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[POSITION_COLLATERAL_SEED, position.as_ref()],
+            &crate::ID
+        )
+    }
+
+    /// Split the pledge once its position settles for `payout` quote against
+    /// the `margin` it counted for. A payout above the margin hands every
+    /// token back with the gain in quote; below it the tokens come back in
+    /// proportion to what is left and the rest cover the loss.
+    pub fn settle(&self, margin: u64, payout: u64) -> PledgeSettlement {
+        if payout >= margin {
+            return PledgeSettlement { quote: payout - margin, returned: self.amount, seized: 0 };
+        }
+        let returned = (self.amount as u128 * payout as u128 / margin as u128) as u64;
+        PledgeSettlement { quote: 0, returned, seized: self.amount - returned }
+    }
+
+    /// Tokens freed when `removed` of the position's `margin` is withdrawn, rounded down
+    pub fn release_share(&self, margin: u64, removed: u64) -> u64 {
+        if margin == 0 {
+            return 0;
+        }
+        (self.amount as u128 * removed.min(margin) as u128 / margin as u128) as u64
+    }
+}
+
+/// Quote tokens a user has taken out of the vaults in the current
+/// `WITHDRAWAL_RATE_LIMIT_WINDOW_SECONDS` window, checked against
+/// `Config::withdrawal_rate_limit` while it is enabled
+#[account]
+#[derive(Default)]
+pub struct UserRateLimit {
+    pub owner: Pubkey,                  // Throttled user
+    pub window_start_ts: i64,           // Start of the current window
+    pub window_outflow: u64,            // Paid out to the user since window_start_ts
+    pub bump: u8,                       // PDA bump seed
+}
+
+impl UserRateLimit {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // owner
+        8 +  // window_start_ts
+        8 +  // window_outflow
+        1 +  // bump
+        16;  // padding
+
+    /// Generate PDA for a user's outflow window
+    pub fn find_pda(owner: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[USER_RATE_LIMIT_SEED, owner.as_ref()],
+            &crate::ID
+        )
+    }
+
+    /// Count `amount` against the window, starting a fresh window once the
+    /// current one has run out. Refuses outflow past `limit` (0 = disabled).
+    pub fn record_outflow(&mut self, amount: u64, limit: u64, now: i64) -> Result<()> {
+        if limit == 0 {
+            return Ok(());
+        }
+        if now >= self.window_start_ts.saturating_add(WITHDRAWAL_RATE_LIMIT_WINDOW_SECONDS) {
+            self.window_start_ts = now;
+            self.window_outflow = 0;
+        }
+        let outflow = self.window_outflow
+            .checked_add(amount)
+            .ok_or(PerpsError::MathOverflow)?;
+        require!(outflow <= limit, PerpsError::ExceedsRiskLimits);
+        self.window_outflow = outflow;
+        Ok(())
+    }
+}
+
+/// One queued payout in a `PendingWithdrawal`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WithdrawalTranche {
+    pub amount: u64,                    // Still owed from this payout
+    pub available_at: i64,              // Earliest claim timestamp
+}
+
+#[account]
+#[derive(Default)]
+pub struct PendingWithdrawal {
+    pub owner: Pubkey,                  // Recipient of the queued payout
+    pub market: Pubkey,                 // Market the payout was realized in
+    pub amount: u64,                    // Total amount queued
+    pub claimed_amount: u64,            // Amount already paid out
+    pub available_at: i64,              // Earliest claim timestamp of any tranche
+    pub created_at: i64,                // Last enqueue timestamp
+    pub bump: u8,                       // PDA bump seed
+    pub tranches: [WithdrawalTranche; MAX_WITHDRAWAL_TRANCHES], // Unpaid payouts, oldest first, empty slots at the end
+}
+
+impl PendingWithdrawal {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // owner
+        32 + // market
+        8 +  // amount
+        8 +  // claimed_amount
+        8 +  // available_at
+        8 +  // created_at
+        1 +  // bump
+        16 * MAX_WITHDRAWAL_TRANCHES + // tranches
+        16;  // padding
+
+    /// Generate PDA for a user's pending withdrawal in a market
+    pub fn find_pda(owner: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[PENDING_WITHDRAWAL_SEED, owner.as_ref(), market.as_ref()],
+            &crate::ID
+        )
+    }
+
+    /// Amount still owed to the owner
+    pub fn remaining(&self) -> u64 {
+        self.amount.saturating_sub(self.claimed_amount)
+    }
+
+    /// Queue another payout on its own delay; earlier tranches keep theirs.
+    /// Once every slot is taken the payout joins the newest tranche, which
+    /// then waits for whichever of the two releases later.
+    pub fn enqueue(&mut self, amount: u64, now: i64, delay_seconds: i64) -> Result<()> {
+        let available_at = now.checked_add(delay_seconds).ok_or(PerpsError::MathOverflow)?;
+        let outstanding = self.remaining();
+        self.amount = outstanding.checked_add(amount).ok_or(PerpsError::MathOverflow)?;
+        self.claimed_amount = 0;
+        self.created_at = now;
+
+        let slot = match self.tranches.iter().position(|t| t.amount == 0) {
+            Some(free) => {
+                self.tranches[free].available_at = available_at;
+                free
+            }
+            None => {
+                let newest = MAX_WITHDRAWAL_TRANCHES - 1;
+                let tranche = &mut self.tranches[newest];
+                tranche.available_at = tranche.available_at.max(available_at);
+                newest
+            }
+        };
+        let tranche = &mut self.tranches[slot];
+        tranche.amount = tranche.amount.checked_add(amount).ok_or(PerpsError::MathOverflow)?;
+        self.refresh_available_at();
+        Ok(())
+    }
+
+    /// Queued amount whose delay has elapsed
+    pub fn matured(&self, now: i64) -> u64 {
+        self.tranches
+            .iter()
+            .filter(|t| t.amount > 0 && now >= t.available_at)
+            .fold(0u64, |sum, t| sum.saturating_add(t.amount))
+    }
+
+    /// Tranche that can be paid right now out of the vault's free balance,
+    /// i.e. what it holds beyond everything else booked in it
+    pub fn claimable(&self, now: i64, free_balance: u64) -> u64 {
+        self.matured(now).min(free_balance)
+    }
+
+    /// Record a payout, drawing down matured tranches oldest first
+    pub fn claim(&mut self, amount: u64, now: i64) -> Result<()> {
+        require!(amount <= self.matured(now), PerpsError::WithdrawalNotReady);
+        let mut left = amount;
+        for tranche in self.tranches.iter_mut().filter(|t| now >= t.available_at) {
+            let paid = tranche.amount.min(left);
+            tranche.amount -= paid;
+            left -= paid;
+        }
+        // Keep unpaid tranches packed at the front in queue order
+        let mut packed = [WithdrawalTranche::default(); MAX_WITHDRAWAL_TRANCHES];
+        for (slot, tranche) in packed.iter_mut().zip(self.tranches.iter().filter(|t| t.amount > 0)) {
+            *slot = *tranche;
+        }
+        self.tranches = packed;
+        self.claimed_amount = self.claimed_amount.checked_add(amount).ok_or(PerpsError::MathOverflow)?;
+        self.refresh_available_at();
+        Ok(())
+    }
+
+    fn refresh_available_at(&mut self) {
+        if let Some(earliest) = self.tranches.iter().filter(|t| t.amount > 0).map(|t| t.available_at).min() {
+            self.available_at = earliest;
+        }
+    }
 }
 
+#[account]
+#[derive(Default)]
+pub struct InsuranceFund {
+    pub total_deposits: u64,            // Total insurance fund deposits
+    pub total_claims: u64,              // Total claims paid out
+    pub vault_authority: Pubkey,        // Authority for the vault
+    pub vault_token_account: Pubkey,    // Token account holding funds
+    pub bump: u8,                       // PDA bump seed
+    pub market: Pubkey,                 // Market this fund backs (default = global backstop)
+    pub uncovered_bad_debt: u64,        // Deficits no fund could cover, awaiting auto-deleveraging
+}
 impl InsuranceFund {
     pub const SPACE: usize = 8 + // discriminator
         8 +  // total_deposits
@@ -310,16 +2169,62 @@ impl InsuranceFund {
         32 + // vault_authority
         32 + // vault_token_account
         1 +  // bump
-        16;  // padding
+        32 + // market
+        8 +  // uncovered_bad_debt
+        8;   // padding
 
-    /// Generate PDA for insurance fund
+    /// Generate PDA for the global (second-loss) insurance fund
     pub fn find_pda() -> (Pubkey, u8) {
         Pubkey::find_program_address(
-            &[b"insurance_fund"],
+            &[INSURANCE_FUND_SEED],
+            &crate::ID
+        )
+    }
+
+    /// Generate PDA for a market's own (first-loss) insurance fund
+    pub fn find_market_pda(market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[INSURANCE_FUND_SEED, market.as_ref()],
             &crate::ID
         )
     }
 
+    pub fn is_global(&self) -> bool {
+        self.market == Pubkey::default()
+    }
+
+    /// Balance still available to cover deficits
+    pub fn available(&self) -> u64 {
+        self.total_deposits.saturating_sub(self.total_claims)
+    }
+
+    /// Record an inflow (deposit or liquidation surplus)
+    pub fn record_deposit(&mut self, amount: u64) -> Result<()> {
+        self.total_deposits = self.total_deposits.checked_add(amount)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Cover as much of `deficit` as the fund holds; returns the amount covered
+    pub fn absorb_deficit(&mut self, deficit: u64) -> u64 {
+        let covered = deficit.min(self.available());
+        self.total_claims += covered;
+        covered
+    }
+
+    /// Book a liquidation deficit that neither this fund nor the backstop covered
+    pub fn record_bad_debt(&mut self, amount: u64) -> Result<()> {
+        self.uncovered_bad_debt = self.uncovered_bad_debt.checked_add(amount)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Bad debt the fund's balance falls short of: what auto-deleveraging
+    /// has to take out of profitable positions
+    pub fn adl_shortfall(&self) -> u64 {
+        self.uncovered_bad_debt.saturating_sub(self.available())
+    }
+
     /// Calculate current fund ratio (deposits / claims)
     pub fn fund_ratio(&self) -> u64 {
         if self.total_claims == 0 {
@@ -332,4 +2237,1054 @@ impl InsuranceFund {
     pub fn is_healthy(&self) -> bool {
         self.fund_ratio() > 15_000 // 150%
     }
+
+    /// Slice of a protocol fee owed to this fund: `fee_bps` of it while the
+    /// fund ratio is under `target_ratio_bps`, capped so deposits land on the
+    /// target. A fund that has never paid a claim has no ratio to measure yet,
+    /// so it takes the full slice until the first claim sets one.
+    pub fn fee_contribution(&self, fee: u64, fee_bps: u16, target_ratio_bps: u32) -> u64 {
+        let slice = fee as u128 * fee_bps as u128 / 10_000;
+        if self.total_claims == 0 {
+            return slice as u64;
+        }
+        if self.fund_ratio() >= target_ratio_bps as u64 {
+            return 0;
+        }
+        let target_deposits = self.total_claims as u128 * target_ratio_bps as u128 / 10_000;
+        let headroom = target_deposits.saturating_sub(self.total_deposits as u128);
+        slice.min(headroom) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_handover_waits_for_the_proposed_key() {
+        let (admin, successor, stranger) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let mut cfg = Config { admin, ..Default::default() };
+
+        // Nothing to accept before a proposal
+        assert_eq!(cfg.accept_admin(successor).unwrap_err(), PerpsError::NoPendingAdmin.into());
+
+        cfg.propose_admin(successor).unwrap();
+        assert_eq!((cfg.admin, cfg.pending_admin), (admin, Some(successor)));
+        // Only the proposed key can take over
+        assert_eq!(cfg.accept_admin(stranger).unwrap_err(), PerpsError::UnauthorizedAccess.into());
+
+        assert_eq!(cfg.accept_admin(successor).unwrap(), admin);
+        assert_eq!((cfg.admin, cfg.pending_admin), (successor, None));
+        assert!(cfg.accept_admin(successor).is_err());
+        assert!(cfg.propose_admin(Pubkey::default()).is_err());
+    }
+
+    #[test]
+    fn test_orders_lapse_after_their_expiry() {
+        let mut stop = StopLossOrder::default();
+        stop.arm(Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique(), 90 * FP, 100, 1_000, Some(2_000), 255);
+        // Live through the expiry second itself, lapsed after it
+        assert!(!stop.is_expired(2_000));
+        assert!(stop.is_expired(2_001));
+        // Re-arming without an expiry makes it good till cancelled
+        stop.arm(stop.owner, stop.market, stop.position_key, 90 * FP, 100, 3_000, None, 255);
+        assert!(!stop.is_expired(i64::MAX));
+
+        let limit = LimitOrder { expires_at: Some(2_000), ..Default::default() };
+        assert!(limit.is_expired(2_001));
+        assert!(!LimitOrder::default().is_expired(i64::MAX));
+
+        // An expiry has to be ahead of the clock when the order is placed
+        assert!(validate_order_expiry(None, 1_000).is_ok());
+        assert!(validate_order_expiry(Some(1_001), 1_000).is_ok());
+        assert_eq!(validate_order_expiry(Some(1_000), 1_000).unwrap_err(), PerpsError::InvalidOrderExpiry.into());
+        // The expiry fits in the old padding; existing accounts read its zeroes as None
+        let full = StopLossOrder { executed_at: Some(0), expires_at: Some(0), ..Default::default() };
+        assert_eq!(8 + full.try_to_vec().unwrap().len() + 7, StopLossOrder::SPACE);
+    }
+
+    #[test]
+    fn test_fee_contributions_stop_once_the_fund_is_at_target() {
+        // Paid $1,000 of claims out of $1,200 deposited: 120% against a 150% target
+        let mut fund = InsuranceFund { total_deposits: 1_200_000_000, total_claims: 1_000_000_000, ..Default::default() };
+        assert_eq!(fund.fund_ratio(), 12_000);
+
+        // 20% of each $1,000 fee until the last $100 of headroom is filled
+        let mut routed = Vec::new();
+        for _ in 0..3 {
+            let amount = fund.fee_contribution(1_000_000_000, 2_000, 15_000);
+            fund.record_deposit(amount).unwrap();
+            routed.push(amount);
+        }
+        assert_eq!(routed, [200_000_000, 100_000_000, 0]);
+        assert_eq!(fund.fund_ratio(), 15_000);
+
+        // A claim reopens headroom; with routing off nothing is taken
+        fund.absorb_deficit(100_000_000);
+        assert_eq!(fund.fee_contribution(1_000_000_000, 2_000, 15_000), 150_000_000);
+        assert_eq!(fund.fee_contribution(1_000_000_000, 0, 15_000), 0);
+
+        // A fresh fund has paid no claims, so it is below any target and takes the full slice
+        let mut fresh = InsuranceFund::default();
+        assert_eq!(fresh.fee_contribution(1_000_000_000, 2_000, 15_000), 200_000_000);
+        fresh.record_deposit(200_000_000).unwrap();
+        assert_eq!(fresh.fee_contribution(1_000_000_000, 2_000, 15_000), 200_000_000);
+    }
+
+    #[test]
+    fn test_one_sided_skew_ratio_does_not_overflow() {
+        let mut market = Market { total_long_size: u64::MAX, ..Default::default() };
+        assert_eq!(market.skew_ratio(), u32::MAX);
+        assert!(market.is_one_sided());
+        assert!(!market.is_balanced());
+
+        market.total_short_size = 1;
+        assert_eq!(market.skew_ratio(), u32::MAX);
+        assert!(!market.is_one_sided());
+
+        market.total_long_size = 1_000_000;
+        market.total_short_size = 1_000_000;
+        assert_eq!(market.skew_ratio(), 10_000);
+        assert!(market.is_balanced());
+
+        let empty = Market::default();
+        assert_eq!(empty.skew_ratio(), 10_000);
+        assert!(!empty.is_one_sided());
+    }
+
+    #[test]
+    fn test_open_interest_caps_hold_each_side_separately() {
+        let mut market = Market { max_long_oi: 1_000, max_short_oi: 500, ..Default::default() };
+        market.increase_open_interest(true, 1_000).unwrap();
+        assert!(market.increase_open_interest(true, 1).is_err());
+        assert_eq!(market.total_long_size, 1_000);
+
+        // The short side has its own room
+        market.increase_open_interest(false, 500).unwrap();
+        assert!(market.increase_open_interest(false, 1).is_err());
+
+        // Closes free capacity, and 0 means uncapped
+        market.reduce_open_interest(true, 400);
+        market.increase_open_interest(true, 400).unwrap();
+        market.max_short_oi = 0;
+        market.increase_open_interest(false, 1_000_000).unwrap();
+    }
+
+    #[test]
+    fn test_withdrawal_queue_threshold() {
+        let mut market = Market::default();
+        assert!(!market.queues_withdrawal(u64::MAX)); // disabled by default
+
+        market.withdrawal_queue_threshold = 10_000;
+        assert!(!market.queues_withdrawal(10_000));
+        assert!(market.queues_withdrawal(10_001));
+    }
+
+    #[test]
+    fn test_pending_withdrawal_lifecycle() {
+        let mut pending = PendingWithdrawal::default();
+        pending.enqueue(50_000, 1_000, 3_600).unwrap();
+        assert_eq!(pending.remaining(), 50_000);
+
+        // Nothing before the delay elapses
+        assert_eq!(pending.claimable(4_599, u64::MAX), 0);
+        assert!(pending.claim(1, 4_599).is_err());
+
+        // Tranche limited by the vault's free balance
+        let first = pending.claimable(4_600, 20_000);
+        assert_eq!(first, 20_000);
+        pending.claim(first, 4_600).unwrap();
+        assert_eq!(pending.remaining(), 30_000);
+
+        // Rest once liquidity returns
+        let second = pending.claimable(5_000, 1_000_000);
+        assert_eq!(second, 30_000);
+        pending.claim(second, 5_000).unwrap();
+        assert_eq!(pending.remaining(), 0);
+        assert_eq!(pending.claimable(6_000, 1_000_000), 0);
+    }
+
+    #[test]
+    fn test_pending_withdrawal_tranches_keep_their_own_delay() {
+        let mut pending = PendingWithdrawal::default();
+        pending.enqueue(50_000, 1_000, 3_600).unwrap();
+        pending.claim(20_000, 4_600).unwrap();
+
+        // A later payout waits its own delay without pushing back the first
+        pending.enqueue(10_000, 2_000, 3_600).unwrap();
+        assert_eq!(pending.remaining(), 40_000);
+        assert_eq!(pending.available_at, 4_600);
+        assert_eq!(pending.matured(4_600), 30_000);
+        assert!(pending.claim(30_001, 4_600).is_err());
+
+        pending.claim(30_000, 4_600).unwrap();
+        assert_eq!(pending.available_at, 5_600);
+        assert_eq!(pending.claimable(5_599, u64::MAX), 0);
+        assert_eq!(pending.claimable(5_600, u64::MAX), 10_000);
+    }
+
+    #[test]
+    fn test_pending_withdrawal_overflow_joins_the_newest_tranche() {
+        let mut pending = PendingWithdrawal::default();
+        for i in 0..MAX_WITHDRAWAL_TRANCHES as i64 {
+            pending.enqueue(1_000, 1_000 + i, 100).unwrap();
+        }
+        pending.enqueue(5_000, 2_000, 100).unwrap();
+
+        // Older tranches are untouched, the newest waits for the later release
+        assert_eq!(pending.tranches[0], WithdrawalTranche { amount: 1_000, available_at: 1_100 });
+        assert_eq!(
+            pending.tranches[MAX_WITHDRAWAL_TRANCHES - 1],
+            WithdrawalTranche { amount: 6_000, available_at: 2_100 }
+        );
+        assert_eq!(pending.matured(1_100 + MAX_WITHDRAWAL_TRANCHES as i64), 1_000 * (MAX_WITHDRAWAL_TRANCHES as u64 - 1));
+
+        // Paying the matured ones frees their slots for new payouts
+        pending.claim(1_000 * (MAX_WITHDRAWAL_TRANCHES as u64 - 1), 1_500).unwrap();
+        assert_eq!(pending.tranches[0], WithdrawalTranche { amount: 6_000, available_at: 2_100 });
+        pending.enqueue(500, 1_600, 100).unwrap();
+        assert_eq!(pending.tranches[1], WithdrawalTranche { amount: 500, available_at: 1_700 });
+        assert_eq!(pending.available_at, 1_700);
+    }
+
+    #[test]
+    fn test_market_insurance_funds_are_isolated() {
+        let mut meme_fund = InsuranceFund { total_deposits: 1_000, market: Pubkey::new_unique(), ..Default::default() };
+        let mut btc_fund = InsuranceFund { total_deposits: 5_000, market: Pubkey::new_unique(), ..Default::default() };
+        let mut global = InsuranceFund { total_deposits: 2_000, ..Default::default() };
+        assert!(global.is_global() && !meme_fund.is_global());
+
+        // Meme market blows up with a 1_500 deficit: its own fund takes the
+        // first loss, the global backstop the rest, BTC's fund is untouched
+        let from_market = meme_fund.absorb_deficit(1_500);
+        let from_global = global.absorb_deficit(1_500 - from_market);
+
+        assert_eq!((from_market, from_global), (1_000, 500));
+        assert_eq!(meme_fund.available(), 0);
+        assert_eq!(global.available(), 1_500);
+        assert_eq!(btc_fund.available(), 5_000);
+
+        // Later surplus in the meme market refills only that fund
+        meme_fund.record_deposit(300).unwrap();
+        assert_eq!(meme_fund.available(), 300);
+        assert_eq!(btc_fund.absorb_deficit(0), 0);
+    }
+
+    #[test]
+    fn test_vault_with_wrong_authority_is_rejected() {
+        let (config_key, quote_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let cfg = Config { quote_mint, ..Default::default() };
+        assert!(cfg.is_quote_vault(&config_key, &config_key, &quote_mint));
+
+        // A vault owned by anyone but the config PDA can't back payouts
+        let attacker = Pubkey::new_unique();
+        assert!(!cfg.is_quote_vault(&config_key, &attacker, &quote_mint));
+        // Nor can a config-held account in some other mint
+        assert!(!cfg.is_quote_vault(&config_key, &config_key, &Pubkey::new_unique()));
+    }
+
+    #[test]
+    fn test_rounding_buffer_keeps_vault_solvent_over_many_trades() {
+        // Two-decimal quote mint under six-decimal prices: every payout rounds down
+        let mut cfg = Config { price_decimals: 6, quote_decimals: 2, fee_bps: 7, ..Default::default() };
+        let mut vault: u64 = 0;
+        let mut seed: u64 = 42;
+        for _ in 0..5_000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let size = (seed >> 40) % 97 + 1;
+            let entry_fp = 1_000_000 + (seed >> 20) as u128 % 99_000_000;
+            let exit_fp = entry_fp * 9 / 10 + (seed >> 8) as u128 % (entry_fp / 5);
+            let margin = 200_000 + (seed >> 33) % 500_000; // Always covers the loss
+
+            // A long and a short trade against each other, so PnL nets to zero
+            vault += 2 * margin;
+            let pnl_fp = size as i128 * (exit_fp as i128 - entry_fp as i128);
+            for pnl_fp in [pnl_fp, -pnl_fp] {
+                let settled = crate::math::close_settlement(
+                    cfg.quote_to_fp(margin).unwrap(), pnl_fp, size as u128 * exit_fp, cfg.fee_bps,
+                );
+                let payout = cfg.settle_to_quote(settled.payout_fp).unwrap();
+                let fee = cfg.settle_to_quote(settled.fee_fp).unwrap();
+                let (payout, fee) = cfg.cushion_payout(payout, fee, vault).unwrap();
+                vault -= payout + fee;
+            }
+            // Whatever rounding left behind is exactly the banked buffer
+            assert_eq!(cfg.quote_to_fp(vault).unwrap(), cfg.rounding_buffer_fp);
+        }
+        assert!(cfg.rounding_buffer().unwrap() > 0);
+
+        // A last withdrawal the vault falls a unit short of is absorbed, not failed
+        let buffer_fp = cfg.rounding_buffer_fp;
+        assert_eq!(cfg.cushion_payout(vault + 1, 0, vault).unwrap(), (vault, 0));
+        assert_eq!(cfg.rounding_buffer_fp, buffer_fp - cfg.quote_to_fp(1).unwrap());
+        // The fee gives way first
+        assert_eq!(cfg.cushion_payout(vault, 1, vault).unwrap(), (vault, 0));
+        // A gap beyond the buffer is a genuine shortfall
+        assert!(cfg.absorb_rounding_shortfall(vault + 1_000_000, vault).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rounding_buffer_sweep_is_capped() {
+        let mut cfg = Config { price_decimals: 6, quote_decimals: 2, ..Default::default() };
+        assert_eq!(cfg.settle_to_quote(12_345_678).unwrap(), 1_234);
+        assert_eq!(cfg.rounding_buffer_fp, 5_678);
+        cfg.settle_to_quote(9_994_322).unwrap();
+        assert_eq!(cfg.rounding_buffer().unwrap(), 1);
+
+        assert!(cfg.take_rounding_buffer(2).is_err());
+        cfg.take_rounding_buffer(1).unwrap();
+        assert_eq!(cfg.rounding_buffer_fp, 0);
+    }
+
+    #[test]
+    fn test_maintenance_margin_raise_waits_for_timelock() {
+        let mut market = Market { maintenance_margin_bps: 500, ..Default::default() };
+        market.schedule_maintenance_margin(1_000, 1_000).unwrap();
+        let effective_ts = 1_000 + MAINTENANCE_MARGIN_TIMELOCK_SECONDS;
+
+        // Not in force yet, but new risk is already held to it
+        assert_eq!(market.maintenance_margin_effective_ts, effective_ts);
+        assert_eq!(market.maintenance_margin_bps_at(1_000), 500);
+        assert_eq!(market.maintenance_margin_bps_at(effective_ts - 1), 500);
+        assert_eq!(market.upcoming_maintenance_margin_bps(), 1_000);
+
+        // $100 equity against $1,500 notional: fine at 5%, liquidatable at 10%
+        let (equity_fp, notional_fp) = (100 * FP as i128, 1_500 * FP);
+        let liquidatable = |m: &Market, now| equity_fp < (notional_fp * m.maintenance_margin_bps_at(now) as u128 / 10_000) as i128;
+        market.settle_maintenance_margin(effective_ts - 1);
+        assert_eq!(market.maintenance_margin_bps, 500);
+        assert!(!liquidatable(&market, effective_ts - 1));
+
+        market.settle_maintenance_margin(effective_ts);
+        assert_eq!((market.maintenance_margin_bps, market.pending_maintenance_margin_bps), (1_000, 0));
+        assert!(liquidatable(&market, effective_ts));
+    }
+
+    #[test]
+    fn test_position_crossing_a_margin_tier_needs_more_maintenance() {
+        let mut market = Market { maintenance_margin_bps: 500, ..Default::default() };
+        let tiers = [
+            MarginTier { min_base_size: 100, maintenance_margin_bps: 750 },
+            MarginTier { min_base_size: 1_000, maintenance_margin_bps: 1_000 },
+            MarginTier::default(),
+            MarginTier::default(),
+        ];
+        market.schedule_margin_tiers(tiers, 0).unwrap();
+        // Tightening waits out the timelock like a maintenance margin raise
+        assert_eq!(market.maintenance_margin_bps_for(500, 0), 500);
+        assert_eq!(market.upcoming_maintenance_margin_bps_for(500), 750);
+        market.settle_maintenance_margin(MAINTENANCE_MARGIN_TIMELOCK_SECONDS);
+        assert_eq!(market.margin_tiers, tiers);
+
+        let now = MAINTENANCE_MARGIN_TIMELOCK_SECONDS;
+        assert_eq!(market.maintenance_margin_bps_for(99, now), 500);
+        assert_eq!(market.maintenance_margin_bps_for(100, now), 750);
+        assert_eq!(market.maintenance_margin_bps_for(1_000, now), 1_000);
+
+        // Two longs from $100 carrying the same ~6.06% margin, either side of the first tier
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        let small = UserPosition { base_size: 99, is_long: true, entry_price_fp: 100 * FP, margin_deposited: 600_000_000, ..Default::default() };
+        let large = UserPosition { base_size: 100, margin_deposited: 606_060_606, ..small.clone() };
+        // At entry the small one clears 5%, the large one misses 7.5%
+        let price_fp = 100 * FP;
+        let bps = |up: &UserPosition| market.maintenance_margin_bps_for(up.base_size.unsigned_abs(), now);
+        assert!(!small.is_liquidatable(&cfg, price_fp, bps(&small)).unwrap());
+        assert!(large.is_liquidatable(&cfg, price_fp, bps(&large)).unwrap());
+        // Its liquidation price moves toward entry with the stricter tier
+        let at_base = crate::math::liquidation_price_fp(100 * FP, 1_000 * FP, 100, 500, true).unwrap();
+        let at_tier = crate::math::liquidation_price_fp(100 * FP, 1_000 * FP, 100, bps(&large), true).unwrap();
+        assert!(at_tier > at_base);
+        // And opening it at 6% margin is refused
+        assert!(crate::oracle::check_maintenance(price_fp, 99, 594 * FP as i128, bps(&small)).is_ok());
+        assert_eq!(
+            crate::oracle::check_maintenance(price_fp, 100, 600 * FP as i128, bps(&large)).unwrap_err(),
+            PerpsError::WouldBeLiquidated.into()
+        );
+
+        // Loosening applies at once
+        market.schedule_margin_tiers([MarginTier::default(); MARGIN_TIERS], now).unwrap();
+        assert_eq!(market.maintenance_margin_bps_for(1_000, now), 500);
+        assert_eq!(market.margin_tiers_effective_ts, 0);
+    }
+
+    #[test]
+    fn test_maintenance_margin_cut_is_immediate_and_cancels_raise() {
+        let mut market = Market { maintenance_margin_bps: 500, ..Default::default() };
+        market.schedule_maintenance_margin(800, 0).unwrap();
+        market.schedule_maintenance_margin(400, 10).unwrap();
+        assert_eq!(market.maintenance_margin_bps, 400);
+        assert_eq!(market.pending_maintenance_margin_bps, 0);
+        assert_eq!(market.maintenance_margin_bps_at(MAINTENANCE_MARGIN_TIMELOCK_SECONDS), 400);
+    }
+
+    #[test]
+    fn test_active_orders_are_capped_per_user() {
+        let mut orders = UserOrders::default();
+        for _ in 0..3 {
+            orders.add_order(3).unwrap();
+        }
+        assert_eq!(orders.add_order(3).unwrap_err(), PerpsError::ExceedsPositionLimits.into());
+        assert_eq!(orders.active_orders, 3);
+
+        // Cancelling or executing one frees a slot
+        orders.remove_order();
+        orders.add_order(3).unwrap();
+        assert_eq!(orders.active_orders, 3);
+
+        // Uncapped configs (pre-upgrade accounts) never refuse
+        assert!(orders.add_order(0).is_ok());
+    }
+
+    #[test]
+    fn test_withdrawal_rate_limit_throttles_within_window() {
+        let limit = 1_000_000;
+        let mut window = UserRateLimit::default();
+        let start = 1_700_000_000;
+
+        // Outflow up to the limit goes through
+        window.record_outflow(600_000, limit, start).unwrap();
+        window.record_outflow(400_000, limit, start + 60).unwrap();
+        assert_eq!(window.window_outflow, limit);
+
+        // One more unit in the same window is throttled and not counted
+        assert_eq!(window.record_outflow(1, limit, start + 120).unwrap_err(), PerpsError::ExceedsRiskLimits.into());
+        assert_eq!(window.window_outflow, limit);
+
+        // Once the window runs out it starts over
+        let next = start + WITHDRAWAL_RATE_LIMIT_WINDOW_SECONDS;
+        window.record_outflow(limit, limit, next).unwrap();
+        assert_eq!(window.window_start_ts, next);
+        assert_eq!(window.window_outflow, limit);
+
+        // Disabled (the default) never throttles
+        assert!(window.record_outflow(u64::MAX, 0, next).is_ok());
+    }
+
+    #[test]
+    fn test_position_under_the_absolute_cap_can_still_dominate_thin_oi() {
+        // 40 units open in a market that allows 1_000 per position and 25% of OI each
+        let market = Market {
+            total_long_size: 30,
+            total_short_size: 10,
+            max_position_base: 1_000,
+            max_position_oi_fraction_bps: 2_500,
+            ..Default::default()
+        };
+
+        // 100 units is well under max_position_base but would be 100 / 140 of OI
+        assert!(100 <= market.max_position_base);
+        assert_eq!(market.ensure_within_concentration(100).unwrap_err(), PerpsError::ConcentrationLimitExceeded.into());
+
+        // 13 of 53 is just under a quarter, 14 of 54 just over
+        assert!(market.ensure_within_concentration(13).is_ok());
+        assert!(market.ensure_within_concentration(14).is_err());
+
+        // Off by default, and an empty market has no OI to measure against
+        assert!(Market { max_position_oi_fraction_bps: 0, ..market.clone() }.ensure_within_concentration(100).is_ok());
+        assert!(Market { max_position_oi_fraction_bps: 2_500, ..Default::default() }.ensure_within_concentration(100).is_ok());
+
+        // Adding to a position already in the OI counts it once: 5 held plus
+        // 6 more is 11 of 46, just under a quarter, and 12 of 47 just over
+        assert!(market.ensure_increase_within_concentration(5, 6).is_ok());
+        assert!(market.ensure_increase_within_concentration(5, 7).is_err());
+    }
+
+    #[test]
+    fn test_unversioned_accounts_are_rejected_until_migrated() {
+        let mut config = Config { version: ACCOUNT_VERSION, ..Default::default() };
+        let mut market = Market { version: ACCOUNT_VERSION, ..Default::default() };
+
+        // A position written before versioning: the field sits in old padding, so it reads 0
+        let mut bytes = Vec::with_capacity(UserPosition::SPACE);
+        UserPosition { base_size: 10, status: PositionStatus::Open, ..Default::default() }
+            .try_serialize(&mut bytes)
+            .unwrap();
+        bytes.resize(UserPosition::SPACE, 0);
+        let mut up = UserPosition::try_deserialize(&mut &bytes[..]).unwrap();
+        assert_eq!(up.version, 0);
+        assert_eq!(
+            ensure_current_versions(&config, &market, &[&up]).unwrap_err(),
+            PerpsError::AccountNeedsMigration.into()
+        );
+
+        up.version = ACCOUNT_VERSION;
+        ensure_current_versions(&config, &market, &[&up]).unwrap();
+
+        // Stale config or market blocks the trade just the same
+        config.version = 0;
+        assert!(ensure_current_versions(&config, &market, &[&up]).is_err());
+        config.version = ACCOUNT_VERSION;
+        market.version = 0;
+        assert!(ensure_current_versions(&config, &market, &[]).is_err());
+    }
+
+    #[test]
+    fn test_creator_sweeps_at_most_what_has_accrued() {
+        let mut market = Market { creator: Pubkey::new_unique(), ..Default::default() };
+        assert!(market.draw_creator_rewards(1).is_err());
+
+        market.retain_creator_share(1_000_000, 1_000).unwrap();
+        assert!(market.draw_creator_rewards(100_001).is_err());
+        assert!(market.draw_creator_rewards(0).is_err());
+        assert_eq!(market.creator_rewards_accrued, 100_000);
+
+        market.draw_creator_rewards(60_000).unwrap();
+        market.draw_creator_rewards(40_000).unwrap();
+        assert_eq!(market.creator_rewards_accrued, 0);
+    }
+
+    #[test]
+    fn test_creator_accrues_their_fee_share_until_claimed() {
+        let mut market = Market { creator: Pubkey::new_unique(), ..Default::default() };
+
+        // 10% of each fee stays behind for the creator
+        assert_eq!(market.retain_creator_share(1_000_000, 1_000).unwrap(), 900_000);
+        assert_eq!(market.retain_creator_share(250_000, 1_000).unwrap(), 225_000);
+        assert_eq!(market.creator_rewards_accrued, 125_000);
+
+        assert_eq!(market.take_creator_rewards(), 125_000);
+        assert_eq!(market.creator_rewards_accrued, 0);
+        assert_eq!(market.take_creator_rewards(), 0);
+
+        // Markets without a recorded creator forward the whole fee
+        let mut legacy = Market::default();
+        assert_eq!(legacy.retain_creator_share(1_000_000, 1_000).unwrap(), 1_000_000);
+        assert_eq!(legacy.creator_rewards_accrued, 0);
+    }
+
+    #[test]
+    fn test_collateral_funds_a_trade_and_takes_its_settlement() {
+        let mut collateral = CollateralAccount::default();
+
+        // Deposit once up front
+        collateral.credit(500_000_000).unwrap();
+
+        // The open draws its margin from the balance
+        collateral.debit(200_000_000).unwrap();
+        assert_eq!(collateral.balance, 300_000_000);
+
+        // The close settles margin plus PnL back into it
+        collateral.credit(238_000_000).unwrap();
+        assert_eq!(collateral.balance, 538_000_000);
+
+        // Withdrawals can't take more than is there
+        assert_eq!(collateral.debit(538_000_001).unwrap_err(), PerpsError::InsufficientFunds.into());
+        collateral.debit(538_000_000).unwrap();
+        assert_eq!(collateral.balance, 0);
+    }
+
+    #[test]
+    fn test_pledged_collateral_is_valued_at_its_haircut_and_settled_in_kind() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        // A 9-decimal token at $20, counted at 80%
+        let accepted = AcceptedCollateral { collateral_factor_bps: 8_000, decimals: 9, ..Default::default() };
+        let price_fp = 20 * FP;
+        assert_eq!(accepted.margin_value(&cfg, 5_000_000_000, price_fp).unwrap(), 80_000_000);
+
+        // $100 of margin takes 6.25 tokens, and an odd amount rounds the tokens up
+        assert_eq!(accepted.tokens_for_margin(&cfg, 100_000_000, price_fp).unwrap(), 6_250_000_000);
+        let tokens = accepted.tokens_for_margin(&cfg, 100_000_001, price_fp).unwrap();
+        assert_eq!(tokens, 6_250_000_063);
+        assert!(accepted.margin_value(&cfg, tokens, price_fp).unwrap() >= 100_000_001);
+
+        // A gain is paid in quote on top of every token; a loss keeps its share of them
+        let pledge = PositionCollateral { amount: 6_250_000_000, ..Default::default() };
+        assert_eq!(pledge.settle(100_000_000, 130_000_000), PledgeSettlement { quote: 30_000_000, returned: 6_250_000_000, seized: 0 });
+        assert_eq!(pledge.settle(100_000_000, 60_000_000), PledgeSettlement { quote: 0, returned: 3_750_000_000, seized: 2_500_000_000 });
+        assert_eq!(pledge.settle(100_000_000, 0), PledgeSettlement { quote: 0, returned: 0, seized: 6_250_000_000 });
+
+        // Withdrawing a quarter of the margin frees a quarter of the tokens
+        assert_eq!(pledge.release_share(100_000_000, 25_000_000), 1_562_500_000);
+    }
+
+    #[test]
+    fn test_total_positions_cap_rejects_opens_until_one_closes() {
+        let max_total_positions = 2;
+        let mut stats = ProtocolStats::default();
+        stats.record_open(max_total_positions).unwrap();
+        stats.record_open(max_total_positions).unwrap();
+
+        // The protocol is full
+        assert_eq!(stats.record_open(max_total_positions).unwrap_err(), PerpsError::ExceedsRiskLimits.into());
+        assert_eq!(stats.active_positions, 2);
+
+        // A full close frees a slot for the next open
+        stats.record_close();
+        stats.record_open(max_total_positions).unwrap();
+        assert_eq!(stats.active_positions, 2);
+
+        // Positions opened before the counter existed can't take it below zero
+        let mut fresh = ProtocolStats::default();
+        fresh.record_close();
+        assert_eq!(fresh.active_positions, 0);
+    }
+
+    #[test]
+    fn test_protocol_stats_accumulate_volume_fees_and_liquidations() {
+        let mut stats = ProtocolStats::default();
+        stats.record_open(10).unwrap();
+        stats.record_volume(5_000_000_000, 5_000_000).unwrap();
+        stats.record_fees(2_000_000).unwrap();
+        stats.record_liquidation(1_000_000).unwrap();
+
+        let event = stats.updated_event();
+        assert_eq!(event.total_volume, 5_000_000_000);
+        assert_eq!(event.total_fees_collected, 8_000_000);
+        assert_eq!(event.active_positions, 1);
+        assert_eq!(event.total_liquidations, 1);
+
+        // Counters fail loudly rather than wrap
+        stats.total_fees_collected = u64::MAX;
+        assert_eq!(stats.record_fees(1).unwrap_err(), PerpsError::MathOverflow.into());
+        stats.total_liquidations = u32::MAX;
+        assert_eq!(stats.record_liquidation(0).unwrap_err(), PerpsError::MathOverflow.into());
+    }
+
+    #[test]
+    fn test_user_position_cap_rejects_opens_until_one_closes() {
+        let max_positions_per_user = 2;
+        let mut user = UserAccount::default();
+        user.record_open(max_positions_per_user).unwrap();
+        user.record_open(max_positions_per_user).unwrap();
+
+        // A third market is refused while both positions are open
+        assert_eq!(user.record_open(max_positions_per_user).unwrap_err(), PerpsError::ExceedsPositionLimits.into());
+        assert_eq!(user.open_position_count, 2);
+
+        user.record_close();
+        user.record_open(max_positions_per_user).unwrap();
+        assert_eq!(user.open_position_count, 2);
+    }
+
+    #[test]
+    fn test_cross_profit_carries_a_leg_isolated_margin_would_liquidate() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        let mut pool = CrossMarginAccount { collateral_balance: 10_000_000, ..Default::default() };
+        pool.allocate(5_000_000).unwrap();
+        pool.allocate(5_000_000).unwrap();
+        assert_eq!(pool.allocate(1).unwrap_err(), PerpsError::InsufficientMargin.into());
+
+        let loser = UserPosition { is_long: true, base_size: 1, entry_price_fp: 100 * FP, margin_deposited: 5_000_000, margin_mode: MarginMode::Cross, ..Default::default() };
+        let winner = UserPosition { is_long: false, base_size: -1, entry_price_fp: 100 * FP, margin_deposited: 5_000_000, margin_mode: MarginMode::Cross, ..Default::default() };
+        let legs = [CrossLeg::new(&loser, 96 * FP, 500), CrossLeg::new(&winner, 90 * FP, 500)];
+
+        // On its own margin the long is under maintenance
+        let isolated_equity_fp = cfg.quote_to_fp(loser.margin_deposited).unwrap() as i128 + legs[0].pnl_fp();
+        assert!(isolated_equity_fp < legs[0].maintenance_fp() as i128);
+
+        // Pooled, the short's profit keeps the account healthy
+        assert_eq!(pool.equity_fp(&cfg, &legs).unwrap(), 16_000_000);
+        assert!(!pool.is_liquidatable(&cfg, &legs).unwrap());
+        assert_eq!(loser.ensure_isolated().unwrap_err(), PerpsError::CrossMarginUnsupported.into());
+
+        // Closing the loser frees its allocation and books its loss in the pool
+        pool.release(5_000_000, 6_000_000);
+        assert_eq!((pool.collateral_balance, pool.free_collateral(), pool.open_positions), (6_000_000, 1_000_000, 1));
+    }
+
+    #[test]
+    fn test_entry_fees_split_by_liquidity_role() {
+        let cfg = Config { maker_fee_bps: -2, taker_fee_bps: 5, ..Default::default() };
+        assert_eq!(cfg.entry_fee(10_000_000, false), 5_000);
+        assert_eq!(cfg.entry_fee(10_000_000, true), -2_000);
+
+        // Both round towards zero, so a dust rebate is never paid where the taker fee rounds away
+        let even = Config { maker_fee_bps: -3, taker_fee_bps: 3, ..Default::default() };
+        assert_eq!((even.entry_fee(3_333, false), even.entry_fee(3_333, true)), (0, 0));
+        assert_eq!(even.entry_fee(3_334, false) + even.entry_fee(3_334, true), 0);
+
+        // Unset rates charge nothing, as before the split
+        assert_eq!(Config::default().entry_fee(u64::MAX, false), 0);
+    }
+
+    #[test]
+    fn test_market_maker_nets_a_rebate_where_a_taker_pays_the_fee() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, fee_bps: 10, mm_rebate_bps: 2, ..Default::default() };
+        let margin_fp = cfg.quote_to_fp(1_000_000).unwrap();
+        let exit_notional_fp = cfg.quote_to_fp(10_000_000).unwrap(); // flat PnL, 10x the margin
+
+        // A regular close pays the fee out of its equity
+        let taker = crate::math::close_settlement(margin_fp, 0, exit_notional_fp, cfg.fee_bps);
+        assert_eq!(cfg.fp_to_quote(taker.fee_fp).unwrap(), 10_000);
+        assert_eq!(cfg.fp_to_quote(taker.payout_fp).unwrap(), 990_000);
+
+        // A whitelisted maker closes fee-free and is paid the rebate on top
+        let maker = crate::math::close_settlement(margin_fp, 0, exit_notional_fp, 0);
+        let rebate = cfg.maker_rebate(exit_notional_fp, u64::MAX).unwrap();
+        assert_eq!(maker.fee_fp, 0);
+        assert_eq!(cfg.fp_to_quote(maker.payout_fp).unwrap() + rebate, 1_002_000);
+
+        // The pool only pays what it has
+        assert_eq!(cfg.maker_rebate(exit_notional_fp, 500).unwrap(), 500);
+        assert_eq!(cfg.maker_rebate(exit_notional_fp, 0).unwrap(), 0);
+        let no_rebate = Config { mm_rebate_bps: 0, ..cfg };
+        assert_eq!(no_rebate.maker_rebate(exit_notional_fp, u64::MAX).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_recent_index_prices_feed_the_emergency_average() {
+        let mut market = Market::default();
+        let key = Pubkey::new_unique();
+        market.record_index_price(100 * FP);
+        market.record_index_price(102 * FP);
+        // Too few live samples to trust an average yet
+        assert!(crate::oracle::emergency_price_fallback(&key, &market.recent_index_prices_fp).is_err());
+
+        market.record_index_price(104 * FP);
+        assert_eq!(crate::oracle::emergency_price_fallback(&key, &market.recent_index_prices_fp).unwrap(), 102 * FP);
+
+        // The ring overwrites the oldest sample once full
+        for _ in 0..EMERGENCY_PRICE_SAMPLES {
+            market.record_index_price(110 * FP);
+        }
+        assert_eq!(market.recent_index_prices_fp, [110 * FP; EMERGENCY_PRICE_SAMPLES]);
+        assert!(!market.has_source_priority());
+    }
+
+    #[test]
+    fn test_circuit_breaker_trips_on_a_jump_and_clears_on_a_later_slot() {
+        let mut market = Market::default();
+        market.record_index_price(100 * FP);
+        assert_eq!(market.last_index_price_fp(), 100 * FP);
+
+        // 15% against a 10% threshold trips it
+        assert_eq!(market.check_circuit_breaker(100 * FP, 115 * FP, 1_000, 7), Some(1_304));
+        assert_eq!(market.circuit_breaker_slot, 7);
+        // A steady price doesn't clear it within the same slot
+        assert!(market.check_circuit_breaker(115 * FP, 115 * FP, 1_000, 7).is_some());
+        // A later slot inside the threshold does
+        assert_eq!(market.check_circuit_breaker(115 * FP, 116 * FP, 1_000, 8), None);
+        assert_eq!(market.circuit_breaker_slot, 0);
+
+        // 10% on the nose is within threshold, and a zero threshold turns it off
+        assert_eq!(market.check_circuit_breaker(100 * FP, 90 * FP, 1_000, 9), None);
+        assert_eq!(market.check_circuit_breaker(100 * FP, 200 * FP, 0, 10), None);
+    }
+
+    #[test]
+    fn test_market_vaults_are_isolated() {
+        let (btc, eth) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (btc_vault, btc_bump) = Market::find_vault_pda(&btc);
+        let (eth_vault, _) = Market::find_vault_pda(&eth);
+        assert_ne!(btc_vault, eth_vault);
+
+        // BTC's vault never satisfies ETH's seeds, so ETH payouts can't draw on it
+        let as_eth = Pubkey::create_program_address(&[VAULT_SEED, eth.as_ref(), &[btc_bump]], &crate::ID);
+        assert_ne!(as_eth.ok(), Some(btc_vault));
+        assert_eq!(
+            Pubkey::create_program_address(&[VAULT_SEED, btc.as_ref(), &[btc_bump]], &crate::ID).unwrap(),
+            btc_vault
+        );
+        // Nor does the old config-seeded vault
+        let (config_key, _) = Config::find_pda();
+        assert_ne!(Pubkey::find_program_address(&[VAULT_SEED, config_key.as_ref()], &crate::ID).0, btc_vault);
+    }
+
+    #[test]
+    fn test_close_only_window_blocks_opens_before_expiry() {
+        let mut market = Market { expiry_ts: 10_000, close_only_before_expiry_seconds: 3_600, ..Default::default() };
+        assert!(!market.is_close_only(6_399));
+        assert!(market.is_close_only(6_400));
+        assert!(!market.is_expired(9_999));
+        assert!(market.is_expired(10_000) && market.is_close_only(10_000));
+
+        // Closes keep using the live mark until the final price is pinned
+        assert_eq!(market.settlement_mark_fp(101 * FP), 101 * FP);
+        market.final_settlement_price_fp = 98 * FP;
+        assert_eq!(market.settlement_mark_fp(101 * FP), 98 * FP);
+
+        // Perpetuals never close-only or expire
+        let perp = Market::default();
+        assert!(!perp.is_close_only(i64::MAX) && !perp.is_expired(i64::MAX));
+    }
+
+    #[test]
+    fn test_unrealized_pnl_takes_price_difference_first() {
+        // The previous formulation: difference of full notionals, then divide.
+        // None where one of its intermediates doesn't fit.
+        fn notional_first(up: &UserPosition, price_fp: u128) -> Option<i128> {
+            let size = up.base_size.unsigned_abs() as u128;
+            let entry = i128::try_from(size.checked_mul(up.entry_price_fp)?).ok()?;
+            let current = i128::try_from(size.checked_mul(price_fp)?).ok()?;
+            let diff = if up.is_long { current.checked_sub(entry)? } else { entry.checked_sub(current)? };
+            Some(diff / FP as i128)
+        }
+
+        // Long/short, gain/loss, including moves that don't divide evenly
+        for (is_long, size, entry_fp, price_fp) in [
+            (true, 10, 100 * FP, 110 * FP),
+            (true, 10, 100 * FP, 90 * FP),
+            (false, 10, 100 * FP, 90 * FP),
+            (false, 10, 100 * FP, 110 * FP),
+            (true, 3, 100 * FP + 1, 100 * FP + 333_334),
+            (false, 7, 50_000 * FP + 17, 49_999 * FP + 3),
+        ] {
+            let up = UserPosition { is_long, base_size: if is_long { size } else { -size }, entry_price_fp: entry_fp, ..Default::default() };
+            assert_eq!(Some(up.unrealized_pnl_fp(price_fp)), notional_first(&up, price_fp));
+        }
+        let long = UserPosition { is_long: true, base_size: 10, entry_price_fp: 100 * FP, ..Default::default() };
+        assert_eq!(long.unrealized_pnl_fp(110 * FP), 100);
+        let short = UserPosition { is_long: false, base_size: -10, entry_price_fp: 100 * FP, ..Default::default() };
+        assert_eq!(short.unrealized_pnl_fp(110 * FP), -100);
+
+        // A huge position with a small move: the current notional no longer
+        // fits in i128, so the old casts overflow, while the price difference
+        // stays exact
+        let entry_fp = i128::MAX as u128 / i64::MAX as u128;
+        let whale = UserPosition { is_long: true, base_size: i64::MAX, entry_price_fp: entry_fp, ..Default::default() };
+        assert_eq!(whale.unrealized_pnl_fp(entry_fp + FP), i64::MAX as i128);
+        assert_eq!(notional_first(&whale, entry_fp + FP), None);
+    }
+
+    #[test]
+    fn test_keeper_gas_reimbursement_credits_executor() {
+        let cfg = Config { keeper_gas_reimbursement_lamports: 10_000, ..Default::default() };
+        let rent_floor = 890_880;
+        let mut vault = rent_floor + 25_000;
+        let mut keeper = 1_000_000u64;
+
+        for expected in [10_000, 10_000, 5_000, 0] {
+            let paid = cfg.keeper_gas_reimbursement(vault, rent_floor);
+            vault -= paid;
+            keeper += paid;
+            assert_eq!(paid, expected);
+        }
+        assert_eq!(keeper, 1_025_000);
+        assert_eq!(vault, rent_floor);
+
+        // Disabled when the cap is zero
+        let off = Config::default();
+        assert_eq!(off.keeper_gas_reimbursement(10 * rent_floor, rent_floor), 0);
+    }
+
+    #[test]
+    fn test_min_partial_close_boundary() {
+        let market = Market { min_partial_close_pct: 5, ..Default::default() };
+        assert!(!market.allows_partial_close(4));
+        assert!(market.allows_partial_close(5));
+        assert!(market.allows_partial_close(99));
+
+        let unrestricted = Market::default();
+        assert!(unrestricted.allows_partial_close(1));
+    }
+
+    #[test]
+    fn test_close_state_is_settled_in_account_data() {
+        let mut market = Market { total_long_size: 15, total_short_size: 4, ..Default::default() };
+        let mut position = UserPosition {
+            base_size: 10, is_long: true, margin_deposited: 500, entry_price_fp: 100 * FP,
+            ..Default::default()
+        };
+
+        market.reduce_open_interest(true, 10);
+        position.settle_full_close(25 * FP as i128, 3, 1_700_000_000);
+
+        // What a re-entrant instruction would deserialize once the accounts are persisted
+        let mut data = Vec::new();
+        position.try_serialize(&mut data).unwrap();
+        let reread = UserPosition::try_deserialize(&mut data.as_slice()).unwrap();
+        assert_eq!((reread.base_size, reread.margin_deposited), (0, 0));
+        assert_eq!(reread.realized_pnl_fp, 25 * FP as i128);
+        assert_eq!(reread.total_fees_paid, 3);
+
+        let mut data = Vec::new();
+        market.try_serialize(&mut data).unwrap();
+        let reread = Market::try_deserialize(&mut data.as_slice()).unwrap();
+        assert_eq!((reread.total_long_size, reread.total_short_size), (5, 4));
+    }
+
+    #[test]
+    fn test_market_accumulators_match_event_totals() {
+        let mut market = Market::default();
+        // (pnl_fp, fees_fp) as emitted by PositionClosed / PartialPositionClosed / LiquidationExecuted
+        let events: [(i128, u128); 5] = [
+            (25 * FP as i128, 2 * FP),
+            (-(40 * FP as i128), 3 * FP),
+            (7 * FP as i128, FP / 2),
+            (-(100 * FP as i128), 5 * FP),
+            (0, 0),
+        ];
+        for (pnl_fp, fees_fp) in events {
+            market.record_settlement(pnl_fp, fees_fp).unwrap();
+        }
+
+        assert_eq!(market.cumulative_trader_pnl_fp, events.iter().map(|e| e.0).sum::<i128>());
+        assert_eq!(market.cumulative_fees_fp, events.iter().map(|e| e.1).sum::<u128>());
+
+        market.cumulative_fees_fp = u128::MAX;
+        assert!(market.record_settlement(0, 1).is_err());
+    }
+
+    #[test]
+    fn test_position_status_gates_operations() {
+        let open = [PositionStatus::Open];
+        let mut position = UserPosition::default();
+        assert_eq!(position.current_status(), PositionStatus::Closed);
+        assert_eq!(position.ensure_status(&open).unwrap_err(), PerpsError::PositionNotFound.into());
+        assert!(position.ensure_status(&[PositionStatus::Closed]).is_ok());
+
+        position.status = PositionStatus::Liquidating;
+        position.base_size = 10;
+        assert_eq!(position.ensure_status(&open).unwrap_err(), PerpsError::PositionBeingLiquidated.into());
+        assert!(position.ensure_status(&[PositionStatus::Open, PositionStatus::Liquidating]).is_ok());
+
+        position.settle_full_close(0, 0, 1_000);
+        position.status = PositionStatus::PendingSettlement;
+        assert_eq!(position.ensure_status(&open).unwrap_err(), PerpsError::PositionPendingSettlement.into());
+
+        position.status = PositionStatus::Open;
+        position.base_size = 10;
+        assert_eq!(
+            position.ensure_status(&[PositionStatus::Closed]).unwrap_err(),
+            PerpsError::PositionAlreadyOpen.into()
+        );
+    }
+
+    #[test]
+    fn test_legacy_position_with_size_reads_as_open() {
+        let position = UserPosition { base_size: -5, ..Default::default() };
+        assert_eq!(position.current_status(), PositionStatus::Open);
+        assert!(position.ensure_status(&[PositionStatus::Open]).is_ok());
+    }
+
+    #[test]
+    fn test_open_protection_window() {
+        let mut market = Market::default();
+        // Zero default: never protected
+        assert!(!market.liquidation_protected(1_000, 1_000, FP as i128));
+
+        market.open_protection_seconds = 30;
+        assert!(market.liquidation_protected(1_000, 1_000, FP as i128));
+        assert!(market.liquidation_protected(1_000, 1_029, 1));
+        assert!(!market.liquidation_protected(1_000, 1_030, FP as i128));
+
+        // Insolvent positions can be liquidated straight away
+        assert!(!market.liquidation_protected(1_000, 1_001, 0));
+        assert!(!market.liquidation_protected(1_000, 1_001, -(FP as i128)));
+    }
+
+    #[test]
+    fn test_holding_period_gates_closes() {
+        let mut market = Market::default();
+        // Zero default: a close in the same second is allowed
+        assert!(market.ensure_holding_period(1_000, 1_000).is_ok());
+
+        market.min_holding_seconds = 15;
+        assert_eq!(market.ensure_holding_period(1_000, 1_014).unwrap_err(), PerpsError::HoldingPeriodNotElapsed.into());
+        assert!(market.ensure_holding_period(1_000, 1_015).is_ok());
+    }
+
+    #[test]
+    fn test_vault_books_only_what_it_holds() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        let mut market = Market { creator_rewards_accrued: 5, ..Default::default() };
+        let isolated = UserPosition { margin_deposited: 100, ..Default::default() };
+        let cross = UserPosition { margin_mode: MarginMode::Cross, ..isolated.clone() };
+        let pledged = UserPosition { collateral_pledged: true, ..isolated.clone() };
+        assert_eq!((isolated.vault_margin(), cross.vault_margin(), pledged.vault_margin()), (100, 0, 0));
+
+        // An open, a limit escrow, then a partial close handing back 40
+        market.lock_margin(isolated.vault_margin()).unwrap();
+        market.lock_margin(30).unwrap();
+        market.rebook_margin(100, 60).unwrap();
+        assert_eq!(market.total_margin_locked, 90);
+        assert_eq!(market.vault_obligations(&cfg).unwrap(), 95);
+
+        // Releasing more than was booked is drift, not something to round away
+        assert_eq!(market.release_margin(1_000).unwrap_err(), PerpsError::MathOverflow.into());
+        assert_eq!(market.total_margin_locked, 90);
+
+        // Insurance paid in for a deficit is owed to the winners until their profit settles
+        market.hold_insurance_claims(20 * FP).unwrap();
+        assert_eq!(market.vault_obligations(&cfg).unwrap(), 95 + 20_000_000);
+        market.record_settlement(-(5 * FP as i128), 0).unwrap();
+        assert_eq!(market.insurance_claims_held_fp, 20 * FP);
+        market.record_settlement(15 * FP as i128, 0).unwrap();
+        market.record_settlement(15 * FP as i128, 0).unwrap();
+        assert_eq!(market.vault_obligations(&cfg).unwrap(), 95);
+    }
+
+    #[test]
+    fn test_twap_over_a_partially_filled_window() {
+        let mut twap = OracleTwap::default();
+        assert_eq!(twap.twap_fp(1_000, 60).unwrap_err(), PerpsError::OracleFeedNotFound.into());
+
+        // 100 from t=1000, 110 from t=1030
+        twap.record(100 * FP, 1_000).unwrap();
+        assert_eq!(twap.twap_fp(1_000, 60).unwrap(), 100 * FP);
+        twap.record(110 * FP, 1_030).unwrap();
+        assert_eq!(twap.last_update_ts(), 1_030);
+
+        // At t=1040 a 60s window reaches before the first sample: 30s at 100, 10s at 110
+        assert_eq!(twap.twap_fp(1_040, 60).unwrap(), 102_500_000);
+        // A 20s window only sees 10s of each
+        assert_eq!(twap.twap_fp(1_040, 20).unwrap(), 105 * FP);
+        // A window inside the latest price's run is just that price
+        assert_eq!(twap.twap_fp(1_040, 5).unwrap(), 110 * FP);
+
+        // A one-second spike barely moves a minute-long average
+        twap.record(200 * FP, 1_099).unwrap();
+        let averaged = twap.twap_fp(1_100, 60).unwrap();
+        assert!(averaged < 112 * FP, "{averaged}");
+
+        // Same-second updates replace the sample; going back in time is refused
+        twap.record(111 * FP, 1_099).unwrap();
+        assert_eq!(twap.count, 3);
+        assert_eq!(twap.twap_fp(1_100, 1).unwrap(), 111 * FP);
+        assert_eq!(twap.record(FP, 1_098).unwrap_err(), PerpsError::BadOracle.into());
+    }
+
+    #[test]
+    fn test_twap_ring_wraps_and_forgets_the_oldest() {
+        let mut twap = OracleTwap::default();
+        // Ten seconds at 1000, then one sample a second at 100, 101, ...
+        twap.record(1_000 * FP, 0).unwrap();
+        for i in 0..TWAP_SAMPLES as u128 {
+            twap.record((100 + i) * FP, 10 + i as i64).unwrap();
+        }
+        assert_eq!(twap.count as usize, TWAP_SAMPLES);
+        assert_eq!(twap.cursor, 1);
+        assert_eq!(twap.samples[0].price_fp, (100 + TWAP_SAMPLES as u128 - 1) * FP);
+
+        // The 1000 sample was overwritten, so a long window averages only what the ring holds
+        let now = 10 + TWAP_SAMPLES as i64;
+        let expected = (100..100 + TWAP_SAMPLES as u128).sum::<u128>() * FP / TWAP_SAMPLES as u128;
+        assert_eq!(twap.twap_fp(now, 3_600).unwrap(), expected);
+        assert_eq!(twap.twap_fp(now, 2).unwrap(), (200 + TWAP_SAMPLES as u128 * 2 - 3) * FP / 2);
+    }
+
+    #[test]
+    fn test_is_liquidatable_matches_the_liquidate_maintenance_check() {
+        let cfg = Config { price_decimals: 6, quote_decimals: 6, ..Default::default() };
+        // 10 long at 100 with 100 margin, 5% maintenance
+        let up = UserPosition { is_long: true, base_size: 10, entry_price_fp: 100 * FP, margin_deposited: 100 * FP as u64, ..Default::default() };
+        assert_eq!(up.equity_fp(&cfg, 100 * FP).unwrap(), 100 * FP as i128);
+        assert!(!up.is_liquidatable(&cfg, 100 * FP, 500).unwrap());
+
+        // At 95: equity 50 against 47.5 required; at 94: 40 against 47
+        assert!(!up.is_liquidatable(&cfg, 95 * FP, 500).unwrap());
+        assert!(up.is_liquidatable(&cfg, 94 * FP, 500).unwrap());
+
+        // Funding owed counts against equity
+        let owing = UserPosition { funding_debt_fp: 3 * FP as i128, ..up.clone() };
+        assert!(owing.is_liquidatable(&cfg, 95 * FP, 500).unwrap());
+
+        let short = UserPosition { is_long: false, base_size: -10, ..up.clone() };
+        assert!(short.is_liquidatable(&cfg, 106 * FP, 500).unwrap());
+        assert!(!UserPosition { base_size: 0, ..up }.is_liquidatable(&cfg, FP, 500).unwrap());
+    }
+
+    #[test]
+    fn test_only_registered_pyth_feeds_accepted() {
+        let primary = Pubkey::new_unique();
+        let fallback = Pubkey::new_unique();
+        let mut market = Market { pyth_oracle: Some(primary), ..Default::default() };
+        market.pyth_fallback_oracles[0] = fallback;
+
+        assert!(market.is_pyth_feed(&primary));
+        assert!(market.is_pyth_feed(&fallback));
+        assert!(!market.is_pyth_feed(&Pubkey::new_unique()));
+        // Unused fallback slots don't whitelist the default key
+        assert!(!market.is_pyth_feed(&Pubkey::default()));
+    }
 }
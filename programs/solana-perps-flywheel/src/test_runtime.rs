@@ -0,0 +1,298 @@
+// Off-chain stand-ins for the runtime, so a unit test can run a whole
+// handler: a clock and rent, and a token program that moves balances and hands each
+// transfer to the test while the handler is still mid-instruction. They are
+// only live inside `run_at` on the calling thread; everywhere else the
+// default stubs apply (no clock, CPIs are no-ops).
+
+use std::cell::RefCell;
+use std::sync::Once;
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::program_stubs::{self, SyscallStubs};
+use anchor_lang::solana_program::sysvar::clock::Clock;
+use anchor_spl::token::spl_token;
+
+use crate::state::*;
+
+/// One `transfer_checked` the mock token program has been asked to make
+pub struct Transfer {
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub amount: u64,
+}
+
+type TransferHook = Box<dyn FnMut(&Transfer)>;
+
+thread_local! {
+    static NOW: RefCell<Option<i64>> = const { RefCell::new(None) };
+    static ON_TRANSFER: RefCell<Option<TransferHook>> = const { RefCell::new(None) };
+}
+
+struct Stubs;
+
+impl SyscallStubs for Stubs {
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        let Some(now) = NOW.with(|now| *now.borrow()) else {
+            return anchor_lang::solana_program::program_error::UNSUPPORTED_SYSVAR;
+        };
+        let clock = Clock { unix_timestamp: now, ..Default::default() };
+        // SAFETY: the caller hands us room for one `Clock`
+        unsafe { *(var_addr as *mut Clock) = clock };
+        0
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        if NOW.with(|now| now.borrow().is_none()) {
+            return anchor_lang::solana_program::program_error::UNSUPPORTED_SYSVAR;
+        }
+        // SAFETY: the caller hands us room for one `Rent`
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        0
+    }
+
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        _signers_seeds: &[&[&[u8]]],
+    ) -> std::result::Result<(), ProgramError> {
+        // TransferChecked: tag 12, amount, decimals; source, mint, destination, authority
+        let installed = NOW.with(|now| now.borrow().is_some());
+        if !installed || instruction.program_id != spl_token::ID || instruction.data.first() != Some(&12) {
+            return Ok(());
+        }
+        let amount = u64::from_le_bytes(instruction.data[1..9].try_into().unwrap());
+        let transfer = Transfer {
+            from: instruction.accounts[0].pubkey,
+            to: instruction.accounts[2].pubkey,
+            amount,
+        };
+        let info = |key: &Pubkey| account_infos.iter().find(|info| info.key == key).unwrap();
+        move_tokens(info(&transfer.from), info(&transfer.to), amount)?;
+
+        // The hook runs with nothing else installed, so whatever it calls back
+        // into moves tokens but can't recurse into it
+        let hook = ON_TRANSFER.with(|hook| hook.borrow_mut().take());
+        if let Some(mut hook) = hook {
+            hook(&transfer);
+            ON_TRANSFER.with(|slot| *slot.borrow_mut() = Some(hook));
+        }
+        Ok(())
+    }
+}
+
+fn move_tokens<'a>(from: &AccountInfo<'a>, to: &AccountInfo<'a>, amount: u64) -> std::result::Result<(), ProgramError> {
+    for (info, debit) in [(from, true), (to, false)] {
+        let mut data = info.try_borrow_mut_data()?;
+        let mut state = spl_token::state::Account::unpack(&data)?;
+        state.amount = if debit {
+            state.amount.checked_sub(amount).ok_or(ProgramError::InsufficientFunds)?
+        } else {
+            state.amount + amount
+        };
+        spl_token::state::Account::pack(state, &mut data)?;
+    }
+    Ok(())
+}
+
+/// Run `f` on this thread with the clock at `now` and `on_transfer` called
+/// after each token transfer the program makes
+pub fn run_at<R>(now: i64, on_transfer: impl FnMut(&Transfer) + 'static, f: impl FnOnce() -> R) -> R {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        program_stubs::set_syscall_stubs(Box::new(Stubs));
+    });
+    NOW.with(|slot| *slot.borrow_mut() = Some(now));
+    ON_TRANSFER.with(|slot| *slot.borrow_mut() = Some(Box::new(on_transfer)));
+    let result = f();
+    NOW.with(|slot| *slot.borrow_mut() = None);
+    ON_TRANSFER.with(|slot| *slot.borrow_mut() = None);
+    result
+}
+
+fn leak_info(key: Pubkey, owner: Pubkey, data: Vec<u8>, is_signer: bool, executable: bool) -> &'static AccountInfo<'static> {
+    Box::leak(Box::new(AccountInfo::new(
+        Box::leak(Box::new(key)),
+        is_signer,
+        true,
+        Box::leak(Box::new(1_000_000_000)),
+        Box::leak(data.into_boxed_slice()),
+        Box::leak(Box::new(owner)),
+        executable,
+        0,
+    )))
+}
+
+/// The program's address for `seeds`, and its bump
+pub fn pda(seeds: &[&[u8]]) -> (Pubkey, u8) {
+    Pubkey::find_program_address(seeds, &crate::ID)
+}
+
+/// A program account holding `value`, with `space` bytes to grow into
+pub fn program_account<T: AccountSerialize>(value: &T, space: usize) -> &'static AccountInfo<'static> {
+    program_account_at(Pubkey::new_unique(), value, space)
+}
+
+/// `program_account` at a fixed address, e.g. a PDA
+pub fn program_account_at<T: AccountSerialize>(key: Pubkey, value: &T, space: usize) -> &'static AccountInfo<'static> {
+    let mut data = Vec::with_capacity(space);
+    value.try_serialize(&mut data).unwrap();
+    data.resize(space.max(data.len()), 0);
+    leak_info(key, crate::ID, data, false, false)
+}
+
+/// A legacy SPL token account of `mint` holding `amount`
+pub fn token_account(mint: Pubkey, owner: Pubkey, amount: u64) -> &'static AccountInfo<'static> {
+    token_account_at(Pubkey::new_unique(), mint, owner, amount)
+}
+
+/// `token_account` at a fixed address, e.g. a PDA
+pub fn token_account_at(key: Pubkey, mint: Pubkey, owner: Pubkey, amount: u64) -> &'static AccountInfo<'static> {
+    let mut data = vec![0; spl_token::state::Account::LEN];
+    let state = spl_token::state::Account {
+        mint,
+        owner,
+        amount,
+        state: spl_token::state::AccountState::Initialized,
+        ..Default::default()
+    };
+    spl_token::state::Account::pack(state, &mut data).unwrap();
+    leak_info(key, spl_token::ID, data, false, false)
+}
+
+/// A legacy SPL mint with `decimals`
+pub fn mint(decimals: u8) -> &'static AccountInfo<'static> {
+    let mut data = vec![0; spl_token::state::Mint::LEN];
+    let state = spl_token::state::Mint { decimals, is_initialized: true, ..Default::default() };
+    spl_token::state::Mint::pack(state, &mut data).unwrap();
+    leak_info(Pubkey::new_unique(), spl_token::ID, data, false, false)
+}
+
+pub fn signer() -> &'static AccountInfo<'static> {
+    leak_info(Pubkey::new_unique(), Pubkey::default(), vec![], true, false)
+}
+
+pub fn program(id: Pubkey) -> &'static AccountInfo<'static> {
+    leak_info(id, Pubkey::default(), vec![], false, true)
+}
+
+/// What `info` holds right now, as the program would load it
+pub fn load<T: AccountDeserialize>(info: &AccountInfo) -> T {
+    T::try_deserialize(&mut &info.try_borrow_data().unwrap()[..]).unwrap()
+}
+
+pub fn token_balance(info: &AccountInfo) -> u64 {
+    spl_token::state::Account::unpack(&info.try_borrow_data().unwrap()).unwrap().amount
+}
+
+type Info = &'static AccountInfo<'static>;
+
+/// One 10 unit long from $100 on $100 of margin, alone in its market's vault,
+/// with every account a close or liquidation of it touches, each at the
+/// address the account constraints expect
+#[derive(Clone, Copy)]
+pub struct Book {
+    pub config: Info,
+    pub market: Info,
+    pub user: Info,
+    pub user_position: Info,
+    pub protocol_stats: Info,
+    pub user_account: Info,
+    pub vault_token: Info,
+    pub user_token: Info,
+    pub fee_destination: Info,
+    pub insurance_fund: Info,
+    pub insurance_vault_token: Info,
+    pub oracle: Info,
+    pub quote_mint: Info,
+    pub token_program: Info,
+    pub system_program: Info,
+}
+
+impl Book {
+    /// The book with `config` on 6-decimal prices and quote, and the oracle
+    /// at `price_fp` as of `now`
+    pub fn new(config: Config, price_fp: u128, now: i64) -> Self {
+        let user = signer();
+        let quote_mint = mint(6);
+        let fee_destination = token_account(*quote_mint.key, Pubkey::new_unique(), 0);
+        let (config_key, config_bump) = pda(&[CONFIG_SEED]);
+        let config = program_account_at(
+            config_key,
+            &Config {
+                version: ACCOUNT_VERSION,
+                price_decimals: 6,
+                quote_decimals: 6,
+                quote_mint: *quote_mint.key,
+                fee_destination: *fee_destination.key,
+                bump: config_bump,
+                ..config
+            },
+            Config::SPACE,
+        );
+        let market_key = Pubkey::new_unique();
+        let (vault_key, vault_bump) = pda(&[VAULT_SEED, market_key.as_ref()]);
+        let market = program_account_at(
+            market_key,
+            &Market {
+                version: ACCOUNT_VERSION,
+                total_long_size: 10,
+                total_margin_locked: 100_000_000,
+                maintenance_margin_bps: 500,
+                vault_bump,
+                ..Default::default()
+            },
+            Market::SPACE,
+        );
+        let (position_key, position_bump) = pda(&[POSITION_SEED, user.key.as_ref(), market_key.as_ref()]);
+        let position = UserPosition {
+            version: ACCOUNT_VERSION,
+            owner: *user.key,
+            market: market_key,
+            is_long: true,
+            base_size: 10,
+            entry_price_fp: 100 * FP,
+            margin_deposited: 100_000_000,
+            status: PositionStatus::Open,
+            bump: position_bump,
+            ..Default::default()
+        };
+        let (stats_key, stats_bump) = pda(&[PROTOCOL_STATS_SEED]);
+        let stats = ProtocolStats { active_positions: 1, bump: stats_bump, ..Default::default() };
+        let (user_account_key, user_account_bump) = pda(&[USER_ACCOUNT_SEED, user.key.as_ref()]);
+        let user_account = UserAccount { open_position_count: 1, bump: user_account_bump, ..Default::default() };
+        let insurance_vault_token = token_account(*quote_mint.key, Pubkey::new_unique(), 0);
+        let (fund_key, fund_bump) = pda(&[INSURANCE_FUND_SEED, market_key.as_ref()]);
+        let insurance_fund = InsuranceFund {
+            vault_token_account: *insurance_vault_token.key,
+            market: market_key,
+            bump: fund_bump,
+            ..Default::default()
+        };
+        Self {
+            config,
+            market,
+            user,
+            user_position: program_account_at(position_key, &position, UserPosition::SPACE),
+            protocol_stats: program_account_at(stats_key, &stats, ProtocolStats::SPACE),
+            user_account: program_account_at(user_account_key, &user_account, UserAccount::SPACE),
+            vault_token: token_account_at(vault_key, *quote_mint.key, config_key, 100_000_000),
+            user_token: token_account(*quote_mint.key, *user.key, 0),
+            fee_destination,
+            insurance_fund: program_account_at(fund_key, &insurance_fund, InsuranceFund::SPACE),
+            insurance_vault_token,
+            oracle: program_account(&OraclePrice { price_fp, last_updated_ts: now, ..Default::default() }, OraclePrice::SPACE),
+            quote_mint,
+            token_program: program(anchor_spl::token::ID),
+            system_program: program(anchor_lang::system_program::ID),
+        }
+    }
+
+    /// The position and its market as a token program called mid-handler
+    /// would find them
+    pub fn settled_state(&self) -> (UserPosition, Market) {
+        (load(self.user_position), load(self.market))
+    }
+}
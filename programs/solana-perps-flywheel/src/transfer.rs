@@ -0,0 +1,151 @@
+// Token movements for both SPL Token and Token-2022 (Token Extensions) mints.
+// Everything goes through `transfer_checked`, which Token-2022 requires once a
+// mint charges a transfer fee. The fee comes out of what the destination
+// receives, so a transfer into an account the program keeps books for is
+// grossed up to land exactly the amount booked.
+
+use anchor_lang::prelude::*;
+use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFeeConfig;
+use anchor_spl::token_2022::spl_token_2022::extension::{BaseStateWithExtensions, StateWithExtensions};
+use anchor_spl::token_2022::spl_token_2022::state::Mint as MintState;
+use anchor_spl::token_interface::{self, Mint, TokenInterface, TransferChecked};
+
+use crate::errors::PerpsError;
+use crate::state::{Config, Market};
+
+/// Reject a transfer through a token program that doesn't own the mint, so a
+/// Token-2022 mint never goes through legacy SPL Token or the other way round
+pub fn ensure_token_program(mint: &InterfaceAccount<Mint>, token_program: &Interface<TokenInterface>) -> Result<()> {
+    require_keys_eq!(*mint.to_account_info().owner, token_program.key(), PerpsError::TokenProgramMismatch);
+    Ok(())
+}
+
+/// The mint's transfer fee schedule at `epoch`, applied to `amount`: the fee
+/// withheld from a transfer of `amount` or, with `gross_up`, the extra a
+/// sender adds so that `amount` arrives. Legacy mints and mints without the
+/// extension charge nothing.
+fn epoch_transfer_fee(mint_data: &[u8], epoch: u64, amount: u64, gross_up: bool) -> Result<u64> {
+    let Ok(mint) = StateWithExtensions::<MintState>::unpack(mint_data) else {
+        return Ok(0);
+    };
+    let Ok(fee_config) = mint.get_extension::<TransferFeeConfig>() else {
+        return Ok(0);
+    };
+    let fee = if gross_up {
+        fee_config.calculate_inverse_epoch_fee(epoch, amount)
+    } else {
+        fee_config.calculate_epoch_fee(epoch, amount)
+    };
+    Ok(fee.ok_or(PerpsError::MathOverflow)?)
+}
+
+fn transfer_fee(mint: &InterfaceAccount<Mint>, amount: u64, gross_up: bool) -> Result<u64> {
+    let info = mint.to_account_info();
+    if *info.owner != anchor_spl::token_2022::ID || amount == 0 {
+        return Ok(0);
+    }
+    let data = info.try_borrow_data()?;
+    epoch_transfer_fee(&data, Clock::get()?.epoch, amount, gross_up)
+}
+
+/// Move `amount` of `mint` from `from` to `to`, signed with `signer_seeds`
+/// when the authority is a program account. Returns what `to` receives once
+/// any transfer fee is withheld.
+pub fn transfer_checked<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<u64> {
+    ensure_token_program(mint, token_program)?;
+    let fee = transfer_fee(mint, amount, false)?;
+    token_interface::transfer_checked(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            TransferChecked { from, mint: mint.to_account_info(), to, authority },
+            signer_seeds,
+        ),
+        amount,
+        mint.decimals,
+    )?;
+    Ok(amount - fee)
+}
+
+/// As `transfer_checked`, but the sender covers any transfer fee so exactly
+/// `amount` lands in `to`. For accounts whose balance the program books:
+/// vaults, escrows and funds. Returns what the sender paid.
+pub fn transfer_checked_exact<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<u64> {
+    let gross = amount
+        .checked_add(transfer_fee(mint, amount, true)?)
+        .ok_or(PerpsError::MathOverflow)?;
+    transfer_checked(token_program, mint, from, to, authority, signer_seeds, gross)?;
+    Ok(gross)
+}
+
+/// Revert unless `market`'s vault still holds everything booked against
+/// it. Called once a path is done moving tokens out of the vault, so a
+/// settlement that overpays can't dip into other traders' margin.
+pub fn vault_solvency_check(vault: &AccountInfo, config: &Config, market: &Market) -> Result<()> {
+    // The balance sits at the same offset in SPL Token and Token-2022 accounts
+    let balance = anchor_spl::token::accessor::amount(vault)?;
+    require!(balance as u128 >= market.vault_obligations(config)?, PerpsError::InsufficientLiquidity);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_spl::token_2022::spl_token_2022::extension::{ExtensionType, StateWithExtensionsMut, BaseStateWithExtensionsMut};
+    use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFee;
+    use anchor_lang::solana_program::program_option::COption;
+
+    fn mint_data(extensions: &[ExtensionType]) -> Vec<u8> {
+        let len = ExtensionType::try_calculate_account_len::<MintState>(extensions).unwrap();
+        vec![0; len]
+    }
+
+    #[test]
+    fn test_transfer_fees_are_withheld_and_grossed_up() {
+        // 1% up to 5 tokens (6 decimals)
+        let mut data = mint_data(&[ExtensionType::TransferFeeConfig]);
+        let mut mint = StateWithExtensionsMut::<MintState>::unpack_uninitialized(&mut data).unwrap();
+        let fee_config = mint.init_extension::<TransferFeeConfig>(true).unwrap();
+        let fee = TransferFee {
+            epoch: 0.into(),
+            maximum_fee: 5_000_000.into(),
+            transfer_fee_basis_points: 100.into(),
+        };
+        fee_config.older_transfer_fee = fee;
+        fee_config.newer_transfer_fee = fee;
+        mint.base = MintState { mint_authority: COption::None, supply: 0, decimals: 6, is_initialized: true, freeze_authority: COption::None };
+        mint.pack_base();
+        mint.init_account_type().unwrap();
+
+        // 1% of 100 withheld; 101.02 sent lands the full 100
+        assert_eq!(epoch_transfer_fee(&data, 10, 100_000_000, false).unwrap(), 1_000_000);
+        let extra = epoch_transfer_fee(&data, 10, 100_000_000, true).unwrap();
+        let gross = 100_000_000 + extra;
+        assert_eq!(gross - epoch_transfer_fee(&data, 10, gross, false).unwrap(), 100_000_000);
+        // Past the maximum the fee is flat
+        assert_eq!(epoch_transfer_fee(&data, 10, 10_000_000_000, false).unwrap(), 5_000_000);
+
+        // A mint without the extension, or a legacy one, charges nothing
+        let mut plain = mint_data(&[]);
+        let mut mint = StateWithExtensionsMut::<MintState>::unpack_uninitialized(&mut plain).unwrap();
+        mint.base = MintState { decimals: 6, is_initialized: true, ..Default::default() };
+        mint.pack_base();
+        assert_eq!(epoch_transfer_fee(&plain, 10, 100_000_000, false).unwrap(), 0);
+        assert_eq!(epoch_transfer_fee(&[0; 82], 10, 100_000_000, true).unwrap(), 0);
+    }
+}
@@ -0,0 +1,298 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { SolanaPerpslywheel } from "../target/types/solana_perps_flywheel";
+import { PublicKey, Keypair, SystemProgram } from "@solana/web3.js";
+import {
+  TOKEN_PROGRAM_ID,
+  createMint,
+  createAccount,
+  getAccount,
+  mintTo,
+} from "@solana/spl-token";
+import { expect } from "chai";
+
+// End-to-end open/close round trips against a live program. Prices are pinned
+// with force_set_oracle_price, so this needs the localnet-only instructions:
+//   anchor test -- --features test-helpers
+describe("close_position settlement (feature = test-helpers)", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+  const program = anchor.workspace.SolanaPerpslywheel as Program<SolanaPerpslywheel>;
+
+  const FP = 1_000_000;
+  const USDC = 1_000_000; // 6-decimal quote
+  const FEE_BPS = 100;
+  const SYMBOL = Buffer.from("CLOSETEST\0\0\0");
+
+  const SPEND = 1_000 * USDC;
+  const LEVERAGE = 5;
+  const ENTRY_PRICE = 100;
+
+  let admin: Keypair;
+  let trader: Keypair;
+  let configPda: PublicKey;
+  let oraclePda: PublicKey;
+  let protocolStatsPda: PublicKey;
+  let vaultPda: PublicKey;
+  let positionPda: PublicKey;
+  let userAccountPda: PublicKey;
+  let insuranceFundPda: PublicKey;
+  let insuranceFundVault: PublicKey;
+  let market: PublicKey;
+  let quoteMint: PublicKey;
+  let feeDestination: PublicKey;
+  let traderToken: PublicKey;
+
+  const pda = (seeds: Buffer[]) => PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+
+  const setPrice = async (price: number) => {
+    await program.methods
+      .forceSetOraclePrice(Array.from(SYMBOL), new anchor.BN(price * FP), new anchor.BN(0), null)
+      .accounts({
+        config: configPda,
+        admin: admin.publicKey,
+        oracle: oraclePda,
+        oracleTwap: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+  };
+
+  const balance = async (account: PublicKey) =>
+    Number((await getAccount(provider.connection, account)).amount);
+
+  const openAt = async (isLong: boolean, price: number) => {
+    await setPrice(price);
+    await program.methods
+      .openPosition(isLong, new anchor.BN(SPEND), LEVERAGE, new anchor.BN(0), new anchor.BN(0), { isolated: {} }, 50)
+      .accountsPartial({
+        user: trader.publicKey,
+        config: configPda,
+        market: market,
+        oracle: oraclePda,
+        pythOracle: null,
+        switchboardOracle: null,
+        userPosition: positionPda,
+        protocolStats: protocolStatsPda,
+        userAccount: userAccountPda,
+        userToken: traderToken,
+        vaultToken: vaultPda,
+        stopLossOrder: null,
+        takeProfitOrder: null,
+        userOrders: null,
+        insuranceFund: null,
+        insuranceVaultToken: null,
+        collateralAccount: null,
+        crossMarginAccount: null,
+        acceptedCollateral: null,
+        collateralOracle: null,
+        positionCollateral: null,
+        collateralVault: null,
+        collateralUserToken: null,
+        collateralMint: null,
+        feeDestination: null,
+        userRateLimit: null,
+        quoteMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([trader])
+      .rpc();
+  };
+
+  const closeAt = async (price: number) => {
+    await setPrice(price);
+    await program.methods
+      .closePosition()
+      .accountsPartial({
+        user: trader.publicKey,
+        config: configPda,
+        market: market,
+        oracle: oraclePda,
+        pythOracle: null,
+        switchboardOracle: null,
+        userPosition: positionPda,
+        protocolStats: protocolStatsPda,
+        userAccount: userAccountPda,
+        userToken: traderToken,
+        vaultToken: vaultPda,
+        feeDestination,
+        marketMaker: null,
+        pendingWithdrawal: null,
+        userRateLimit: null,
+        collateralAccount: null,
+        crossMarginAccount: null,
+        crossVault: null,
+        acceptedCollateral: null,
+        positionCollateral: null,
+        collateralVault: null,
+        collateralUserToken: null,
+        collateralMint: null,
+        insuranceFund: insuranceFundPda,
+        insuranceVaultToken: insuranceFundVault,
+        quoteMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([trader])
+      .rpc();
+  };
+
+  // What the program should pay back, in quote units: margin + pnl - fee on
+  // exit notional, with the fee capped at equity and nothing paid below zero
+  const expectedPayout = (isLong: boolean, exitPrice: number) => {
+    const margin = SPEND / LEVERAGE;
+    const baseSize = Math.floor(SPEND / (ENTRY_PRICE * USDC));
+    const pnl = (isLong ? 1 : -1) * baseSize * (exitPrice - ENTRY_PRICE) * USDC;
+    const equity = margin + pnl;
+    if (equity <= 0) return 0;
+    const fee = Math.min(Math.floor((baseSize * exitPrice * USDC * FEE_BPS) / 10_000), equity);
+    return equity - fee;
+  };
+
+  before(async function () {
+    admin = Keypair.generate();
+    trader = Keypair.generate();
+    for (const kp of [admin, trader]) {
+      await provider.connection.confirmTransaction(
+        await provider.connection.requestAirdrop(kp.publicKey, 10 * anchor.web3.LAMPORTS_PER_SOL)
+      );
+    }
+
+    configPda = pda([Buffer.from("config")]);
+    oraclePda = pda([Buffer.from("oracle"), SYMBOL]);
+    protocolStatsPda = pda([Buffer.from("protocol_stats")]);
+
+    // Another suite may already own the config; prices can only be pinned by its admin
+    if (await provider.connection.getAccountInfo(configPda)) {
+      this.skip();
+    }
+
+    quoteMint = await createMint(provider.connection, admin, admin.publicKey, null, 6);
+    const rewardMint = await createMint(provider.connection, admin, admin.publicKey, null, 9);
+    feeDestination = await createAccount(provider.connection, admin, quoteMint, admin.publicKey);
+    const insuranceVault = await createAccount(
+      provider.connection, admin, quoteMint, Keypair.generate().publicKey
+    );
+
+    await program.methods
+      .initializeConfig(FEE_BPS, 500, 1000)
+      .accounts({
+        config: configPda,
+        quoteMint,
+        feeDestination,
+        insuranceVault,
+        creatorRewardMint: rewardMint,
+        admin: admin.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    // Cases jump the price up to 30% between trades; widen the trade-path
+    // circuit breaker so reopening at the entry price isn't refused
+    await program.methods
+      .updateRiskParameters(null, new anchor.BN(5_000), null)
+      .accounts({ config: configPda, admin: admin.publicKey })
+      .signers([admin])
+      .rpc();
+
+    await setPrice(ENTRY_PRICE);
+
+    // No skew and balanced reserves, so the mark is exactly the oracle price
+    market = pda([Buffer.from("market"), SYMBOL]);
+    await program.methods
+      .createMarket(
+        Array.from(SYMBOL), 6, 0, new anchor.BN(1_000_000), 500, 10,
+        new anchor.BN(1_000 * FP), new anchor.BN(1_000 * FP),
+        new anchor.BN(0), new anchor.BN(0)
+      )
+      .accounts({
+        config: configPda,
+        market: market,
+        oracle: oraclePda,
+        payer: admin.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    vaultPda = pda([Buffer.from("vault"), market.toBuffer()]);
+    await program.methods
+      .initializeMarketVault()
+      .accounts({
+        config: configPda,
+        admin: admin.publicKey,
+        market: market,
+        quoteMint,
+        vaultToken: vaultPda,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    // Closes route the fund's slice of the fee, so the market needs its own fund
+    insuranceFundPda = pda([Buffer.from("insurance_fund"), market.toBuffer()]);
+    insuranceFundVault = await createAccount(
+      provider.connection, admin, quoteMint, insuranceFundPda, Keypair.generate()
+    );
+    await program.methods
+      .initializeMarketInsuranceFund()
+      .accounts({
+        config: configPda,
+        admin: admin.publicKey,
+        market: market,
+        insuranceFund: insuranceFundPda,
+        insuranceVaultToken: insuranceFundVault,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    // Winning trades are paid out of the vault, so give it depth beyond the trader's margin
+    await mintTo(provider.connection, admin, quoteMint, vaultPda, admin, 100_000 * USDC);
+
+    positionPda = pda([Buffer.from("position"), trader.publicKey.toBuffer(), market.toBuffer()]);
+    userAccountPda = pda([Buffer.from("user_account"), trader.publicKey.toBuffer()]);
+    traderToken = await createAccount(provider.connection, trader, quoteMint, trader.publicKey);
+    await mintTo(provider.connection, admin, quoteMint, traderToken, admin, 100_000 * USDC);
+  });
+
+  const cases: Array<[string, boolean, number]> = [
+    ["long closed higher", true, 110],
+    ["long closed lower", true, 95],
+    ["long closed flat", true, ENTRY_PRICE],
+    ["short closed lower", false, 90],
+    ["short closed higher", false, 104],
+    ["short closed flat", false, ENTRY_PRICE],
+    ["long losing more than its margin", true, 70],
+    ["short losing more than its margin", false, 130],
+  ];
+
+  for (const [name, isLong, exitPrice] of cases) {
+    it(`settles a ${name} to margin + pnl - fee`, async () => {
+      const before = await balance(traderToken);
+      await openAt(isLong, ENTRY_PRICE);
+      expect(before - (await balance(traderToken))).to.equal(SPEND / LEVERAGE);
+
+      await closeAt(exitPrice);
+      const received = (await balance(traderToken)) - (before - SPEND / LEVERAGE);
+      // Fixed-point to quote conversion may round by a unit
+      expect(Math.abs(received - expectedPayout(isLong, exitPrice))).to.be.at.most(1);
+
+      const position = await program.account.userPosition.fetch(positionPda);
+      expect(position.baseSize.toNumber()).to.equal(0);
+      expect(position.marginDeposited.toNumber()).to.equal(0);
+      expect(position.status).to.deep.equal({ closed: {} });
+
+      const user = await program.account.userAccount.fetch(userAccountPda);
+      expect(user.openPositionCount).to.equal(0);
+
+      const m = await program.account.market.fetch(market);
+      expect(m.totalLongSize.toNumber()).to.equal(0);
+      expect(m.totalShortSize.toNumber()).to.equal(0);
+    });
+  }
+});
@@ -47,6 +47,42 @@ describe("PerpAscend Protocol - Comprehensive Integration Tests", () => {
 
   const FP = 1_000_000; // Fixed point 1e6
   const BTC_SYMBOL = Buffer.from("BTC\0\0\0\0\0\0\0\0\0");
+  const MAX_SLIPPAGE_BPS = 50;
+
+  const pda = (seeds: Buffer[]) => PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+
+  // Everything open_position takes for a plain isolated open with no orders attached
+  const openPositionAccounts = (user: PublicKey, userToken: PublicKey, userPosition: PublicKey) => ({
+    user,
+    config: configPda,
+    market: marketPda,
+    oracle: oraclePda,
+    pythOracle: null,
+    switchboardOracle: null,
+    userPosition,
+    protocolStats: pda([Buffer.from("protocol_stats")]),
+    userAccount: pda([Buffer.from("user_account"), user.toBuffer()]),
+    userToken,
+    vaultToken: pda([Buffer.from("vault"), marketPda.toBuffer()]),
+    stopLossOrder: null,
+    takeProfitOrder: null,
+    userOrders: null,
+    insuranceFund: null,
+    insuranceVaultToken: null,
+    collateralAccount: null,
+    crossMarginAccount: null,
+    acceptedCollateral: null,
+    collateralOracle: null,
+    positionCollateral: null,
+    collateralVault: null,
+    collateralUserToken: null,
+    collateralMint: null,
+    feeDestination: null,
+    userRateLimit: null,
+    quoteMint,
+    tokenProgram: TOKEN_PROGRAM_ID,
+    systemProgram: SystemProgram.programId,
+  });
 
   before(async () => {
     console.log("🚀 Setting up comprehensive integration tests...");
@@ -286,19 +322,13 @@ describe("PerpAscend Protocol - Comprehensive Integration Tests", () => {
       .openPosition(
         true,  // is_long
         new anchor.BN(quoteToSpend),
-        leverage
+        leverage,
+        new anchor.BN(0), // no stop-loss
+        new anchor.BN(0), // no take-profit
+        { isolated: {} },
+        MAX_SLIPPAGE_BPS
       )
-      .accounts({
-        user: user1.publicKey,
-        config: configPda,
-        market: marketPda,
-        oracle: oraclePda,
-        userPosition: positionPda,
-        userToken: user1QuoteAccount,
-        vaultToken: vaultQuoteAccount,
-        tokenProgram: TOKEN_PROGRAM_ID,
-        systemProgram: SystemProgram.programId,
-      })
+      .accountsPartial(openPositionAccounts(user1.publicKey, user1QuoteAccount, positionPda))
       .signers([user1])
       .rpc();
 
@@ -336,19 +366,13 @@ describe("PerpAscend Protocol - Comprehensive Integration Tests", () => {
       .openPosition(
         false,  // is_long (short position)
         new anchor.BN(quoteToSpend),
-        leverage
+        leverage,
+        new anchor.BN(0), // no stop-loss
+        new anchor.BN(0), // no take-profit
+        { isolated: {} },
+        MAX_SLIPPAGE_BPS
       )
-      .accounts({
-        user: user2.publicKey,
-        config: configPda,
-        market: marketPda,
-        oracle: oraclePda,
-        userPosition: positionPda,
-        userToken: user2QuoteAccount,
-        vaultToken: vaultQuoteAccount,
-        tokenProgram: TOKEN_PROGRAM_ID,
-        systemProgram: SystemProgram.programId,
-      })
+      .accountsPartial(openPositionAccounts(user2.publicKey, user2QuoteAccount, positionPda))
       .signers([user2])
       .rpc();
 
@@ -446,19 +470,13 @@ describe("PerpAscend Protocol - Comprehensive Integration Tests", () => {
         .openPosition(
           true,
           new anchor.BN(500 * 1_000_000),
-          5
+          5,
+          new anchor.BN(0), // no stop-loss
+          new anchor.BN(0), // no take-profit
+          { isolated: {} },
+          MAX_SLIPPAGE_BPS
         )
-        .accounts({
-          user: user1.publicKey,
-          config: configPda,
-          market: marketPda,
-          oracle: oraclePda,
-          userPosition: positionPda,
-          userToken: user1QuoteAccount,
-          vaultToken: vaultQuoteAccount,
-          tokenProgram: TOKEN_PROGRAM_ID,
-          systemProgram: SystemProgram.programId,
-        })
+        .accountsPartial(openPositionAccounts(user1.publicKey, user1QuoteAccount, positionPda))
         .signers([user1])
         .rpc();
       
@@ -0,0 +1,111 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { SolanaPerpslywheel } from "../target/types/solana_perps_flywheel";
+import { PublicKey, Keypair, SystemProgram } from "@solana/web3.js";
+import { createMint, createAccount } from "@solana/spl-token";
+import { expect } from "chai";
+
+// Requires a build with the localnet-only instructions:
+//   anchor test -- --features test-helpers
+describe("Oracle test helpers (feature = test-helpers)", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+  const program = anchor.workspace.SolanaPerpslywheel as Program<SolanaPerpslywheel>;
+
+  const FP = 1_000_000;
+  const SOL_SYMBOL = Buffer.from("SOL\0\0\0\0\0\0\0\0\0");
+
+  let admin: Keypair;
+  let configPda: PublicKey;
+  let oraclePda: PublicKey;
+
+  before(async () => {
+    admin = Keypair.generate();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(admin.publicKey, 10 * anchor.web3.LAMPORTS_PER_SOL)
+    );
+
+    [configPda] = PublicKey.findProgramAddressSync([Buffer.from("config")], program.programId);
+    [oraclePda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("oracle"), SOL_SYMBOL],
+      program.programId
+    );
+
+    const existing = await provider.connection.getAccountInfo(configPda);
+    if (!existing) {
+      const quoteMint = await createMint(provider.connection, admin, admin.publicKey, null, 6);
+      const rewardMint = await createMint(provider.connection, admin, admin.publicKey, null, 9);
+      const feeDestination = await createAccount(provider.connection, admin, quoteMint, admin.publicKey);
+      const insuranceVault = await createAccount(
+        provider.connection, admin, quoteMint, Keypair.generate().publicKey
+      );
+
+      await program.methods
+        .initializeConfig(100, 500, 1000)
+        .accounts({
+          config: configPda,
+          quoteMint,
+          feeDestination,
+          insuranceVault,
+          creatorRewardMint: rewardMint,
+          admin: admin.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([admin])
+        .rpc();
+    }
+  });
+
+  it("seeds an oracle price without the circuit breaker", async () => {
+    await program.methods
+      .forceSetOraclePrice(Array.from(SOL_SYMBOL), new anchor.BN(150 * FP), new anchor.BN(FP / 10), null)
+      .accounts({
+        config: configPda,
+        admin: admin.publicKey,
+        oracle: oraclePda,
+        oracleTwap: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    let oracle = await program.account.oraclePrice.fetch(oraclePda);
+    expect(oracle.priceFp.toNumber()).to.equal(150 * FP);
+    expect(oracle.isValid).to.be.true;
+
+    // A 10x jump would trip the circuit breaker in update_oracle_price
+    await program.methods
+      .forceSetOraclePrice(Array.from(SOL_SYMBOL), new anchor.BN(1500 * FP), new anchor.BN(FP), null)
+      .accounts({
+        config: configPda,
+        admin: admin.publicKey,
+        oracle: oraclePda,
+        oracleTwap: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    oracle = await program.account.oraclePrice.fetch(oraclePda);
+    expect(oracle.priceFp.toNumber()).to.equal(1500 * FP);
+    expect(oracle.confidenceFp.toNumber()).to.equal(FP);
+  });
+
+  it("can backdate the update timestamp to simulate a stale feed", async () => {
+    const staleTs = Math.floor(Date.now() / 1000) - 3600;
+    await program.methods
+      .forceSetOraclePrice(Array.from(SOL_SYMBOL), new anchor.BN(150 * FP), new anchor.BN(0), new anchor.BN(staleTs))
+      .accounts({
+        config: configPda,
+        admin: admin.publicKey,
+        oracle: oraclePda,
+        oracleTwap: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    const oracle = await program.account.oraclePrice.fetch(oraclePda);
+    expect(oracle.lastUpdatedTs.toNumber()).to.equal(staleTs);
+  });
+});
@@ -0,0 +1,270 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { SolanaPerpslywheel } from "../target/types/solana_perps_flywheel";
+import { PublicKey, Keypair, SystemProgram } from "@solana/web3.js";
+import {
+  TOKEN_PROGRAM_ID,
+  createMint,
+  createAccount,
+  getAccount,
+  mintTo,
+} from "@solana/spl-token";
+import { expect } from "chai";
+
+// set_take_profit / execute_take_profit against a live program: the owner arms
+// the order and an unrelated keeper cranks it once the mark crosses. Prices are
+// pinned with force_set_oracle_price, so this needs the localnet-only instructions:
+//   anchor test -- --features test-helpers
+describe("take-profit orders (feature = test-helpers)", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+  const program = anchor.workspace.SolanaPerpslywheel as Program<SolanaPerpslywheel>;
+
+  const FP = 1_000_000;
+  const USDC = 1_000_000; // 6-decimal quote
+  const FEE_BPS = 100;
+  const SYMBOL = Buffer.from("TPTEST\0\0\0\0\0\0");
+
+  const SPEND = 1_000 * USDC;
+  const LEVERAGE = 5;
+  const ENTRY_PRICE = 100;
+
+  let admin: Keypair;
+  let trader: Keypair;
+  let keeper: Keypair;
+  let configPda: PublicKey;
+  let oraclePda: PublicKey;
+  let protocolStatsPda: PublicKey;
+  let vaultPda: PublicKey;
+  let positionPda: PublicKey;
+  let userAccountPda: PublicKey;
+  let takeProfitPda: PublicKey;
+  let userOrdersPda: PublicKey;
+  let market: PublicKey;
+  let quoteMint: PublicKey;
+  let feeDestination: PublicKey;
+  let traderToken: PublicKey;
+
+  const pda = (seeds: Buffer[]) => PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+
+  const setPrice = async (price: number) => {
+    await program.methods
+      .forceSetOraclePrice(Array.from(SYMBOL), new anchor.BN(price * FP), new anchor.BN(0), null)
+      .accounts({
+        config: configPda,
+        admin: admin.publicKey,
+        oracle: oraclePda,
+        oracleTwap: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+  };
+
+  const balance = async (account: PublicKey) =>
+    Number((await getAccount(provider.connection, account)).amount);
+
+  const setTakeProfit = (triggerPrice: number, closePercentage: number) =>
+    program.methods
+      .setTakeProfit(new anchor.BN(triggerPrice * FP), closePercentage, null)
+      .accountsPartial({
+        config: configPda,
+        market,
+        user: trader.publicKey,
+        userPosition: positionPda,
+        takeProfitOrder: takeProfitPda,
+        userOrders: userOrdersPda,
+        oracle: oraclePda,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([trader])
+      .rpc();
+
+  const executeTakeProfit = () =>
+    program.methods
+      .executeTakeProfit()
+      .accountsPartial({
+        config: configPda,
+        market,
+        executor: keeper.publicKey,
+        userPosition: positionPda,
+        protocolStats: protocolStatsPda,
+        userAccount: userAccountPda,
+        takeProfitOrder: takeProfitPda,
+        userOrders: userOrdersPda,
+        vaultToken: vaultPda,
+        userToken: traderToken,
+        feeDestinationToken: feeDestination,
+        userRateLimit: null,
+        oracle: oraclePda,
+        quoteMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([keeper])
+      .rpc();
+
+  before(async function () {
+    admin = Keypair.generate();
+    trader = Keypair.generate();
+    keeper = Keypair.generate();
+    for (const kp of [admin, trader, keeper]) {
+      await provider.connection.confirmTransaction(
+        await provider.connection.requestAirdrop(kp.publicKey, 10 * anchor.web3.LAMPORTS_PER_SOL)
+      );
+    }
+
+    configPda = pda([Buffer.from("config")]);
+    oraclePda = pda([Buffer.from("oracle"), SYMBOL]);
+    protocolStatsPda = pda([Buffer.from("protocol_stats")]);
+
+    // Another suite may already own the config; prices can only be pinned by its admin
+    if (await provider.connection.getAccountInfo(configPda)) {
+      this.skip();
+    }
+
+    quoteMint = await createMint(provider.connection, admin, admin.publicKey, null, 6);
+    const rewardMint = await createMint(provider.connection, admin, admin.publicKey, null, 9);
+    feeDestination = await createAccount(provider.connection, admin, quoteMint, admin.publicKey);
+    const insuranceVault = await createAccount(
+      provider.connection, admin, quoteMint, Keypair.generate().publicKey
+    );
+
+    await program.methods
+      .initializeConfig(FEE_BPS, 500, 1000)
+      .accounts({
+        config: configPda,
+        quoteMint,
+        feeDestination,
+        insuranceVault,
+        creatorRewardMint: rewardMint,
+        admin: admin.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    // The test moves the price 25%; widen the trade-path circuit breaker to match
+    await program.methods
+      .updateRiskParameters(null, new anchor.BN(5_000), null)
+      .accounts({ config: configPda, admin: admin.publicKey })
+      .signers([admin])
+      .rpc();
+
+    await setPrice(ENTRY_PRICE);
+
+    // No skew and balanced reserves, so the mark is exactly the oracle price
+    market = pda([Buffer.from("market"), SYMBOL]);
+    await program.methods
+      .createMarket(
+        Array.from(SYMBOL), 6, 0, new anchor.BN(1_000_000), 500, 10,
+        new anchor.BN(1_000 * FP), new anchor.BN(1_000 * FP),
+        new anchor.BN(0), new anchor.BN(0)
+      )
+      .accounts({
+        config: configPda,
+        market: market,
+        oracle: oraclePda,
+        payer: admin.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    vaultPda = pda([Buffer.from("vault"), market.toBuffer()]);
+    await program.methods
+      .initializeMarketVault()
+      .accounts({
+        config: configPda,
+        admin: admin.publicKey,
+        market: market,
+        quoteMint,
+        vaultToken: vaultPda,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+    await mintTo(provider.connection, admin, quoteMint, vaultPda, admin, 100_000 * USDC);
+
+    positionPda = pda([Buffer.from("position"), trader.publicKey.toBuffer(), market.toBuffer()]);
+    userAccountPda = pda([Buffer.from("user_account"), trader.publicKey.toBuffer()]);
+    takeProfitPda = pda([Buffer.from("take_profit"), trader.publicKey.toBuffer(), market.toBuffer()]);
+    userOrdersPda = pda([Buffer.from("user_orders"), trader.publicKey.toBuffer()]);
+    traderToken = await createAccount(provider.connection, trader, quoteMint, trader.publicKey);
+    await mintTo(provider.connection, admin, quoteMint, traderToken, admin, 100_000 * USDC);
+
+    await program.methods
+      .openPosition(true, new anchor.BN(SPEND), LEVERAGE, new anchor.BN(0), new anchor.BN(0), { isolated: {} }, 50)
+      .accountsPartial({
+        user: trader.publicKey,
+        config: configPda,
+        market: market,
+        oracle: oraclePda,
+        pythOracle: null,
+        switchboardOracle: null,
+        userPosition: positionPda,
+        protocolStats: protocolStatsPda,
+        userAccount: userAccountPda,
+        userToken: traderToken,
+        vaultToken: vaultPda,
+        stopLossOrder: null,
+        takeProfitOrder: null,
+        userOrders: null,
+        insuranceFund: null,
+        insuranceVaultToken: null,
+        collateralAccount: null,
+        crossMarginAccount: null,
+        acceptedCollateral: null,
+        collateralOracle: null,
+        positionCollateral: null,
+        collateralVault: null,
+        collateralUserToken: null,
+        collateralMint: null,
+        feeDestination: null,
+        userRateLimit: null,
+        quoteMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([trader])
+      .rpc();
+  });
+
+  it("refuses a long's trigger at or below the mark", async () => {
+    try {
+      await setTakeProfit(95, 100);
+      expect.fail("a long's take profit below the mark should be refused");
+    } catch (error) {
+      expect(error.toString()).to.include("InvalidTakeProfit");
+    }
+  });
+
+  it("is not executable before the mark reaches the trigger", async () => {
+    await setTakeProfit(120, 50);
+    await setPrice(115);
+    try {
+      await executeTakeProfit();
+      expect.fail("an untriggered take profit should not execute");
+    } catch (error) {
+      expect(error.toString()).to.include("TakeProfitNotTriggered");
+    }
+  });
+
+  it("lets any keeper close the configured share once triggered", async () => {
+    const before = await balance(traderToken);
+    await setPrice(125);
+    await executeTakeProfit();
+
+    // Half of 10 units: $100 of margin back, $125 of pnl, 1% fee on $625 of exit notional
+    const received = (await balance(traderToken)) - before;
+    expect(Math.abs(received - (100 * USDC + 125 * USDC - 6_250_000))).to.be.at.most(1);
+
+    const position = await program.account.userPosition.fetch(positionPda);
+    expect(position.baseSize.toNumber()).to.equal(5);
+    expect(position.status).to.deep.equal({ open: {} });
+
+    const order = await program.account.takeProfitOrder.fetch(takeProfitPda);
+    expect(order.isActive).to.be.false;
+    expect(order.executedAt).to.not.be.null;
+  });
+});